@@ -0,0 +1,319 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::privilege::needs_privilege;
+use crate::{Dots, DotsAddArgs};
+use shared::bos;
+use shared::fs as sfs;
+
+// the part of the structured layout (see DOTFILE_START_DIRS) a new dotfile goes under, before its
+// `home/` or `root/`
+fn layout_prefix(dots: &Dots, args: &DotsAddArgs) -> PathBuf {
+    let env = &dots.env;
+
+    if args.os {
+        let id = env.distro.as_ref().unwrap_or(&env.os);
+        PathBuf::from("os").join(id)
+    } else if args.per_user {
+        PathBuf::from("user").join(&env.user)
+    } else if args.guix_home {
+        PathBuf::from("guix")
+    } else if args.nix_home {
+        PathBuf::from("nix")
+    } else {
+        PathBuf::new()
+    }
+}
+
+// same thing `link` would map the source back to
+fn source_for(dots: &Dots, args: &DotsAddArgs, set_dir: &Path, dest: &Path) -> Result<PathBuf> {
+    let prefix = set_dir.join(layout_prefix(dots, args));
+
+    match dest.strip_prefix(&dots.env.home) {
+        Ok(rel) if !args.root => Ok(prefix.join("home").join(rel)),
+        _ => {
+            let rel = dest
+                .strip_prefix("/")
+                .map_err(|_| anyhow!("Expected an absolute path, got {}", dest.display()))?;
+            Ok(prefix.join("root").join(rel))
+        }
+    }
+}
+
+// the given path, made absolute, without resolving the final component (it may well be a symlink
+// we need to refuse)
+fn absolute(dots: &Dots, path: &Path) -> Result<PathBuf> {
+    let path = dots.absolute_path(path)?;
+
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("Can't add {}", path.display()))?;
+    let parent = match path.parent() {
+        Some(parent) => fs::canonicalize(parent)
+            .with_context(|| format!("Failed to resolve {}", parent.display()))?,
+        None => return Err(anyhow!("Can't add {}", path.display())),
+    };
+
+    Ok(parent.join(name))
+}
+
+fn files_under(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+
+    for entry in fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))? {
+        let path = entry?.path();
+        if sfs::is_dir(&path) && !sfs::is_symlink(&path) {
+            files.extend(files_under(&path)?);
+        } else {
+            files.push(path);
+        }
+    }
+
+    Ok(files)
+}
+
+// `made` gets every link and directory created along the way, in order, so they can be undone
+fn link_all(
+    links: &[(PathBuf, PathBuf)],
+    owner: Option<bos::Owner>,
+    made: &mut Vec<PathBuf>,
+) -> Result<()> {
+    for (link, target) in links.iter() {
+        if let Some(parent) = link.parent() {
+            for dir in sfs::create_dir_all_missing(parent)? {
+                made.push(dir.clone());
+                sfs::chown(&dir, owner)?;
+            }
+        }
+        sfs::create_symlink(target, link)?;
+        made.push(link.clone());
+        sfs::chown(link, owner)?;
+    }
+
+    Ok(())
+}
+
+// undoes a link_all that failed partway and moves what was added back where it came from
+fn roll_back(made: Vec<PathBuf>, source: &Path, dest: &Path) {
+    for path in made.into_iter().rev() {
+        let removed = if sfs::is_symlink(&path) {
+            fs::remove_file(&path)
+        } else {
+            fs::remove_dir(&path)
+        };
+        if let Err(e) = removed {
+            eprintln!("Failed to remove {}: {}", path.display(), e);
+        }
+    }
+    if let Err(e) = sfs::move_file(source, dest) {
+        eprintln!(
+            "Failed to move {} back to {}: {:#}",
+            source.display(),
+            dest.display(),
+            e
+        );
+    }
+}
+
+impl Dots {
+    // `~` expanded, relative to the current directory otherwise
+    pub(crate) fn absolute_path(&self, path: &Path) -> Result<PathBuf> {
+        let path = self.env.expand_home(path);
+        if path.is_absolute() {
+            return Ok(path);
+        }
+        Ok(std::env::current_dir()
+            .context("Failed to get the current directory")?
+            .join(path))
+    }
+
+    pub fn add(&mut self, args: &DotsAddArgs) -> Result<()> {
+        let set_dir = match args.to.as_ref() {
+            Some(to) => self.env.expand_home(to),
+            None => self
+                .args
+                .dotfiles
+                .as_ref()
+                .map(|d| self.env.expand_home(Path::new(d)))
+                .ok_or_else(|| anyhow!("No source set given (use --to <dir>)"))?,
+        };
+        if !sfs::is_dir(&set_dir) {
+            return Err(anyhow!(
+                "Source set {} is not a directory",
+                set_dir.display()
+            ));
+        }
+
+        for path in args.paths.iter() {
+            let dest = absolute(self, path)?;
+            self.add_one(args, &set_dir, &dest)
+                .with_context(|| format!("Failed to add {}", dest.display()))?;
+        }
+
+        Ok(())
+    }
+
+    fn add_one(&mut self, args: &DotsAddArgs, set_dir: &Path, dest: &Path) -> Result<()> {
+        if sfs::is_symlink(dest) {
+            return match self.state.get_source(dest) {
+                Some(source) => Err(anyhow!("Already linked to {}", source.display())),
+                None => Err(anyhow!("It's a symlink; add whatever it points to instead")),
+            };
+        }
+        if !sfs::path_exists(dest) {
+            return Err(anyhow!("No such file or directory"));
+        }
+        if dest.starts_with(set_dir) {
+            return Err(anyhow!("It's already inside the source set"));
+        }
+        if needs_privilege(dest) {
+            return Err(anyhow!(
+                "It needs elevated privileges to move, which `add` doesn't do"
+            ));
+        }
+
+        let source = source_for(self, args, set_dir, dest)?;
+        if sfs::path_exists(&source) {
+            return Err(anyhow!(
+                "{} already exists in the source set",
+                source.display()
+            ));
+        }
+
+        let is_dir = sfs::is_dir(dest);
+
+        if args.dry_run {
+            println!(
+                "[ DRY RUN --- Add ] {} -> {}",
+                dest.display(),
+                source.display()
+            );
+            return Ok(());
+        }
+
+        if let Some(parent) = source.parent() {
+            for dir in sfs::create_dir_all_missing(parent)? {
+                sfs::chown(&dir, self.env.owner)?;
+            }
+        }
+        sfs::move_file(dest, &source)?;
+
+        // directories are linked file by file, same as `link` would; the directories themselves
+        // were the user's, so they aren't recorded as ours to prune
+        let links: Vec<(PathBuf, PathBuf)> = if is_dir {
+            files_under(&source)?
+                .into_iter()
+                .map(|file| (dest.join(file.strip_prefix(&source).unwrap()), file))
+                .collect()
+        } else {
+            vec![(dest.to_path_buf(), source.clone())]
+        };
+
+        let mut made = vec![];
+        if let Err(e) = link_all(&links, self.env.owner, &mut made) {
+            // put everything back the way it was before reporting
+            roll_back(made, &source, dest);
+            return Err(e).context("Failed to link it, nothing was added");
+        }
+
+        println!("[ ADD ] {} -> {}", dest.display(), source.display());
+        for (link, target) in links.into_iter() {
+            if args.verbose {
+                println!("Linked {} -> {}", link.display(), target.display());
+            }
+            self.state
+                .insert_from_set(link, target, set_dir.to_path_buf());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing, Commands};
+
+    fn add_args(paths: &[&Path], to: &Path) -> DotsAddArgs {
+        DotsAddArgs {
+            paths: paths.iter().map(|p| p.to_path_buf()).collect(),
+            to: Some(to.to_path_buf()),
+            os: false,
+            per_user: false,
+            guix_home: false,
+            nix_home: false,
+            root: false,
+            dry_run: false,
+            verbose: false,
+        }
+    }
+
+    #[test]
+    fn added_files_and_dirs_move_into_the_set_and_are_linked_back() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = fs::canonicalize(tmp.path()).unwrap();
+        let home = root.join("home");
+        let set = root.join("set");
+        fs::create_dir_all(home.join(".config/nvim/lua")).unwrap();
+        fs::create_dir_all(&set).unwrap();
+        fs::write(home.join(".bashrc"), "bash").unwrap();
+        fs::write(home.join(".config/nvim/lua/init.lua"), "lua").unwrap();
+
+        let bashrc = home.join(".bashrc");
+        let nvim = home.join(".config/nvim");
+        let args = add_args(&[&bashrc, &nvim], &set);
+        let mut dots = testing::dots(Commands::Add(add_args(&[], &set)), &root, [""; 0]);
+        dots.add(&args).unwrap();
+
+        let source = set.join("home/.bashrc");
+        assert_eq!(fs::read_to_string(&source).unwrap(), "bash");
+        assert_eq!(sfs::read_link(&bashrc).unwrap(), source);
+        assert_eq!(dots.state.source_set(&bashrc), Some(&set));
+
+        // a directory stays a real directory, with its files linked one by one
+        let init = nvim.join("lua/init.lua");
+        assert!(!sfs::is_symlink(&nvim));
+        assert_eq!(
+            sfs::read_link(&init).unwrap(),
+            set.join("home/.config/nvim/lua/init.lua")
+        );
+        assert_eq!(dots.state.len(), 2);
+        assert_eq!(dots.state.dirs().count(), 0);
+
+        // and neither can be added twice
+        let e = dots.add(&add_args(&[&bashrc], &set)).unwrap_err();
+        assert!(format!("{:#}", e).contains("Already linked"), "{:#}", e);
+    }
+
+    #[test]
+    fn a_link_that_fails_puts_everything_back() {
+        let root = tempfile::tempdir().unwrap();
+        let dest = root.path().join("home/.config/app");
+        let source = root.path().join("set/home/.config/app");
+        fs::create_dir_all(dest.join("sub")).unwrap();
+        fs::write(dest.join("sub/a"), "a").unwrap();
+        fs::write(dest.join("b"), "b").unwrap();
+        fs::create_dir_all(source.parent().unwrap()).unwrap();
+        sfs::move_file(&dest, &source).unwrap();
+
+        // the second link can't be made (its parent is a file)
+        let blocked = root.path().join("blocked");
+        fs::write(&blocked, "").unwrap();
+        let links = [
+            (dest.join("sub/a"), source.join("sub/a")),
+            (blocked.join("b"), source.join("b")),
+        ];
+        let mut made = vec![];
+        assert!(link_all(&links, None, &mut made).is_err());
+        assert_eq!(made, [dest.clone(), dest.join("sub"), dest.join("sub/a")]);
+
+        roll_back(made, &source, &dest);
+
+        assert!(!sfs::path_exists(&source));
+        assert_eq!(fs::read_to_string(dest.join("sub/a")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(dest.join("b")).unwrap(), "b");
+        assert!(!sfs::is_symlink(&dest.join("sub/a")));
+    }
+}
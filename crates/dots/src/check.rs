@@ -0,0 +1,1078 @@
+use std::collections::{BTreeSet, HashSet, VecDeque};
+use std::fmt;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use globset::GlobBuilder;
+use serde::Serialize;
+use toml_edit::{ImDocument, Item, TableLike};
+
+use crate::config::{target_config_path, LayeredConfig};
+use crate::decisions::POLICY_REASONS;
+use crate::interp;
+use crate::stats::OutputFormat;
+use crate::trackfile::{Trackfile, DOTFILE_START_DIRS};
+use crate::when;
+use crate::{Dots, DotsCheckArgs};
+use shared::bos;
+use shared::fs as sfs;
+
+// what each table accepts; anything else is reported as unknown
+pub const ROOT_KEYS: &[&str] = &["general", "dots", "dotfiles"];
+pub const GENERAL_KEYS: &[&str] = &["inherits", "strict"];
+pub const OPTION_KEYS: &[&str] = &["use", "use_target", "exclude", "inherits"]; // [dots] and [[dotfiles]]
+pub const DOTS_KEYS: &[&str] = &["elevate", "hooks", "policy", "shell"];
+pub const DOTFILE_KEYS: &[&str] = &[
+    "path",
+    "replace",
+    "use_config",
+    "hooks",
+    "includes",
+    "excludes",
+    "map",
+];
+pub const SHELL_KEYS: &[&str] = &["interpreter", "timeout"];
+pub const HOOK_KEYS: &[&str] = &["pre_link", "post_link", "pre_unlink", "post_unlink"];
+pub const POLICY_KEYS: &[&str] = &["rule"];
+pub const RULE_KEYS: &[&str] = &["dest", "reasons", "action"];
+pub const USE_KEYS: &[&str] = &["when", "target", "exclude"]; // anything else in a `use` table is a var
+pub const WHEN_KEYS: &[&str] = &[
+    "all",
+    "any",
+    "not",
+    "os",
+    "distro",
+    "hostname",
+    "user",
+    "env",
+    "exists",
+    "command",
+    "guix_home",
+    "nix_home",
+    "shell",
+    "if",
+    "test_command",
+];
+// the `when` keys that take an exact value or a glob
+pub const WHEN_PATTERN_KEYS: &[&str] = &["os", "distro", "hostname", "user"];
+pub const VAR_KEYS: &[&str] = &["shell", "env", "value"];
+pub const INHERITABLE: &[&str] = &["use", "use_target", "exclude"];
+pub const ELEVATIONS: &[&str] = &["sudo", "doas", "run0"];
+pub const INTERPRETERS: &[&str] = &["sh", "bash", "fish"];
+pub const POLICY_ACTIONS: &[&str] = &["yes", "no", "backup"];
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Note => write!(f, "note"),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub file: PathBuf,
+    pub line: usize, // 1-based
+    pub col: usize,  // 1-based, in chars
+    pub len: usize,  // chars to underline, at least 1
+    pub message: String,
+    #[serde(skip)]
+    pub span: Option<Range<usize>>, // bytes into the file
+    #[serde(skip)]
+    text: String, // the line it's on
+}
+
+impl Diagnostic {
+    fn new(
+        severity: Severity,
+        file: &Path,
+        content: &str,
+        span: Option<Range<usize>>,
+        message: String,
+    ) -> Self {
+        let (line, col, len, text) = match &span {
+            Some(span) => {
+                let start = span.start.min(content.len());
+                let line_start = content[..start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+                let line_end = content[start..]
+                    .find('\n')
+                    .map(|i| start + i)
+                    .unwrap_or(content.len());
+                let end = span.end.clamp(start, line_end);
+
+                (
+                    content[..start].matches('\n').count() + 1,
+                    content[line_start..start].chars().count() + 1,
+                    content[start..end].chars().count().max(1),
+                    content[line_start..line_end].trim_end().to_string(),
+                )
+            }
+            None => (1, 1, 1, String::new()),
+        };
+
+        Self {
+            severity,
+            file: file.to_path_buf(),
+            line,
+            col,
+            len,
+            message,
+            span,
+            text,
+        }
+    }
+
+    // rustc style: where, then the line itself with the offending part underlined
+    pub fn render(&self) -> String {
+        let number = self.line.to_string();
+        let gutter = " ".repeat(number.len());
+        let mut out = format!(
+            "{}: {}\n{} --> {}:{}:{}\n",
+            self.severity,
+            self.message,
+            gutter,
+            self.file.display(),
+            self.line,
+            self.col
+        );
+
+        if !self.text.is_empty() {
+            // tabs would throw the caret off
+            let text = self.text.replace('\t', " ");
+            out.push_str(&format!("{} |\n", gutter));
+            out.push_str(&format!("{} | {}\n", number, text));
+            out.push_str(&format!(
+                "{} | {}{}\n",
+                gutter,
+                " ".repeat(self.col - 1),
+                "^".repeat(self.len)
+            ));
+        }
+
+        out
+    }
+}
+
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            cur.push((prev[j] + cost).min(prev[j + 1] + 1).min(cur[j] + 1));
+        }
+        prev = cur;
+    }
+
+    prev[b.len()]
+}
+
+// a `use` table or entry's own vars, i.e., what its placeholders can refer to
+type Vars = BTreeSet<String>;
+
+struct FileCheck<'a> {
+    file: &'a Path,
+    content: &'a str,
+    env: &'a bos::Env,
+    base: PathBuf, // relative paths in the file resolve against this
+    diagnostics: Vec<Diagnostic>,
+    reachable: Vec<PathBuf>, // other configs its [[dotfiles]] point at
+}
+
+impl<'a> FileCheck<'a> {
+    fn error(&mut self, span: Option<Range<usize>>, message: String) {
+        self.diagnostics.push(Diagnostic::new(
+            Severity::Error,
+            self.file,
+            self.content,
+            span,
+            message,
+        ));
+    }
+
+    fn warn(&mut self, span: Option<Range<usize>>, message: String) {
+        self.diagnostics.push(Diagnostic::new(
+            Severity::Warning,
+            self.file,
+            self.content,
+            span,
+            message,
+        ));
+    }
+
+    fn note(&mut self, span: Option<Range<usize>>, message: String) {
+        self.diagnostics.push(Diagnostic::new(
+            Severity::Note,
+            self.file,
+            self.content,
+            span,
+            message,
+        ));
+    }
+
+    // narrows a key or string's span down to `offset..offset + len` of its text, accounting for
+    // the quotes around it (if any)
+    fn narrow(
+        &self,
+        span: Option<Range<usize>>,
+        offset: usize,
+        len: usize,
+    ) -> Option<Range<usize>> {
+        let span = span?;
+        let raw = self.content.get(span.clone())?;
+        let quote = usize::from(raw.starts_with('"') || raw.starts_with('\''));
+        let start = span.start + quote + offset;
+        Some(start..(start + len).min(span.end))
+    }
+
+    fn key_span(table: &dyn TableLike, key: &str) -> Option<Range<usize>> {
+        table
+            .key(key)
+            .and_then(|k| k.span())
+            .or_else(|| table.get(key).and_then(Item::span))
+    }
+
+    fn unknown_keys(&mut self, table: &dyn TableLike, allowed: &[&[&str]], what: &str) {
+        for (key, _) in table.iter() {
+            if allowed.iter().any(|keys| keys.contains(&key)) {
+                continue;
+            }
+
+            let closest = allowed
+                .iter()
+                .flat_map(|keys| keys.iter())
+                .map(|k| (distance(key, k), k))
+                .filter(|(d, _)| *d <= 2)
+                .min();
+            let message = match closest {
+                Some((_, k)) => {
+                    format!("unknown key `{}` in {} (did you mean `{}`?)", key, what, k)
+                }
+                None => format!("unknown key `{}` in {}", key, what),
+            };
+            self.error(Self::key_span(table, key), message);
+        }
+    }
+
+    // a table, inline or not
+    fn table<'i>(&mut self, item: &'i Item, what: &str) -> Option<&'i dyn TableLike> {
+        let table = item.as_table_like();
+        if table.is_none() {
+            self.error(item.span(), format!("{} should be a table", what));
+        }
+        table
+    }
+
+    // `[[x]]`, or `x = [{ ... }, ...]`
+    fn tables<'i>(
+        &mut self,
+        item: &'i Item,
+        what: &str,
+    ) -> Vec<(&'i dyn TableLike, Option<Range<usize>>)> {
+        if let Some(aot) = item.as_array_of_tables() {
+            return aot
+                .iter()
+                .map(|t| (t as &dyn TableLike, t.span()))
+                .collect();
+        }
+        if let Some(array) = item.as_array() {
+            let mut tables = vec![];
+            for value in array.iter() {
+                match value.as_inline_table() {
+                    Some(t) => tables.push((t as &dyn TableLike, value.span())),
+                    None => self.error(value.span(), format!("{} should only hold tables", what)),
+                }
+            }
+            return tables;
+        }
+
+        self.error(
+            item.span(),
+            format!("{} should be an array of tables", what),
+        );
+        vec![]
+    }
+
+    fn string<'i>(&mut self, item: &'i Item, what: &str) -> Option<&'i str> {
+        let s = item.as_str();
+        if s.is_none() {
+            self.error(item.span(), format!("{} should be a string", what));
+        }
+        s
+    }
+
+    fn bool(&mut self, item: &Item, what: &str) {
+        if item.as_bool().is_none() {
+            self.error(item.span(), format!("{} should be true or false", what));
+        }
+    }
+
+    // a string, or an array of them
+    fn strings<'i>(&mut self, item: &'i Item, what: &str) -> Vec<(&'i str, Option<Range<usize>>)> {
+        if let Some(s) = item.as_str() {
+            return vec![(s, item.span())];
+        }
+        if let Some(array) = item.as_array() {
+            let mut strings = vec![];
+            for value in array.iter() {
+                match value.as_str() {
+                    Some(s) => strings.push((s, value.span())),
+                    None => self.error(value.span(), format!("{} should only hold strings", what)),
+                }
+            }
+            return strings;
+        }
+
+        self.error(
+            item.span(),
+            format!("{} should be a string or an array of strings", what),
+        );
+        vec![]
+    }
+
+    fn one_of(&mut self, item: &Item, allowed: &[&str], what: &str) {
+        let Some(s) = self.string(item, what) else {
+            return;
+        };
+        if !allowed.contains(&s) {
+            self.error(
+                item.span(),
+                format!("{} must be one of: {}", what, allowed.join(", ")),
+            );
+        }
+    }
+
+    fn undefined_placeholders(&mut self, path: &str, span: Option<Range<usize>>, vars: &Vars) {
+        let names = match interp::vars(path) {
+            Ok(names) => names,
+            Err(e) => {
+                self.error(span, format!("invalid template: {}", e));
+                return;
+            }
+        };
+        for name in names {
+            if !vars.contains(&name) {
+                // point at where it's spelled out, defaults included
+                let placeholder = format!("<{}>", name);
+                let narrowed = match path.find(&placeholder) {
+                    Some(offset) => self.narrow(span.clone(), offset, placeholder.len()),
+                    None => span.clone(),
+                };
+                self.error(
+                    narrowed,
+                    format!(
+                        "undefined placeholder `<{}>` (no var `{}` is set here)",
+                        name, name
+                    ),
+                );
+            }
+        }
+    }
+
+    fn check_root(&mut self, root: &toml_edit::Table) {
+        self.unknown_keys(root, &[ROOT_KEYS], "the top level");
+
+        if let Some(item) = root.get("general") {
+            if let Some(general) = self.table(item, "`general`") {
+                self.unknown_keys(general, &[GENERAL_KEYS], "[general]");
+                if let Some(strict) = general.get("strict") {
+                    self.bool(strict, "`strict`");
+                }
+            }
+        }
+
+        if let Some(item) = root.get("dots") {
+            if let Some(dots) = self.table(item, "`dots`") {
+                self.check_dots(dots);
+            }
+        }
+
+        if let Some(item) = root.get("dotfiles") {
+            for (dotfile, span) in self.tables(item, "`dotfiles`") {
+                self.check_dotfile(dotfile, span);
+            }
+        }
+    }
+
+    fn check_dots(&mut self, dots: &dyn TableLike) {
+        self.unknown_keys(dots, &[OPTION_KEYS, DOTS_KEYS], "[dots]");
+
+        let base = self.base.clone();
+        self.check_options(dots, Some(&base));
+
+        if let Some(item) = dots.get("elevate") {
+            self.one_of(item, ELEVATIONS, "`elevate`");
+        }
+        if let Some(item) = dots.get("hooks") {
+            self.check_hooks(item);
+        }
+        if let Some(item) = dots.get("shell") {
+            if let Some(shell) = self.table(item, "`shell`") {
+                self.unknown_keys(shell, &[SHELL_KEYS], "[dots.shell]");
+                if let Some(interpreter) = shell.get("interpreter") {
+                    self.one_of(interpreter, INTERPRETERS, "`interpreter`");
+                }
+                if let Some(timeout) = shell.get("timeout") {
+                    if !timeout.as_integer().is_some_and(|t| t > 0) {
+                        self.error(
+                            timeout.span(),
+                            "`timeout` should be a positive number of seconds".to_string(),
+                        );
+                    }
+                }
+            }
+        }
+        if let Some(item) = dots.get("policy") {
+            let Some(policy) = self.table(item, "`policy`") else {
+                return;
+            };
+            self.unknown_keys(policy, &[POLICY_KEYS], "[dots.policy]");
+            if let Some(rules) = policy.get("rule") {
+                for (rule, span) in self.tables(rules, "`rule`") {
+                    self.check_rule(rule, span);
+                }
+            }
+        }
+    }
+
+    fn check_rule(&mut self, rule: &dyn TableLike, span: Option<Range<usize>>) {
+        self.unknown_keys(rule, &[RULE_KEYS], "[[dots.policy.rule]]");
+
+        match rule.get("dest") {
+            Some(dest) => {
+                self.string(dest, "`dest`");
+            }
+            None => self.error(span.clone(), "policy rule has no `dest`".to_string()),
+        }
+        match rule.get("action") {
+            Some(action) => self.one_of(action, POLICY_ACTIONS, "`action`"),
+            None => self.error(span, "policy rule has no `action`".to_string()),
+        }
+        if let Some(reasons) = rule.get("reasons") {
+            for (reason, span) in self.strings(reasons, "`reasons`") {
+                if !POLICY_REASONS.contains(&reason) {
+                    self.error(
+                        span,
+                        format!(
+                            "unknown reason `{}` (expected one of: {})",
+                            reason,
+                            POLICY_REASONS.join(", ")
+                        ),
+                    );
+                }
+            }
+        }
+    }
+
+    fn check_hooks(&mut self, item: &Item) {
+        let Some(hooks) = self.table(item, "`hooks`") else {
+            return;
+        };
+        self.unknown_keys(hooks, &[HOOK_KEYS], "hooks");
+        for (key, value) in hooks.iter() {
+            if HOOK_KEYS.contains(&key) {
+                self.string(value, &format!("`{}`", key));
+            }
+        }
+    }
+
+    fn check_dotfile(&mut self, dotfile: &dyn TableLike, span: Option<Range<usize>>) {
+        self.unknown_keys(dotfile, &[OPTION_KEYS, DOTFILE_KEYS], "[[dotfiles]]");
+
+        // where this entry's own relative paths point into; unknown for remote sets
+        let mut set_dir = None;
+        match dotfile.get("path") {
+            None => self.error(span, "`[[dotfiles]]` entry has no `path`".to_string()),
+            Some(item) => {
+                if let Some(path) = self.string(item, "`path`") {
+                    if path.is_empty() {
+                        self.error(item.span(), "empty `path`".to_string());
+                    } else if !path.contains("://") {
+                        let resolved = self.base.join(self.env.expand_home(Path::new(path)));
+                        if sfs::is_file(&resolved) {
+                            self.reachable.push(resolved);
+                        } else if sfs::is_dir(&resolved) {
+                            if let Ok(Some(config)) = Trackfile::find_config(&resolved) {
+                                self.reachable.push(config);
+                            }
+                            set_dir = Some(resolved);
+                        } else {
+                            self.warn(
+                                item.span(),
+                                format!("`{}` doesn't exist", resolved.display()),
+                            );
+                        }
+                    }
+                }
+            }
+        }
+
+        for key in ["replace", "use_config"] {
+            if let Some(item) = dotfile.get(key) {
+                self.bool(item, &format!("`{}`", key));
+            }
+        }
+        if let Some(item) = dotfile.get("hooks") {
+            self.check_hooks(item);
+        }
+
+        if let Some(item) = dotfile.get("includes") {
+            for (include, span) in self.strings(item, "`includes`") {
+                let first = include.split('/').next().unwrap_or_default();
+                if !DOTFILE_START_DIRS.contains(&first) {
+                    self.error(
+                        span,
+                        format!(
+                            "`{}` isn't under any of the top-level directories ({})",
+                            include,
+                            DOTFILE_START_DIRS.join(", ")
+                        ),
+                    );
+                }
+            }
+        }
+        if let Some(item) = dotfile.get("excludes") {
+            self.check_exclude(item, set_dir.as_deref(), None);
+        }
+
+        if let Some(item) = dotfile.get("map") {
+            if let Some(map) = self.table(item, "`map`") {
+                for (key, value) in map.iter() {
+                    if key.is_empty() {
+                        self.error(
+                            Self::key_span(map, key),
+                            "empty source in `map`".to_string(),
+                        );
+                    }
+                    if let Some(dest) = self.string(value, "a `map` destination") {
+                        if dest.is_empty() {
+                            self.error(value.span(), format!("empty destination for `{}`", key));
+                        }
+                    }
+                }
+            }
+        }
+
+        self.check_options(dotfile, set_dir.as_deref());
+    }
+
+    // `use`, `use_target`, `exclude` and `inherits`, shared by [dots] and [[dotfiles]]
+    fn check_options(&mut self, options: &dyn TableLike, set_dir: Option<&Path>) {
+        let has_use_target = options.contains_key("use_target");
+        if let Some(item) = options.get("use_target") {
+            // the vars come from whichever `use` entry ends up using it
+            self.check_target(item, None);
+        }
+
+        if let Some(item) = options.get("exclude") {
+            self.check_exclude(item, set_dir, None);
+        }
+
+        if let Some(item) = options.get("inherits") {
+            for (inherit, span) in self.strings(item, "`inherits`") {
+                if !INHERITABLE.contains(&inherit) {
+                    self.error(
+                        span,
+                        format!(
+                            "`{}` can't be inherited (expected one of: {})",
+                            inherit,
+                            INHERITABLE.join(", ")
+                        ),
+                    );
+                }
+            }
+        }
+
+        let Some(item) = options.get("use") else {
+            return;
+        };
+        let Some(uses) = self.table(item, "`use`") else {
+            return;
+        };
+
+        for (key, item) in uses.iter() {
+            let key_span = Self::key_span(uses, key);
+
+            if let Some(target) = item.as_str() {
+                self.undefined_placeholders(key, key_span, &Vars::new());
+                self.check_target_path(target, item.span(), Some(&Vars::new()));
+            } else if item.as_bool().is_some() {
+                self.undefined_placeholders(key, key_span.clone(), &Vars::new());
+                if !has_use_target {
+                    self.error(
+                        key_span,
+                        format!(
+                            "`use.\"{}\"` has no target (and there's no `use_target`)",
+                            key
+                        ),
+                    );
+                }
+            } else if let Some(table) = item.as_table_like() {
+                self.check_use(key, key_span, table, set_dir, has_use_target);
+            } else if item.is_array() || item.is_array_of_tables() {
+                for (table, _) in self.tables(item, &format!("`use.\"{}\"`", key)) {
+                    self.check_use(key, key_span.clone(), table, set_dir, has_use_target);
+                }
+            } else {
+                self.error(
+                    item.span(),
+                    format!("`use.\"{}\"` should be a target, true/false, a table or an array of tables", key),
+                );
+            }
+        }
+    }
+
+    fn check_use(
+        &mut self,
+        key: &str,
+        key_span: Option<Range<usize>>,
+        table: &dyn TableLike,
+        set_dir: Option<&Path>,
+        has_use_target: bool,
+    ) {
+        let mut vars = Vars::new();
+        let mut templates = vec![];
+        for (name, value) in table.iter() {
+            if USE_KEYS.contains(&name) {
+                continue;
+            }
+            vars.insert(name.to_string());
+
+            // can refer to the others, which are only all known once we're through
+            if let Some(template) = value.as_str() {
+                templates.push((template, value.span()));
+                continue;
+            }
+            let Some(var) = value.as_table_like() else {
+                self.error(
+                    value.span(),
+                    format!(
+                        "var `{}` should be a string or a table of `shell`, `env` or `value`",
+                        name
+                    ),
+                );
+                continue;
+            };
+
+            self.unknown_keys(var, &[VAR_KEYS], &format!("var `{}`", name));
+            let set: Vec<&str> = VAR_KEYS
+                .iter()
+                .copied()
+                .filter(|k| var.contains_key(k))
+                .collect();
+            match set.len() {
+                0 => self.error(
+                    Self::key_span(table, name),
+                    format!("var `{}` needs one of `shell`, `env` or `value`", name),
+                ),
+                1 => {}
+                _ => self.error(
+                    Self::key_span(table, name),
+                    format!(
+                        "var `{}` sets {}; only one of `shell`, `env` or `value` can be used",
+                        name,
+                        set.iter()
+                            .map(|k| format!("`{}`", k))
+                            .collect::<Vec<_>>()
+                            .join(" and ")
+                    ),
+                ),
+            }
+        }
+
+        for (template, span) in templates {
+            self.undefined_placeholders(template, span, &vars);
+        }
+        self.undefined_placeholders(key, key_span.clone(), &vars);
+
+        if let Some(when) = table.get("when") {
+            if when.as_bool().is_none() {
+                if let Some(when_table) = self.table(when, "`when`") {
+                    self.check_when(when_table);
+                    self.when_outcome(when, Self::key_span(table, "when"));
+                }
+            }
+        }
+
+        match table.get("target") {
+            Some(target) => self.check_target(target, Some(&vars)),
+            None if !has_use_target => self.error(
+                key_span,
+                format!(
+                    "`use.\"{}\"` has no `target` (and there's no `use_target`)",
+                    key
+                ),
+            ),
+            None => {}
+        }
+
+        if let Some(exclude) = table.get("exclude") {
+            self.check_exclude(exclude, set_dir, Some(&vars));
+        }
+    }
+
+    // a path, or a table of suffix = path
+    fn check_when(&mut self, when: &dyn TableLike) {
+        self.unknown_keys(when, &[WHEN_KEYS], "`when`");
+
+        for key in WHEN_PATTERN_KEYS {
+            if let Some(item) = when.get(key) {
+                if let Some(pattern) = self.string(item, &format!("`{}`", key)) {
+                    self.pattern(pattern, item.span());
+                }
+            }
+        }
+        if let Some(item) = when.get("env") {
+            if let Some(vars) = self.table(item, "`env`") {
+                for (var, item) in vars.iter() {
+                    if let Some(pattern) = self.string(item, &format!("`env.{}`", var)) {
+                        self.pattern(pattern, item.span());
+                    }
+                }
+            }
+        }
+        for key in ["exists", "command", "shell", "if", "test_command"] {
+            if let Some(item) = when.get(key) {
+                self.string(item, &format!("`{}`", key));
+            }
+        }
+        for key in ["guix_home", "nix_home"] {
+            if let Some(item) = when.get(key) {
+                self.bool(item, &format!("`{}`", key));
+            }
+        }
+
+        for key in ["all", "any"] {
+            if let Some(item) = when.get(key) {
+                for (nested, _) in self.tables(item, &format!("`{}`", key)) {
+                    self.check_when(nested);
+                }
+            }
+        }
+        if let Some(item) = when.get("not") {
+            if let Some(nested) = self.table(item, "`not`") {
+                self.check_when(nested);
+            }
+        }
+    }
+
+    // what it comes to on this machine, and which predicates settled it. left out if that would
+    // take running a shell
+    fn when_outcome(&mut self, item: &Item, span: Option<Range<usize>>) {
+        let Ok(value) = item.clone().into_value() else {
+            return;
+        };
+        // a bad pattern has already been reported
+        let Some(Ok(verdict)) = when::check_when(&value.to_string(), self.env) else {
+            return;
+        };
+
+        if verdict.holds {
+            self.note(span, format!("`when` holds here ({})", verdict));
+        } else {
+            self.note(
+                span,
+                format!(
+                    "`when` doesn't hold here, so this entry is skipped ({})",
+                    verdict
+                ),
+            );
+        }
+    }
+
+    fn pattern(&mut self, pattern: &str, span: Option<Range<usize>>) {
+        if let Err(e) = GlobBuilder::new(pattern).build() {
+            self.error(span, format!("invalid pattern `{}`: {}", pattern, e.kind()));
+        }
+    }
+
+    fn check_target(&mut self, item: &Item, vars: Option<&Vars>) {
+        if let Some(target) = item.as_str() {
+            self.check_target_path(target, item.span(), vars);
+            return;
+        }
+
+        let Some(targets) = item.as_table_like() else {
+            self.error(
+                item.span(),
+                "`target` should be a path or a table of suffix = path".to_string(),
+            );
+            return;
+        };
+        if targets.is_empty() {
+            self.error(item.span(), "empty `target`".to_string());
+        }
+        for (suffix, value) in targets.iter() {
+            if let Some(vars) = vars {
+                self.undefined_placeholders(suffix, Self::key_span(targets, suffix), vars);
+            }
+            if let Some(target) = self.string(value, "a target") {
+                self.check_target_path(target, value.span(), vars);
+            }
+        }
+    }
+
+    fn check_target_path(&mut self, target: &str, span: Option<Range<usize>>, vars: Option<&Vars>) {
+        if target.trim().is_empty() {
+            self.error(span, "empty target".to_string());
+            return;
+        }
+        if let Some(offset) = target.find('*') {
+            self.error(
+                self.narrow(span.clone(), offset, 1),
+                "`*` can't be used in a target, it has to resolve to a single path".to_string(),
+            );
+        }
+        if let Some(vars) = vars {
+            self.undefined_placeholders(target, span, vars);
+        }
+    }
+
+    fn check_exclude(&mut self, item: &Item, set_dir: Option<&Path>, vars: Option<&Vars>) {
+        for (exclude, span) in self.strings(item, "`exclude`") {
+            if exclude.is_empty() {
+                self.error(span, "empty exclude".to_string());
+                continue;
+            }
+            if let Some(vars) = vars {
+                self.undefined_placeholders(exclude, span.clone(), vars);
+            }
+
+            // anything resolved at link time can't be checked from here
+            let Some(set_dir) = set_dir else {
+                continue;
+            };
+            if exclude.contains(['<', '*', '$']) {
+                continue;
+            }
+            let path = set_dir.join(self.env.expand_home(Path::new(exclude)));
+            if !sfs::path_exists(&path) {
+                self.warn(
+                    span,
+                    format!(
+                        "exclude `{}` matches nothing in {}",
+                        exclude,
+                        set_dir.display()
+                    ),
+                );
+            }
+        }
+    }
+}
+
+// everything wrong with one config, and the other configs it points to. works off `content`
+// rather than what's on disk, so unsaved buffers can be checked too
+pub fn check_config(file: &Path, content: &str, env: &bos::Env) -> (Vec<Diagnostic>, Vec<PathBuf>) {
+    let doc = match ImDocument::parse(content) {
+        Ok(doc) => doc,
+        Err(e) => {
+            let diagnostic = Diagnostic::new(
+                Severity::Error,
+                file,
+                content,
+                e.span(),
+                e.message().trim().replace('\n', ": "),
+            );
+            return (vec![diagnostic], vec![]);
+        }
+    };
+
+    let mut check = FileCheck {
+        file,
+        content,
+        env,
+        base: file.parent().map(Path::to_path_buf).unwrap_or_default(),
+        diagnostics: vec![],
+        reachable: vec![],
+    };
+    check.check_root(doc.as_table());
+
+    (check.diagnostics, check.reachable)
+}
+
+// the given configs, and every config reachable from them through [[dotfiles]]
+pub fn check_all(files: Vec<PathBuf>, env: &bos::Env) -> (Vec<Diagnostic>, usize) {
+    let mut diagnostics = vec![];
+    let mut seen = HashSet::new();
+    let mut queue: VecDeque<PathBuf> = files.into();
+
+    while let Some(file) = queue.pop_front() {
+        let key = std::fs::canonicalize(&file).unwrap_or_else(|_| file.clone());
+        if !seen.insert(key) {
+            continue;
+        }
+
+        let content = match sfs::read_to_string(&file) {
+            Ok(content) => content,
+            Err(e) => {
+                diagnostics.push(Diagnostic::new(
+                    Severity::Error,
+                    &file,
+                    "",
+                    None,
+                    format!("{:#}", e),
+                ));
+                continue;
+            }
+        };
+
+        let (found, reachable) = check_config(&file, &content, env);
+        diagnostics.extend(found);
+        queue.extend(reachable);
+    }
+
+    (diagnostics, seen.len())
+}
+
+#[derive(Serialize)]
+struct CheckReport<'a> {
+    files: usize,
+    errors: usize,
+    warnings: usize,
+    diagnostics: &'a [Diagnostic],
+}
+
+impl Dots {
+    // runs before any config is loaded, since a broken one is what it's there to find
+    pub fn check(&self, args: &DotsCheckArgs) -> Result<()> {
+        let target = match args.target.clone().or_else(|| self.args.dotfiles.clone()) {
+            Some(target) => Some(self.absolute_path(Path::new(&target))?),
+            None => None,
+        };
+
+        let files: Vec<PathBuf> = LayeredConfig::files(&self.env, target.as_deref())?
+            .into_iter()
+            .filter_map(|(_, path)| path.filter(|p| sfs::is_file(p)))
+            .collect();
+        if let Some(target) = target.as_ref() {
+            if args.target.is_some() && target_config_path(target)?.is_none() {
+                return Err(anyhow!("No config found at {}", target.display()));
+            }
+        }
+        if files.is_empty() {
+            println!("No config to check");
+            return Ok(());
+        }
+
+        let (diagnostics, checked) = check_all(files, &self.env);
+        let errors = diagnostics
+            .iter()
+            .filter(|d| d.severity == Severity::Error)
+            .count();
+        let warnings = diagnostics
+            .iter()
+            .filter(|d| d.severity == Severity::Warning)
+            .count();
+
+        match self.args.format {
+            OutputFormat::Text => {
+                for diagnostic in diagnostics.iter() {
+                    println!("{}", diagnostic.render());
+                }
+                println!(
+                    "Checked {} file(s): {} error(s), {} warning(s)",
+                    checked, errors, warnings
+                );
+            }
+            OutputFormat::Json => {
+                let report = CheckReport {
+                    files: checked,
+                    errors,
+                    warnings,
+                    diagnostics: &diagnostics,
+                };
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&report)
+                        .context("Failed to serialize diagnostics")?
+                );
+            }
+        }
+
+        if errors > 0 {
+            return Err(anyhow!("{} error(s) in config", errors));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(content: &str) -> Vec<Diagnostic> {
+        let env = bos::Env::detect();
+        check_config(Path::new("/nonexistent/dots.toml"), content, &env).0
+    }
+
+    fn only(content: &str) -> Diagnostic {
+        let mut diagnostics = check(content);
+        assert_eq!(diagnostics.len(), 1, "{:?}", diagnostics);
+        diagnostics.remove(0)
+    }
+
+    #[test]
+    fn unknown_key_points_at_the_key() {
+        let d = only("[dots]\nelevat = \"sudo\"\n");
+        assert_eq!(d.severity, Severity::Error);
+        assert_eq!((d.line, d.col, d.len), (2, 1, 6));
+        assert!(
+            d.message.contains("did you mean `elevate`?"),
+            "{}",
+            d.message
+        );
+    }
+
+    #[test]
+    fn bad_value_points_at_the_value() {
+        let d = only("[dots]\n  elevate = \"su\"\n");
+        assert_eq!((d.line, d.col, d.len), (2, 13, 4));
+        assert!(d.message.starts_with("`elevate` must be one of"));
+    }
+
+    #[test]
+    fn placeholder_is_narrowed_to_where_it_is_spelled_out() {
+        let d = only("[dots.use.\"config\"]\ntarget = \"~/<nmae>/x\"\nname = \"a\"\n");
+        assert_eq!((d.line, d.col, d.len), (2, 13, 6));
+        assert!(d.message.contains("`<nmae>`"));
+    }
+
+    #[test]
+    fn columns_count_chars_not_bytes() {
+        let d = only("[dots.use]\n\"é\" = { name = \"a\", target = \"<nope>\" }\n");
+        assert_eq!((d.line, d.col, d.len), (2, 31, 6));
+
+        let rendered = d.render();
+        assert!(
+            rendered.contains(" --> /nonexistent/dots.toml:2:31\n"),
+            "{}",
+            rendered
+        );
+        assert!(
+            rendered.ends_with(&format!(" | {}^^^^^^\n", " ".repeat(30))),
+            "{}",
+            rendered
+        );
+    }
+
+    #[test]
+    fn parse_errors_are_diagnostics_too() {
+        let d = only("[dots]\nelevate = \n");
+        assert_eq!(d.severity, Severity::Error);
+        assert_eq!(d.line, 2);
+    }
+}
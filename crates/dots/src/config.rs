@@ -0,0 +1,635 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
+use toml::{Table, Value};
+use toml_edit::ImDocument;
+
+use crate::stats::OutputFormat;
+use crate::{Dots, DotsConfigShowArgs};
+use shared::bos;
+use shared::fs as sfs;
+
+pub const SYSTEM_CONFIG: &str = "/etc/bos/config.toml";
+
+// first hit wins within each layer
+const USER_CONFIG_NAMES: [&str; 4] = [
+    "dots/config.toml",
+    "dots/config",
+    "bos/config.toml",
+    "bos/config",
+];
+const HOME_CONFIG_NAMES: [&str; 4] = [".dots.toml", ".bos.toml", ".dots", ".bos"];
+
+// lowest to highest precedence
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Layer {
+    System,
+    User,
+    Home,
+    Target,
+    Cli,
+}
+
+impl fmt::Display for Layer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Layer::System => write!(f, "system"),
+            Layer::User => write!(f, "user"),
+            Layer::Home => write!(f, "home"),
+            Layer::Target => write!(f, "target"),
+            Layer::Cli => write!(f, "command line"),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct Origin {
+    pub layer: Layer,
+    pub path: Option<PathBuf>, // None for command line overrides
+    pub line: Option<usize>,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.path, self.line) {
+            (Some(path), Some(line)) => write!(f, "{}:{}", path.display(), line),
+            (Some(path), None) => write!(f, "{}", path.display()),
+            (None, _) => write!(f, "{}", self.layer),
+        }
+    }
+}
+
+// one config file (or the command line overrides), with where each of its values is
+pub struct ConfigLayer {
+    pub layer: Layer,
+    pub path: Option<PathBuf>,
+    table: Table,
+    origins: BTreeMap<String, Origin>, // dotted key of every leaf value
+    content: String,                   // as read, for vetting exactly what was parsed
+}
+
+fn join_key(prefix: &str, key: &str) -> String {
+    if prefix.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", prefix, key)
+    }
+}
+
+fn line_of(content: &str, offset: usize) -> usize {
+    content[..offset.min(content.len())].matches('\n').count() + 1
+}
+
+// tables are descended into (they merge key by key); everything else, arrays included, is a leaf
+// that a later layer replaces wholesale
+fn collect_origins(
+    table: &toml_edit::Table,
+    prefix: &str,
+    content: &str,
+    origin: &Origin,
+    origins: &mut BTreeMap<String, Origin>,
+) {
+    for (key, item) in table.iter() {
+        let path = join_key(prefix, key);
+        let span = table
+            .key(key)
+            .and_then(|k| k.span())
+            .or_else(|| item.span())
+            .or_else(|| {
+                item.as_array_of_tables()
+                    .and_then(|aot| aot.get(0))
+                    .and_then(|t| t.span())
+            });
+        let here = Origin {
+            line: span.map(|s| line_of(content, s.start)).or(origin.line),
+            ..origin.clone()
+        };
+
+        match item {
+            toml_edit::Item::Table(t) => collect_origins(t, &path, content, &here, origins),
+            toml_edit::Item::Value(toml_edit::Value::InlineTable(t)) => {
+                collect_inline_origins(t, &path, content, &here, origins)
+            }
+            _ => {
+                origins.insert(path, here);
+            }
+        }
+    }
+}
+
+fn collect_inline_origins(
+    table: &toml_edit::InlineTable,
+    prefix: &str,
+    content: &str,
+    origin: &Origin,
+    origins: &mut BTreeMap<String, Origin>,
+) {
+    for (key, value) in table.iter() {
+        let path = join_key(prefix, key);
+        let span = table
+            .key(key)
+            .and_then(|k| k.span())
+            .or_else(|| value.span());
+        let here = Origin {
+            line: span.map(|s| line_of(content, s.start)).or(origin.line),
+            ..origin.clone()
+        };
+
+        match value {
+            toml_edit::Value::InlineTable(t) => {
+                collect_inline_origins(t, &path, content, &here, origins)
+            }
+            _ => {
+                origins.insert(path, here);
+            }
+        }
+    }
+}
+
+impl ConfigLayer {
+    pub fn read(layer: Layer, path: &Path) -> Result<Self> {
+        let content = sfs::read_to_string(path)?;
+
+        let table: Table = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse config {}", path.display()))?;
+        let doc = ImDocument::parse(content.as_str())
+            .with_context(|| format!("Failed to parse config {}", path.display()))?;
+
+        let origin = Origin {
+            layer,
+            path: Some(path.to_path_buf()),
+            line: None,
+        };
+        let mut origins = BTreeMap::new();
+        collect_origins(doc.as_table(), "", &content, &origin, &mut origins);
+
+        Ok(Self {
+            layer,
+            path: Some(path.to_path_buf()),
+            table,
+            origins,
+            content,
+        })
+    }
+
+    // `key.path=value`; the value is read as TOML if it parses, a plain string otherwise
+    pub fn from_overrides(overrides: &[String]) -> Result<Self> {
+        let mut table = Table::new();
+        let mut origins = BTreeMap::new();
+
+        for entry in overrides.iter() {
+            let (key, raw) = entry
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Invalid override `{}` (expected KEY=VALUE)", entry))?;
+            let key = key.trim();
+            if key.is_empty() || key.split('.').any(str::is_empty) {
+                return Err(anyhow!("Invalid key in override `{}`", entry));
+            }
+
+            let value = toml::from_str::<Table>(&format!("v = {}", raw))
+                .ok()
+                .and_then(|mut t| t.remove("v"))
+                .unwrap_or_else(|| Value::String(raw.to_string()));
+
+            let mut parts: Vec<&str> = key.split('.').collect();
+            let last = parts.pop().unwrap();
+            let mut current = &mut table;
+            for part in parts.into_iter() {
+                let next = current
+                    .entry(part.to_string())
+                    .or_insert_with(|| Value::Table(Table::new()));
+                if !next.is_table() {
+                    *next = Value::Table(Table::new());
+                }
+                current = next.as_table_mut().unwrap();
+            }
+            current.insert(last.to_string(), value);
+
+            origins.retain(|path: &String, _| !path.starts_with(&format!("{}.", key)));
+            origins.insert(
+                key.to_string(),
+                Origin {
+                    layer: Layer::Cli,
+                    path: None,
+                    line: None,
+                },
+            );
+        }
+
+        Ok(Self {
+            layer: Layer::Cli,
+            path: None,
+            table,
+            origins,
+            content: String::new(),
+        })
+    }
+}
+
+fn first_existing(dir: &Path, names: &[&str]) -> Option<PathBuf> {
+    names
+        .iter()
+        .map(|name| dir.join(name))
+        .find(|path| sfs::is_file(path))
+}
+
+// XDG_CONFIG_HOME is only ours if we're not acting for someone else
+fn config_home(env: &bos::Env) -> PathBuf {
+    match std::env::var("XDG_CONFIG_HOME") {
+        Ok(dir) if !dir.is_empty() && env.owner.is_none() => PathBuf::from(dir),
+        _ => env.home.join(".config"),
+    }
+}
+
+// where the user layer and the home layer look, i.e., the configs that are the user's own (not
+// ones that came along with a target)
+pub fn user_config_paths(env: &bos::Env) -> Vec<PathBuf> {
+    let config_home = config_home(env);
+    let dirs = ["dots", "bos"].into_iter().map(|dir| config_home.join(dir));
+    let files = HOME_CONFIG_NAMES.iter().map(|name| env.home.join(name));
+    dirs.chain(files).collect()
+}
+
+// the config file a target brings along: the file itself, or whatever `Trackfile` would pick up
+// in the directory
+pub fn target_config_path(target: &Path) -> Result<Option<PathBuf>> {
+    if sfs::is_file(target) {
+        return Ok(Some(target.to_path_buf()));
+    }
+    if sfs::is_dir(target) {
+        return crate::trackfile::Trackfile::find_config(target);
+    }
+    Ok(None)
+}
+
+// every layer that exists, merged: tables key by key, anything else replaced by the later layer
+#[derive(Default)]
+pub struct LayeredConfig {
+    layers: Vec<(Layer, Option<PathBuf>)>,
+    sources: Vec<(PathBuf, String)>, // every file's content, as it was parsed
+    table: Table,
+    origins: BTreeMap<String, Origin>,
+}
+
+impl LayeredConfig {
+    // every file that would make up a layer, lowest precedence first, existing or not
+    pub fn files(env: &bos::Env, target: Option<&Path>) -> Result<Vec<(Layer, Option<PathBuf>)>> {
+        let mut files = vec![];
+
+        files.push((Layer::System, Some(PathBuf::from(SYSTEM_CONFIG))));
+
+        files.push((
+            Layer::User,
+            first_existing(&config_home(env), &USER_CONFIG_NAMES),
+        ));
+        files.push((Layer::Home, first_existing(&env.home, &HOME_CONFIG_NAMES)));
+
+        if let Some(target) = target {
+            files.push((Layer::Target, target_config_path(target)?));
+        }
+
+        Ok(files)
+    }
+
+    pub fn load(env: &bos::Env, target: Option<&Path>, overrides: &[String]) -> Result<Self> {
+        let mut config = Self::default();
+        for (layer, path) in Self::files(env, target)?.into_iter() {
+            let Some(path) = path.filter(|p| sfs::is_file(p)) else {
+                continue;
+            };
+            config.push(ConfigLayer::read(layer, &path)?);
+        }
+        if !overrides.is_empty() {
+            config.push(ConfigLayer::from_overrides(overrides)?);
+        }
+
+        Ok(config)
+    }
+
+    pub fn push(&mut self, layer: ConfigLayer) {
+        merge(&mut self.table, &layer.table, "", &layer, &mut self.origins);
+        if let Some(path) = layer.path.clone() {
+            self.sources.push((path, layer.content));
+        }
+        self.layers.push((layer.layer, layer.path));
+    }
+
+    pub fn layers(&self) -> &[(Layer, Option<PathBuf>)] {
+        &self.layers
+    }
+
+    pub fn sources(&self) -> &[(PathBuf, String)] {
+        &self.sources
+    }
+
+    pub fn table(&self) -> &Table {
+        &self.table
+    }
+
+    pub fn origin(&self, key: &str) -> Option<&Origin> {
+        self.origins.get(key)
+    }
+
+    // every leaf value with its dotted key, in key order
+    pub fn values(&self) -> Vec<(String, &Value, Option<&Origin>)> {
+        let mut values = vec![];
+        flatten(&self.table, "", &mut values);
+        values
+            .into_iter()
+            .map(|(key, value)| {
+                let origin = self.origins.get(&key);
+                (key, value, origin)
+            })
+            .collect()
+    }
+
+    // the most specific file that contributed, for anything resolving paths relative to it
+    pub fn path(&self) -> Option<&PathBuf> {
+        self.layers.iter().rev().find_map(|(_, path)| path.as_ref())
+    }
+}
+
+fn merge(
+    into: &mut Table,
+    from: &Table,
+    prefix: &str,
+    layer: &ConfigLayer,
+    origins: &mut BTreeMap<String, Origin>,
+) {
+    for (key, value) in from.iter() {
+        let path = join_key(prefix, key);
+
+        if let (Some(Value::Table(into)), Value::Table(from)) = (into.get_mut(key), value) {
+            merge(into, from, &path, layer, origins);
+            continue;
+        }
+
+        into.insert(key.clone(), value.clone());
+
+        // whatever was under this key before is gone now
+        let nested = format!("{}.", path);
+        origins.retain(|p, _| *p != path && !p.starts_with(&nested));
+        for (p, origin) in layer
+            .origins
+            .range(path.clone()..)
+            .filter(|(p, _)| **p == path || p.starts_with(&nested))
+        {
+            origins.insert(p.clone(), origin.clone());
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ShownValue<'a> {
+    key: String,
+    value: &'a Value,
+    origin: Option<&'a Origin>,
+}
+
+#[derive(Serialize)]
+struct ShownLayer<'a> {
+    layer: Layer,
+    path: Option<&'a PathBuf>,
+}
+
+#[derive(Serialize)]
+struct Shown<'a> {
+    layers: Vec<ShownLayer<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    config: Option<&'a Table>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    values: Option<Vec<ShownValue<'a>>>, // with --origin
+}
+
+impl Dots {
+    pub fn config_show(&self, args: &DotsConfigShowArgs) -> Result<()> {
+        let layers = &self.layers;
+
+        if let OutputFormat::Json = self.args.format {
+            let shown: Vec<ShownLayer> = layers
+                .layers()
+                .iter()
+                .map(|(layer, path)| ShownLayer {
+                    layer: *layer,
+                    path: path.as_ref(),
+                })
+                .collect();
+            let shown = if args.origin {
+                let values = layers
+                    .values()
+                    .into_iter()
+                    .map(|(key, value, origin)| ShownValue { key, value, origin })
+                    .collect();
+                Shown {
+                    layers: shown,
+                    config: None,
+                    values: Some(values),
+                }
+            } else {
+                Shown {
+                    layers: shown,
+                    config: Some(layers.table()),
+                    values: None,
+                }
+            };
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&shown).context("Failed to serialize config")?
+            );
+            return Ok(());
+        }
+
+        if layers.layers().is_empty() {
+            println!("# no config found");
+            return Ok(());
+        }
+        println!("# layers, lowest precedence first:");
+        for (layer, path) in layers.layers().iter() {
+            match path {
+                Some(path) => println!("#   {}: {}", layer, path.display()),
+                None => println!("#   {}", layer),
+            }
+        }
+        println!();
+
+        if !args.origin {
+            print!(
+                "{}",
+                toml::to_string_pretty(layers.table()).context("Failed to serialize config")?
+            );
+            return Ok(());
+        }
+
+        for (key, value, origin) in layers.values() {
+            match origin {
+                Some(origin) => println!("{} = {}  # {}", key, value, origin),
+                None => println!("{} = {}", key, value),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn flatten<'a>(table: &'a Table, prefix: &str, values: &mut Vec<(String, &'a Value)>) {
+    for (key, value) in table.iter() {
+        let path = join_key(prefix, key);
+        match value {
+            Value::Table(t) => flatten(t, &path, values),
+            _ => values.push((path, value)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn layer(root: &Path, layer: Layer, content: &str) -> ConfigLayer {
+        let path = root.join(format!("{}.toml", layer));
+        fs::create_dir_all(root).unwrap();
+        fs::write(&path, content).unwrap();
+        ConfigLayer::read(layer, &path).unwrap()
+    }
+
+    fn at(config: &LayeredConfig, key: &str) -> (Layer, Option<usize>) {
+        let origin = config.origin(key).unwrap();
+        (origin.layer, origin.line)
+    }
+
+    #[test]
+    fn later_layer_replaces_a_value() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let mut config = LayeredConfig::default();
+        config.push(layer(root, Layer::User, "[general]\nstrict = false\n"));
+        config.push(layer(root, Layer::Home, "\n[general]\nstrict = true\n"));
+
+        assert_eq!(config.table()["general"]["strict"], Value::Boolean(true));
+        assert_eq!(at(&config, "general.strict"), (Layer::Home, Some(3)));
+        assert_eq!(
+            config.origin("general.strict").unwrap().path,
+            Some(root.join("home.toml"))
+        );
+    }
+
+    #[test]
+    fn tables_merge_key_by_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let mut config = LayeredConfig::default();
+        config.push(layer(
+            root,
+            Layer::System,
+            "[dots]\nelevate = \"doas\"\n\n[dots.shell]\ntimeout = 5\n",
+        ));
+        config.push(layer(
+            root,
+            Layer::User,
+            "[dots.shell]\ninterpreter = \"bash\"\n",
+        ));
+
+        let shell = &config.table()["dots"]["shell"];
+        assert_eq!(shell["timeout"], Value::Integer(5));
+        assert_eq!(shell["interpreter"], Value::String("bash".to_string()));
+        assert_eq!(at(&config, "dots.elevate"), (Layer::System, Some(2)));
+        assert_eq!(at(&config, "dots.shell.timeout"), (Layer::System, Some(5)));
+        assert_eq!(
+            at(&config, "dots.shell.interpreter"),
+            (Layer::User, Some(2))
+        );
+    }
+
+    #[test]
+    fn arrays_are_replaced_wholesale() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let mut config = LayeredConfig::default();
+        config.push(layer(
+            root,
+            Layer::Home,
+            "[dots]\nexclude = [\"a\", \"b\"]\n",
+        ));
+        config.push(layer(root, Layer::Target, "[dots]\nexclude = [\"c\"]\n"));
+
+        assert_eq!(
+            config.table()["dots"]["exclude"],
+            Value::Array(vec![Value::String("c".to_string())])
+        );
+        assert_eq!(at(&config, "dots.exclude"), (Layer::Target, Some(2)));
+    }
+
+    #[test]
+    fn inline_table_keys_get_the_line_they_are_on() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let mut config = LayeredConfig::default();
+        config.push(layer(
+            root,
+            Layer::Home,
+            "[dots]\nelevate = \"sudo\"\nshell = { timeout = 5, interpreter = \"fish\" }\n",
+        ));
+
+        assert_eq!(at(&config, "dots.elevate"), (Layer::Home, Some(2)));
+        assert_eq!(at(&config, "dots.shell.timeout"), (Layer::Home, Some(3)));
+        assert_eq!(
+            at(&config, "dots.shell.interpreter"),
+            (Layer::Home, Some(3))
+        );
+    }
+
+    #[test]
+    fn overrides_win_and_are_read_as_toml_when_they_parse() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let mut config = LayeredConfig::default();
+        config.push(layer(
+            root,
+            Layer::Home,
+            "[dots]\nelevate = \"sudo\"\n[dots.shell]\ntimeout = 5\n",
+        ));
+        config.push(
+            ConfigLayer::from_overrides(&[
+                "dots.elevate=doas".to_string(),
+                "dots.shell.timeout=30".to_string(),
+            ])
+            .unwrap(),
+        );
+
+        assert_eq!(
+            config.table()["dots"]["elevate"],
+            Value::String("doas".to_string())
+        );
+        assert_eq!(
+            config.table()["dots"]["shell"]["timeout"],
+            Value::Integer(30)
+        );
+        assert_eq!(at(&config, "dots.shell.timeout"), (Layer::Cli, None));
+        assert_eq!(config.origin("dots.elevate").unwrap().path, None);
+        assert_eq!(config.layers().last(), Some(&(Layer::Cli, None)));
+    }
+
+    #[test]
+    fn a_value_replacing_a_table_drops_what_was_under_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let mut config = LayeredConfig::default();
+        config.push(layer(root, Layer::Home, "[dots.shell]\ntimeout = 5\n"));
+        config.push(ConfigLayer::from_overrides(&["dots.shell=false".to_string()]).unwrap());
+
+        assert_eq!(config.table()["dots"]["shell"], Value::Boolean(false));
+        assert_eq!(at(&config, "dots.shell"), (Layer::Cli, None));
+        assert!(config.origin("dots.shell.timeout").is_none());
+    }
+
+    #[test]
+    fn bad_overrides_are_refused() {
+        assert!(ConfigLayer::from_overrides(&["dots.elevate".to_string()]).is_err());
+        assert!(ConfigLayer::from_overrides(&["dots..elevate=doas".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,313 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use globset::{GlobBuilder, GlobMatcher};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::handlers::{Reason, UserChoiceState};
+use shared::bos;
+use shared::fs as sfs;
+
+// the only reasons a policy can answer for; everything else never needs a flag or a prompt
+pub const POLICY_REASONS: [&str; 4] = [
+    "force_dangerously",
+    "force_file",
+    "force_symlink",
+    "force_correct_symlink",
+];
+
+// under the cache dir; where --save-answers writes to unless --policy says otherwise
+pub const DEFAULT_POLICY_FILE: &str = "policy.toml";
+
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyAction {
+    Yes,
+    No,
+    Backup, // yes, but move whatever is in the way aside first
+}
+
+impl fmt::Display for PolicyAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PolicyAction::Yes => write!(f, "yes"),
+            PolicyAction::No => write!(f, "no"),
+            PolicyAction::Backup => write!(f, "backup"),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyRule {
+    pub dest: String, // glob over the (absolute) destination, `~` is expanded
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reasons: Vec<String>, // empty matches any of POLICY_REASONS
+    pub action: PolicyAction,
+}
+
+impl PolicyRule {
+    fn validate(&self) -> Result<()> {
+        for reason in self.reasons.iter() {
+            if !POLICY_REASONS.contains(&reason.as_str()) {
+                return Err(anyhow!(
+                    "Unknown reason `{}` in policy rule for `{}` (expected one of: {})",
+                    reason,
+                    self.dest,
+                    POLICY_REASONS.join(", ")
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn matches_reason(&self, reason: &Reason) -> bool {
+        self.reasons.is_empty() || self.reasons.iter().any(|r| r == reason.key())
+    }
+}
+
+// `[[rule]]` in a policy file, `[[dots.policy.rule]]` in config
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyRules {
+    #[serde(default, rename = "rule")]
+    pub rules: Vec<PolicyRule>,
+}
+
+#[derive(Debug, Default)]
+pub struct DecisionPolicy {
+    // only the file's own rules are ever written back; config rules come after them
+    file_rules: Vec<PolicyRule>,
+    config_rules: Vec<PolicyRule>,
+    matchers: Vec<GlobMatcher>, // file_rules then config_rules, same order
+    path: Option<PathBuf>,
+    dirty: bool,
+}
+
+impl DecisionPolicy {
+    pub fn load(path: &Path, config: Option<&PolicyRules>, env: &bos::Env) -> Result<Self> {
+        let file_rules = if sfs::path_exists(path) {
+            let content = sfs::read_to_string(path)?;
+            let rules: PolicyRules = toml::from_str(&content)
+                .with_context(|| format!("Failed to parse policy file {}", path.display()))?;
+            rules.rules
+        } else {
+            vec![]
+        };
+
+        let mut policy = Self {
+            file_rules,
+            config_rules: config.map(|c| c.rules.clone()).unwrap_or_default(),
+            path: Some(path.to_path_buf()),
+            ..Default::default()
+        };
+        policy.compile(env)?;
+
+        Ok(policy)
+    }
+
+    fn compile(&mut self, env: &bos::Env) -> Result<()> {
+        self.matchers = vec![];
+        for rule in self.file_rules.iter().chain(self.config_rules.iter()) {
+            rule.validate()?;
+
+            let pattern = env.expand_home(Path::new(&rule.dest));
+            let glob = GlobBuilder::new(&pattern.to_string_lossy())
+                .literal_separator(true)
+                .build()
+                .with_context(|| format!("Invalid destination glob `{}` in policy", rule.dest))?;
+            self.matchers.push(glob.compile_matcher());
+        }
+        Ok(())
+    }
+
+    fn rules(&self) -> impl Iterator<Item = &PolicyRule> {
+        self.file_rules.iter().chain(self.config_rules.iter())
+    }
+
+    // first matching rule wins
+    pub fn decide(&self, dest: &Path, reason: &Reason) -> Option<PolicyAction> {
+        self.rules()
+            .zip(self.matchers.iter())
+            .find(|(rule, matcher)| rule.matches_reason(reason) && matcher.is_match(dest))
+            .map(|(rule, _)| rule.action)
+    }
+
+    // "yes to all"/"no to all" answers apply to every destination for that reason, so that's what
+    // gets saved. appended, so anything more specific that was already there still wins
+    pub fn remember(&mut self, user_choices: &UserChoiceState, env: &bos::Env) -> Result<()> {
+        for (reason, always) in user_choices.decided() {
+            let action = if always {
+                PolicyAction::Yes
+            } else {
+                PolicyAction::No
+            };
+
+            let already = self.file_rules.iter().any(|rule| {
+                rule.dest == "**" && rule.reasons == [reason.key()] && rule.action == action
+            });
+            if already {
+                continue;
+            }
+
+            self.file_rules.push(PolicyRule {
+                dest: "**".to_string(),
+                reasons: vec![reason.key().to_string()],
+                action,
+            });
+            self.dirty = true;
+        }
+
+        self.compile(env)
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    // under sudo the file (and whatever directories it needed) belongs to the user, like the
+    // trackfile
+    pub fn save(&mut self, env: &bos::Env) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if !self.dirty {
+            return Ok(());
+        }
+
+        let rules = PolicyRules {
+            rules: self.file_rules.clone(),
+        };
+        let content = toml::to_string_pretty(&rules).context("Failed to serialize policy")?;
+
+        if let Some(parent) = path.parent() {
+            for dir in sfs::create_dir_all_missing(parent)? {
+                sfs::chown(&dir, env.owner)?;
+            }
+        }
+        sfs::write_file(path, content.as_bytes())
+            .with_context(|| format!("Failed to write policy file {}", path.display()))?;
+        sfs::chown(path, env.owner)?;
+
+        self.dirty = false;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::fs;
+
+    #[test]
+    fn the_first_rule_matching_both_glob_and_reason_wins() {
+        let root = tempfile::tempdir().unwrap();
+        let env = testing::env(root.path());
+        let path = root.path().join("policy.toml");
+        fs::write(
+            &path,
+            r#"
+[[rule]]
+dest = "~/.config/**"
+reasons = ["force_file"]
+action = "backup"
+
+[[rule]]
+dest = "/etc/*"
+action = "no"
+"#,
+        )
+        .unwrap();
+        let config: PolicyRules =
+            toml::from_str("[[rule]]\ndest = \"**\"\naction = \"yes\"\n").unwrap();
+        let policy = DecisionPolicy::load(&path, Some(&config), &env).unwrap();
+
+        let init = env.home.join(".config/nvim/init.lua");
+        assert_eq!(
+            policy.decide(&init, &Reason::ForceFile),
+            Some(PolicyAction::Backup)
+        );
+        // a rule naming reasons leaves the others to later rules
+        assert_eq!(
+            policy.decide(&init, &Reason::ForceSymlink),
+            Some(PolicyAction::Yes)
+        );
+
+        // the policy file goes before config, and `*` stays within a directory
+        let hosts = Path::new("/etc/hosts");
+        assert_eq!(
+            policy.decide(hosts, &Reason::ForceDangerously),
+            Some(PolicyAction::No)
+        );
+        let sshd = Path::new("/etc/ssh/sshd_config");
+        assert_eq!(
+            policy.decide(sshd, &Reason::ForceFile),
+            Some(PolicyAction::Yes)
+        );
+
+        let empty = DecisionPolicy::load(&root.path().join("none.toml"), None, &env).unwrap();
+        assert_eq!(empty.decide(&init, &Reason::ForceFile), None);
+    }
+
+    #[test]
+    fn bad_rules_are_refused_on_load() {
+        let root = tempfile::tempdir().unwrap();
+        let env = testing::env(root.path());
+        let path = root.path().join("policy.toml");
+
+        for (rule, message) in [
+            (
+                "dest = \"**\"\nreasons = [\"force_everything\"]",
+                "Unknown reason",
+            ),
+            ("dest = \"[a\"", "Invalid destination glob"),
+        ] {
+            let config: PolicyRules =
+                toml::from_str(&format!("[[rule]]\n{}\naction = \"yes\"\n", rule)).unwrap();
+            let e = DecisionPolicy::load(&path, Some(&config), &env).unwrap_err();
+            assert!(format!("{:#}", e).contains(message), "{:#}", e);
+        }
+    }
+
+    #[test]
+    fn answers_for_everything_are_saved_once_and_after_whats_there() {
+        let root = tempfile::tempdir().unwrap();
+        let env = testing::env(root.path());
+        let path = root.path().join("policy/policy.toml");
+        let config: PolicyRules =
+            toml::from_str("[[rule]]\ndest = \"**\"\naction = \"no\"\n").unwrap();
+        let mut policy = DecisionPolicy::load(&path, Some(&config), &env).unwrap();
+
+        let mut choices = UserChoiceState::default();
+        choices.set_always(Reason::ForceFile).unwrap();
+        choices.set_never(Reason::ForceSymlink).unwrap();
+        policy.remember(&choices, &env).unwrap();
+        assert!(policy.is_dirty());
+        policy.save(&env).unwrap();
+
+        policy.remember(&choices, &env).unwrap();
+        assert!(!policy.is_dirty());
+
+        // only the file's own rules are written, and they're read back ahead of config
+        let saved: PolicyRules = toml::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(saved.rules.len(), 2);
+        let policy = DecisionPolicy::load(&path, Some(&config), &env).unwrap();
+        let dest = env.home.join(".bashrc");
+        assert_eq!(
+            policy.decide(&dest, &Reason::ForceFile),
+            Some(PolicyAction::Yes)
+        );
+        assert_eq!(
+            policy.decide(&dest, &Reason::ForceSymlink),
+            Some(PolicyAction::No)
+        );
+        assert_eq!(
+            policy.decide(&dest, &Reason::ForceDangerously),
+            Some(PolicyAction::No)
+        );
+    }
+}
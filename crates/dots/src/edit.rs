@@ -0,0 +1,288 @@
+use std::collections::BTreeSet;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::handlers::LinkOptions;
+use crate::prompt::confirm;
+use crate::trackfile::*;
+use crate::{Dots, DotsEditArgs, DotsLinkArgs};
+use shared::fs as sfs;
+
+// $VISUAL, then $EDITOR, then vi. may carry its own arguments (e.g., `code --wait`)
+fn editor() -> Result<(String, Vec<String>)> {
+    let editor = env::var("VISUAL")
+        .ok()
+        .filter(|e| !e.trim().is_empty())
+        .or_else(|| env::var("EDITOR").ok().filter(|e| !e.trim().is_empty()))
+        .unwrap_or_else(|| "vi".to_string());
+
+    let mut parts = editor.split_whitespace().map(String::from);
+    let program = parts
+        .next()
+        .ok_or_else(|| anyhow!("No editor set (use $VISUAL or $EDITOR)"))?;
+
+    Ok((program, parts.collect()))
+}
+
+// everything next to the source (and below it), to tell afterwards whether the edit created or
+// renamed anything a relink would need to pick up
+fn snapshot(dir: &Path) -> Result<BTreeSet<PathBuf>> {
+    let mut files = BTreeSet::new();
+    if !sfs::is_dir(dir) {
+        return Ok(files);
+    }
+
+    for entry in fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))? {
+        let path = entry?.path();
+        if sfs::is_dir(&path) && !sfs::is_symlink(&path) {
+            files.extend(snapshot(&path)?);
+        } else {
+            files.insert(path);
+        }
+    }
+
+    Ok(files)
+}
+
+// editor swap and backup files come and go on every save; they're never a reason to relink
+fn is_scratch(path: &Path) -> bool {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    name.ends_with('~')
+        || name.ends_with(".swp")
+        || name.ends_with(".swo")
+        || (name.starts_with(".#") || (name.starts_with('#') && name.ends_with('#')))
+}
+
+// what's at a destination, next to the source we'd open for it
+#[derive(Debug, PartialEq, Eq)]
+enum Entry {
+    Missing,
+    Linked,
+    // a link to something other than the source: output rendered from it, which the next render
+    // overwrites
+    Rendered(PathBuf),
+    // a real file standing in for the source (copy mode), which edits to the source never reach
+    Copied,
+}
+
+fn entry(dest: &Path, source: &Path) -> Entry {
+    if !sfs::path_exists(dest) {
+        return Entry::Missing;
+    }
+    if !sfs::is_symlink(dest) {
+        return Entry::Copied;
+    }
+
+    match sfs::read_link(dest) {
+        Ok(points_to) => {
+            let points_to = match dest.parent() {
+                Some(parent) if points_to.is_relative() => parent.join(points_to),
+                _ => points_to,
+            };
+            // the link may spell the source differently (relative, through other links)
+            let same = |a: &Path, b: &Path| match (fs::canonicalize(a), fs::canonicalize(b)) {
+                (Ok(a), Ok(b)) => a == b,
+                _ => a == b,
+            };
+            if same(&points_to, source) {
+                Entry::Linked
+            } else {
+                Entry::Rendered(points_to)
+            }
+        }
+        Err(_) => Entry::Linked,
+    }
+}
+
+impl Dots {
+    pub fn edit(&mut self, args: &DotsEditArgs) -> Result<()> {
+        let target = args.target.clone().or_else(|| self.args.dotfiles.clone());
+        let dest = self.absolute_path(&args.dest)?;
+
+        // the trackfile knows what's actually linked; only fall back to planning a fresh link when
+        // it doesn't
+        let tracked = self.tracked_source(&dest).cloned();
+        let source = match tracked.clone() {
+            Some(source) => source,
+            None => {
+                let target = target.clone().ok_or_else(|| {
+                    anyhow!(
+                        "{} isn't tracked and no dotfiles target was given (use --target)",
+                        dest.display()
+                    )
+                })?;
+                let plan = Trackfile::generate(target, &self.env, None, &mut *self.prompter)
+                    .context("Failed to resolve link targets")?;
+                plan.get_source(&dest)
+                    .cloned()
+                    .ok_or_else(|| anyhow!("{} isn't managed by these dotfiles", dest.display()))?
+            }
+        };
+
+        // the tracked source is always the file that's authored, so that's what we open, never
+        // whatever the dest actually reads
+        if !sfs::path_exists(&source) {
+            return Err(anyhow!(
+                "Source {} of {} is missing",
+                source.display(),
+                dest.display()
+            ));
+        }
+        match entry(&dest, &source) {
+            // an untracked file in the way is just something a link would replace
+            Entry::Copied if tracked.is_some() => {
+                return Err(anyhow!(
+                    "{} is a copy of {}, not a link to it, so editing the source won't change it \
+                     (relink it first, or edit the copy itself)",
+                    dest.display(),
+                    source.display()
+                ));
+            }
+            Entry::Rendered(output) if args.verbose => {
+                println!(
+                    "{} is rendered to {}; opening the source instead",
+                    source.display(),
+                    output.display()
+                );
+            }
+            _ => {}
+        }
+
+        let watched = source
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_else(|| source.clone());
+        let before = snapshot(&watched)?;
+
+        let (program, editor_args) = editor()?;
+        if args.verbose {
+            println!("Opening {} with {}", source.display(), program);
+        }
+        let status = Command::new(&program)
+            .args(&editor_args)
+            .arg(&source)
+            .status()
+            .with_context(|| format!("Failed to run editor `{}`", program))?;
+        if !status.success() {
+            return Err(anyhow!("Editor `{}` exited with {}", program, status));
+        }
+
+        let after = snapshot(&watched)?;
+        let changed: Vec<&PathBuf> = before
+            .symmetric_difference(&after)
+            .filter(|path| !is_scratch(path))
+            .collect();
+        if changed.is_empty() || args.no_relink {
+            return Ok(());
+        }
+
+        if args.verbose {
+            for path in changed.iter() {
+                let what = if after.contains(*path) {
+                    "created"
+                } else {
+                    "removed"
+                };
+                println!("  {} {}", what, path.display());
+            }
+        }
+
+        let Some(target) = target else {
+            println!(
+                "Files were created or renamed next to {}; relink to pick them up",
+                source.display()
+            );
+            return Ok(());
+        };
+
+        let prompt = format!(
+            "{} file(s) were created or renamed next to {}. Relink {}?",
+            changed.len(),
+            source.display(),
+            target
+        );
+        if !confirm(&mut *self.prompter, prompt) {
+            println!("Not relinking");
+            return Ok(());
+        }
+
+        let link_args = DotsLinkArgs {
+            target: PathBuf::from(target),
+            verbose: args.verbose,
+            ..Default::default()
+        };
+        let opts = LinkOptions {
+            format: self.args.format,
+            ..Default::default()
+        };
+        self.relink(&link_args, &opts)
+            .context("Relink after edit failed")?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing, Commands};
+
+    fn edit_args(dest: &Path) -> DotsEditArgs {
+        DotsEditArgs {
+            dest: dest.to_path_buf(),
+            target: None,
+            no_relink: true,
+            verbose: false,
+        }
+    }
+
+    #[test]
+    fn a_tracked_copy_is_refused_rather_than_losing_the_edit() {
+        let root = tempfile::tempdir().unwrap();
+        let source = root.path().join("set/gitconfig");
+        fs::create_dir_all(source.parent().unwrap()).unwrap();
+        fs::write(&source, "").unwrap();
+        let dest = root.path().join("home/.gitconfig");
+        fs::create_dir_all(dest.parent().unwrap()).unwrap();
+        fs::write(&dest, "").unwrap();
+        assert_eq!(entry(&dest, &source), Entry::Copied);
+
+        let mut dots = testing::dots(Commands::Edit(edit_args(&dest)), root.path(), [""; 0]);
+        dots.state.insert(dest.clone(), source.clone());
+
+        let e = dots.edit(&edit_args(&dest)).unwrap_err().to_string();
+        assert!(e.contains("is a copy of"), "{}", e);
+    }
+
+    #[test]
+    fn a_rendered_dest_is_told_apart_from_its_source() {
+        let root = tempfile::tempdir().unwrap();
+        let source = root.path().join("set/config.tmpl");
+        let output = root.path().join("cache/rendered/config");
+        for file in [&source, &output] {
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            fs::write(file, "").unwrap();
+        }
+        let home = root.path().join("home");
+        fs::create_dir_all(&home).unwrap();
+
+        let rendered = home.join("config");
+        sfs::create_symlink(&output, &rendered).unwrap();
+        assert_eq!(entry(&rendered, &source), Entry::Rendered(output));
+
+        // relative links are read relative to where they are
+        let linked = home.join("linked");
+        sfs::create_symlink(Path::new("../set/config.tmpl"), &linked).unwrap();
+        assert_eq!(entry(&linked, &source), Entry::Linked);
+
+        assert_eq!(entry(&home.join("nothing"), &source), Entry::Missing);
+    }
+}
@@ -0,0 +1,162 @@
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
+
+use crate::stats::OutputFormat;
+use crate::trackfile::*;
+use crate::{Dots, DotsExplainArgs};
+use shared::fs as sfs;
+use shared::fs::FilesystemStatus;
+
+#[derive(Serialize, Debug)]
+pub struct Explanation {
+    pub dest: PathBuf,
+    pub planned: Option<PathBuf>,
+    pub source_set: Option<PathBuf>,
+    pub tracked: Option<PathBuf>,
+    pub tracked_in: Option<&'static str>,
+    pub derivations: Vec<Derivation>,
+    pub status: String,
+    pub points_to: Option<PathBuf>,
+}
+
+impl Explanation {
+    pub fn display(&self) {
+        println!("{}", self.dest.display());
+
+        match &self.planned {
+            Some(source) => match &self.source_set {
+                Some(set) => println!(
+                    "  planned: -> {} (from {})",
+                    source.display(),
+                    set.display()
+                ),
+                None => println!("  planned: -> {}", source.display()),
+            },
+            None => println!("  planned: nothing maps here"),
+        }
+        match (&self.tracked, self.tracked_in) {
+            (Some(source), Some(trackfile)) => {
+                println!("  tracked: -> {} ({})", source.display(), trackfile)
+            }
+            _ => println!("  tracked: no"),
+        }
+        match &self.points_to {
+            Some(target) => println!("  status:  {} -> {}", self.status, target.display()),
+            None => println!("  status:  {}", self.status),
+        }
+
+        if self.derivations.is_empty() {
+            return;
+        }
+
+        println!("  candidates:");
+        for derivation in self.derivations.iter() {
+            let outcome = match &derivation.outcome {
+                Outcome::Chosen => "CHOSEN".to_string(),
+                Outcome::Excluded { by } => format!("EXCLUDED by {}", by.display()),
+                Outcome::Shadowed { by } => format!("SHADOWED by {}", by.display()),
+                Outcome::Ignored => "IGNORED (replace = false)".to_string(),
+                Outcome::NotIncluded => "NOT INCLUDED (by the set's includes)".to_string(),
+            };
+            println!("    [ {} ] {}", outcome, derivation.source.display());
+
+            if let Some(set) = &derivation.source_set {
+                match &derivation.config {
+                    Some(config) => {
+                        println!(
+                            "      [[dotfiles]] {} in {}",
+                            set.display(),
+                            config.display()
+                        )
+                    }
+                    None => println!("      [[dotfiles]] {}", set.display()),
+                }
+            }
+            // only worth spelling out when it took more than the one config to get here
+            if derivation.via.len() > 1 || derivation.via.iter().any(|i| i.url.is_some()) {
+                let via: Vec<String> = derivation.via.iter().map(Include::to_string).collect();
+                println!("      via: {}", via.join(" -> "));
+            }
+            println!("      rule: {}", derivation.rule);
+            if let Some(when) = &derivation.when {
+                println!("      when: {}", when);
+            }
+            if !derivation.vars.is_empty() {
+                let vars: Vec<String> = derivation
+                    .vars
+                    .iter()
+                    .map(|(k, v)| format!("{}={}", k, v))
+                    .collect();
+                println!("      vars: {}", vars.join(" "));
+            }
+        }
+    }
+}
+
+impl Dots {
+    pub fn explain(&mut self, args: &DotsExplainArgs) -> Result<()> {
+        let target = args
+            .target
+            .clone()
+            .or_else(|| self.args.dotfiles.clone())
+            .ok_or_else(|| anyhow!("No dotfiles target given (use --target)"))?;
+        let plan = Trackfile::generate(target, &self.env, None, &mut *self.prompter)
+            .context("Failed to resolve link targets")?;
+
+        let path = self.absolute_path(&args.path)?;
+
+        // given a source, explain whatever it would be linked to
+        let dest = if plan.get_source(&path).is_some() || self.tracked_source(&path).is_some() {
+            path
+        } else if let Some(dest) = plan.dest_of(&path) {
+            dest.clone()
+        } else if let Some(dest) = self.state.dest_of(&path) {
+            dest.clone()
+        } else {
+            path
+        };
+
+        let (tracked, tracked_in) = match self.state.get_source(&dest) {
+            Some(source) => (Some(source.clone()), Some("trackfile")),
+            None => match self.system_state.get_source(&dest) {
+                Some(source) => (Some(source.clone()), Some("system trackfile")),
+                None => (None, None),
+            },
+        };
+
+        let status = sfs::get_status(&dest);
+        let points_to = match &status {
+            FilesystemStatus::Symlink { points_to, .. } => points_to.clone(),
+            _ => None,
+        };
+        let status = match &status {
+            FilesystemStatus::Symlink { dangling: true, .. } => "dangling symlink".to_string(),
+            FilesystemStatus::Error(e) => e.clone(),
+            status => status.to_string(),
+        };
+
+        let explanation = Explanation {
+            planned: plan.get_source(&dest).cloned(),
+            source_set: plan.source_set(&dest).cloned(),
+            tracked,
+            tracked_in,
+            derivations: plan.derivations(&dest).to_vec(),
+            status,
+            points_to,
+            dest,
+        };
+
+        match self.args.format {
+            OutputFormat::Text => explanation.display(),
+            OutputFormat::Json => println!(
+                "{}",
+                serde_json::to_string_pretty(&explanation)
+                    .context("Failed to serialize explanation")?
+            ),
+        }
+
+        Ok(())
+    }
+}
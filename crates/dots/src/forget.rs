@@ -0,0 +1,220 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::{Dots, DotsForgetArgs};
+use shared::fs as sfs;
+
+impl Dots {
+    pub fn forget(&mut self, args: &DotsForgetArgs) -> Result<()> {
+        for path in args.dests.iter() {
+            let path = self.absolute_path(path)?;
+
+            // a directory forgets every tracked link under it
+            let dests: Vec<PathBuf> = self
+                .state
+                .iter()
+                .map(|(dest, _)| dest)
+                .filter(|dest| dest.starts_with(&path))
+                .cloned()
+                .collect();
+
+            if dests.is_empty() {
+                if self.system_state.get_source(&path).is_some() {
+                    return Err(anyhow!(
+                        "{} is tracked in the system trackfile, which `forget` doesn't touch",
+                        path.display()
+                    ));
+                }
+                return Err(anyhow!("{} isn't tracked", path.display()));
+            }
+
+            for dest in dests.iter() {
+                self.forget_one(args, dest)
+                    .with_context(|| format!("Failed to forget {}", dest.display()))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn forget_one(&mut self, args: &DotsForgetArgs, dest: &Path) -> Result<()> {
+        let source = self.state.get_source(dest).cloned().unwrap();
+        let is_ours = sfs::is_symlink(dest) && sfs::read_link(dest)? == source;
+        // the source is only safe to delete once there's a copy of it at the destination
+        if args.delete_source && !is_ours {
+            return Err(anyhow!(
+                "{} is no longer our link, so the source wouldn't be copied there; not deleting {} (forget it without --delete-source)",
+                dest.display(),
+                source.display()
+            ));
+        }
+
+        // a linked directory can't be swapped for a copy in one rename; its links are what to
+        // forget instead
+        if is_ours && sfs::is_dir(&source) {
+            return Err(anyhow!(
+                "Source {} is a directory; forget the files under it one by one instead",
+                source.display()
+            ));
+        }
+
+        if args.dry_run {
+            if is_ours {
+                println!(
+                    "[ DRY RUN --- Forget ] {} (copied from {})",
+                    dest.display(),
+                    source.display()
+                );
+            } else {
+                println!("[ DRY RUN --- Forget ] {} (entry only)", dest.display());
+            }
+            if args.delete_source {
+                println!("[ DRY RUN --- Remove ] {}", source.display());
+            }
+            return Ok(());
+        }
+
+        // whatever is there now isn't our link, so it's left alone and only the entry goes
+        if is_ours {
+            if !sfs::is_file(&source) {
+                return Err(anyhow!(
+                    "Source {} is missing, nothing to leave in its place",
+                    source.display()
+                ));
+            }
+
+            // copied next to the link first, then swapped in, so there's never a moment with
+            // nothing at the destination
+            let name = dest
+                .file_name()
+                .ok_or_else(|| anyhow!("Invalid destination {}", dest.display()))?;
+            let tmp = dest.with_file_name(format!(".{}.dots-forget", name.to_string_lossy()));
+
+            fs::copy(&source, &tmp).with_context(|| {
+                format!("Failed to copy {} to {}", source.display(), tmp.display())
+            })?;
+            let permissions = fs::metadata(&source)
+                .with_context(|| format!("Failed to read metadata of {}", source.display()))?
+                .permissions();
+            fs::set_permissions(&tmp, permissions)
+                .with_context(|| format!("Failed to set permissions of {}", tmp.display()))?;
+            sfs::chown(&tmp, self.env.owner)?;
+
+            fs::rename(&tmp, dest).with_context(|| {
+                format!(
+                    "Failed to replace {} with {}",
+                    dest.display(),
+                    tmp.display()
+                )
+            })?;
+        } else if args.verbose {
+            println!(
+                "{} is no longer our link, only forgetting the entry",
+                dest.display()
+            );
+        }
+
+        self.state.remove(dest);
+        println!("[ FORGET ] {}", dest.display());
+
+        if args.delete_source {
+            sfs::remove_file(&source)?;
+            if args.verbose {
+                println!("Removed {}", source.display());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing, Commands};
+
+    fn forget_args(dest: &Path, delete_source: bool, dry_run: bool) -> DotsForgetArgs {
+        DotsForgetArgs {
+            dests: vec![dest.to_path_buf()],
+            delete_source,
+            dry_run,
+            verbose: false,
+        }
+    }
+
+    // `dest` linked to a tracked `source` holding `content`
+    fn linked(root: &Path, content: &str, delete_source: bool) -> (Dots, PathBuf, PathBuf) {
+        let source = root.join("set/gitconfig");
+        fs::create_dir_all(source.parent().unwrap()).unwrap();
+        fs::write(&source, content).unwrap();
+        let dest = root.join("home/.gitconfig");
+        fs::create_dir_all(dest.parent().unwrap()).unwrap();
+        sfs::create_symlink(&source, &dest).unwrap();
+
+        let command = Commands::Forget(forget_args(&dest, delete_source, false));
+        let mut dots = testing::dots(command, root, [""; 0]);
+        dots.state.insert(dest.clone(), source.clone());
+        (dots, dest, source)
+    }
+
+    #[test]
+    fn a_forgotten_link_is_replaced_by_a_copy_of_its_source() {
+        let root = tempfile::tempdir().unwrap();
+        let (mut dots, dest, source) = linked(root.path(), "[user]", false);
+
+        dots.forget(&forget_args(&dest, false, true)).unwrap();
+        assert!(sfs::is_symlink(&dest));
+        assert!(dots.state.contains_dest(&dest));
+
+        dots.forget(&forget_args(&dest, false, false)).unwrap();
+        assert!(!sfs::is_symlink(&dest));
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "[user]");
+        assert!(sfs::path_exists(&source));
+        assert!(!dots.state.contains_dest(&dest));
+    }
+
+    #[test]
+    fn delete_source_only_goes_once_theres_a_copy() {
+        let root = tempfile::tempdir().unwrap();
+        let (mut dots, dest, source) = linked(root.path(), "[user]", true);
+
+        dots.forget(&forget_args(&dest, true, false)).unwrap();
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "[user]");
+        assert!(!sfs::path_exists(&source));
+
+        // something else took the link's place, so the source is all that's left of it
+        let root = tempfile::tempdir().unwrap();
+        let (mut dots, dest, source) = linked(root.path(), "[user]", true);
+        fs::remove_file(&dest).unwrap();
+        fs::write(&dest, "theirs").unwrap();
+
+        let e = dots.forget(&forget_args(&dest, true, false)).unwrap_err();
+        assert!(format!("{:#}", e).contains("no longer our link"), "{:#}", e);
+        assert!(sfs::path_exists(&source));
+        assert!(dots.state.contains_dest(&dest));
+    }
+
+    #[test]
+    fn a_directory_source_is_not_reported_missing() {
+        let root = tempfile::tempdir().unwrap();
+        let source = root.path().join("set/nvim");
+        fs::create_dir_all(&source).unwrap();
+        let dest = root.path().join("home/nvim");
+        fs::create_dir_all(dest.parent().unwrap()).unwrap();
+        sfs::create_symlink(&source, &dest).unwrap();
+
+        let command = Commands::Forget(forget_args(&dest, false, false));
+        let mut dots = testing::dots(command, root.path(), [""; 0]);
+        dots.state.insert(dest.clone(), source.clone());
+
+        let e = format!(
+            "{:#}",
+            dots.forget(&forget_args(&dest, false, false)).unwrap_err()
+        );
+        assert!(e.contains("is a directory"), "{}", e);
+        assert!(sfs::is_symlink(&dest));
+        assert!(dots.state.contains_dest(&dest));
+    }
+}
@@ -2,18 +2,14 @@ use std::fmt::{self, Display};
 
 use anyhow::{self, anyhow};
 
+use crate::hooks::*;
+use crate::stats::*;
 use crate::*;
 use shared::fs::FilesystemStatus;
 use shared::fs::{self as sfs, is_symlink};
 
 type Flags = DotsLinkArgs;
 
-pub enum Choice {
-    Yes { all: bool },
-    No { all: bool },
-    Quit,
-}
-
 pub enum ChoiceState {
     Unset,
     Never,
@@ -33,19 +29,28 @@ pub struct UserChoiceState {
     force_symlink: ChoiceState,
     force_file: ChoiceState,
     force_dangerously: ChoiceState,
+    skipped_dirs: Vec<PathBuf>, // "skip everything under this directory"
 }
 
 impl UserChoiceState {
-    pub fn get(&self, reason: Reason) -> Option<u8> {
+    pub fn get(&self, reason: &Reason) -> Option<&ChoiceState> {
         Some(match reason {
-            ForceDangerously => self.force_dangerously,
-            ForceFile => self.force_file,
-            ForceSymlink => self.force_symlink,
-            ForceCorrectSymlink => self.force_correct_symlink,
+            ForceDangerously => &self.force_dangerously,
+            ForceFile => &self.force_file,
+            ForceSymlink => &self.force_symlink,
+            ForceCorrectSymlink => &self.force_correct_symlink,
             _ => return None,
         })
     }
 
+    pub fn skip_dir(&mut self, dir: &Path) {
+        self.skipped_dirs.push(dir.to_path_buf());
+    }
+
+    pub fn in_skipped_dir(&self, dest: &Path) -> bool {
+        self.skipped_dirs.iter().any(|dir| dest.starts_with(dir))
+    }
+
     pub fn set(&mut self, reason: Reason, value: ChoiceState) -> Result<()> {
         match reason {
             ForceDangerously => self.force_dangerously = value,
@@ -65,120 +70,139 @@ impl UserChoiceState {
     pub fn set_always(&mut self, reason: Reason) -> Result<()> {
         self.set(reason, Always)
     }
-}
 
-pub enum Opts {
-    YesNo,
-    YesNoAll,
-    All,
+    // every reason answered for the rest of the run (true = always)
+    pub fn decided(&self) -> Vec<(Reason, bool)> {
+        [
+            (ForceDangerously, &self.force_dangerously),
+            (ForceFile, &self.force_file),
+            (ForceSymlink, &self.force_symlink),
+            (ForceCorrectSymlink, &self.force_correct_symlink),
+        ]
+        .into_iter()
+        .filter_map(|(reason, state)| match state {
+            Always => Some((reason, true)),
+            Never => Some((reason, false)),
+            Unset => None,
+        })
+        .collect()
+    }
 }
 
-impl Opts {
-    pub fn get() -> &str {
-        match opts {
-            Opts::YesNo => "[Y]es/[N]o",
-            Opts::YesNoAll => "[Y]es/[N]o/[Y]es[A]all/[N]o[All]",
-            Opts::All => "[Y]es/[N]o/[Y]es[A]all/[N]o[A]ll/[I]nfo/[Q]uit",
-        }
-    }
+// only ever offered when there's a source to absorb into, i.e., when linking
+fn absorb_on_unlink(dest: &Path) -> anyhow::Error {
+    anyhow!("Nothing to absorb {} into while unlinking", dest.display())
+}
 
-    pub fn process(&self, input: &str) -> Option<Choice> {
-        match self {
-            Opts::YesNo => match input {
-                "y" | "yes" => Some(Choice::Yes { all: false }),
-                "n" | "no" => Some(Choice::No { all: false }),
-                _ => None,
-            },
-            Opts::YesNoAll => process_options(Opts::YesNo, input).or_else(|| match input {
-                "ya" | "yesall" => Some(Choice::Yes { all: true }),
-                "na" | "noall" => Some(Choice::No { all: true }),
-                _ => None,
-            }),
-            Opts::All => process_options(Opts::YesNoAll, input).or_else(|| match input {
-                "i" | "info" => Some(println!(reason.info())),
-                "q" | "quit" | "cancel" => return Some(Choice::Quit),
-                _ => None,
-            }),
+// a read-only peek at what's in the way, then back to the same question
+fn show_diff(prompter: &mut dyn Prompter, dest: &Path, source: &Path) {
+    match Command::new("diff")
+        .arg("-u")
+        .arg(dest)
+        .arg(source)
+        .output()
+    {
+        Ok(output) => {
+            let diff = String::from_utf8_lossy(&output.stdout);
+            if diff.is_empty() {
+                prompter.say("No differences.");
+            } else {
+                prompter.say(diff.trim_end());
+            }
         }
+        Err(e) => prompter.say(&format!("Failed to run diff: {}", e)),
     }
 }
 
-pub fn prompt_user_choice(prompt: String, reason: Reason, opts: Opts) -> Choice {
-    let choices_help = opts.get();
-    let full_prompt = format!("{}\n{}: ", prompt_message, choices_help);
-
-    loop {
-        print!("{}", full_prompt);
-
-        if let Err(e) = io::stdout().flush() {
-            eprintln!("Error flushing stdout: {}. Retrying...", e);
-            continue;
-        }
-
-        let mut input = String::new();
-        match io::stdin().read_line(&mut input) {
-            Ok(_) => {
-                let processed_input = opts.process(input.trim().to_lowercase().as_str());
-
-                if let Some(choice) = processed_input {
-                    return choice;
-                } else {
-                    println!("Invalid input. Please choose from {}.", choices_help);
-                }
-            }
-            Err(error) => {
-                println!("Error reading input: {}. Please try again.", error);
-            }
-        }
+fn show_file(prompter: &mut dyn Prompter, dest: &Path) {
+    match sfs::read_to_string(dest) {
+        Ok(content) => prompter.say(content.trim_end()),
+        Err(e) => prompter.say(&format!("{:#}", e)),
     }
 }
 
 pub fn prompt_user(
-    user_choice_state: &UserChoiceState,
-    reason: Reason,
+    prompter: &mut dyn Prompter,
+    user_choice_state: &mut UserChoiceState,
+    reason: &Reason,
     dest_path: &PathBuf,
     points_to: Option<&PathBuf>,
+    source: Option<&PathBuf>, // what we'd link in its place (link only)
 ) -> Op {
-    let user_choice = match user_choice_state.get(reason).unwrap() {
-        Never => Choice::No,
-        Always => Choice::Yes,
-        _ => prompt_user_choice(
-            if let Some(source_path) = points_to {
-                format!(
-                    "[ {} ] Remove symlink at {} (points to: {})",
-                    reason.short_flag(),
-                    dest_path.display(),
-                    source_path.display()
-                )
-            } else {
-                format!(
-                    "[ {} ] Remove file (not a symlink!) at {}",
-                    reason.short_flag(),
-                    dest_path.display()
-                )
-            },
-            reason,
+    if user_choice_state.in_skipped_dir(dest_path) {
+        return Denied(reason.clone());
+    }
+
+    let (prompt, opts) = if let Some(source_path) = points_to {
+        (
+            format!(
+                "[ {} ] Remove symlink at {} (points to: {})",
+                reason.short_flag(),
+                dest_path.display(),
+                source_path.display()
+            ),
+            Opts::All,
+        )
+    } else if source.is_some() {
+        (
+            format!(
+                "[ {} ] Replace file (not a symlink!) at {}",
+                reason.short_flag(),
+                dest_path.display()
+            ),
+            Opts::File,
+        )
+    } else {
+        (
+            format!(
+                "[ {} ] Remove file (not a symlink!) at {}",
+                reason.short_flag(),
+                dest_path.display()
+            ),
             Opts::All,
-        ),
+        )
+    };
+
+    let user_choice = match user_choice_state.get(reason) {
+        Some(Never) => Choice::No { all: false },
+        Some(Always) => Choice::Yes { all: false },
+        _ => loop {
+            match prompt_user_choice(prompter, prompt.clone(), reason, opts) {
+                Choice::Diff => match source {
+                    Some(source) => show_diff(prompter, dest_path, source),
+                    None => prompter.say("Nothing to diff against."),
+                },
+                Choice::Show => show_file(prompter, dest_path),
+                choice => break choice,
+            }
+        },
     };
 
     match user_choice {
         Choice::No { all } => {
             if all {
-                user_choice_state.set_never(reason);
+                let _ = user_choice_state.set_never(reason.clone());
             }
-            Denied(reason)
+            Denied(reason.clone())
         }
         Choice::Yes { all } => {
             if all {
-                user_choice_state.set_always(reason);
+                let _ = user_choice_state.set_always(reason.clone());
             }
-            Confirmed(reason)
+            Confirmed(reason.clone())
+        }
+        Choice::Absorb => Absorb(reason.clone()),
+        Choice::SkipDir => {
+            if let Some(dir) = dest_path.parent() {
+                user_choice_state.skip_dir(dir);
+            }
+            Denied(reason.clone())
         }
-        Choice::Quit => Denied(UserQuit),
+        _ => Denied(UserQuit),
     }
 }
 
+#[derive(Clone)]
 pub enum Reason {
     ForceDangerously,
     ForceFile,
@@ -213,6 +237,31 @@ impl Reason {
         }
     }
 
+    // needed a force flag (or the user's ok) that wasn't given
+    pub fn is_conflict(&self) -> bool {
+        matches!(
+            self,
+            ForceDangerously | ForceFile | ForceSymlink | ForceCorrectSymlink
+        )
+    }
+
+    // stable name used in stats (and anything else machine readable)
+    pub fn key(&self) -> &'static str {
+        match self {
+            ForceDangerously => "force_dangerously",
+            ForceFile => "force_file",
+            ForceSymlink => "force_symlink",
+            ForceCorrectSymlink => "force_correct_symlink",
+            DanglingSymlink => "dangling_symlink",
+            CorrectSymlink => "correct_symlink",
+            IntendedSymlink => "intended_symlink",
+            NotFound => "not_found",
+            StatusInvalid => "status_invalid",
+            StatusError(_) => "status_error",
+            UserQuit => "user_quit",
+        }
+    }
+
     pub fn short_flag(&self) -> str {
         match self {
             ForceDangerously => "--force-dangerously",
@@ -252,13 +301,19 @@ impl Reason {
     pub fn consult_user(
         &self,
         flags: &Flags,
-        user_choices: &UserChoiceState,
+        policy: &DecisionPolicy,
+        prompter: &mut dyn Prompter,
+        user_choices: &mut UserChoiceState,
         dest_path: &PathBuf,
         points_to: Option<&PathBuf>,
+        source: Option<&PathBuf>,
     ) -> Op {
         if !self.short_flag().is_empty() {
-            if flags.interactive {
-                prompt_user(user_choices, self, dest_path, points_to)
+            // pre-answered, so neither flags nor the user get a say
+            if let Some(action) = policy.decide(dest_path, self) {
+                Op::decided(action, self)
+            } else if flags.interactive {
+                prompt_user(prompter, user_choices, self, dest_path, points_to, source)
             } else {
                 Op::verify(self.test_flags(flags), self)
             }
@@ -271,6 +326,8 @@ impl Reason {
 pub enum Op {
     Confirmed(Reason),
     Denied(Reason),
+    Backup(Reason), // confirmed, but whatever is in the way gets moved aside instead of removed
+    Absorb(Reason), // confirmed, but whatever is in the way replaces the source first
 }
 use Op::*;
 
@@ -279,6 +336,8 @@ impl fmt::Display for Op {
         let (reason, format_string) = match self {
             Confirmed(reason) => (reason, "({} was used)"),
             Denied(reason) => (reason, "(use {} to remove)"),
+            Backup(reason) => (reason, "(backed up per policy)"),
+            Absorb(reason) => (reason, "(absorbed into the source set)"),
         };
 
         let parts = vec![
@@ -298,7 +357,7 @@ impl Op {
     #[inline]
     pub fn or(self, opb: Self) -> Self {
         match self {
-            x @ Confirmed(_) => x,
+            x @ (Confirmed(_) | Backup(_) | Absorb(_)) => x,
             Denied(_) => opb,
         }
     }
@@ -309,7 +368,7 @@ impl Op {
         F: FnOnce() -> Self,
     {
         match self {
-            x @ Confirmed(_) => x,
+            x @ (Confirmed(_) | Backup(_) | Absorb(_)) => x,
             Denied(reason) => f(reason),
         }
     }
@@ -320,7 +379,7 @@ impl Op {
         F: FnOnce() -> Self,
     {
         match self {
-            x @ Confirmed(_) => x,
+            x @ (Confirmed(_) | Backup(_) | Absorb(_)) => x,
             Denied(reason) => f(reason),
         }
     }
@@ -329,12 +388,14 @@ impl Op {
         match self {
             Confirmed(reason) => reason,
             Denied(reason) => reason,
+            Backup(reason) => reason,
+            Absorb(reason) => reason,
         }
     }
 
     pub fn was_confirmed(&self) -> bool {
         match self {
-            Confirmed(_) => true,
+            Confirmed(_) | Backup(_) | Absorb(_) => true,
             _ => false,
         }
     }
@@ -350,6 +411,14 @@ impl Op {
         &self.reason().info()
     }
 
+    pub fn decided(action: PolicyAction, reason: &Reason) -> Op {
+        match action {
+            PolicyAction::Yes => Confirmed(reason.clone()),
+            PolicyAction::No => Denied(reason.clone()),
+            PolicyAction::Backup => Backup(reason.clone()),
+        }
+    }
+
     pub fn verify<R: Reason>(cond: bool, reason: R) -> Op<R> {
         if cond {
             Confirmed(reason)
@@ -359,131 +428,337 @@ impl Op {
     }
 }
 
-pub fn try_bail(flags: &Flags, e: String, bail_msg: String) -> Result<bool> {
-    let err = Err(anyhow::Error::new(e).context(bail_msg));
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Skip conflicting or failing entries and keep going
+    #[default]
+    Skip,
+    /// Halt on the first conflict or error
+    Bail,
+    /// Halt on the first error, but skip over conflicts
+    BailErrors,
+    /// Ask whether to continue on each conflict or error
+    Ask,
+}
 
-    println!("[ BAIL ] {}", e);
-    if args.interactive {
-        match prompt_user_choice(
-            "Continue execution?".to_string(),
-            StatusError(e),
-            Opts::YesNoAll,
-        ) {
-            Choice::No { .. } => err,
-            Choice::Yes { all } => Ok(all),
-            _ => {} // Quit not offered
+impl fmt::Display for ErrorPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorPolicy::Skip => write!(f, "skip"),
+            ErrorPolicy::Bail => write!(f, "bail"),
+            ErrorPolicy::BailErrors => write!(f, "bail-errors"),
+            ErrorPolicy::Ask => write!(f, "ask"),
         }
-    } else {
-        err
     }
 }
 
-#[derive(Default)]
-pub struct LinkStats {
-    errors: i32,
-    targets: i32,
-    symlinks_added: i32,
-    symlinks_removed: i32,
-    files_removed: i32,
-    targets_skipped: i32,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tripped {
+    Error,
+    Conflict,
+    UserAbort,
+}
+
+// what stopped an operation early; attached to the error so callers can tell them apart (e.g., for
+// the exit status)
+#[derive(Debug)]
+pub struct PolicyTripped {
+    pub policy: ErrorPolicy,
+    pub tripped: Tripped,
+    pub dest: Option<PathBuf>,
 }
 
-impl LinkStats {
-    pub fn new(targets: i32) -> Self {
-        let stats = &mut Self::default();
-        stats.targets = targets;
-        stats
+impl PolicyTripped {
+    pub fn exit_code(&self) -> i32 {
+        match self.tripped {
+            Tripped::Error => 2,
+            Tripped::Conflict => 3,
+            Tripped::UserAbort => 4,
+        }
     }
+}
 
-    pub fn display(&self, dry_run: bool) {}
+impl fmt::Display for PolicyTripped {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let what = match self.tripped {
+            Tripped::Error => "Halted on error",
+            Tripped::Conflict => "Halted on conflict",
+            Tripped::UserAbort => "Canceled by user",
+        };
+        match &self.dest {
+            Some(dest) => write!(
+                f,
+                "{} at {} (--on-error {})",
+                what,
+                dest.display(),
+                self.policy
+            ),
+            None => write!(f, "{} (--on-error {})", what, self.policy),
+        }
+    }
 }
 
-#[derive(Default)]
-pub struct UnlinkStats {
-    errors: i32,
-    targets: i32,
-    symlinks_removed: i32,
-    files_removed: i32,
-    targets_skipped: i32,
+impl std::error::Error for PolicyTripped {}
+
+// applies the chosen ErrorPolicy over the course of a single operation
+pub struct ErrorHandler {
+    policy: ErrorPolicy,
+    keep_going: bool,  // user answered "yes to all" when asked
+    interactive: bool, // conflicts were already prompted for, don't ask twice
 }
 
-impl UnlinkStats {
-    pub fn new(targets: i32) -> Self {
-        let stats = &mut Self::default();
-        stats.targets = targets;
-        stats
-    }
-
-    pub fn display(&self, dry_run: bool) {
-        let targets_removed = self.symlinks_removed + self.files_removed;
-        if targets_removed > 0 {
-            if dry_run {
-                println!(
-                    "Would have successfully unlinked {}/{} potential entries.",
-                    targets_removed,
-                    self.targets.len()
-                );
-            } else {
-                println!(
-                    "Successfully unlinked {}/{} potential entries.",
-                    targets_removed,
-                    self.targets.len()
-                );
+impl ErrorHandler {
+    pub fn new(policy: ErrorPolicy, interactive: bool) -> Self {
+        Self {
+            policy,
+            keep_going: false,
+            interactive,
+        }
+    }
+
+    fn trip(&self, tripped: Tripped, dest: Option<&Path>) -> PolicyTripped {
+        PolicyTripped {
+            policy: self.policy,
+            tripped,
+            dest: dest.map(Path::to_path_buf),
+        }
+    }
+
+    fn ask(
+        &mut self,
+        prompter: &mut dyn Prompter,
+        message: String,
+        dest: Option<&Path>,
+    ) -> Result<()> {
+        if self.keep_going {
+            return Ok(());
+        }
+
+        match prompt_user_choice(
+            prompter,
+            format!("{}\nContinue?", message),
+            &StatusError(message),
+            Opts::YesNoAll,
+        ) {
+            Choice::Yes { all } => {
+                self.keep_going = all;
+                Ok(())
             }
-            println!("Symlinks removed: {}", self.symlinks_removed);
-            println!("Files removed:    {}", self.files_removed);
-        } else {
-            if dry_run {
-                println!(
-                    "No entries would have been unlinked (skipped {}).",
-                    self.targets_skipped
-                );
-            } else {
-                println!(
-                    "No entries were unlinked (skipped {}).",
-                    self.targets_skipped
-                );
+            _ => Err(self.trip(Tripped::UserAbort, dest).into()),
+        }
+    }
+
+    // an entry was skipped; only matters if it was skipped because of a conflict (i.e., it would
+    // have needed a force flag or a yes from the user)
+    pub fn conflict(&mut self, prompter: &mut dyn Prompter, dest: &Path, op: &Op) -> Result<()> {
+        if let UserQuit = op.reason() {
+            return Err(self.trip(Tripped::UserAbort, Some(dest)).into());
+        }
+        if !op.reason().is_conflict() {
+            return Ok(());
+        }
+
+        match self.policy {
+            ErrorPolicy::Skip | ErrorPolicy::BailErrors => Ok(()),
+            ErrorPolicy::Ask if self.interactive => Ok(()),
+            ErrorPolicy::Bail => {
+                say!("[ BAIL ] {}: {}", dest.display(), op);
+                Err(self.trip(Tripped::Conflict, Some(dest)).into())
             }
+            ErrorPolicy::Ask => self.ask(
+                prompter,
+                format!("[ CONFLICT ] {}: {}", dest.display(), op),
+                Some(dest),
+            ),
         }
-        Ok(())
     }
-}
 
-#[derive(Default)]
-pub struct RelinkStats {
-    errors: i32,
-    targets: i32,
-    symlinks_added: i32,
-    symlinks_removed: i32,
-    files_removed: i32,
-    targets_skipped: i32,
-}
+    pub fn error(
+        &mut self,
+        prompter: &mut dyn Prompter,
+        dest: Option<&Path>,
+        e: anyhow::Error,
+    ) -> Result<()> {
+        // already tripped further down, just let it through
+        if e.downcast_ref::<PolicyTripped>().is_some() {
+            return Err(e);
+        }
 
-impl RelinkStats {
-    pub fn new(unlink_stats: UnlinkStats, link_stats: LinkStats) -> Self {
-        //TODO actually do it lmao
-        unlink_stats
+        match self.policy {
+            ErrorPolicy::Skip => Ok(()),
+            ErrorPolicy::Bail | ErrorPolicy::BailErrors => {
+                say!("[ BAIL ] {:#}", e);
+                Err(e.context(self.trip(Tripped::Error, dest)))
+            }
+            ErrorPolicy::Ask => match dest {
+                Some(d) => self.ask(
+                    prompter,
+                    format!("[ ERROR ] {}: {:#}", d.display(), e),
+                    dest,
+                ),
+                None => self.ask(prompter, format!("[ ERROR ] {:#}", e), dest),
+            },
+        }
     }
 }
 
 #[derive(Default)]
-pub struct LinkOptions {
-    trackfile: Option<&Trackfile>,
-    silent: bool,
+pub struct LinkOptions<'a> {
+    pub trackfile: Option<&'a Trackfile>,
+    pub silent: bool,
+    pub format: OutputFormat,
 }
 
 impl Dots {
+    // link/unlink/relink's plan when none was handed in; nested configs are vetted (and may ask)
+    // on the way, like any other command that generates one
+    fn generate_targets(&mut self, target: &Path) -> Result<Trackfile> {
+        Trackfile::generate(
+            target.to_string_lossy().to_string(),
+            &self.env,
+            None,
+            &mut *self.prompter,
+        )
+    }
+
+    // root-owned destinations live in the system trackfile, everything else in the user's
+    pub fn tracked_source(&self, dest: &Path) -> Option<&PathBuf> {
+        self.state
+            .get_source(dest)
+            .or_else(|| self.system_state.get_source(dest))
+    }
+
+    pub fn elevation(&self, args: &DotsLinkArgs) -> Elevation {
+        args.elevate
+            .or_else(|| self.config.dots.as_ref().and_then(|d| d.elevate))
+            .unwrap_or_default()
+    }
+
+    // hand everything queued up during the pass to the helper in one go, so the user is asked for
+    // their password (at most) once
+    pub fn flush_privileged(&mut self, args: &DotsLinkArgs) -> Result<HelperReport> {
+        if self.privileged.is_empty() {
+            return Ok(HelperReport::default());
+        }
+
+        let batch = HelperBatch {
+            actions: std::mem::take(&mut self.privileged),
+        };
+
+        let elevation = self.elevation(args);
+        say!(
+            "Running {} privileged operation(s) via {}...",
+            batch.actions.len(),
+            elevation
+        );
+
+        let report = run_batch(elevation, &batch)?;
+
+        // mirror what the helper recorded so later lookups in this run see it
+        for (action, result) in batch.actions.iter().zip(report.results.iter()) {
+            if result.error.is_some() {
+                continue;
+            }
+            match action {
+                HelperAction::Link { dest, source, .. } => {
+                    self.system_state.insert(dest.clone(), source.clone())
+                }
+                HelperAction::Unlink { dest, .. } => {
+                    self.system_state.remove(dest);
+                }
+            }
+            for dir in result.dirs_created.iter() {
+                self.system_state.insert_dir(dir.clone());
+            }
+            for dir in result.dirs_removed.iter() {
+                self.system_state.remove_dir(dir);
+            }
+        }
+
+        Ok(report)
+    }
+
+    // [dots] hooks wrap the whole operation; a source set's own hooks only run when some of its
+    // destinations are involved. failures are handed back so the caller can apply its bail policy
+    pub fn run_hooks(
+        &self,
+        args: &DotsLinkArgs,
+        kind: HookKind,
+        targets: &Trackfile,
+        dests: &[PathBuf],
+    ) -> Vec<anyhow::Error> {
+        let mut errors = vec![];
+
+        let global = self
+            .config
+            .dots
+            .as_ref()
+            .and_then(|d| d.hooks.as_ref())
+            .and_then(|h| h.get(kind))
+            .filter(|_| !dests.is_empty());
+
+        let is_pre = matches!(kind, HookKind::PreLink | HookKind::PreUnlink);
+
+        if is_pre {
+            if let Some(command) = global {
+                if let Err(e) = run_hook(kind, command, None, dests, &HashMap::new(), args.dry_run)
+                {
+                    errors.push(e);
+                }
+            }
+        }
+
+        for set in targets.hooks() {
+            let Some(command) = set.hooks.get(kind) else {
+                continue;
+            };
+
+            let set_dests: Vec<PathBuf> = dests
+                .iter()
+                .filter(|dest| targets.source_set(dest) == Some(&set.source_set))
+                .cloned()
+                .collect();
+            if set_dests.is_empty() {
+                continue;
+            }
+
+            if let Err(e) = run_hook(
+                kind,
+                command,
+                Some(&set.source_set),
+                &set_dests,
+                &set.vars,
+                args.dry_run,
+            ) {
+                errors.push(e);
+            }
+        }
+
+        if !is_pre {
+            if let Some(command) = global {
+                if let Err(e) = run_hook(kind, command, None, dests, &HashMap::new(), args.dry_run)
+                {
+                    errors.push(e);
+                }
+            }
+        }
+
+        errors
+    }
+
     pub fn try_perform_link(
         &mut self,
         args: &DotsLinkArgs,
-        stats: &LinkStats,
-        user_choices: &UserChoiceState,
+        stats: &mut LinkStats,
+        user_choices: &mut UserChoiceState,
+        errors: &mut ErrorHandler,
         target_dest: &PathBuf,
         target_source: &PathBuf,
+        privileged: bool,
     ) -> Result<()> {
         let dest_status = sfs::get_status(target_dest);
-        let is_tracked = self.state.contains_dest(target_dest);
-        let tracked_source = self.state.get_source(target_dest);
+        let tracked_source = self.tracked_source(target_dest);
+        let is_tracked = tracked_source.is_some();
 
         let operation = match dest_status {
             FilesystemStatus::NotFound => Confirmed(NotFound),
@@ -498,57 +773,173 @@ impl Dots {
                     Denied(IntendedSymlink)
                 } else if is_tracked {
                     if points_to.unwrap() == tracked_source.unwrap() {
-                        ForceCorrectSymlink.consult_user(args, user_choices, target_dest, points_to)
+                        ForceCorrectSymlink.consult_user(
+                            args,
+                            &self.policy,
+                            &mut *self.prompter,
+                            user_choices,
+                            target_dest,
+                            points_to,
+                            Some(target_source),
+                        )
                     } else {
-                        ForceSymlink.consult_user(args, user_choices, target_dest, points_to)
+                        ForceSymlink.consult_user(
+                            args,
+                            &self.policy,
+                            &mut *self.prompter,
+                            user_choices,
+                            target_dest,
+                            points_to,
+                            Some(target_source),
+                        )
                     }
                 } else {
-                    ForceDangerously.consult_user(args, user_choices, target_dest, points_to)
+                    ForceDangerously.consult_user(
+                        args,
+                        &self.policy,
+                        &mut *self.prompter,
+                        user_choices,
+                        target_dest,
+                        points_to,
+                        Some(target_source),
+                    )
                 }
             }
             FilesystemStatus::File => {
                 if is_tracked {
-                    ForceFile.consult_user(args, user_choices, target_dest, None)
+                    ForceFile.consult_user(
+                        args,
+                        &self.policy,
+                        &mut *self.prompter,
+                        user_choices,
+                        target_dest,
+                        None,
+                        Some(target_source),
+                    )
                 } else {
-                    ForceDangerously.consult_user(args, user_choices, target_dest, None)
+                    ForceDangerously.consult_user(
+                        args,
+                        &self.policy,
+                        &mut *self.prompter,
+                        user_choices,
+                        target_dest,
+                        None,
+                        Some(target_source),
+                    )
                 }
             }
             _ => Confirmed(StatusInvalid), // shouldn't conflict as we only ever link files ??
         };
 
+        stats.record_reason(operation.reason());
+
+        // already where it should be: nothing to do, and nothing that was skipped either
+        if let Denied(IntendedSymlink) = operation {
+            stats.unchanged += 1;
+            if args.dry_run {
+                say!("[ DRY RUN --- Unchanged ] {}", target_dest.display());
+            } else if args.verbose {
+                say!("Already linked: {}", target_dest.display());
+            }
+            return Ok(());
+        }
+
         // --- Dry Run ---
         if args.dry_run {
-            println!("{} -> {}", target_dest.display(), target_source.display());
+            say!("{} -> {}", target_dest.display(), target_source.display());
+            if privileged && operation.was_confirmed() {
+                say!("[ DRY RUN --- Privileged ] via {}", self.elevation(args));
+            }
+            if let Backup(_) = operation {
+                stats.backed_up += 1;
+                say!("[ DRY RUN --- Backup ] {}", target_dest.display());
+            }
             match operation {
-                Confirmed(reason) => {
+                Absorb(_) => {
+                    stats.absorbed += 1;
+                    stats.symlinks_added += 1;
+                    say!("[ DRY RUN --- Absorb+Link ] {}", operation);
+                }
+                Confirmed(ref reason) | Backup(ref reason) => {
                     stats.symlinks_added += 1;
                     match reason {
-                        NotFound | StatusInvalid | IntendedSymlink => {
-                            println!("[ DRY RUN --- Link ] {}", operation)
+                        NotFound | StatusInvalid => {
+                            say!("[ DRY RUN --- Link ] {}", operation)
                         }
                         DanglingSymlink | ForceCorrectSymlink | ForceSymlink => {
                             stats.symlinks_removed += 1;
-                            println!("[ DRY RUN --- Remove+Link ] {}", operation);
+                            say!("[ DRY RUN --- Remove+Link ] {}", operation);
                         }
                         _ => {
                             stats.files_removed += 1;
-                            println!("[ DRY RUN --- Remove+Link ] {}", operation)
+                            say!("[ DRY RUN --- Remove+Link ] {}", operation)
                         }
                     }
                 }
-                Denied(reason) => match reason {
-                    StatusError(e) => return Err(e),
-                    _ => {
-                        stats.targets_skipped += 1;
-                        println!("[ DRY RUN --- Skip ] {}", operation)
-                    }
-                },
+                Denied(StatusError(e)) => return Err(anyhow!(e)),
+                Denied(_) => {
+                    stats.skip(target_dest, &operation);
+                    say!("[ DRY RUN --- Skip ] {}", operation);
+                    errors.conflict(&mut *self.prompter, target_dest, &operation)?;
+                }
             }
             return Ok(());
         }
 
         // --- Perform Link ---
         match operation {
+            Confirmed(ref reason) | Backup(ref reason) if privileged => {
+                // deferred to the helper; stats are filled in from its report
+                self.privileged.push(HelperAction::Link {
+                    dest: target_dest.clone(),
+                    source: target_source.clone(),
+                    remove_existing: !matches!(reason, NotFound | StatusInvalid),
+                    backup: matches!(operation, Backup(_)),
+                });
+
+                if args.verbose {
+                    say!(
+                        "Queued privileged link {} -> {}",
+                        target_dest.display(),
+                        target_source.display()
+                    );
+                }
+
+                Ok(())
+            }
+            Absorb(_) if privileged => Err(anyhow!(
+                "Can't absorb {}: it needs elevated privileges",
+                target_dest.display()
+            )),
+            Backup(_) => {
+                let backup = sfs::backup(target_dest)?;
+                stats.backed_up += 1;
+                say!(
+                    "[ BACKUP ] {} -> {}",
+                    target_dest.display(),
+                    backup.display()
+                );
+
+                self.link_into_place(args, stats, target_dest, target_source)
+            }
+            Absorb(_) => {
+                // the existing file becomes the repo copy, so nothing is lost by linking over it
+                sfs::move_file(target_dest, target_source).with_context(|| {
+                    format!(
+                        "Failed to absorb {} into {}",
+                        target_dest.display(),
+                        target_source.display()
+                    )
+                })?;
+                stats.absorbed += 1;
+                say!(
+                    "[ ABSORB ] {} -> {}",
+                    target_dest.display(),
+                    target_source.display()
+                );
+
+                self.link_into_place(args, stats, target_dest, target_source)
+            }
             Confirmed(reason) => {
                 match reason {
                     NotFound | StatusInvalid => {} // shouldn't be any conflicts to remove ??
@@ -568,97 +959,209 @@ impl Dots {
                         }
 
                         if args.verbose {
-                            println!("Removed {} at {}", dest_status, target_dest.display());
+                            say!("Removed {} at {}", dest_status, target_dest.display());
                         }
                     }
                 }
 
-                // should probably only be a necessary check for NotFound ?? but whatever
-                if let Some(parent) = target_dest.parent() {
-                    sfs::create_dir_all(parent).with_context(|| {
-                        format!(
-                            "Failed to create parent directory for {}",
-                            target_dest.display()
-                        )
-                    })?;
-                }
-
-                sfs::create_symlink(&target_source, &target_dest).with_context(|| {
-                    format!(
-                        "Failed to create symlink {} -> {}",
-                        target_dest.display(),
-                        target_source.display()
-                    )
-                })?;
-                stats.symlinks_added += 1;
+                self.link_into_place(args, stats, target_dest, target_source)
+            }
+            Denied(StatusError(e)) => Err(anyhow!(e)),
+            Denied(_) => {
+                stats.skip(target_dest, &operation);
 
                 if args.verbose {
-                    println!(
-                        "Linked {} -> {}",
-                        target_dest.display(),
-                        target_source.display()
-                    );
+                    say!("Skipping link for {}: {}", target_dest.display(), operation);
                 }
 
-                // update trackfile
-                self.state.insert(target_dest, target_source);
+                errors.conflict(&mut *self.prompter, target_dest, &operation)
+            }
+        }
+    }
 
-                Ok(())
+    // whatever was in the way is already gone (or backed up)
+    fn link_into_place(
+        &mut self,
+        args: &DotsLinkArgs,
+        stats: &mut LinkStats,
+        target_dest: &PathBuf,
+        target_source: &PathBuf,
+    ) -> Result<()> {
+        // should probably only be a necessary check for NotFound ?? but whatever
+        if let Some(parent) = target_dest.parent() {
+            let created = sfs::create_dir_all_missing(parent).with_context(|| {
+                format!(
+                    "Failed to create parent directory for {}",
+                    target_dest.display()
+                )
+            })?;
+            // under sudo (or --user) these would otherwise end up owned by root; only ever inside
+            // the user's home, though (anything else goes through the helper)
+            for dir in created.into_iter() {
+                if dir.starts_with(&self.env.home) {
+                    sfs::chown(&dir, self.env.owner)?;
+                }
+                // remembered so unlink/clean can remove them again once empty
+                self.state.insert_dir(dir);
             }
-            Denied(reason) => match reason {
-                StatusError(e) => Err(e),
-                _ => {
-                    stats.targets_skipped += 1;
+        }
 
-                    if args.verbose {
-                        println!("Skipping link for {}: {}", target_dest.display(), operation);
-                    }
+        sfs::create_symlink(&target_source, &target_dest).with_context(|| {
+            format!(
+                "Failed to create symlink {} -> {}",
+                target_dest.display(),
+                target_source.display()
+            )
+        })?;
+        if target_dest.starts_with(&self.env.home) {
+            sfs::chown(&target_dest, self.env.owner)?;
+        }
+        stats.symlinks_added += 1;
+        stats.changed.push(target_dest.clone());
 
-                    Ok(())
-                }
-            },
+        if args.verbose {
+            say!(
+                "Linked {} -> {}",
+                target_dest.display(),
+                target_source.display()
+            );
         }
+
+        // update trackfile
+        self.state.insert(target_dest, target_source);
+
+        Ok(())
     }
 
     // source of truth (correctness of symlink) is from CURRENT trackfile state
     pub fn link(&mut self, args: &DotsLinkArgs, opts: &LinkOptions) -> Result<LinkStats> {
+        let mut user_choices = UserChoiceState::default();
+        let result = self.link_pass(args, opts, &mut user_choices);
+
+        // remembered even if the pass bailed partway; those answers were still given
+        if args.save_answers && !args.dry_run {
+            self.policy
+                .remember(&user_choices, &self.env)
+                .context("Failed to remember answers")?;
+        }
+
+        result
+    }
+
+    fn link_pass(
+        &mut self,
+        args: &DotsLinkArgs,
+        opts: &LinkOptions,
+        user_choices: &mut UserChoiceState,
+    ) -> Result<LinkStats> {
+        let generated;
         let targets = match opts.trackfile {
             Some(tf) => tf,
-            None => Trackfile::generate(args.target, self.env)
-                .context("Failed to resolve link targets")?,
+            None => {
+                generated = self
+                    .generate_targets(&args.target)
+                    .context("Failed to resolve link targets")?;
+                &generated
+            }
         };
 
         if targets.is_empty() {
-            println!("No dotfiles found to link based on the provided target and filters.");
-            return Ok(());
+            say!("No dotfiles found to link based on the provided target and filters.");
+            return Ok(LinkStats::new(Phase::Link, 0));
         }
 
-        println!("Preparing to link {} dotfiles...", targets.len());
+        say!("Preparing to link {} dotfiles...", targets.len());
 
-        let stats = LinkStats::new(targets.len());
+        let mut stats = LinkStats::new(Phase::Link, targets.len());
 
-        let mut user_never_bail = false;
-        let user_choices = UserChoiceState::default();
+        let mut errors = ErrorHandler::new(args.errors.policy(), args.interactive);
 
-        for (target_dest, target_source) in targets.into_iter() {
-            let bail_msg = format!(
-                "User bailed link operation from {} to {}",
-                target_dest.display(),
-                target_source.display()
+        let planned: Vec<PathBuf> = targets.iter().map(|(dest, _)| dest.clone()).collect();
+        for e in self.run_hooks(args, HookKind::PreLink, &targets, &planned) {
+            stats.error(None, &e);
+            eprintln!("[ ERROR ] {:#}", e);
+            errors.error(&mut *self.prompter, None, e)?;
+        }
+
+        let (user_targets, privileged_targets) = partition(&targets, &self.env.home);
+        if !privileged_targets.is_empty() {
+            say!(
+                "{} of these require elevated privileges and will be linked via {}.",
+                privileged_targets.len(),
+                self.elevation(args)
             );
+        }
 
-            if let Err(e) =
-                self.try_perform_link(args, &stats, &user_choices, &target_dest, &target_source)
-            {
-                stats.errors += 1;
-                if args.bail && !user_never_bail {
-                    user_never_bail = try_bail(args, e, bail_msg)?;
+        let plan = user_targets
+            .into_iter()
+            .map(|entry| (entry, false))
+            .chain(privileged_targets.into_iter().map(|entry| (entry, true)));
+
+        for ((target_dest, target_source), privileged) in plan {
+            stats.record_set(targets.source_set(&target_dest));
+
+            if let Err(e) = self.try_perform_link(
+                args,
+                &mut stats,
+                user_choices,
+                &mut errors,
+                &target_dest,
+                &target_source,
+                privileged,
+            ) {
+                stats.error(Some(&target_dest), &e);
+                errors.error(&mut *self.prompter, Some(&target_dest), e)?;
+            }
+        }
+
+        if !args.dry_run {
+            // if the helper can't run, none of the batch happened, but everything before it did
+            let report = match self.flush_privileged(args) {
+                Ok(report) => report,
+                Err(e) => {
+                    eprintln!("[ ERROR ] {:#}", e);
+                    stats.error(None, &e);
+                    errors.error(&mut *self.prompter, None, e)?;
+                    HelperReport::default()
+                }
+            };
+            for result in report.results {
+                if let Some(e) = result.error {
+                    let e = anyhow!(e);
+                    eprintln!("[ ERROR ] {}: {}", result.dest.display(), e);
+                    stats.error(Some(&result.dest), &e);
+                    errors.error(&mut *self.prompter, Some(&result.dest), e)?;
+                    continue;
+                }
+
+                stats.symlinks_added += 1;
+                if let Some(backup) = &result.backed_up {
+                    stats.backed_up += 1;
+                    say!(
+                        "[ BACKUP ] {} -> {}",
+                        result.dest.display(),
+                        backup.display()
+                    );
+                }
+                stats.changed.push(result.dest);
+                if result.removed_symlink {
+                    stats.symlinks_removed += 1;
+                } else if result.removed_file {
+                    stats.files_removed += 1;
                 }
             }
         }
 
+        let changed = stats.changed.clone();
+        for e in self.run_hooks(args, HookKind::PostLink, &targets, &changed) {
+            stats.error(None, &e);
+            eprintln!("[ ERROR ] {:#}", e);
+            errors.error(&mut *self.prompter, None, e)?;
+        }
+
+        stats.finish();
         if !opts.silent {
-            stats.display(args);
+            stats.report(opts.format, args.dry_run);
         }
 
         Ok(stats)
@@ -667,14 +1170,16 @@ impl Dots {
     pub fn try_perform_unlink(
         &mut self,
         args: &DotsLinkArgs,
-        stats: &UnlinkStats,
-        user_choices: &UserChoiceState,
+        stats: &mut UnlinkStats,
+        user_choices: &mut UserChoiceState,
+        errors: &mut ErrorHandler,
         target_dest: &PathBuf,
         target_source: &PathBuf,
+        privileged: bool,
     ) -> Result<()> {
         let dest_status = sfs::get_status(target_dest);
-        let is_tracked = self.state.contains_dest(target_dest);
-        let tracked_source = self.state.get_source(target_dest);
+        let tracked_source = self.tracked_source(target_dest);
+        let is_tracked = tracked_source.is_some();
 
         let operation = match dest_status {
             FilesystemStatus::NotFound => Denied(NotFound),
@@ -689,30 +1194,84 @@ impl Dots {
                     Confirmed(IntendedSymlink)
                 } else if is_tracked {
                     if points_to.unwrap() == tracked_source.unwrap() {
-                        ForceCorrectSymlink.consult_user(args, user_choices, target_dest, points_to)
+                        ForceCorrectSymlink.consult_user(
+                            args,
+                            &self.policy,
+                            &mut *self.prompter,
+                            user_choices,
+                            target_dest,
+                            points_to,
+                            None,
+                        )
                     } else {
-                        ForceSymlink.consult_user(args, user_choices, target_dest, points_to)
+                        ForceSymlink.consult_user(
+                            args,
+                            &self.policy,
+                            &mut *self.prompter,
+                            user_choices,
+                            target_dest,
+                            points_to,
+                            None,
+                        )
                     }
                 } else {
-                    ForceDangerously.consult_user(args, user_choices, target_dest, points_to)
+                    ForceDangerously.consult_user(
+                        args,
+                        &self.policy,
+                        &mut *self.prompter,
+                        user_choices,
+                        target_dest,
+                        points_to,
+                        None,
+                    )
                 }
             }
             FilesystemStatus::File => {
                 if is_tracked {
-                    ForceFile.consult_user(args, user_choices, target_dest, None)
+                    ForceFile.consult_user(
+                        args,
+                        &self.policy,
+                        &mut *self.prompter,
+                        user_choices,
+                        target_dest,
+                        None,
+                        None,
+                    )
                 } else {
-                    ForceDangerously.consult_user(args, user_choices, target_dest, None)
+                    ForceDangerously.consult_user(
+                        args,
+                        &self.policy,
+                        &mut *self.prompter,
+                        user_choices,
+                        target_dest,
+                        None,
+                        None,
+                    )
                 }
             }
             _ => Denied(StatusInvalid),
         };
 
+        stats.record_reason(operation.reason());
+
         // --- Dry Run ---
         if args.dry_run {
-            println!("Unlink {}", target_dest.display());
+            say!("Unlink {}", target_dest.display());
+            if privileged && operation.was_confirmed() {
+                say!("[ DRY RUN --- Privileged ] via {}", self.elevation(args));
+            }
             match operation {
+                Absorb(_) => return Err(absorb_on_unlink(target_dest)),
+                Backup(reason) => {
+                    say!("[ DRY RUN --- Backup ] {}", operation);
+                    stats.backed_up += 1;
+                    match reason {
+                        ForceCorrectSymlink | ForceSymlink => stats.symlinks_removed += 1,
+                        _ => stats.files_removed += 1,
+                    }
+                }
                 Confirmed(reason) => {
-                    println!("[ DRY RUN --- Remove ] {}", operation);
+                    say!("[ DRY RUN --- Remove ] {}", operation);
                     match reason {
                         DanglingSymlink | IntendedSymlink | ForceCorrectSymlink | ForceSymlink => {
                             stats.symlinks_removed += 1
@@ -723,8 +1282,9 @@ impl Dots {
                 Denied(reason) => match reason {
                     StatusError(e) => return Err(e),
                     _ => {
-                        println!("[ DRY RUN --- Skip ] {}", operation);
-                        stats.targets_skipped += 1;
+                        say!("[ DRY RUN --- Skip ] {}", operation);
+                        stats.skip(target_dest, &operation);
+                        errors.conflict(&mut *self.prompter, target_dest, &operation)?;
                     }
                 },
             }
@@ -733,15 +1293,38 @@ impl Dots {
 
         // --- Perform Unlink ---
         match operation {
-            Confirmed(reason) => {
-                // only symlinks and files are valid for removal
-                sfs::remove_file(target_dest).with_context(|| {
-                    format!(
-                        "Failed to remove {} at {}",
-                        dest_status,
-                        target_dest.display()
-                    )
-                })?;
+            Absorb(_) => Err(absorb_on_unlink(target_dest)),
+            Confirmed(_) | Backup(_) if privileged => {
+                self.privileged.push(HelperAction::Unlink {
+                    dest: target_dest.clone(),
+                    backup: matches!(operation, Backup(_)),
+                });
+
+                if args.verbose {
+                    say!("Queued privileged unlink {}", target_dest.display());
+                }
+
+                Ok(())
+            }
+            Confirmed(_) | Backup(_) => {
+                if let Backup(_) = operation {
+                    let backup = sfs::backup(target_dest)?;
+                    stats.backed_up += 1;
+                    say!(
+                        "[ BACKUP ] {} -> {}",
+                        target_dest.display(),
+                        backup.display()
+                    );
+                } else {
+                    // only symlinks and files are valid for removal
+                    sfs::remove_file(target_dest).with_context(|| {
+                        format!(
+                            "Failed to remove {} at {}",
+                            dest_status,
+                            target_dest.display()
+                        )
+                    })?;
+                }
                 if let FilesystemStatus::Symlink { .. } = dest_status {
                     stats.symlinks_removed += 1;
                 } else {
@@ -749,27 +1332,41 @@ impl Dots {
                 }
 
                 if args.verbose {
-                    println!("Removed {} at {}", dest_status, target_dest.display());
+                    say!("Removed {} at {}", dest_status, target_dest.display());
                 }
 
+                stats.changed.push(target_dest.clone());
+
                 // update trackfile
                 self.state.remove(target_dest);
 
+                if let Some(parent) = target_dest.parent() {
+                    let pruned = self.state.prune_dirs(parent, false)?;
+                    stats.dirs_removed += pruned.len();
+
+                    if args.verbose {
+                        for dir in pruned.iter() {
+                            say!("Removed empty directory {}", dir.display());
+                        }
+                    }
+                }
+
                 Ok(())
             }
             Denied(reason) => match reason {
                 StatusError(e) => Err(e),
                 _ => {
-                    stats.targets_skipped += 1;
+                    stats.skip(target_dest, &operation);
 
                     if args.verbose {
-                        println!(
+                        say!(
                             "Skipping unlink for {}: {}",
                             target_dest.display(),
                             operation
                         );
                     }
-                    Ok(())
+
+                    errors.conflict(&mut *self.prompter, target_dest, &operation)
                 }
             },
         }
@@ -782,83 +1379,580 @@ impl Dots {
 
     // source of truth (correctness of symlink) is from GENERATED trackfile state
     pub fn unlink(&mut self, args: &DotsLinkArgs, opts: &LinkOptions) -> Result<UnlinkStats> {
+        let mut user_choices = UserChoiceState::default();
+        let result = self.unlink_pass(args, opts, &mut user_choices);
+
+        // remembered even if the pass bailed partway; those answers were still given
+        if args.save_answers && !args.dry_run {
+            self.policy
+                .remember(&user_choices, &self.env)
+                .context("Failed to remember answers")?;
+        }
+
+        result
+    }
+
+    fn unlink_pass(
+        &mut self,
+        args: &DotsLinkArgs,
+        opts: &LinkOptions,
+        user_choices: &mut UserChoiceState,
+    ) -> Result<UnlinkStats> {
+        let generated;
         let targets = match opts.trackfile {
             Some(tf) => tf,
-            None => Trackfile::generate(self, args.target)
-                .context("Failed to resolve unlink targets")?,
+            None => {
+                generated = self
+                    .generate_targets(&args.target)
+                    .context("Failed to resolve unlink targets")?;
+                &generated
+            }
         };
 
         if targets.is_empty() {
-            println!("No dotfiles found to unlink based on the provided target and filters.");
-            return Ok(());
+            say!("No dotfiles found to unlink based on the provided target and filters.");
+            return Ok(UnlinkStats::new(Phase::Unlink, 0));
         }
 
-        println!(
+        say!(
             "Preparing to unlink {} specified dotfiles...",
             targets.len()
         );
 
-        let stats = UnlinkStats::new(targets.len());
+        let mut stats = UnlinkStats::new(Phase::Unlink, targets.len());
 
-        let mut user_never_bail = false;
-        let user_choices = UserChoiceState::default();
+        let mut errors = ErrorHandler::new(args.errors.policy(), args.interactive);
 
-        for (target_dest, target_source) in targets.into_iter() {
-            let bail_msg = format!(
-                "User bailed unlink operation on {} (from {})",
-                target_dest.display(),
-                target_source.display()
-            );
+        let planned: Vec<PathBuf> = targets.iter().map(|(dest, _)| dest.clone()).collect();
+        for e in self.run_hooks(args, HookKind::PreUnlink, &targets, &planned) {
+            stats.error(None, &e);
+            eprintln!("[ ERROR ] {:#}", e);
+            errors.error(&mut *self.prompter, None, e)?;
+        }
+
+        let (user_targets, privileged_targets) = partition(&targets, &self.env.home);
+        let plan = user_targets
+            .into_iter()
+            .map(|entry| (entry, false))
+            .chain(privileged_targets.into_iter().map(|entry| (entry, true)));
+
+        for ((target_dest, target_source), privileged) in plan {
+            stats.record_set(targets.source_set(&target_dest));
+
+            if let Err(e) = self.try_perform_unlink(
+                args,
+                &mut stats,
+                user_choices,
+                &mut errors,
+                &target_dest,
+                &target_source,
+                privileged,
+            ) {
+                stats.error(Some(&target_dest), &e);
+                errors.error(&mut *self.prompter, Some(&target_dest), e)?;
+            }
+        }
+
+        if !args.dry_run {
+            // if the helper can't run, none of the batch happened, but everything before it did
+            let report = match self.flush_privileged(args) {
+                Ok(report) => report,
+                Err(e) => {
+                    eprintln!("[ ERROR ] {:#}", e);
+                    stats.error(None, &e);
+                    errors.error(&mut *self.prompter, None, e)?;
+                    HelperReport::default()
+                }
+            };
+            for result in report.results {
+                if let Some(e) = result.error {
+                    let e = anyhow!(e);
+                    eprintln!("[ ERROR ] {}: {}", result.dest.display(), e);
+                    stats.error(Some(&result.dest), &e);
+                    errors.error(&mut *self.prompter, Some(&result.dest), e)?;
+                    continue;
+                }
 
-            if let Err(e) =
-                self.try_perform_unlink(args, &stats, &user_choices, &target_dest, &target_source)
-            {
-                stats.errors += 1;
-                if args.bail && !user_never_bail {
-                    user_never_bail = try_bail(args, e, bail_msg)?;
+                if result.removed_symlink {
+                    stats.symlinks_removed += 1;
+                } else {
+                    stats.files_removed += 1;
                 }
+                stats.dirs_removed += result.dirs_removed.len();
+                if let Some(backup) = &result.backed_up {
+                    stats.backed_up += 1;
+                    say!(
+                        "[ BACKUP ] {} -> {}",
+                        result.dest.display(),
+                        backup.display()
+                    );
+                }
+                stats.changed.push(result.dest);
             }
         }
 
+        let changed = stats.changed.clone();
+        for e in self.run_hooks(args, HookKind::PostUnlink, &targets, &changed) {
+            stats.error(None, &e);
+            eprintln!("[ ERROR ] {:#}", e);
+            errors.error(&mut *self.prompter, None, e)?;
+        }
+
+        stats.finish();
         if !opts.silent {
-            stats.display(args);
+            stats.report(opts.format, args.dry_run);
         }
 
         Ok(stats)
     }
 
-    pub fn relink(&mut self, args: &DotsLinkArgs, opts: &mut LinkOptions) -> Result<RelinkStats> {
-        match opts.trackfile {
-            Some(tf) => {}
+    pub fn relink(&mut self, args: &DotsLinkArgs, opts: &LinkOptions) -> Result<RelinkStats> {
+        let generated;
+        let targets = match opts.trackfile {
+            Some(tf) => tf,
             None => {
-                opts.trackfile = Some(
-                    Trackfile::generate(args.target, self.env)
-                        .context("Failed to resolve relink targets")?,
-                )
+                generated = self
+                    .generate_targets(&args.target)
+                    .context("Failed to resolve relink targets")?;
+                &generated
             }
-        }
-        let targets = opts.trackfile.unwrap();
+        };
 
         if targets.is_empty() {
-            println!("No dotfiles found to relink based on the provided target and filters.");
-            return Ok(());
+            say!("No dotfiles found to relink based on the provided target and filters.");
+            return Ok(RelinkStats::new(
+                UnlinkStats::new(Phase::Unlink, 0),
+                LinkStats::new(Phase::Link, 0),
+            ));
         }
 
-        println!(
+        say!(
             "Preparing to relink {} specified dotfiles...",
             targets.len()
         );
 
-        let unlink_stats = self.unlink(args, opts)?;
-        let link_stats = self.link(args, opts)?;
+        // both phases work off the one plan, and report together below
+        let phase_opts = LinkOptions {
+            trackfile: Some(targets),
+            silent: true,
+            format: opts.format,
+        };
+        let unlink_stats = self.unlink(args, &phase_opts)?;
+        let link_stats = self.link(args, &phase_opts)?;
+
+        let stats = RelinkStats::new(unlink_stats, link_stats);
+        if !opts.silent {
+            stats.report(opts.format, args.dry_run);
+        }
 
-        Ok(RelinkStats::new(unlink_stats, link_stats))
+        Ok(stats)
     }
 
     pub fn status(&self, args: &DotsStatusArgs, opts: &LinkOptions) -> Result<()> {
+        // before anything else: `clean` would throw all of these away
+        if let Some((old_root, new_root)) = self.detect_relocation() {
+            say!(
+                "Tracked links are dangling because {} seems to have moved to {}. To repair them, run:",
+                old_root.display(),
+                new_root.display()
+            );
+            say!(
+                "  dots relocate {} {}",
+                old_root.display(),
+                new_root.display()
+            );
+            return Ok(());
+        }
+
         Err(anyhow!("[ STATUS ] Not implemented yet :p"))
     }
+    // drop what's left behind in the trackfile: dangling links we created, entries whose
+    // destination is already gone, and directories we created that are now empty
     pub fn clean(&mut self, args: &DotsCleanArgs, opts: &LinkOptions) -> Result<()> {
-        Err(anyhow!("[ CLEAN ] Not implemented yet :p"))
+        let entries: Vec<(PathBuf, PathBuf)> = self
+            .state
+            .iter()
+            .map(|(dest, source)| (dest.clone(), source.clone()))
+            .collect();
+
+        let mut links_removed = 0;
+        let mut entries_forgotten = 0;
+        let mut errors = ErrorHandler::new(args.errors.policy(), args.interactive);
+
+        for (dest, source) in entries.iter() {
+            match sfs::get_status(dest) {
+                FilesystemStatus::Symlink { dangling: true, .. } => {
+                    if args.interactive {
+                        let choice = prompt_user_choice(
+                            &mut *self.prompter,
+                            format!(
+                                "Remove dangling symlink at {} (points to: {})",
+                                dest.display(),
+                                source.display()
+                            ),
+                            &DanglingSymlink,
+                            Opts::YesNo,
+                        );
+                        match choice {
+                            Choice::Yes { .. } => {}
+                            Choice::Quit => {
+                                errors.conflict(&mut *self.prompter, dest, &Denied(UserQuit))?;
+                                continue;
+                            }
+                            _ => continue,
+                        }
+                    }
+
+                    if args.dry_run {
+                        say!("[ DRY RUN --- Remove ] {}", dest.display());
+                    } else if let Err(e) = sfs::remove_file(dest) {
+                        eprintln!("[ ERROR ] {}: {:#}", dest.display(), e);
+                        errors.error(&mut *self.prompter, Some(dest), e)?;
+                        continue;
+                    } else {
+                        self.state.remove(dest);
+                    }
+                    links_removed += 1;
+                }
+                FilesystemStatus::NotFound => {
+                    if args.dry_run {
+                        say!("[ DRY RUN --- Forget ] {}", dest.display());
+                    } else {
+                        self.state.remove(dest);
+                    }
+                    entries_forgotten += 1;
+                }
+                _ => {}
+            }
+        }
+
+        let dirs_removed = self.state.prune_all_dirs(args.dry_run)?;
+        if args.dry_run {
+            for dir in dirs_removed.iter() {
+                say!("[ DRY RUN --- Remove Dir ] {}", dir.display());
+            }
+        }
+
+        if !opts.silent {
+            let prefix = if args.dry_run {
+                "Would have removed"
+            } else {
+                "Removed"
+            };
+            say!(
+                "{} {} dangling symlink(s) and {} empty dir(s).",
+                prefix,
+                links_removed,
+                dirs_removed.len()
+            );
+            say!(
+                "Forgot {} entries whose destination no longer exists.",
+                entries_forgotten
+            );
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    use crate::prompt::ScriptedPrompter;
+    use crate::testing;
+
+    // a regular file in the way of a link, and the source that would replace it
+    fn conflict() -> (TempDir, PathBuf, PathBuf) {
+        let root = tempfile::tempdir().unwrap();
+        let dest = root.path().join("home").join("config");
+        let source = root.path().join("dots").join("config");
+        fs::create_dir_all(dest.parent().unwrap()).unwrap();
+        fs::create_dir_all(source.parent().unwrap()).unwrap();
+        fs::write(&dest, "mine\n").unwrap();
+        fs::write(&source, "theirs\n").unwrap();
+        (root, dest, source)
+    }
+
+    fn ask(
+        prompter: &mut ScriptedPrompter,
+        state: &mut UserChoiceState,
+        dest: &PathBuf,
+        source: &PathBuf,
+    ) -> Op {
+        prompt_user(prompter, state, &ForceFile, dest, None, Some(source))
+    }
+
+    #[test]
+    fn diff_is_shown_before_asking_again() {
+        let (_root, dest, source) = conflict();
+        let mut prompter = ScriptedPrompter::new(["d", "y"]);
+
+        let op = ask(
+            &mut prompter,
+            &mut UserChoiceState::default(),
+            &dest,
+            &source,
+        );
+        assert!(matches!(op, Confirmed(ForceFile)));
+        assert!(prompter.transcript[0].contains("Replace file (not a symlink!)"));
+        assert!(prompter.transcript[0].contains("[D]iff/[S]how/[A]bsorb/[SD] skip dir"));
+        assert!(
+            prompter.transcript[2].contains("-mine") && prompter.transcript[2].contains("+theirs")
+        );
+        // the same question again
+        assert_eq!(prompter.transcript[3], prompter.transcript[0]);
+
+        // nothing was touched by looking
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "mine\n");
+    }
+
+    #[test]
+    fn show_prints_whats_in_the_way() {
+        let (_root, dest, source) = conflict();
+        let mut prompter = ScriptedPrompter::new(["show", "n"]);
+
+        let op = ask(
+            &mut prompter,
+            &mut UserChoiceState::default(),
+            &dest,
+            &source,
+        );
+        assert!(matches!(op, Denied(ForceFile)));
+        assert_eq!(prompter.transcript[2], "mine");
+    }
+
+    #[test]
+    fn absorb_moves_whats_in_the_way_into_the_source_and_links_it() {
+        let (root, dest, source) = conflict();
+        let args = DotsLinkArgs {
+            interactive: true,
+            ..testing::link_args(root.path())
+        };
+        let mut dots = testing::dots(
+            Commands::Link(testing::link_args(root.path())),
+            root.path(),
+            ["a"],
+        );
+        dots.state.insert(dest.clone(), source.clone());
+
+        let mut stats = LinkStats::new(Phase::Link, 1);
+        let mut errors = ErrorHandler::new(args.errors.policy(), args.interactive);
+        dots.try_perform_link(
+            &args,
+            &mut stats,
+            &mut UserChoiceState::default(),
+            &mut errors,
+            &dest,
+            &source,
+            false,
+        )
+        .unwrap();
+
+        // what was in the way is the repo copy now, and the dest reads it through the link
+        assert_eq!(fs::read_to_string(&source).unwrap(), "mine\n");
+        assert_eq!(fs::read_link(&dest).unwrap(), source);
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "mine\n");
+        assert_eq!((stats.absorbed, stats.symlinks_added), (1, 1));
+        assert!(stats.skipped.is_empty() && stats.errors.is_empty());
+    }
+
+    #[test]
+    fn a_link_thats_already_right_is_unchanged_not_skipped() {
+        let (root, dest, source) = conflict();
+        fs::remove_file(&dest).unwrap();
+        std::os::unix::fs::symlink(&source, &dest).unwrap();
+
+        for dry_run in [false, true] {
+            let args = DotsLinkArgs {
+                dry_run,
+                ..testing::link_args(root.path())
+            };
+            let mut dots = testing::dots(
+                Commands::Link(testing::link_args(root.path())),
+                root.path(),
+                Vec::<String>::new(),
+            );
+            let mut stats = LinkStats::new(Phase::Link, 1);
+            // a skip would be a conflict, which bails right away
+            let mut errors = ErrorHandler::new(ErrorPolicy::Bail, false);
+            dots.try_perform_link(
+                &args,
+                &mut stats,
+                &mut UserChoiceState::default(),
+                &mut errors,
+                &dest,
+                &source,
+                false,
+            )
+            .unwrap();
+
+            assert_eq!(stats.unchanged, 1);
+            assert_eq!(stats.changes(), 0);
+            assert!(stats.skipped.is_empty() && stats.errors.is_empty());
+            assert_eq!(fs::read_link(&dest).unwrap(), source);
+        }
+    }
+
+    #[test]
+    fn skip_dir_denies_the_rest_of_the_directory_unasked() {
+        let (root, dest, source) = conflict();
+        let mut state = UserChoiceState::default();
+
+        let mut prompter = ScriptedPrompter::new(["sd"]);
+        assert!(matches!(
+            ask(&mut prompter, &mut state, &dest, &source),
+            Denied(ForceFile)
+        ));
+
+        // a sibling isn't asked about at all
+        let sibling = dest.with_file_name("other");
+        let mut prompter = ScriptedPrompter::default();
+        assert!(matches!(
+            ask(&mut prompter, &mut state, &sibling, &source),
+            Denied(ForceFile)
+        ));
+        assert!(prompter.transcript.is_empty());
+
+        // anywhere else still is
+        let elsewhere = root.path().join("elsewhere");
+        let mut prompter = ScriptedPrompter::new(["y"]);
+        assert!(matches!(
+            ask(&mut prompter, &mut state, &elsewhere, &source),
+            Confirmed(ForceFile)
+        ));
+        assert_eq!(prompter.transcript.len(), 2);
+    }
+
+    #[test]
+    fn running_out_of_answers_quits() {
+        let (_root, dest, source) = conflict();
+        let mut prompter = ScriptedPrompter::new(["nonsense"]);
+
+        let op = ask(
+            &mut prompter,
+            &mut UserChoiceState::default(),
+            &dest,
+            &source,
+        );
+        assert!(matches!(op, Denied(UserQuit)));
+        assert!(prompter.transcript[2].starts_with("Invalid input."));
+    }
+
+    #[test]
+    fn dots_hooks_wrap_the_hooks_of_the_sets_involved() {
+        let root = tempfile::tempdir().unwrap();
+        let log = root.path().join("log");
+        let append = |what: &str| {
+            format!(
+                "echo \"{}\" >> {}",
+                what,
+                shell::quote(&log.to_string_lossy())
+            )
+        };
+
+        let mut dots = testing::dots(
+            Commands::Link(testing::link_args(root.path())),
+            root.path(),
+            [""; 0],
+        );
+        dots.config = toml::from_str(&format!(
+            "[dots.hooks]\npre_link = {:?}\npost_link = {:?}\n",
+            append("dots $DOTS_HOOK"),
+            append("dots $DOTS_HOOK"),
+        ))
+        .unwrap();
+
+        let mut targets = Trackfile::default();
+        let dest = root.path().join("home/.config/nvim");
+        let set = root.path().join("base");
+        targets.insert_from_set(dest.clone(), set.join("nvim"), set.clone());
+        for (source_set, name) in [(set.clone(), "base"), (root.path().join("other"), "other")] {
+            let command = append(&format!("{} $DOTS_HOOK $DOTS_VAR_app", name));
+            targets.add_hooks(SetHooks {
+                source_set,
+                hooks: Hooks {
+                    pre_link: Some(command.clone()),
+                    post_link: Some(command),
+                    ..Default::default()
+                },
+                vars: HashMap::from([("app".to_string(), "nvim".to_string())]),
+            });
+        }
+
+        let args = testing::link_args(root.path());
+        for kind in [HookKind::PreLink, HookKind::PostLink] {
+            let errors = dots.run_hooks(&args, kind, &targets, std::slice::from_ref(&dest));
+            assert!(errors.is_empty(), "{:?}", errors);
+        }
+        // nothing linked, nothing to run
+        assert!(
+            dots.run_hooks(&args, HookKind::PostLink, &targets, &[])
+                .is_empty()
+        );
+
+        // the other set had no part in it
+        assert_eq!(
+            fs::read_to_string(&log).unwrap(),
+            "dots pre_link\nbase pre_link nvim\nbase post_link nvim\ndots post_link\n"
+        );
+    }
+
+    #[test]
+    fn each_way_of_stopping_has_its_own_exit_code() {
+        let dest = Path::new("/home/someone/.config");
+        let conflict = Denied(ForceFile);
+        let code = |result: Result<()>| crate::exit_code(&result);
+        let mut prompter = ScriptedPrompter::new(["n"]);
+
+        // errors halt with 2, but only when told to
+        let mut errors = ErrorHandler::new(ErrorPolicy::Skip, false);
+        assert_eq!(
+            code(errors.error(&mut prompter, Some(dest), anyhow!("boom"))),
+            0
+        );
+        let mut errors = ErrorHandler::new(ErrorPolicy::BailErrors, false);
+        assert_eq!(
+            code(errors.error(&mut prompter, Some(dest), anyhow!("boom"))),
+            2
+        );
+
+        // conflicts halt with 3, and only under bail
+        assert_eq!(code(errors.conflict(&mut prompter, dest, &conflict)), 0);
+        let mut errors = ErrorHandler::new(ErrorPolicy::Bail, false);
+        assert_eq!(code(errors.conflict(&mut prompter, dest, &conflict)), 3);
+
+        // saying no to going on is 4, and so is quitting at a conflict prompt
+        let mut errors = ErrorHandler::new(ErrorPolicy::Ask, false);
+        assert_eq!(code(errors.error(&mut prompter, None, anyhow!("boom"))), 4);
+        let mut errors = ErrorHandler::new(ErrorPolicy::Skip, true);
+        assert_eq!(
+            code(errors.conflict(&mut prompter, dest, &Denied(UserQuit))),
+            4
+        );
+
+        // anything else that went wrong is just a failure
+        assert_eq!(code(Err(anyhow!("boom"))), 1);
+    }
+
+    #[test]
+    fn asking_on_errors_stops_after_yes_to_all() {
+        let dest = Path::new("/home/someone/.config");
+        let mut prompter = ScriptedPrompter::new(["ya"]);
+
+        let mut errors = ErrorHandler::new(ErrorPolicy::Ask, false);
+        for _ in 0..3 {
+            errors
+                .error(&mut prompter, Some(dest), anyhow!("boom"))
+                .unwrap();
+        }
+        // an interactive run already asked about the conflict itself
+        let mut errors = ErrorHandler::new(ErrorPolicy::Ask, true);
+        errors
+            .conflict(&mut prompter, dest, &Denied(ForceFile))
+            .unwrap();
+
+        assert_eq!(prompter.transcript.len(), 2);
     }
 }
@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use crate::shell;
+
+// [dots.hooks] / [dotfiles.hooks]
+#[derive(Deserialize, JsonSchema, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Hooks {
+    pub pre_link: Option<String>,
+    pub post_link: Option<String>,
+    pub pre_unlink: Option<String>,
+    pub post_unlink: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookKind {
+    PreLink,
+    PostLink,
+    PreUnlink,
+    PostUnlink,
+}
+
+impl fmt::Display for HookKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                HookKind::PreLink => "pre_link",
+                HookKind::PostLink => "post_link",
+                HookKind::PreUnlink => "pre_unlink",
+                HookKind::PostUnlink => "post_unlink",
+            }
+        )
+    }
+}
+
+impl Hooks {
+    pub fn get(&self, kind: HookKind) -> Option<&String> {
+        match kind {
+            HookKind::PreLink => self.pre_link.as_ref(),
+            HookKind::PostLink => self.post_link.as_ref(),
+            HookKind::PreUnlink => self.pre_unlink.as_ref(),
+            HookKind::PostUnlink => self.post_unlink.as_ref(),
+        }
+    }
+}
+
+// hooks belonging to a single source set, along with the `replace_map` vars it was resolved with
+#[derive(Debug, Clone, Default)]
+pub struct SetHooks {
+    pub source_set: PathBuf,
+    pub hooks: Hooks,
+    pub vars: HashMap<String, String>,
+}
+
+// everything a hook gets to see, on top of the caller's environment:
+// - DOTS_HOOK: which hook is running (e.g., post_link)
+// - DOTS_SOURCE_SET: the source set the hook belongs to (empty for [dots] hooks)
+// - DOTS_CHANGED: newline separated destinations (planned ones for pre_*, changed ones for post_*)
+// - the set's `replace_map` vars, as DOTS_VAR_<name> so a var called PATH or HOME can't shadow the
+//   real one for the hook
+pub fn hook_env(
+    kind: HookKind,
+    source_set: Option<&PathBuf>,
+    changed: &[PathBuf],
+    vars: &HashMap<String, String>,
+) -> HashMap<String, String> {
+    let mut env: HashMap<String, String> = vars
+        .iter()
+        .map(|(k, v)| (format!("DOTS_VAR_{}", k), v.clone()))
+        .collect();
+
+    env.insert("DOTS_HOOK".to_string(), kind.to_string());
+    env.insert(
+        "DOTS_SOURCE_SET".to_string(),
+        source_set
+            .map(|s| s.display().to_string())
+            .unwrap_or_default(),
+    );
+    env.insert(
+        "DOTS_CHANGED".to_string(),
+        changed
+            .iter()
+            .map(|p| p.display().to_string())
+            .collect::<Vec<String>>()
+            .join("\n"),
+    );
+
+    env
+}
+
+pub fn run_hook(
+    kind: HookKind,
+    command: &str,
+    source_set: Option<&PathBuf>,
+    changed: &[PathBuf],
+    vars: &HashMap<String, String>,
+    dry_run: bool,
+) -> Result<()> {
+    let label = match source_set {
+        Some(set) => format!("{} ({})", kind, set.display()),
+        None => kind.to_string(),
+    };
+
+    if dry_run {
+        say!("[ DRY RUN --- Hook ] {}: {}", label, command);
+        return Ok(());
+    }
+
+    say!("[ HOOK ] {}: {}", label, command);
+    let env = hook_env(kind, source_set, changed, vars);
+    shell::runner()
+        .exec(command, &env)
+        .with_context(|| format!("Hook {} failed", label))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vars_are_prefixed_so_they_cant_shadow_what_we_set() {
+        let vars = HashMap::from([
+            ("HOOK".to_string(), "mine".to_string()),
+            ("PATH".to_string(), "/nowhere".to_string()),
+        ]);
+        let set = PathBuf::from("/dots/base");
+        let changed = [PathBuf::from("/home/a"), PathBuf::from("/home/b")];
+
+        let env = hook_env(HookKind::PostLink, Some(&set), &changed, &vars);
+        assert_eq!(env["DOTS_HOOK"], "post_link");
+        assert_eq!(env["DOTS_VAR_HOOK"], "mine");
+        assert_eq!(env["DOTS_VAR_PATH"], "/nowhere");
+        assert!(!env.contains_key("PATH"));
+        assert_eq!(env["DOTS_SOURCE_SET"], "/dots/base");
+        assert_eq!(env["DOTS_CHANGED"], "/home/a\n/home/b");
+
+        // [dots] hooks don't belong to any set
+        let env = hook_env(HookKind::PreUnlink, None, &[], &HashMap::new());
+        assert_eq!(env["DOTS_SOURCE_SET"], "");
+        assert_eq!(env["DOTS_CHANGED"], "");
+        assert_eq!(env.len(), 3);
+    }
+}
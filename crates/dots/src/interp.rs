@@ -0,0 +1,539 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{anyhow, Result};
+use nix::unistd::User;
+
+use shared::bos;
+
+// the one template language for sources, targets, excludes and `replace_map` values:
+//   <var>            a var (from `replace_map`, or bound by matching `*`)
+//   ${ENV}, $ENV     an environment variable
+//   ${ENV:-default}  ... or `default` (itself a template) if it's unset or empty
+//   ${ENV:?message}  ... or an error saying `message`
+//   ~, ~user         a home directory, at the very start only
+//   \x               `x` as is (e.g., `\<`, `\$`, `\~`, `\\`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Piece {
+    Text(String),
+    Var(String),
+    Env {
+        name: String,
+        fallback: Option<Fallback>,
+    },
+    Home(Option<String>), // whose; None for the user we're acting for
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fallback {
+    Default(String),
+    Error(String),
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+// `template` is only there to say where things went wrong
+fn syntax(template: &str, at: usize, message: &str) -> anyhow::Error {
+    anyhow!("{} at column {} of `{}`", message, at + 1, template)
+}
+
+pub fn parse(template: &str) -> Result<Vec<Piece>> {
+    let mut pieces = vec![];
+    let mut text = String::new();
+    let chars: Vec<(usize, char)> = template.char_indices().collect();
+    let col = |i: usize| chars.get(i).map(|(b, _)| *b).unwrap_or(template.len());
+
+    let flush = |text: &mut String, pieces: &mut Vec<Piece>| {
+        if !text.is_empty() {
+            pieces.push(Piece::Text(std::mem::take(text)));
+        }
+    };
+
+    let mut i = 0;
+    if chars.first().map(|(_, c)| *c) == Some('~') {
+        let end = chars
+            .iter()
+            .position(|(_, c)| *c == '/')
+            .unwrap_or(chars.len());
+        let user: String = chars[1..end].iter().map(|(_, c)| c).collect();
+        pieces.push(Piece::Home((!user.is_empty()).then_some(user)));
+        i = end;
+    }
+
+    while i < chars.len() {
+        let c = chars[i].1;
+        match c {
+            '\\' => {
+                let Some((_, escaped)) = chars.get(i + 1) else {
+                    return Err(syntax(template, col(i), "Nothing to escape after `\\`"));
+                };
+                text.push(*escaped);
+                i += 2;
+            }
+            '<' => {
+                let Some(len) = chars[i + 1..].iter().position(|(_, c)| *c == '>') else {
+                    return Err(syntax(template, col(i), "Unclosed `<`"));
+                };
+                let name: String = chars[i + 1..i + 1 + len].iter().map(|(_, c)| c).collect();
+                if name.is_empty() {
+                    return Err(syntax(template, col(i), "Empty `<>`"));
+                }
+                flush(&mut text, &mut pieces);
+                pieces.push(Piece::Var(name));
+                i += len + 2;
+            }
+            '$' if chars.get(i + 1).map(|(_, c)| *c) == Some('{') => {
+                // the fallback can have `${...}`s of its own
+                let mut depth = 0;
+                let mut close = None;
+                for (j, (_, c)) in chars.iter().enumerate().skip(i + 2) {
+                    match c {
+                        '{' => depth += 1,
+                        '}' if depth == 0 => {
+                            close = Some(j);
+                            break;
+                        }
+                        '}' => depth -= 1,
+                        _ => {}
+                    }
+                }
+                let Some(close) = close else {
+                    return Err(syntax(template, col(i), "Unclosed `${`"));
+                };
+
+                let inner: String = chars[i + 2..close].iter().map(|(_, c)| c).collect();
+                let name_len = inner.chars().take_while(|c| is_name_char(*c)).count();
+                let (name, rest) = inner.split_at(name_len);
+                if name.is_empty() {
+                    return Err(syntax(template, col(i), "Missing variable name in `${}`"));
+                }
+                let fallback = if rest.is_empty() {
+                    None
+                } else if let Some(default) = rest.strip_prefix(":-") {
+                    Some(Fallback::Default(default.to_string()))
+                } else if let Some(message) = rest.strip_prefix(":?") {
+                    Some(Fallback::Error(message.to_string()))
+                } else {
+                    return Err(syntax(
+                        template,
+                        col(i + 2 + name_len),
+                        "Expected `}`, `:-` or `:?`",
+                    ));
+                };
+
+                flush(&mut text, &mut pieces);
+                pieces.push(Piece::Env {
+                    name: name.to_string(),
+                    fallback,
+                });
+                i = close + 1;
+            }
+            '$' => {
+                let len = chars[i + 1..]
+                    .iter()
+                    .take_while(|(_, c)| is_name_char(*c))
+                    .count();
+                // a lone `$` is just a `$`
+                if len == 0 {
+                    text.push('$');
+                    i += 1;
+                    continue;
+                }
+                let name: String = chars[i + 1..i + 1 + len].iter().map(|(_, c)| c).collect();
+                flush(&mut text, &mut pieces);
+                pieces.push(Piece::Env {
+                    name,
+                    fallback: None,
+                });
+                i += len + 1;
+            }
+            c => {
+                text.push(c);
+                i += 1;
+            }
+        }
+    }
+    flush(&mut text, &mut pieces);
+
+    Ok(pieces)
+}
+
+// every `<var>` a template refers to, including those in `${ENV:-default}`s (which can nest)
+pub fn vars(template: &str) -> Result<Vec<String>> {
+    let mut found = vec![];
+    for piece in parse(template)? {
+        match piece {
+            Piece::Var(name) => found.push(name),
+            Piece::Env {
+                fallback: Some(Fallback::Default(default)),
+                ..
+            } => found.extend(vars(&default)?),
+            _ => {}
+        }
+    }
+    Ok(found)
+}
+
+fn home_of(user: Option<&str>, env: &bos::Env) -> Result<String> {
+    match user {
+        None => Ok(env.home.to_string_lossy().to_string()),
+        Some(name) => User::from_name(name)
+            .map_err(|e| anyhow!("Failed to look up user '{}': {}", name, e))?
+            .map(|u| u.dir.to_string_lossy().to_string())
+            .ok_or_else(|| anyhow!("No such user '{}' (for `~{}`)", name, name)),
+    }
+}
+
+// `lookup` gets asked for each `<var>`; None means there's no such var
+pub fn expand(
+    template: &str,
+    env: &bos::Env,
+    lookup: &mut dyn FnMut(&str) -> Result<Option<String>>,
+) -> Result<String> {
+    let mut out = String::new();
+
+    for piece in parse(template)? {
+        match piece {
+            Piece::Text(text) => out.push_str(&text),
+            Piece::Home(user) => out.push_str(&home_of(user.as_deref(), env)?),
+            Piece::Var(name) => match lookup(&name)? {
+                Some(value) => out.push_str(&value),
+                None => {
+                    return Err(anyhow!("Undefined var `<{}>` in `{}`", name, template));
+                }
+            },
+            Piece::Env { name, fallback } => {
+                // like the shell: a fallback covers for empty, too, but a bare empty var is just empty
+                let value = env.get(&name);
+                match (value.clone().filter(|v| !v.is_empty()), fallback) {
+                    (Some(value), _) => out.push_str(&value),
+                    (None, None) if value.is_some() => {}
+                    (None, Some(Fallback::Default(default))) => {
+                        out.push_str(&expand(&default, env, lookup)?)
+                    }
+                    (None, Some(Fallback::Error(message))) if message.is_empty() => {
+                        return Err(anyhow!("`${}` is unset or empty (in `{}`)", name, template));
+                    }
+                    (None, Some(Fallback::Error(message))) => {
+                        return Err(anyhow!("`${}`: {} (in `{}`)", name, message, template));
+                    }
+                    (None, None) => {
+                        return Err(anyhow!(
+                            "Environment variable `${}` isn't set (in `{}`)",
+                            name,
+                            template
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+// against vars that are already resolved
+pub fn expand_with(
+    template: &str,
+    env: &bos::Env,
+    vars: &HashMap<String, String>,
+) -> Result<String> {
+    expand(template, env, &mut |name| Ok(vars.get(name).cloned()))
+}
+
+// a segment that's nothing but one `<var>`, which is what can stand in for `*`
+pub fn lone_var(segment: &str) -> Option<&str> {
+    let name = segment.strip_prefix('<')?.strip_suffix('>')?;
+    (!name.is_empty() && !name.contains(['<', '>'])).then_some(name)
+}
+
+// a path template's segments: split on `/`, except inside `${...}` (whose default can be a path)
+pub fn split_path(template: &str) -> Vec<&str> {
+    let mut segments = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    let mut escaped = false;
+    let mut prev = '\0';
+
+    for (i, c) in template.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '{' if prev == '$' || depth > 0 => depth += 1,
+            '}' if depth > 0 => depth -= 1,
+            '/' if depth == 0 => {
+                segments.push(&template[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+        prev = c;
+    }
+    segments.push(&template[start..]);
+
+    segments.into_iter().filter(|s| !s.is_empty()).collect()
+}
+
+// `replace_map` vars whose values may refer to each other: each one resolved once, in whatever
+// order they depend on each other. `fixed` ones (e.g., from `shell` or `value`) are taken as is
+pub fn resolve_vars(
+    templates: &BTreeMap<String, String>,
+    fixed: HashMap<String, String>,
+    env: &bos::Env,
+) -> Result<HashMap<String, String>> {
+    fn resolve(
+        name: &str,
+        templates: &BTreeMap<String, String>,
+        env: &bos::Env,
+        resolved: &mut HashMap<String, String>,
+        stack: &mut Vec<String>,
+    ) -> Result<Option<String>> {
+        if let Some(value) = resolved.get(name) {
+            return Ok(Some(value.clone()));
+        }
+        let Some(template) = templates.get(name) else {
+            return Ok(None);
+        };
+        if let Some(start) = stack.iter().position(|n| n == name) {
+            let cycle: Vec<&str> = stack[start..].iter().map(String::as_str).collect();
+            return Err(anyhow!(
+                "Vars refer to each other in a cycle: {} -> {}",
+                cycle.join(" -> "),
+                name
+            ));
+        }
+
+        stack.push(name.to_string());
+        let value = expand(template, env, &mut |other| {
+            resolve(other, templates, env, resolved, stack)
+        })
+        .map_err(|e| anyhow!("In var `{}`: {}", name, e))?;
+        stack.pop();
+
+        resolved.insert(name.to_string(), value.clone());
+        Ok(Some(value))
+    }
+
+    let mut resolved = fixed;
+    for name in templates.keys() {
+        resolve(name, templates, env, &mut resolved, &mut vec![])?;
+    }
+    Ok(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env() -> bos::Env {
+        let mut env = bos::Env::detect();
+        env.home = "/home/test".into();
+        env.user = "test".to_string();
+        env
+    }
+
+    fn text(s: &str) -> Piece {
+        Piece::Text(s.to_string())
+    }
+
+    fn env_var(name: &str, fallback: Option<Fallback>) -> Piece {
+        Piece::Env {
+            name: name.to_string(),
+            fallback,
+        }
+    }
+
+    fn vars_of(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    // nothing sets this, so it's as unset as it gets
+    const UNSET: &str = "DOTS_INTERP_TEST_UNSET";
+
+    #[test]
+    fn parses_every_piece() {
+        assert_eq!(
+            parse("<a>.conf").unwrap(),
+            vec![Piece::Var("a".to_string()), text(".conf")]
+        );
+        assert_eq!(
+            parse("${HOME}/x").unwrap(),
+            vec![env_var("HOME", None), text("/x")]
+        );
+        assert_eq!(
+            parse("$HOME-$USER").unwrap(),
+            vec![env_var("HOME", None), text("-"), env_var("USER", None)]
+        );
+        assert_eq!(
+            parse("${X:-<a>/${Y:-z}}").unwrap(),
+            vec![env_var(
+                "X",
+                Some(Fallback::Default("<a>/${Y:-z}".to_string()))
+            )]
+        );
+        assert_eq!(
+            parse("${X:?set it}").unwrap(),
+            vec![env_var("X", Some(Fallback::Error("set it".to_string())))]
+        );
+        assert_eq!(parse("~").unwrap(), vec![Piece::Home(None)]);
+        assert_eq!(
+            parse("~bob/x").unwrap(),
+            vec![Piece::Home(Some("bob".to_string())), text("/x")]
+        );
+        // only at the very start
+        assert_eq!(parse("a/~").unwrap(), vec![text("a/~")]);
+    }
+
+    #[test]
+    fn escapes_and_lone_dollars_are_text() {
+        assert_eq!(parse(r"\<a\>").unwrap(), vec![text("<a>")]);
+        assert_eq!(parse(r"\$HOME").unwrap(), vec![text("$HOME")]);
+        assert_eq!(parse(r"\~/x").unwrap(), vec![text("~/x")]);
+        assert_eq!(parse(r"a\\b").unwrap(), vec![text(r"a\b")]);
+        assert_eq!(parse("a$").unwrap(), vec![text("a$")]);
+        assert_eq!(parse("$/x").unwrap(), vec![text("$/x")]);
+    }
+
+    #[test]
+    fn syntax_errors_say_where() {
+        for (template, message) in [
+            ("a<b", "Unclosed `<` at column 2"),
+            ("<>", "Empty `<>` at column 1"),
+            ("x${A", "Unclosed `${` at column 2"),
+            ("${}", "Missing variable name"),
+            ("${A:x}", "Expected `}`, `:-` or `:?` at column 4"),
+            (r"a\", "Nothing to escape"),
+        ] {
+            let e = parse(template).unwrap_err().to_string();
+            assert!(e.contains(message), "{}: {}", template, e);
+        }
+    }
+
+    #[test]
+    fn vars_include_nested_defaults() {
+        assert_eq!(
+            vars(r"<a>/${X:-<b>/${Y:-<c>}}/\<d>").unwrap(),
+            vec!["a", "b", "c"]
+        );
+        assert!(vars("${X:-<a}").is_err());
+    }
+
+    #[test]
+    fn expands_against_vars_and_env() {
+        let env = env();
+        let vars = vars_of(&[("a", "b")]);
+
+        assert_eq!(
+            expand_with("${HOME}/<a>", &env, &vars).unwrap(),
+            "/home/test/b"
+        );
+        assert_eq!(
+            expand_with("~/.config", &env, &vars).unwrap(),
+            "/home/test/.config"
+        );
+        assert_eq!(
+            expand_with(&format!("${{{}:-<a>/$USER}}", UNSET), &env, &vars).unwrap(),
+            "b/test"
+        );
+        assert_eq!(
+            expand_with(
+                &format!("${{{}:-${{{}:-deep}}}}", UNSET, UNSET),
+                &env,
+                &vars
+            )
+            .unwrap(),
+            "deep"
+        );
+
+        let root = User::from_name("root").unwrap().unwrap().dir;
+        assert_eq!(
+            expand_with("~root/x", &env, &vars).unwrap(),
+            format!("{}/x", root.display())
+        );
+    }
+
+    #[test]
+    fn expansion_errors() {
+        let env = env();
+        let vars = vars_of(&[]);
+        let err = |template: String| expand_with(&template, &env, &vars).unwrap_err().to_string();
+
+        assert!(err("<nope>".to_string()).contains("Undefined var `<nope>`"));
+        assert!(err(format!("${}", UNSET)).contains("isn't set"));
+        assert!(err(format!("${{{}:?set it first}}", UNSET)).contains("set it first"));
+        assert!(err(format!("${{{}:?}}", UNSET)).contains("is unset or empty"));
+        assert!(err(format!("${{{}:-<nope>}}", UNSET)).contains("Undefined var `<nope>`"));
+        assert!(err("~dots_interp_no_such_user".to_string()).contains("No such user"));
+    }
+
+    #[test]
+    fn splits_paths_outside_braces() {
+        assert_eq!(
+            split_path("/a//${X:-b/c}/<d>/"),
+            vec!["a", "${X:-b/c}", "<d>"]
+        );
+        assert_eq!(split_path(r"a\/b/c"), vec![r"a\/b", "c"]);
+    }
+
+    #[test]
+    fn lone_vars() {
+        assert_eq!(lone_var("<a>"), Some("a"));
+        assert_eq!(lone_var("<a>.conf"), None);
+        assert_eq!(lone_var("<a><b>"), None);
+        assert_eq!(lone_var("<>"), None);
+        assert_eq!(lone_var("a"), None);
+    }
+
+    #[test]
+    fn resolves_vars_in_dependency_order() {
+        let env = env();
+        let templates: BTreeMap<String, String> =
+            [("a", "<b>/x"), ("b", "${HOME}"), ("c", "<fixed>-<a>")]
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+
+        let resolved = resolve_vars(&templates, vars_of(&[("fixed", "f")]), &env).unwrap();
+        assert_eq!(resolved["a"], "/home/test/x");
+        assert_eq!(resolved["b"], "/home/test");
+        assert_eq!(resolved["c"], "f-/home/test/x");
+    }
+
+    #[test]
+    fn var_cycles_and_undefined_vars_are_errors() {
+        let env = env();
+        let templates = |pairs: &[(&str, &str)]| -> BTreeMap<String, String> {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+
+        let e = resolve_vars(
+            &templates(&[("a", "<b>"), ("b", "<a>")]),
+            HashMap::new(),
+            &env,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(e.contains("cycle: a -> b -> a"), "{}", e);
+
+        let e = resolve_vars(&templates(&[("a", "<a>")]), HashMap::new(), &env)
+            .unwrap_err()
+            .to_string();
+        assert!(e.contains("cycle: a -> a"), "{}", e);
+
+        let e = resolve_vars(&templates(&[("a", "x/<nope>")]), HashMap::new(), &env)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            e.contains("In var `a`") && e.contains("Undefined var `<nope>`"),
+            "{}",
+            e
+        );
+    }
+}
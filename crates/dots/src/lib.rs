@@ -2,16 +2,14 @@ use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::fs;
-use std::io;
-use std::io::Write;
-use std::os::unix::fs::DirEntryExt2;
 use std::path::{Path, PathBuf};
 use std::prelude::v1::*;
 use std::process::Command;
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use clap::{Parser, Subcommand, ValueEnum};
 use derive_builder::Builder;
+use schemars::JsonSchema;
 use serde::Deserialize;
 use serde::{
     de::{Error as SerdeError, MapAccess, SeqAccess, Visitor},
@@ -20,97 +18,186 @@ use serde::{
 use toml::Table;
 
 use shared::bos;
+use shared::fs as sfs;
 
+use crate::config::*;
+use crate::decisions::*;
+use crate::explain::*;
 use crate::handlers::*;
+use crate::hooks::*;
+use crate::privilege::*;
+use crate::prompt::*;
 use crate::staging::*;
+use crate::stats::*;
 use crate::trackfile::*;
 
+// progress and status text: stdout, unless that's reserved for a JSON report
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if $crate::stats::json_output() {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    };
+}
+
+mod add;
+pub mod check;
+pub mod config;
+pub mod decisions;
+mod edit;
+pub mod explain;
+mod forget;
 mod handlers;
+pub mod hooks;
+pub mod interp;
+mod lsp;
+mod mv;
+pub mod privilege;
+pub mod prompt;
+mod recover;
+mod relocate;
+pub mod schema;
+pub mod shell;
 pub mod staging;
+pub mod stats;
+#[cfg(test)]
+mod testing;
 pub mod trackfile; // TEMPORARY (for staging changes as part of large refactors bc whynot)
+pub mod trust;
+pub mod when;
 
-#[derive(Deserialize, Debug, Clone)]
-struct RawUseTable<'de> {
-    // `when` can be absent, or a table.
-    // Using a custom deserializer for Option ensures absence is None,
-    // and presence (even `when={}`) deserializes TomlUseWhen.
-    #[serde(default, deserialize_with = "deserialize_optional_raw_use_when")]
-    when: Option<RawUseWhen<'de>>,
+// the toml deserializer doesn't lend out strings, so everything here is owned
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
+struct RawUseTable {
+    // `when` can be absent, a bool, or a table (even `when = {}`, which always holds)
+    #[serde(default)]
+    when: Option<RawUseWhenType>,
 
-    #[serde(borrow)]
-    target: Option<RawUseTargetType<'de>>,
+    target: Option<RawUseTargetType>,
 
-    #[serde(borrow)]
-    exclude: Option<RawExcludeType<'de>>,
+    exclude: Option<RawExcludeType>,
 
     // Captures all other keys (like "name", "dir", etc.) into a map.
     // These will form the basis of `replace_map`.
-    #[serde(flatten, borrow)]
-    vars: HashMap<String, RawUseVarType<'de>>,
+    #[serde(flatten)]
+    vars: HashMap<String, RawUseVarType>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
 #[serde(untagged)]
-enum RawUseWhenType<'de> {
-    Bool(&'de bool),
-    Table(RawUseWhen<'de>),
+enum RawUseWhenType {
+    Bool(bool),
+    Table(RawUseWhen),
 }
 
-// For the `when` field: { shell: string, if: string, command: string }
-#[derive(Deserialize, Debug, Default, Clone, Copy)] // Copy if all fields are simple borrowed strs
-struct RawUseWhen<'de> {
-    #[serde(borrow)]
-    shell: Option<&'de str>,
-    #[serde(rename = "if", borrow)]
-    test_if: Option<&'de str>,
-    #[serde(borrow)]
-    test_command: Option<&'de str>,
+// For the `when` field: { os, distro, hostname, user, env.VAR, exists, command, guix_home,
+// nix_home } are checked by us (see when.rs), as are { all, any, not } built out of them;
+// { shell, if, test_command } go through $SHELL
+#[derive(Deserialize, JsonSchema, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+struct RawUseWhen {
+    os: Option<String>,
+    distro: Option<String>,
+    hostname: Option<String>,
+    user: Option<String>,
+    env: Option<HashMap<String, String>>,
+    exists: Option<String>,
+    command: Option<String>,
+    guix_home: Option<bool>,
+    nix_home: Option<bool>,
+
+    // nested predicates: every one of `all`, at least one of `any`, `not` being false
+    all: Option<Vec<RawUseWhen>>,
+    any: Option<Vec<RawUseWhen>>,
+    not: Option<Box<RawUseWhen>>,
+
+    shell: Option<String>,
+    #[serde(rename = "if")]
+    test_if: Option<String>,
+    test_command: Option<String>,
 }
 
 // For the `target` field: string OR table {key: value}
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
 #[serde(untagged)]
-enum RawUseTargetType<'de> {
-    Str(&'de str),
-    Table(HashMap<String, &'de str>),
+enum RawUseTargetType {
+    Str(String),
+    Table(HashMap<String, String>),
+}
+
+impl RawUseTargetType {
+    // a plain path is the target for the whole source (an empty suffix)
+    fn into_targets(self) -> Vec<(PathBuf, PathBuf)> {
+        match self {
+            RawUseTargetType::Str(s) => vec![(PathBuf::from(""), PathBuf::from(s))],
+            RawUseTargetType::Table(map) => map
+                .into_iter()
+                .map(|(k, v)| (PathBuf::from(k), PathBuf::from(v)))
+                .collect(),
+        }
+    }
 }
 
 // For the `exclude` field: string OR vec of strings
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
 #[serde(untagged)]
-enum RawExcludeType<'de> {
-    Str(&'de str),
-    Vec(Vec<&'de str>),
+enum RawExcludeType {
+    Str(String),
+    Vec(Vec<String>),
+}
+
+impl RawExcludeType {
+    fn into_paths(self) -> Vec<PathBuf> {
+        match self {
+            RawExcludeType::Str(s) => vec![PathBuf::from(s)],
+            RawExcludeType::Vec(v) => v.into_iter().map(PathBuf::from).collect(),
+        }
+    }
+}
+
+// `use_target` and `exclude` in [dots]/[[dotfiles]] take the same shapes as in a `use` table
+fn deserialize_use_target<'de, D>(
+    deserializer: D,
+) -> Result<Option<Vec<(PathBuf, PathBuf)>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Option::<RawUseTargetType>::deserialize(deserializer)?.map(RawUseTargetType::into_targets))
+}
+
+fn deserialize_exclude<'de, D>(deserializer: D) -> Result<Option<Vec<PathBuf>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Option::<RawExcludeType>::deserialize(deserializer)?.map(RawExcludeType::into_paths))
 }
 
 // For values in the `vars` map (e.g., "name" = "$BOS_OS" OR "name" = {env = "$BOS_OS"})
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
 #[serde(untagged)]
-enum RawUseVarType<'de> {
-    Str(&'de str),         // Simple string value
-    Table(RawUseVar<'de>), // Table like {env="...", value="...", shell="..."}
+enum RawUseVarType {
+    Str(String),         // Simple string value
+    Table(RawUseVar), // Table like {env="...", value="...", shell="..."}
 }
 
-#[derive(Deserialize, Debug, Default, Clone, Copy)]
-struct RawUseVar<'de> {
-    #[serde(borrow)]
-    shell: Option<&'de str>,
-    #[serde(borrow)]
-    env: Option<&'de str>,
-    #[serde(borrow)]
-    value: Option<&'de str>,
+#[derive(Deserialize, JsonSchema, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+struct RawUseVar {
+    shell: Option<String>,
+    env: Option<String>,
+    value: Option<String>,
 }
 
 // This enum helps deserialize the varied values of the `dots.use` map
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, JsonSchema, Debug)]
 #[serde(untagged)]
-enum RawUseTableType<'de> {
-    Str(&'de str),
-    Bool(&'de bool),
-    #[serde(borrow)]
-    Table(RawUseTable<'de>), // A single UseTable
-    #[serde(borrow)]
-    Array(Vec<RawUseTable<'de>>), // An array of UseTables
+enum RawUseTableType {
+    Str(String),
+    Bool(bool),
+    Table(RawUseTable),      // A single UseTable
+    Array(Vec<RawUseTable>), // An array of UseTables
 }
 
 fn deserialize_dots_use_map<'de, D>(
@@ -120,7 +207,7 @@ where
     D: Deserializer<'de>,
 {
     // Deserialize the entire `dots.use` table into a map of keys to our intermediate source value
-    let raw_map_option: Option<HashMap<PathBuf, RawUseTableType<'de>>> =
+    let raw_map_option: Option<HashMap<PathBuf, RawUseTableType>> =
         Option::deserialize(deserializer)?;
 
     match raw_map_option {
@@ -139,7 +226,10 @@ where
                         }
                         current_key_dots_use_list.push(DotsUse {
                             when: true,
-                            target: s_val,
+                            decided_by: None,
+                            target: Some(vec![(PathBuf::new(), PathBuf::from(s_val))]),
+                            exclude: None,
+                            replace_map: HashMap::new(),
                         })
                     }
                     RawUseTableType::Bool(b_val) => {
@@ -148,6 +238,7 @@ where
                         if b_val {
                             current_key_dots_use_list.push(DotsUse {
                                 when: true,
+                                decided_by: None,
                                 target: None,
                                 exclude: None,
                                 replace_map: HashMap::new(),
@@ -194,93 +285,73 @@ where
 }
 
 fn convert_raw_table_to_dots_use(raw_table: RawUseTable) -> Result<DotsUse> {
-    // 1. Process `vars` (RawUseVarType) into `replace_map: HashMap<String, String>`
+    // 1. Process `vars` (RawUseVarType) into `replace_map: HashMap<String, String>`. plain strings
+    // are templates that can refer to each other, so they're resolved together once the rest are in
+    let mut templates = BTreeMap::new();
     let mut replace_map = HashMap::new();
     for (key, raw_var_type) in raw_table.vars {
-        let value_str = match raw_var_type {
+        match raw_var_type {
             RawUseVarType::Str(s) => {
-                if s.starts_with("$") || s.starts_with("~") {
-                    shell::echo(s.to_string(), None)?
-                } else {
-                    s
-                }
+                templates.insert(key, s.to_string());
             }
             RawUseVarType::Table(raw_use_var) => {
                 let mut defined_fields = 0;
                 let mut final_val: Option<String> = None;
 
                 if let Some(v) = raw_use_var.value {
-                    final_val = v;
+                    final_val = Some(v);
                     defined_fields += 1;
                 }
                 if let Some(e) = raw_use_var.env {
-                    final_val = shell::echo(e.to_string(), None)?;
+                    // the variable's name (`$NAME` is fine too)
+                    let name = e.trim_start_matches('$');
+                    final_val = Some(when::env().get(name).ok_or_else(|| {
+                        anyhow!(
+                            "Environment variable `${}` isn't set (for var '{}')",
+                            name,
+                            key
+                        )
+                    })?);
                     defined_fields += 1;
                 }
                 if let Some(s) = raw_use_var.shell {
-                    final_val = shell::run(s.to_string(), None)?;
+                    final_val = Some(shell::runner().run(&s, &HashMap::new())?);
                     defined_fields += 1;
                 }
 
                 if defined_fields > 1 {
-                    return Err(format!(
+                    return Err(anyhow!(
                         "Multiple fields defined in UseVar for key '{}'",
                         key
                     ));
                 }
-                final_val.ok_or_else(|| format!("No value found in UseVar for key '{}'", key))?
+                let value_str = final_val
+                    .ok_or_else(|| anyhow!("No value found in UseVar for key '{}'", key))?;
+                replace_map.insert(key, value_str);
             }
-        };
-        replace_map.insert(key, value_str);
+        }
     }
+    let replace_map = interp::resolve_vars(&templates, replace_map, when::env())?;
 
     // 2. Process `when: Option<RawUseWhen>` into `when: bool`
-    let final_when = match raw_table.when {
-        None => true, // `when` omitted defaults to true
-        Some(RawUseWhenType::Bool(w)) => w,
+    let (final_when, decided_by) = match raw_table.when {
+        None => (true, None), // `when` omitted defaults to true
+        Some(RawUseWhenType::Bool(w)) => (w, None),
         Some(RawUseWhenType::Table(w)) => {
-            let shell = if let Some(s) = w.shell {
-                shell::run_for_bool(s.to_string(), Some(&replace_map))?
-            } else {
-                true
-            };
-
-            let test_if = if let Some(i) = w.test_if {
-                shell::test_if(i.to_string(), Some(&replace_map))?
-            } else {
-                true
-            };
-
-            let test_command = if let Some(c) = w.test_command {
-                shell::test_command(c.to_string(), Some(&replace_map))?
-            } else {
-                true
-            };
-
-            shell && test_if && test_command
+            let verdict = w.evaluate(when::env(), &replace_map)?;
+            (verdict.holds, Some(verdict.to_string()))
         }
     };
 
     // 3. Process `target: Option<RawUseTargetType>` into `Option<Vec<(PathBuf, PathBuf)>>`
-    let final_target = match raw_table.target {
-        None => None,
-        Some(RawUseTargetType::Str(s)) => Some(vec![(PathBuf::from(""), PathBuf::from(s))]),
-        Some(RawUseTargetType::Table(map)) => Some(
-            map.into_iter()
-                .map(|(k, v)| (PathBuf::from(k), PathBuf::from(v)))
-                .collect(),
-        ),
-    };
+    let final_target = raw_table.target.map(RawUseTargetType::into_targets);
 
-    // 4. Process `exclude: Option<RawExcludeType>` into `Option<Vec<String>>`
-    let final_exclude = match raw_table.exclude {
-        None => None,
-        Some(RawExcludeType::Str(s)) => Some(vec![s.to_string()]),
-        Some(RawExcludeType::Vec(v)) => Some(v.into_iter().map(String::from).collect()),
-    };
+    // 4. Process `exclude: Option<RawExcludeType>` into `Option<Vec<PathBuf>>`
+    let final_exclude = raw_table.exclude.map(RawExcludeType::into_paths);
 
     Ok(DotsUse {
         when: final_when,
+        decided_by,
         target: final_target,
         exclude: final_exclude,
         replace_map,
@@ -294,6 +365,22 @@ pub struct Args {
     #[command(subcommand)]
     command: Commands,
     dotfiles: Option<String>, // only optional if ran before
+
+    /// Manage this user's dotfiles (defaults to the invoking user when run via sudo/doas)
+    #[arg(long, global = true)]
+    user: Option<String>,
+
+    /// How to report results
+    #[arg(long, global = true, value_enum, default_value_t)]
+    format: OutputFormat,
+
+    /// Override a config value for this run (e.g., `-c dots.elevate=doas`), after every config file
+    #[arg(short = 'c', long = "config", global = true, value_name = "KEY=VALUE")]
+    overrides: Vec<String>,
+
+    /// Never run shell commands from configs; anything that needs one fails instead
+    #[arg(long, global = true)]
+    no_shell: bool,
 }
 
 #[derive(Subcommand)]
@@ -303,9 +390,36 @@ pub enum Commands {
     Relink(DotsLinkArgs),
     Status(DotsStatusArgs),
     Clean(DotsCleanArgs),
+    /// Move existing files/directories into a source set and link them back
+    Add(DotsAddArgs),
+    /// Stop managing destinations, leaving a copy of their source in place of each link
+    Forget(DotsForgetArgs),
+    /// Show why a destination (or source) maps where it does
+    #[command(alias = "which")]
+    Explain(DotsExplainArgs),
+    /// Open the source behind a managed destination in $VISUAL/$EDITOR
+    Edit(DotsEditArgs),
+    /// Move a file or directory inside a source set, updating the links to it
+    #[command(alias = "move")]
+    Mv(DotsMvArgs),
+    /// Repoint every link into a source set that was moved elsewhere
+    Relocate(DotsRelocateArgs),
+    /// Rebuild a lost trackfile from the links found into known source sets
+    Recover(DotsRecoverArgs),
+    /// Inspect the effective configuration
+    Config(DotsConfigArgs),
+    /// Validate every reachable config, reporting problems with their file:line:col
+    Check(DotsCheckArgs),
+    /// Print a JSON Schema for config files, for editors (e.g., taplo/Even Better TOML)
+    Schema(DotsSchemaArgs),
+    /// Run a language server for config files over stdin/stdout
+    Lsp(DotsLspArgs),
+    /// Internal: apply a batch of privileged operations read from stdin (spawned via sudo/doas/run0)
+    #[command(hide = true)]
+    Helper(DotsHelperArgs),
 }
 
-#[derive(clap::Args)]
+#[derive(clap::Args, Default)]
 pub struct DotsLinkArgs {
     target: PathBuf,
 
@@ -337,232 +451,356 @@ pub struct DotsLinkArgs {
     #[arg(long)]
     interactive: bool,
 
+    /// Command used to run privileged operations (e.g., linking `root/` targets)
+    #[arg(long, value_enum)]
+    elevate: Option<Elevation>,
+
+    #[command(flatten)]
+    errors: ErrorPolicyArgs,
+
+    /// Pre-answered conflicts (destination globs and reasons mapped to yes, no or backup)
+    #[arg(long)]
+    policy: Option<PathBuf>,
+
+    /// Save "yes to all"/"no to all" answers into the policy file so they aren't asked again
+    #[arg(long)]
+    save_answers: bool,
+
     #[arg(short, long)]
     verbose: bool,
 }
 
+#[derive(clap::Args, Clone, Copy, Default)]
+#[group(multiple = false)]
+pub struct ErrorPolicyArgs {
+    /// What to do when an entry conflicts or fails
+    #[arg(long, value_enum)]
+    on_error: Option<ErrorPolicy>,
+
+    /// Halt on the first conflict or error (same as `--on-error bail`)
+    #[arg(long)]
+    bail: bool,
+
+    /// Skip conflicting or failing entries and keep going (same as `--on-error skip`)
+    #[arg(long)]
+    keep_going: bool,
+
+    /// Ask whether to continue on each conflict or error (same as `--on-error ask`)
+    #[arg(long)]
+    ask_on_error: bool,
+}
+
+impl ErrorPolicyArgs {
+    pub fn policy(&self) -> ErrorPolicy {
+        if let Some(policy) = self.on_error {
+            policy
+        } else if self.bail {
+            ErrorPolicy::Bail
+        } else if self.ask_on_error {
+            ErrorPolicy::Ask
+        } else {
+            ErrorPolicy::Skip
+        }
+    }
+}
+
 #[derive(clap::Args)]
 pub struct DotsStatusArgs {}
 
 #[derive(clap::Args)]
-pub struct DotsCleanArgs {
+pub struct DotsAddArgs {
+    #[arg(required = true)]
+    paths: Vec<PathBuf>,
+
+    /// Source set to add to (defaults to the dotfiles argument)
+    #[arg(short, long)]
+    to: Option<PathBuf>,
+
+    /// Add under `os/<id>/` (the detected distro, or OS)
+    #[arg(long, group = "layout")]
+    os: bool,
+
+    /// Add under `user/<name>/` (the current user, or the global --user). Named --per-user since
+    /// --user <name> is already taken
+    #[arg(long, group = "layout")]
+    per_user: bool,
+
+    /// Add under `guix/`
+    #[arg(long, group = "layout")]
+    guix_home: bool,
+
+    /// Add under `nix/`
+    #[arg(long, group = "layout")]
+    nix_home: bool,
+
+    /// Add under `root/` even if the path is inside the home directory
+    #[arg(long, group = "layout")]
+    root: bool,
+
     /// Perform a dry run, showing actions without modifying filesystem or trackfile
     #[arg(long)]
     dry_run: bool,
 
-    /// Prompt for confirmation before potentially destructive actions
     #[arg(short, long)]
-    interactive: bool,
+    verbose: bool,
 }
 
-pub enum Inheritable {
-    Use,
-    UseTarget,
-    Exclude,
+#[derive(clap::Args)]
+pub struct DotsForgetArgs {
+    #[arg(required = true)]
+    dests: Vec<PathBuf>,
+
+    /// Also delete the file from the source set
+    #[arg(long)]
+    delete_source: bool,
+
+    /// Perform a dry run, showing actions without modifying filesystem or trackfile
+    #[arg(long)]
+    dry_run: bool,
+
+    #[arg(short, long)]
+    verbose: bool,
 }
 
-fn _resolve_path(
-    base_path: &Path,
-    path: &Path,
-    parts: Components,
-    env: &HashMap<String, String>,
-) -> Result<Vec<(PathBuf, &HashMap<String, String>)>> {
-    let mut new_path = PathBuf::new();
-
-    if let Some(part) = parts.next() {
-        match part {
-            Component::Normal(os_str) => {
-                if let Some(s) = os_str.to_str() {
-                    if s == "*" {
-                        let paths: Vec<(PathBuf, &HashMap<String, String>)> = Vec::new();
-
-                        for entry in fs::read_dir(base_path.join(path))? {
-                            let entry = entry?;
-                            if !fs::metadata(entry.path())?.is_dir() {
-                                continue;
-                            }
+#[derive(clap::Args)]
+pub struct DotsExplainArgs {
+    /// Destination or source path
+    path: PathBuf,
 
-                            paths.append(&mut _resolve_path(
-                                base_path,
-                                &path.join(entry.file_name_ref()),
-                                parts,
-                                env,
-                            )?)
-                        }
+    /// Dotfiles to resolve against (defaults to the dotfiles argument)
+    #[arg(short, long)]
+    target: Option<String>,
+}
 
-                        return Ok(paths);
-                    }
+#[derive(clap::Args)]
+pub struct DotsEditArgs {
+    /// Managed destination whose source to open
+    dest: PathBuf,
 
-                    if s.starts_with('<') && s.ends_with('>') {
-                        let key = &s[1..s.len() - 1];
-                        if let Some(value) = env.get(key) {
-                            if value == "*" {
-                                let paths: Vec<(PathBuf, &HashMap<String, String>)> = Vec::new();
+    /// Dotfiles to resolve against when the destination isn't tracked, and to relink afterwards
+    /// (defaults to the dotfiles argument)
+    #[arg(short, long)]
+    target: Option<String>,
 
-                                for entry in fs::read_dir(base_path.join(path))? {
-                                    let entry = entry?;
-                                    if !fs::metadata(entry.path())?.is_dir() {
-                                        continue;
-                                    }
+    /// Don't offer to relink when the edit created or renamed files
+    #[arg(long)]
+    no_relink: bool,
 
-                                    let dir = entry.file_name_ref();
-
-                                    // basically
-                                    // copy env
-                                    // change env value to the dir name
-                                    // pass that new env in instead
-                                    // then right before returning a given atomic string,
-                                    // use that env to process target suffix (which may also hit
-                                    // this branch and alter env if a certain key hadn't been set
-                                    // by the primary path)
-                                    // then use what should be a complete env to process the actual
-                                    // target
-                                    // Note that the actual target probably shouldn't ever allow *
-                                    // And so basically the function should probably return the
-                                    // current env ref along with a given pathbuf
-                                    // then in the non_ variant, we append each suffix and process
-                                    // each resulting primary path, then take the altered env and
-                                    // process the target corresponding to each suffix
-                                    //
-                                    // crazy/<dir>/ball/home -> e.g., resolves env with dir = "test"
-                                    // then process "~/<dir>/ball"
-                                    // gives:
-                                    // crazy/test/ball/home -> /home/usr/test/ball
-
-                                    let new_env = env.clone();
-                                    new_env.insert(key, dir.to_str());
-
-                                    paths.append(&mut _resolve_path(
-                                        base_path,
-                                        &path.join(entry.file_name_ref()),
-                                        parts,
-                                        &new_env,
-                                    ))
-                                }
+    #[arg(short, long)]
+    verbose: bool,
+}
 
-                                return Ok(paths);
-                            } else {
-                                _resolve_path(base_path, &path.join(value), parts, env)
-                            }
-                        } else {
-                            _resolve_path(base_path, &path.join(s), parts, env)
-                        }
-                    } else if s.starts_with('$') || s.starts_with('~') {
-                        let part = shell::echo(s, Some(env)).or(s);
-                        _resolve_path(base_path, &path.join(part), parts, env)
-                    } else {
-                        _resolve_path(base_path, &path.join(s), parts, env)
-                    }
-                } else {
-                    // Non-UTF8 component, push as is
-                    _resolve_path(base_path, &path.join(os_str), parts, env)
-                }
-            }
-            _ => _resolve_path(base_path, &path.join(part.as_os_str()), parts, env),
-        }
-    } else {
-        Ok(vec![(path, env)])
-    }
+#[derive(clap::Args)]
+pub struct DotsMvArgs {
+    /// Source file or directory to move
+    from: PathBuf,
+
+    /// Where to move it (into it, if it's an existing directory)
+    to: PathBuf,
+
+    /// Source set both paths are in (defaults to the dotfiles argument)
+    #[arg(short, long)]
+    set: Option<PathBuf>,
+
+    /// Allow moves that change which machines get the file (e.g., `home/` to `os/linux/home/`)
+    #[arg(long)]
+    force: bool,
+
+    /// Perform a dry run, showing actions without modifying filesystem or trackfile
+    #[arg(long)]
+    dry_run: bool,
+
+    #[arg(short, long)]
+    verbose: bool,
 }
-fn resolve_path(
-    base_path: &Path,
-    path: &Path,
-    env: &HashMap<String, String>,
-) -> Result<Vec<(PathBuf, &HashMap<String, String>)>> {
-    _resolve_path(base_path, path, path.components(), env)
+
+#[derive(clap::Args)]
+pub struct DotsRelocateArgs {
+    /// Where the source set used to be
+    old_root: PathBuf,
+
+    /// Where it is now
+    new_root: PathBuf,
+
+    /// Perform a dry run, showing actions without modifying filesystem or trackfile
+    #[arg(long)]
+    dry_run: bool,
+
+    #[arg(short, long)]
+    verbose: bool,
 }
 
-pub mod shell {
-    pub fn run(command: String, env: Option<&HashMap<String, String>>) -> Result<&str> {
-        let shell = Command::new(std::env::var("SHELL").or("sh")).arg("-c");
-        if let Some(env) = env {
-            shell.envs(env);
-        }
+#[derive(clap::Args)]
+pub struct DotsRecoverArgs {
+    /// Directories to scan for links (defaults to the home directory)
+    #[arg(long, num_args = 1..)]
+    scan: Vec<PathBuf>,
 
-        let output = shell.args(command.split(" ")).output()?;
+    /// Also scan /etc (ignored with --scan)
+    #[arg(long)]
+    etc: bool,
 
-        if !output.stderr.is_empty() {
-            io::stderr().write_all(&output.stderr)?;
-        }
+    /// Dotfiles whose source sets links may point into (defaults to the dotfiles argument)
+    #[arg(short, long)]
+    target: Option<String>,
 
-        Ok(output.stdout)
-    }
-    pub fn run_for_bool(command: String, env: Option<&HashMap<String, String>>) -> Result<bool> {
-        let output = run(command, env)?;
+    /// Additional source set directories links may point into
+    #[arg(long = "set")]
+    sets: Vec<PathBuf>,
 
-        match output {
-            "0" | "false" => Ok(false),
-            "1" | "true" => Ok(true),
-            _ => Err("Value returned from shell was not a bool"), // CUSTOM ERROR PLS
-        }
-    }
+    /// Don't ask before saving the recovered entries
+    #[arg(short, long)]
+    yes: bool,
 
-    pub fn test_if(command: String, env: Option<&HashMap<String, String>>) -> Result<bool> {
-        run_for_bool(
-            format!("if [ {} ]; then echo true; else echo false; fi", command),
-            env,
-        )
-    }
-    pub fn test_command(command: String, env: Option<&HashMap<String, String>>) -> Result<bool> {
-        test_if(format!("-x \"$(command -v {})\"", command), env)
-    }
+    /// Only show what would be recovered
+    #[arg(long)]
+    dry_run: bool,
 
-    pub fn echo(command: String, env: Option<&HashMap<String, String>>) -> Result<&str> {
-        run(format!("echo {}", command), env)
-    }
+    #[arg(short, long)]
+    verbose: bool,
 }
 
-pub struct DotsUseWhen {
-    shell: Option<String>,
-    test_if: Option<String>,
-    test_command: Option<String>,
+#[derive(clap::Args)]
+pub struct DotsConfigArgs {
+    #[command(subcommand)]
+    command: ConfigCommands,
 }
-impl DotsUseWhen {
-    pub fn try_is_true(&self, env: HashMap<String, String>) -> Result<bool> {
-        let shell = if let Some(s) = self.shell {
-            shell::run_for_bool(s, None)?
-        } else {
-            true
-        };
 
-        let test_if = if let Some(i) = self.test_if {
-            shell::test_if(i, None)?
-        } else {
-            true
-        };
+#[derive(Subcommand)]
+pub enum ConfigCommands {
+    /// Print the effective config, every layer merged
+    Show(DotsConfigShowArgs),
+}
 
-        let test_command = if let Some(c) = self.test_command {
-            shell::test_command(i, None)?
-        } else {
-            true
-        };
+#[derive(clap::Args)]
+pub struct DotsConfigShowArgs {
+    /// Show the file and line each value came from
+    #[arg(long)]
+    origin: bool,
 
-        Ok(shell && test_if && test_command)
-    }
+    /// Dotfiles whose config to layer in (defaults to the dotfiles argument)
+    #[arg(short, long)]
+    target: Option<String>,
 }
-pub struct DotsUseVar {
-    shell: Option<String>,
-    env: Option<String>,
-    value: Option<&str>,
-}
-impl DotsUseVar {
-    pub fn try_get_value(&self) -> Result<str> {
-        // only one member should ever not be None
-
-        if let Some(v) = self.shell {
-            shell::run(v, None)
-        } else if let Some(v) = self.env {
-            shell::echo(v, None)
-        } else if let Some(v) = self.value {
-            Ok(v)
+
+#[derive(clap::Args)]
+pub struct DotsCheckArgs {
+    /// Dotfiles whose config to check, along with every layer (defaults to the dotfiles argument)
+    target: Option<String>,
+}
+
+#[derive(clap::Args)]
+pub struct DotsSchemaArgs {
+    /// Write the schema to a file instead of printing it
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+pub struct DotsLspArgs {
+    /// Talk over stdin/stdout (the only transport; accepted since most clients pass it)
+    #[arg(long)]
+    stdio: bool,
+}
+
+#[derive(clap::Args)]
+pub struct DotsHelperArgs {}
+
+#[derive(clap::Args)]
+pub struct DotsCleanArgs {
+    /// Perform a dry run, showing actions without modifying filesystem or trackfile
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Prompt for confirmation before potentially destructive actions
+    #[arg(short, long)]
+    interactive: bool,
+
+    #[command(flatten)]
+    errors: ErrorPolicyArgs,
+}
+
+#[derive(Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Inheritable {
+    Use,
+    UseTarget,
+    Exclude,
+}
+
+// crazy/<dir>/ball/home -> e.g., resolves env with dir = "test"
+// then process "~/<dir>/ball"
+// gives:
+// crazy/test/ball/home -> /home/usr/test/ball
+//
+// so a `*` segment (or a `<var>` that's `*`) matches every dir there, and each match carries its
+// own copy of the vars (with that var bound to the dir's name) on to the rest of the path and,
+// from there, to the target. every other segment is interpolated
+fn _resolve_path(
+    base_path: &Path,
+    path: PathBuf,
+    segments: &[&str],
+    env: &HashMap<String, String>,
+) -> Result<Vec<(PathBuf, HashMap<String, String>)>> {
+    let Some((segment, rest)) = segments.split_first() else {
+        return Ok(vec![(path, env.clone())]);
+    };
+
+    let binds = match interp::lone_var(segment) {
+        Some(key) if env.get(key).is_some_and(|v| v == "*") => Some(Some(key)),
+        _ if *segment == "*" => Some(None),
+        _ => None,
+    };
+    let Some(binds) = binds else {
+        // `~` only means home at the very start
+        let part = if path.as_os_str().is_empty() {
+            interp::expand_with(segment, when::env(), env)?
         } else {
-            Err("variable not supplied a value")
+            interp::expand_with(&segment.replacen('~', "\\~", 1), when::env(), env)?
+        };
+        return _resolve_path(base_path, path.join(part), rest, env);
+    };
+
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(base_path.join(&path))? {
+        let entry = entry?;
+        if !fs::metadata(entry.path())?.is_dir() {
+            continue;
+        }
+
+        let dir = entry.file_name().to_string_lossy().to_string();
+        let mut new_env = env.clone();
+        if let Some(key) = binds {
+            new_env.insert(key.to_string(), dir.clone());
         }
+        paths.append(&mut _resolve_path(
+            base_path,
+            path.join(&dir),
+            rest,
+            &new_env,
+        )?);
     }
+
+    Ok(paths)
+}
+fn resolve_path(
+    base_path: &Path,
+    path: &Path,
+    env: &HashMap<String, String>,
+) -> Result<Vec<(PathBuf, HashMap<String, String>)>> {
+    let template = path.to_string_lossy();
+    let root = if template.starts_with('/') {
+        PathBuf::from("/")
+    } else {
+        PathBuf::new()
+    };
+    _resolve_path(base_path, root, &interp::split_path(&template), env)
+        .with_context(|| format!("Failed to resolve {}", path.display()))
 }
 
 pub struct DotsUse {
     pub when: bool,
+    pub decided_by: Option<String>, // what made a `when` table hold, for `dots explain`
     pub target: Option<Vec<(PathBuf, PathBuf)>>,
     pub replace_map: HashMap<String, String>,
     pub exclude: Option<Vec<PathBuf>>,
@@ -574,6 +812,7 @@ impl DotsUse {
         use_path: &PathBuf,
         global_use_target: Option<Vec<(PathBuf, PathBuf)>>,
         global_exclude: Option<Vec<PathBuf>>,
+        trace: &mut Vec<(PathBuf, Derivation)>, // dest, how it got there (or didn't)
     ) -> Result<Option<HashMap<PathBuf, PathBuf>>> {
         // What we have:
         // - source should be fully resolved
@@ -595,33 +834,69 @@ impl DotsUse {
 
         let partial_env = &self.replace_map;
 
-        let use_target = self
-            .target
-            .unwrap_or(global_use_target.expect("No targets provided for 'use'"));
+        let use_target = self.target.clone().or(global_use_target).ok_or_else(|| {
+            anyhow!(
+                "No target for 'use' {} (see `dots check`)",
+                use_path.display()
+            )
+        })?;
         let global_exclude = global_exclude.unwrap_or_default();
-        let local_exclude = self.exclude.unwrap_or_default();
+        let local_exclude = self.exclude.clone().unwrap_or_default();
 
-        let track_map: HashMap<PathBuf, PathBuf> = HashMap::new();
+        let mut track_map: HashMap<PathBuf, PathBuf> = HashMap::new();
         for (suffix, target) in use_target.iter() {
             let sources = resolve_path(base_path, &use_path.join(suffix), partial_env)?;
             for (source, full_env) in sources.iter() {
-                if global_exclude.iter().any(|e| source.starts_with(e)) {
-                    continue;
-                }
+                // * not allowed, so target should only ever return a single path
+                let dest = resolve_path(base_path, target, full_env)?
+                    .pop()
+                    .expect("you done messed up target path resolution brother")
+                    .0;
 
+                // resolved (and excluded) relative to the set, linked to where it actually is
+                let set_source = base_path.join(source);
+
+                let mut derivation = Derivation {
+                    source: set_source.clone(),
+                    source_set: None,
+                    config: None,
+                    via: vec![],
+                    when: self.decided_by.clone(),
+                    rule: format!(
+                        "use \"{}\" : \"{}\" -> \"{}\"",
+                        use_path.display(),
+                        suffix.display(),
+                        target.display()
+                    ),
+                    vars: full_env
+                        .iter()
+                        .map(|(k, v)| (k.clone(), v.clone()))
+                        .collect(),
+                    outcome: Outcome::Chosen,
+                };
+
+                let mut excluded_by = global_exclude
+                    .iter()
+                    .find(|e| source.starts_with(e))
+                    .cloned();
                 for exc in local_exclude.iter() {
-                    if resolve_path(base_path, exc, full_env)?.any(|e| source.starts_with(e)) {
-                        continue;
+                    if excluded_by.is_some() {
+                        break;
                     }
+                    excluded_by = resolve_path(base_path, exc, full_env)?
+                        .into_iter()
+                        .map(|(e, _)| e)
+                        .find(|e| source.starts_with(e));
                 }
 
-                // * not allowed, so target should only ever return a single path
-                let target = resolve_path(base_path, target, full_env)?
-                    .pop()
-                    .expect("you done messed up target path resolution brother")
-                    .0;
+                if let Some(by) = excluded_by {
+                    derivation.outcome = Outcome::Excluded { by };
+                    trace.push((dest, derivation));
+                    continue;
+                }
 
-                track_map.insert(source, target);
+                trace.push((dest.clone(), derivation));
+                track_map.insert(set_source, dest);
             }
         }
 
@@ -629,14 +904,43 @@ impl DotsUse {
     }
 }
 
+// the schema describes these as written in the file, not as they end up once resolved
+#[derive(Deserialize, JsonSchema)]
 pub struct DotsOptions {
+    #[serde(rename = "use", default, deserialize_with = "deserialize_dots_use_map")]
+    #[schemars(with = "Option<HashMap<String, RawUseTableType>>")]
     pub use_map: Option<HashMap<PathBuf, Vec<DotsUse>>>, // Option<HashMap<PathBuf, (map,exc)>>
+    #[serde(default, deserialize_with = "deserialize_use_target")]
+    #[schemars(with = "Option<RawUseTargetType>")]
     pub use_target: Option<Vec<(PathBuf, PathBuf)>>,
+    #[serde(default, deserialize_with = "deserialize_exclude")]
+    #[schemars(with = "Option<RawExcludeType>")]
     pub exclude: Option<Vec<PathBuf>>,
     pub inherits: Option<HashSet<Inheritable>>,
 }
 
 impl DotsOptions {
+    // every `replace_map` var this set's (active) `use` entries resolved to. `use` keys are visited
+    // in sorted order (the map doesn't keep the file's), and later keys don't override earlier ones
+    pub fn replace_vars(&self) -> HashMap<String, String> {
+        let mut vars = HashMap::new();
+
+        if let Some(use_map) = &self.use_map {
+            let mut keys: Vec<&PathBuf> = use_map.keys().collect();
+            keys.sort();
+
+            for uses in keys.into_iter().map(|k| &use_map[k]) {
+                for dots_use in uses.iter() {
+                    for (k, v) in dots_use.replace_map.iter() {
+                        vars.entry(k.clone()).or_insert_with(|| v.clone());
+                    }
+                }
+            }
+        }
+
+        vars
+    }
+
     pub fn inherit(&mut self, from: &Self) -> &Self {
         if let None = from.inherits {
             return self;
@@ -681,15 +985,118 @@ impl DotsOptions {
     }
 }
 
-#[derive(Deserialize)]
+// a link can't leave anything out, so a mapped dir with an exclude somewhere inside it is linked
+// entry by entry instead, down to wherever the exclude is
+fn split_mapped(
+    source: PathBuf,
+    dest: PathBuf,
+    excludes: &[PathBuf],
+) -> Result<Vec<(PathBuf, PathBuf)>> {
+    if excludes.iter().any(|e| source.starts_with(e)) {
+        return Ok(vec![]);
+    }
+    if !excludes.iter().any(|e| e.starts_with(&source)) {
+        return Ok(vec![(source, dest)]);
+    }
+
+    let mut entries = fs::read_dir(&source)
+        .with_context(|| format!("Failed to read {}", source.display()))?
+        .map(|entry| entry.map(|e| e.file_name()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort();
+
+    let mut mapped = vec![];
+    for name in entries.into_iter() {
+        mapped.extend(split_mapped(
+            source.join(&name),
+            dest.join(&name),
+            excludes,
+        )?);
+    }
+    Ok(mapped)
+}
+
+// serde can't refuse unknown keys next to a flattened struct, so neither does the schema; `dots
+// check` is what reports them
+#[derive(Deserialize, JsonSchema)]
 pub struct DotfileConfig {
-    pub path: PathBuf,
+    pub path: PathBuf, // local dir, git url, or another config
     pub replace: Option<bool>,
+    #[serde(default)]
     pub use_config: bool,
+    pub hooks: Option<Hooks>,
+    pub includes: Option<Vec<PathBuf>>, // relative to the set (see DOTFILE_START_DIRS); if any, only what's under them
+    pub excludes: Option<Vec<PathBuf>>, // relative to the set
+    pub map: Option<HashMap<PathBuf, PathBuf>>, // inside the set -> anywhere (see `mapped`)
 
+    #[serde(flatten)]
     pub options: DotsOptions,
 }
 impl DotfileConfig {
+    // what `includes` and `excludes` (both relative to the set) make of a source in it; an exclude
+    // wins over an include
+    pub fn filter(&self, set: &Path, source: &Path) -> Outcome {
+        let under = |paths: &Option<Vec<PathBuf>>| {
+            paths
+                .iter()
+                .flatten()
+                .find(|p| source.starts_with(set.join(p)))
+                .cloned()
+        };
+
+        if let Some(by) = under(&self.excludes) {
+            return Outcome::Excluded { by };
+        }
+        match &self.includes {
+            Some(includes) if !includes.is_empty() && under(&self.includes).is_none() => {
+                Outcome::NotIncluded
+            }
+            _ => Outcome::Chosen,
+        }
+    }
+
+    // `map` as (source, dest, rule), in key order. a mapped file is linked whatever `includes` and
+    // `excludes` say, but a mapped dir only brings what `excludes` leave of it
+    pub fn mapped(&self, set: &Path) -> Result<Vec<(PathBuf, PathBuf, String)>> {
+        let mut mapped = vec![];
+        let Some(map) = &self.map else {
+            return Ok(mapped);
+        };
+        let excludes: Vec<PathBuf> = self
+            .excludes
+            .iter()
+            .flatten()
+            .map(|e| set.join(e))
+            .collect();
+
+        let mut entries: Vec<(&PathBuf, &PathBuf)> = map.iter().collect();
+        entries.sort();
+        for (from, to) in entries {
+            let rule = format!("map \"{}\" -> \"{}\"", from.display(), to.display());
+            let dest = resolve_path(set, to, &HashMap::new())?
+                .pop()
+                .map(|(dest, _)| dest)
+                .filter(|dest| dest.is_absolute())
+                .ok_or_else(|| {
+                    anyhow!(
+                        "map target {} has to be absolute (or start with ~)",
+                        to.display()
+                    )
+                })?;
+
+            let source = set.join(from);
+            if sfs::is_dir(&source) {
+                for (source, dest) in split_mapped(source, dest, &excludes)? {
+                    mapped.push((source, dest, rule.clone()));
+                }
+            } else {
+                mapped.push((source, dest, rule));
+            }
+        }
+
+        Ok(mapped)
+    }
+
     // use from as base value to be extended by self
 
     pub fn extend(&mut self, with: Self) -> Self {
@@ -707,9 +1114,15 @@ impl DotfileConfig {
     }
 }
 
-#[derive(Deserialize)]
+// same as DotfileConfig, unknown keys are left to `dots check`
+#[derive(Deserialize, JsonSchema)]
 pub struct DotsConfig {
+    #[serde(flatten)]
     options: DotsOptions,
+    pub elevate: Option<Elevation>,
+    pub hooks: Option<Hooks>,
+    pub policy: Option<PolicyRules>,
+    pub shell: Option<shell::ShellConfig>,
 }
 impl DotsConfig {
     pub fn extend(&mut self, with: Self) -> Self {
@@ -727,18 +1140,23 @@ impl DotsConfig {
     }
 }
 
-#[derive(Deserialize)]
+// what trackfile generation knows it as
+pub type TomlConfig = Config;
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
+    #[serde(skip)]
+    pub path: Option<PathBuf>, // most specific file it was read from
     pub general: Option<bos::GeneralConfig>,
     pub dots: Option<DotsConfig>,
     pub dotfiles: Option<Vec<DotfileConfig>>, // does nothing in normal configs
 }
 
-const CONFIG_LOCS: Vec<&str> = vec![".config/dots/config", ".dots", ".config/bos/config", ".bos"];
-
 impl Config {
     pub fn new() -> Self {
         let config = Self {
+            path: None,
             general: None,
             dots: None,
             dotfiles: None,
@@ -751,8 +1169,18 @@ impl Config {
 
     pub fn inherited_by(&self, by: &Self) -> Self {}
 
-    pub fn load(mut self, path: PathBuf) -> Self {
-        self
+    // a single file on its own, no layering
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = sfs::read_to_string(path)?;
+        Self::parse(&contents, path)
+    }
+
+    // `contents` as read from `path`, for callers that already looked at it
+    pub fn parse(contents: &str, path: &Path) -> Result<Self> {
+        let mut config: Self = toml::from_str(contents)
+            .with_context(|| format!("Failed to parse config {}", path.display()))?;
+        config.path = Some(path.to_path_buf());
+        Ok(config)
     }
 
     // BAD NONO NOT GOOD
@@ -784,25 +1212,19 @@ impl Config {
         self
     }
 
-    pub fn load_all(mut self, paths: Vec<PathBuf>) -> Self {
-        for path in paths.into_iter() {
-            self.load(path);
-        }
-
-        self
+    pub fn from_layers(layers: &LayeredConfig) -> Result<Self> {
+        let mut config: Self = layers
+            .table()
+            .clone()
+            .try_into()
+            .context("Invalid config")?;
+        config.path = layers.path().cloned();
+        Ok(config)
     }
 
-    pub fn detect() -> Result<Self> {
-        let home = PathBuf::from(std::env::var("HOME").unwrap());
-        for loc in CONFIG_LOCS {
-            loc = home.join(loc);
-            if loc.try_exists() {
-                let contents = fs::read_to_string(loc)?;
-                return Ok(toml::from_str(&contents)?);
-            }
-        }
-
-        Ok(Self::default())
+    // system, user, home, then the target's own config, then `-c` overrides; later ones win
+    pub fn detect(env: &bos::Env, target: Option<&Path>, overrides: &[String]) -> Result<Self> {
+        Self::from_layers(&LayeredConfig::load(env, target, overrides)?)
     }
 }
 
@@ -810,64 +1232,207 @@ impl Config {
 #[builder(pattern = "owned")]
 pub struct Dots {
     args: &Args,
-    #[builder(default = "Toml::detect()")]
-    config: &Config,
+    #[builder(default = "Config::new()")]
+    config: Config,
+    #[builder(setter(skip), default)]
+    layers: LayeredConfig,
     #[builder(default = "bos::Env::detect()")]
-    env: &bos::Env,
-    #[builder(setter(skip), default = "Trackfile::detect()")]
-    state: &Trackfile,
+    env: bos::Env,
+    #[builder(setter(skip), default)]
+    state: Trackfile, // loaded in `run`, and saved there once the command is done with it
+    #[builder(setter(skip), default)]
+    system_state: Trackfile,
+    #[builder(setter(skip), default)]
+    privileged: Vec<HelperAction>,
+    #[builder(setter(skip), default)]
+    policy: DecisionPolicy,
+    #[builder(default = "Box::new(LinePrompter::stdio())")]
+    prompter: Box<dyn Prompter>,
 }
 
 // ~~ TOML ~~
 
-pub fn run(dots: Dots) -> Result<()> {
-    let cache_dir = dirs::cache_dir()
-        .ok_or_else(|| Err(anyhow!("Could not determine user cache directory")))?
-        .join("bos");
+// 0 on success; otherwise tells apart what stopped us (see `PolicyTripped::exit_code`)
+pub fn exit_code(result: &Result<()>) -> i32 {
+    match result {
+        Ok(()) => 0,
+        Err(e) => e
+            .downcast_ref::<PolicyTripped>()
+            .map(PolicyTripped::exit_code)
+            .unwrap_or(1),
+    }
+}
+
+// whatever this run resolves dotfiles from, for its config to be layered in
+fn config_target(args: &Args) -> Option<PathBuf> {
+    let target = match &args.command {
+        Commands::Link(a) | Commands::Unlink(a) | Commands::Relink(a) => {
+            return Some(a.target.clone());
+        }
+        Commands::Explain(a) => a.target.clone(),
+        Commands::Edit(a) => a.target.clone(),
+        Commands::Recover(a) => a.target.clone(),
+        Commands::Config(a) => match &a.command {
+            ConfigCommands::Show(a) => a.target.clone(),
+        },
+        _ => None,
+    };
+    target.or_else(|| args.dotfiles.clone()).map(PathBuf::from)
+}
+
+pub fn run(mut dots: Dots) -> Result<()> {
+    if let Commands::Helper(args) = &dots.args.command {
+        return run_helper(args).context("Privileged helper failed");
+    }
+
+    if let Some(user) = &dots.args.user {
+        dots.env = bos::Env::detect_for(Some(user)).map_err(|e| anyhow!(e))?;
+    }
+    // a copy, since the commands below need all of `dots` mutably
+    let env = dots.env.clone();
+    stats::set_format(dots.args.format);
+    when::set_env(&env);
+    // the language server has nobody to ask (stdin is the client), and runs nothing on its own:
+    // a hover shouldn't have side effects, whoever's config it is
+    let interactive = !matches!(dots.args.command, Commands::Lsp(_));
+    trust::configure(&env, dots.args.no_shell || !interactive, interactive);
+
+    // has to get to the configs before loading them fails on whatever's wrong with them
+    if let Commands::Check(args) = &dots.args.command {
+        return dots.check(args).context("Config check failed");
+    }
+    if let Commands::Schema(args) = &dots.args.command {
+        return dots.schema(args).context("Failed to generate schema");
+    }
+    if let Commands::Lsp(args) = &dots.args.command {
+        return dots.lsp(args).context("Language server failed");
+    }
+
+    let target = config_target(&dots.args).map(|t| env.expand_home(&t));
+    dots.layers = LayeredConfig::load(&env, target.as_deref(), &dots.args.overrides)
+        .context("Failed to load config")?;
+    // before the config gets deserialized, since that's when `when` and vars run
+    for (path, content) in dots.layers.sources().iter() {
+        let chain = [Include {
+            path: path.clone(),
+            url: None,
+        }];
+        trust::vet(&chain, content, &mut *dots.prompter)?;
+    }
+    shell::configure(&shell::ShellConfig::from_table(dots.layers.table())?);
+    dots.config = Config::from_layers(&dots.layers)?;
+
+    // env.cache_dir already points at the invoking user's cache under sudo/doas
+    let cache_dir = env.cache_dir.join("bos");
 
     let trackfile_path = cache_dir.join("trackfile.toml");
 
-    let mut trackfile_state =
-        Trackfile::load(&trackfile_path, env).context("Failed to load trackfile state")?;
+    dots.state =
+        Trackfile::load(&trackfile_path, &env).context("Failed to load trackfile state")?;
+
+    // read-only from here; only the privileged helper ever writes it (and creates its directory,
+    // which `Trackfile::load` would otherwise try to do as us)
+    let system_trackfile_path = PathBuf::from(SYSTEM_TRACKFILE);
+    if sfs::path_exists(&system_trackfile_path) {
+        dots.system_state = Trackfile::load(&system_trackfile_path, &env)
+            .context("Failed to load system trackfile state")?;
+    }
+
+    // pre-answered conflicts: the policy file's own rules first, then [dots.policy]
+    if let Commands::Link(args) | Commands::Unlink(args) | Commands::Relink(args) =
+        &dots.args.command
+    {
+        let policy_path = args
+            .policy
+            .clone()
+            .unwrap_or_else(|| cache_dir.join(DEFAULT_POLICY_FILE));
+        let config_rules = dots.config.dots.as_ref().and_then(|d| d.policy.as_ref());
+        dots.policy = DecisionPolicy::load(&policy_path, config_rules, &env)
+            .context("Failed to load decision policy")?;
+    }
 
     let opts = LinkOptions {
+        format: dots.args.format,
         ..Default::default()
     };
 
-    let dry_run_active = match &dots.args.command {
-        Commands::Link(args) => {
-            dots.link(args, &opts).context("Link operation failed")?;
-            args.dry_run
-        }
-        Commands::Unlink(args) => {
+    // whatever a command got done before failing is still recorded below, so only propagate after
+    let (result, dry_run_active) = match &dots.args.command {
+        Commands::Link(args) => (
+            dots.link(args, &opts)
+                .map(drop)
+                .context("Link operation failed"),
+            args.dry_run,
+        ),
+        Commands::Unlink(args) => (
             dots.unlink(args, &opts)
-                .context("Unlink operation failed")?;
-            args.dry_run
-        }
-        Commands::Relink(args) => {
-            dots.relink(args, &mut opts)
-                .context("Relink operation failed")?;
-            args.dry_run
-        }
-        Commands::Status(args) => {
-            dots.status(args, &opts)
-                .context("Status operation failed")?;
-            false
-        }
-        Commands::Clean(args) => {
-            dots.clean(args, &opts).context("Clean operation failed")?;
-            args.dry_run
+                .map(drop)
+                .context("Unlink operation failed"),
+            args.dry_run,
+        ),
+        Commands::Relink(args) => (
+            dots.relink(args, &opts)
+                .map(drop)
+                .context("Relink operation failed"),
+            args.dry_run,
+        ),
+        Commands::Status(args) => (
+            dots.status(args, &opts).context("Status operation failed"),
+            false,
+        ),
+        Commands::Clean(args) => (
+            dots.clean(args, &opts).context("Clean operation failed"),
+            args.dry_run,
+        ),
+        Commands::Add(args) => (dots.add(args).context("Add operation failed"), args.dry_run),
+        Commands::Forget(args) => (
+            dots.forget(args).context("Forget operation failed"),
+            args.dry_run,
+        ),
+        Commands::Explain(args) => (
+            dots.explain(args).context("Explain operation failed"),
+            false,
+        ),
+        Commands::Edit(args) => (dots.edit(args).context("Edit operation failed"), false),
+        Commands::Mv(args) => (dots.mv(args).context("Move operation failed"), args.dry_run),
+        Commands::Relocate(args) => (
+            dots.relocate(args).context("Relocate operation failed"),
+            args.dry_run,
+        ),
+        Commands::Recover(args) => (
+            dots.recover(args).context("Recover operation failed"),
+            args.dry_run,
+        ),
+        Commands::Config(args) => match &args.command {
+            ConfigCommands::Show(args) => (
+                dots.config_show(args).context("Failed to show config"),
+                false,
+            ),
+        },
+        Commands::Check(_) | Commands::Schema(_) | Commands::Lsp(_) | Commands::Helper(_) => {
+            unreachable!()
         }
     };
 
-    if trackfile_state.is_dirty() && !dry_run_active {
-        trackfile_state
-            .save(&trackfile_path, env)
+    if dots.state.is_dirty() && !dry_run_active {
+        dots.state
+            .save(&trackfile_path, env.clone())
             .context("Failed to save trackfile state")?;
-        println!("Trackfile saved to {}", trackfile_path.display());
-    } else if trackfile_state.is_dirty() && dry_run_active {
-        println!("DRY RUN: Trackfile would have been saved.");
+        sfs::chown(&trackfile_path, env.owner)?;
+        say!("Trackfile saved to {}", trackfile_path.display());
+    } else if dots.state.is_dirty() && dry_run_active {
+        say!("DRY RUN: Trackfile would have been saved.");
     }
 
+    // answers given before a failure are still worth keeping
+    if dots.policy.is_dirty() {
+        dots.policy
+            .save(&env)
+            .context("Failed to save answers to policy file")?;
+        say!("Answers saved to policy file");
+    }
+
+    result?;
+
     Ok(())
 }
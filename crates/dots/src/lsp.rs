@@ -0,0 +1,953 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use lsp_server::{Connection, ErrorCode, Message, Notification, Request, Response};
+use lsp_types::notification::{
+    DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, DidSaveTextDocument,
+    Notification as _, PublishDiagnostics,
+};
+use lsp_types::request::{Completion, GotoDefinition, HoverRequest, Request as _};
+use lsp_types::{
+    CompletionItem, CompletionItemKind, CompletionOptions, CompletionParams, CompletionResponse,
+    CompletionTextEdit, DiagnosticSeverity, DidChangeTextDocumentParams,
+    DidCloseTextDocumentParams, DidOpenTextDocumentParams, DidSaveTextDocumentParams,
+    GotoDefinitionParams, GotoDefinitionResponse, Hover, HoverContents, HoverParams,
+    HoverProviderCapability, Location, MarkupContent, MarkupKind, OneOf, Position,
+    PublishDiagnosticsParams, Range, ServerCapabilities, TextDocumentSyncCapability,
+    TextDocumentSyncKind, TextEdit, Url,
+};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::check::*;
+use crate::decisions::POLICY_REASONS;
+use crate::prompt::ScriptedPrompter;
+use crate::trackfile::*;
+use crate::{Dots, DotsLspArgs};
+use shared::fs as sfs;
+
+// what `[` / `[[` can be followed by
+const HEADERS: &[(&str, bool)] = &[
+    ("general", false),
+    ("dots", false),
+    ("dots.use", false),
+    ("dots.hooks", false),
+    ("dots.policy", false),
+    ("dots.policy.rule", true),
+    ("dotfiles", true),
+    ("dotfiles.use", false),
+    ("dotfiles.hooks", false),
+];
+
+// how many resolved entries a hover lists before cutting off
+const HOVER_LIMIT: usize = 30;
+
+struct Header {
+    path: Vec<String>,
+    array: bool,
+}
+
+// `a."b.c".d` -> a, b.c, d; stops at an unquoted `]`
+fn split_key(s: &str) -> Vec<String> {
+    let mut parts = vec![];
+    let mut part = String::new();
+    let mut quote = None;
+
+    for c in s.chars() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => part.push(c),
+            None => match c {
+                '"' | '\'' => quote = Some(c),
+                '.' => parts.push(std::mem::take(&mut part).trim().to_string()),
+                ']' => break,
+                _ => part.push(c),
+            },
+        }
+    }
+    parts.push(part.trim().to_string());
+
+    parts
+}
+
+fn header(line: &str) -> Option<Header> {
+    let line = line.trim_start();
+    let (rest, array) = match line.strip_prefix("[[") {
+        Some(rest) => (rest, true),
+        None => (line.strip_prefix('[')?, false),
+    };
+    Some(Header {
+        path: split_key(rest),
+        array,
+    })
+}
+
+// the string `before` ends inside of (without its opening quote), if any
+fn open_string(before: &str) -> Option<&str> {
+    let mut open = None;
+    for (i, c) in before.char_indices() {
+        match open {
+            Some((q, _)) if c == q => open = None,
+            None if c == '"' || c == '\'' => open = Some((c, i + 1)),
+            _ => {}
+        }
+    }
+    open.map(|(_, start)| &before[start..])
+}
+
+// `key = value`, splitting on the first `=` that isn't quoted
+fn split_assignment(line: &str) -> Option<(&str, &str)> {
+    let mut quote = None;
+    for (i, c) in line.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == '=' => return Some((&line[..i], &line[i + 1..])),
+            None if c == '#' => return None,
+            None => {}
+        }
+    }
+    None
+}
+
+// the first string in a value, e.g. `"~/dots" # comment` -> ~/dots
+fn string_value(value: &str) -> Option<&str> {
+    let value = value.trim_start();
+    let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let rest = &value[1..];
+    rest.find(quote).map(|end| &rest[..end])
+}
+
+// lsp positions count utf-16 units
+fn byte_col(line: &str, character: u32) -> usize {
+    let mut units = 0;
+    for (i, c) in line.char_indices() {
+        if units >= character as usize {
+            return i;
+        }
+        units += c.len_utf16();
+    }
+    line.len()
+}
+
+fn position(text: &str, offset: usize) -> Position {
+    let mut offset = offset.min(text.len());
+    while !text.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    let before = &text[..offset];
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+
+    Position::new(
+        before.matches('\n').count() as u32,
+        before[line_start..].encode_utf16().count() as u32,
+    )
+}
+
+// the part of the line before `pos` that's being replaced, as a range
+fn replacing(pos: Position, typed: &str) -> Range {
+    let len = typed.encode_utf16().count() as u32;
+    Range::new(
+        Position::new(pos.line, pos.character.saturating_sub(len)),
+        pos,
+    )
+}
+
+fn item(label: &str, kind: CompletionItemKind, range: Range) -> CompletionItem {
+    CompletionItem {
+        label: label.to_string(),
+        kind: Some(kind),
+        text_edit: Some(CompletionTextEdit::Edit(TextEdit::new(
+            range,
+            label.to_string(),
+        ))),
+        ..Default::default()
+    }
+}
+
+// entries of `dir` starting with `prefix`, directories with a trailing slash
+fn markdown(value: String) -> Hover {
+    Hover {
+        contents: HoverContents::Markup(MarkupContent {
+            kind: MarkupKind::Markdown,
+            value,
+        }),
+        range: None,
+    }
+}
+
+fn path_items(dir: &Path, prefix: &str, range: Range) -> Vec<CompletionItem> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return vec![];
+    };
+
+    let mut items: Vec<CompletionItem> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !name.starts_with(prefix) || name == ".git" {
+                return None;
+            }
+            Some(match sfs::is_dir(&entry.path()) {
+                true => item(&format!("{}/", name), CompletionItemKind::FOLDER, range),
+                false => item(&name, CompletionItemKind::FILE, range),
+            })
+        })
+        .collect();
+    items.sort_by(|a, b| a.label.cmp(&b.label));
+
+    items
+}
+
+// keys a table takes; empty where anything goes (or nothing does)
+fn table_keys(table: &[String]) -> Vec<&'static str> {
+    let table: Vec<&str> = table.iter().map(String::as_str).collect();
+    let groups: &[&[&str]] = match table.as_slice() {
+        [] => &[ROOT_KEYS],
+        ["general"] => &[GENERAL_KEYS],
+        ["dots"] => &[OPTION_KEYS, DOTS_KEYS],
+        ["dotfiles"] => &[OPTION_KEYS, DOTFILE_KEYS],
+        [_, "hooks"] => &[HOOK_KEYS],
+        ["dots", "shell"] => &[SHELL_KEYS],
+        ["dots", "policy"] => &[POLICY_KEYS],
+        ["dots", "policy", "rule"] => &[RULE_KEYS],
+        [_, "use", _] => &[USE_KEYS],
+        [_, "use", _, "when", .., "env"] => &[],
+        [_, "use", _, "when", ..] => &[WHEN_KEYS], // and `all`/`any`/`not` nested in it
+        [_, "use", _, _] => &[VAR_KEYS],
+        _ => &[],
+    };
+    groups
+        .iter()
+        .flat_map(|keys| keys.iter().copied())
+        .collect()
+}
+
+// the buffer, line by line. completion works off the text alone, since it rarely parses while
+// it's being typed
+struct Document<'a> {
+    lines: Vec<&'a str>,
+}
+
+impl<'a> Document<'a> {
+    fn new(text: &'a str) -> Self {
+        Self {
+            lines: text.lines().collect(),
+        }
+    }
+
+    fn line(&self, n: usize) -> &'a str {
+        self.lines.get(n).copied().unwrap_or_default()
+    }
+
+    // the header of the table `line` is in (possibly on `line` itself), and where it is
+    fn table(&self, line: usize) -> Option<(usize, Header)> {
+        (0..=line)
+            .rev()
+            .find_map(|n| header(self.line(n)).map(|h| (n, h)))
+    }
+
+    // lines of the table whose header is on `start` (the root table for None)
+    fn body(&self, start: Option<usize>) -> impl Iterator<Item = &'a str> + '_ {
+        let from = start.map(|n| n + 1).unwrap_or(0);
+        self.lines[from.min(self.lines.len())..]
+            .iter()
+            .copied()
+            .take_while(|line| header(line).is_none())
+    }
+
+    fn body_keys(&self, start: Option<usize>) -> Vec<String> {
+        self.body(start)
+            .filter_map(split_assignment)
+            .filter_map(|(key, _)| split_key(key).into_iter().next())
+            .collect()
+    }
+
+    // the key of a multi-line array `line` is inside of
+    fn array_key(&self, line: usize) -> Option<String> {
+        for n in (0..line).rev() {
+            let text = self.line(n);
+            if header(text).is_some() {
+                return None;
+            }
+            if let Some((key, value)) = split_assignment(text) {
+                let open = value.matches('[').count() > value.matches(']').count();
+                return open.then(|| split_key(key).pop()).flatten();
+            }
+        }
+        None
+    }
+
+    // the `<var>`s a placeholder around `line` can refer to: the enclosing `use` table's own vars
+    // first, then every var defined anywhere in the file
+    fn vars(&self, line: usize) -> Vec<(String, bool)> {
+        let mut local = BTreeSet::new();
+        let mut all = BTreeSet::new();
+
+        let use_key = self.table(line).and_then(|(_, h)| match h.path.as_slice() {
+            [_, u, key, ..] if u == "use" => Some(key.clone()),
+            _ => None,
+        });
+
+        for (n, text) in self.lines.iter().enumerate() {
+            let Some(h) = header(text) else {
+                continue;
+            };
+            let (key, vars): (&String, Vec<String>) = match h.path.as_slice() {
+                [_, u, key] if u == "use" => (
+                    key,
+                    self.body_keys(Some(n))
+                        .into_iter()
+                        .filter(|k| !USE_KEYS.contains(&k.as_str()))
+                        .collect(),
+                ),
+                [_, u, key, var] if u == "use" && var != "when" => (key, vec![var.clone()]),
+                _ => continue,
+            };
+            if use_key.as_ref() == Some(key) {
+                local.extend(vars.iter().cloned());
+            }
+            all.extend(vars);
+        }
+
+        let others: Vec<(String, bool)> =
+            all.difference(&local).map(|v| (v.clone(), false)).collect();
+        local.into_iter().map(|v| (v, true)).chain(others).collect()
+    }
+
+    // the `dots.use` key `line` is about: its header, an entry of a `[*.use]` table, or anything
+    // inside the entry's own table
+    fn use_key(&self, line: usize) -> Option<String> {
+        let (start, h) = self.table(line)?;
+        match h.path.as_slice() {
+            [_, u, key, ..] if u == "use" => Some(key.clone()),
+            [_, u] if u == "use" && start != line => {
+                let (key, _) = split_assignment(self.line(line))?;
+                split_key(key).into_iter().next()
+            }
+            _ => None,
+        }
+    }
+
+    // the `path` of the [[dotfiles]] entry `line` is in
+    fn dotfile_path(&self, line: usize) -> Option<&'a str> {
+        let start = (0..=line)
+            .rev()
+            .find(|n| header(self.line(*n)).is_some_and(|h| h.array && h.path == ["dotfiles"]))?;
+        self.body(Some(start)).find_map(|text| {
+            let (key, value) = split_assignment(text)?;
+            (key.trim() == "path")
+                .then(|| string_value(value))
+                .flatten()
+        })
+    }
+}
+
+fn request<P: DeserializeOwned, R: Serialize>(
+    params: serde_json::Value,
+    handle: impl FnOnce(P) -> Result<R>,
+) -> Result<serde_json::Value> {
+    let params = serde_json::from_value(params).context("Invalid parameters")?;
+    Ok(serde_json::to_value(handle(params)?)?)
+}
+
+struct Server<'a> {
+    dots: &'a Dots,
+    docs: HashMap<Url, String>, // open buffers, as last sent
+}
+
+impl Server<'_> {
+    fn path(uri: &Url) -> Result<PathBuf> {
+        uri.to_file_path()
+            .map_err(|_| anyhow!("{} is not a local file", uri))
+    }
+
+    fn text(&self, uri: &Url) -> Result<String> {
+        match self.docs.get(uri) {
+            Some(text) => Ok(text.clone()),
+            None => sfs::read_to_string(&Self::path(uri)?),
+        }
+    }
+
+    // where a path in the config at `file` points to
+    fn resolve(&self, file: &Path, path: &str) -> PathBuf {
+        let base = file.parent().unwrap_or(Path::new("/"));
+        base.join(self.dots.env.expand_home(Path::new(path)))
+    }
+
+    // the source set paths in the table around `line` are relative to
+    fn source_set(
+        &self,
+        file: &Path,
+        doc: &Document,
+        line: usize,
+        table: &[String],
+    ) -> Option<PathBuf> {
+        if table.first().map(String::as_str) != Some("dotfiles") {
+            return file.parent().map(Path::to_path_buf);
+        }
+        let path = doc.dotfile_path(line)?;
+        if path.contains("://") {
+            return None;
+        }
+        Some(self.resolve(file, path)).filter(|dir| sfs::is_dir(dir))
+    }
+
+    fn diagnostics(&self, uri: &Url) -> Result<Notification> {
+        let path = Self::path(uri)?;
+        let text = self.text(uri)?;
+
+        let (found, _) = check_config(&path, &text, &self.dots.env);
+        let diagnostics = found
+            .into_iter()
+            .map(|d| {
+                let range = match &d.span {
+                    Some(span) => {
+                        Range::new(position(&text, span.start), position(&text, span.end))
+                    }
+                    None => Range::default(),
+                };
+                let severity = match d.severity {
+                    Severity::Error => DiagnosticSeverity::ERROR,
+                    Severity::Warning => DiagnosticSeverity::WARNING,
+                    Severity::Note => DiagnosticSeverity::INFORMATION,
+                };
+                lsp_types::Diagnostic {
+                    range,
+                    severity: Some(severity),
+                    source: Some("dots".to_string()),
+                    message: d.message,
+                    ..Default::default()
+                }
+            })
+            .collect();
+
+        Ok(Notification::new(
+            PublishDiagnostics::METHOD.to_string(),
+            PublishDiagnosticsParams {
+                uri: uri.clone(),
+                diagnostics,
+                version: None,
+            },
+        ))
+    }
+
+    fn notify(&mut self, notification: Notification) -> Result<Option<Notification>> {
+        let uri = match notification.method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                let params: DidOpenTextDocumentParams =
+                    serde_json::from_value(notification.params)?;
+                let uri = params.text_document.uri;
+                self.docs.insert(uri.clone(), params.text_document.text);
+                uri
+            }
+            DidChangeTextDocument::METHOD => {
+                let DidChangeTextDocumentParams {
+                    text_document,
+                    content_changes,
+                } = serde_json::from_value(notification.params)?;
+                // full sync, so the last change is the whole document
+                if let Some(change) = content_changes.into_iter().last() {
+                    self.docs.insert(text_document.uri.clone(), change.text);
+                }
+                text_document.uri
+            }
+            // other files may have changed along with it
+            DidSaveTextDocument::METHOD => {
+                let params: DidSaveTextDocumentParams =
+                    serde_json::from_value(notification.params)?;
+                params.text_document.uri
+            }
+            DidCloseTextDocument::METHOD => {
+                let params: DidCloseTextDocumentParams =
+                    serde_json::from_value(notification.params)?;
+                let uri = params.text_document.uri;
+                self.docs.remove(&uri);
+                return Ok(Some(Notification::new(
+                    PublishDiagnostics::METHOD.to_string(),
+                    PublishDiagnosticsParams {
+                        uri,
+                        diagnostics: vec![],
+                        version: None,
+                    },
+                )));
+            }
+            _ => return Ok(None),
+        };
+
+        self.diagnostics(&uri).map(Some)
+    }
+
+    fn respond(&self, req: Request) -> Response {
+        let result = match req.method.as_str() {
+            Completion::METHOD => request(req.params, |p| self.completion(p)),
+            HoverRequest::METHOD => request(req.params, |p| self.hover(p)),
+            GotoDefinition::METHOD => request(req.params, |p| self.definition(p)),
+            _ => {
+                return Response::new_err(
+                    req.id,
+                    ErrorCode::MethodNotFound as i32,
+                    format!("Unsupported request {}", req.method),
+                );
+            }
+        };
+
+        match result {
+            Ok(value) => Response::new_ok(req.id, value),
+            Err(e) => {
+                Response::new_err(req.id, ErrorCode::InternalError as i32, format!("{:#}", e))
+            }
+        }
+    }
+
+    fn completion(&self, params: CompletionParams) -> Result<Option<CompletionResponse>> {
+        let uri = params.text_document_position.text_document.uri;
+        let pos = params.text_document_position.position;
+        let file = Self::path(&uri)?;
+        let text = self.text(&uri)?;
+        let doc = Document::new(&text);
+
+        let n = pos.line as usize;
+        let line = doc.line(n);
+        let before = &line[..byte_col(line, pos.character)];
+        let string = open_string(before);
+
+        // a placeholder being typed, wherever it is
+        if let Some(partial) = string {
+            if let Some(start) = partial.rfind('<').filter(|i| !partial[*i..].contains('>')) {
+                let typed = &partial[start + 1..];
+                let range = replacing(pos, typed);
+                let items = doc
+                    .vars(n)
+                    .into_iter()
+                    .map(|(var, local)| CompletionItem {
+                        sort_text: Some(format!("{}{}", if local { 0 } else { 1 }, var)),
+                        text_edit: Some(CompletionTextEdit::Edit(TextEdit::new(
+                            range,
+                            format!("{}>", var),
+                        ))),
+                        ..item(&var, CompletionItemKind::VARIABLE, range)
+                    })
+                    .collect();
+                return Ok(Some(CompletionResponse::Array(items)));
+            }
+        }
+
+        // a header being typed: either the table's name, or a `use` path in it
+        if let Some(h) = header(before) {
+            let (_, table) = h.path.split_last().unwrap();
+            let items = match string {
+                Some(partial) if table.len() == 2 && table[1] == "use" => {
+                    self.source_paths(&file, &doc, n, table, partial, pos)
+                }
+                Some(_) => vec![],
+                None => {
+                    let typed = before.trim_start().trim_start_matches('[');
+                    let range = replacing(pos, typed);
+                    HEADERS
+                        .iter()
+                        .filter(|(_, array)| *array == h.array)
+                        .map(|(name, _)| item(name, CompletionItemKind::MODULE, range))
+                        .collect()
+                }
+            };
+            return Ok(Some(CompletionResponse::Array(items)));
+        }
+
+        let (start, table) = match doc.table(n) {
+            Some((start, h)) => (Some(start), h.path),
+            None => (None, vec![]),
+        };
+
+        let key = match split_assignment(before) {
+            Some((key, _)) => split_key(key).pop(),
+            None if string.is_some() => doc.array_key(n),
+            None => None,
+        };
+
+        let items = match (key, string) {
+            // a key being typed: `use` paths in a `[*.use]` table, known keys everywhere else
+            (None, Some(partial)) if table.len() == 2 && table[1] == "use" => {
+                self.source_paths(&file, &doc, n, &table, partial, pos)
+            }
+            (None, Some(_)) => vec![],
+            (None, None) => {
+                let typed = before.trim_start();
+                let range = replacing(pos, typed);
+                let existing = doc.body_keys(start);
+                table_keys(&table)
+                    .into_iter()
+                    .filter(|k| !existing.iter().any(|e| e == k))
+                    .map(|k| item(k, CompletionItemKind::PROPERTY, range))
+                    .collect()
+            }
+            (Some(key), Some(partial)) => {
+                let range = replacing(pos, partial);
+                let values = |values: &[&str]| -> Vec<CompletionItem> {
+                    values
+                        .iter()
+                        .map(|v| item(v, CompletionItemKind::ENUM_MEMBER, range))
+                        .collect()
+                };
+                match key.as_str() {
+                    "includes" | "excludes" | "exclude" => {
+                        self.source_paths(&file, &doc, n, &table, partial, pos)
+                    }
+                    "path" if table == ["dotfiles"] => {
+                        let (dir, typed) = match partial.rfind('/') {
+                            Some(i) => (&partial[..=i], &partial[i + 1..]),
+                            None => ("", partial),
+                        };
+                        path_items(&self.resolve(&file, dir), typed, replacing(pos, typed))
+                    }
+                    "elevate" => values(ELEVATIONS),
+                    "interpreter" => values(INTERPRETERS),
+                    "action" => values(POLICY_ACTIONS),
+                    "reasons" => values(POLICY_REASONS.as_slice()),
+                    "inherits" => values(INHERITABLE),
+                    _ => vec![],
+                }
+            }
+            (Some(_), None) => vec![],
+        };
+
+        Ok(Some(CompletionResponse::Array(items)))
+    }
+
+    // paths inside the source set the table at `line` belongs to
+    fn source_paths(
+        &self,
+        file: &Path,
+        doc: &Document,
+        line: usize,
+        table: &[String],
+        partial: &str,
+        pos: Position,
+    ) -> Vec<CompletionItem> {
+        let Some(set) = self.source_set(file, doc, line, table) else {
+            return vec![];
+        };
+        let (dir, typed) = match partial.rfind('/') {
+            Some(i) => (&partial[..=i], &partial[i + 1..]),
+            None => ("", partial),
+        };
+        // placeholders and globs are only resolved when linking
+        if dir.contains(['<', '*', '$']) {
+            return vec![];
+        }
+
+        path_items(&set.join(dir), typed, replacing(pos, typed))
+    }
+
+    // what a `dots.use` entry resolves to on this machine, going by the config as saved
+    fn hover(&self, params: HoverParams) -> Result<Option<Hover>> {
+        let uri = params.text_document_position_params.text_document.uri;
+        let pos = params.text_document_position_params.position;
+        let file = Self::path(&uri)?;
+        let text = self.text(&uri)?;
+        let doc = Document::new(&text);
+
+        let Some(key) = doc.use_key(pos.line as usize) else {
+            return Ok(None);
+        };
+
+        // nothing that reaches outside: git sources aren't cloned, and shell commands never run
+        // here (see `run`), so anything that needs one can't be resolved (and there's no one to
+        // ask about them either; stdin is the client's)
+        let mut opts = TrackfileGenOptions::default();
+        opts.offline = true;
+        let plan = match Trackfile::generate(
+            file.to_string_lossy().to_string(),
+            &self.dots.env,
+            Some(opts),
+            &mut ScriptedPrompter::default(),
+        ) {
+            Ok(plan) => plan,
+            Err(e) => {
+                return Ok(Some(markdown(format!(
+                    "**`use \"{}\"`** can't be resolved here: {:#}\n",
+                    key, e
+                ))))
+            }
+        };
+
+        let rule = format!("use \"{}\"", key);
+        let mut entries: Vec<(&PathBuf, &Derivation)> = plan
+            .all_derivations()
+            .filter(|(_, d)| d.rule.starts_with(&rule))
+            .filter(|(_, d)| d.config.as_ref().is_none_or(|c| *c == file))
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+
+        let mut out = format!("**`{}`** on this machine\n\n", rule);
+        if entries.is_empty() {
+            out.push_str(
+                "Nothing: its `when` doesn't hold here, it matches no sources, or they're from git \
+                 (which isn't cloned for this).\n",
+            );
+        }
+        for (dest, derivation) in entries.iter().take(HOVER_LIMIT) {
+            let source = match &derivation.source_set {
+                Some(set) => derivation
+                    .source
+                    .strip_prefix(set)
+                    .unwrap_or(&derivation.source),
+                None => &derivation.source,
+            };
+            let mut line = format!("- `{}` ← `{}`", dest.display(), source.display());
+            if !derivation.vars.is_empty() {
+                let vars: Vec<String> = derivation
+                    .vars
+                    .iter()
+                    .map(|(k, v)| format!("{} = {}", k, v))
+                    .collect();
+                line.push_str(&format!(" ({})", vars.join(", ")));
+            }
+            match &derivation.outcome {
+                Outcome::Chosen => {}
+                Outcome::Excluded { by } => {
+                    line.push_str(&format!(", excluded by `{}`", by.display()))
+                }
+                Outcome::Shadowed { by } => {
+                    line.push_str(&format!(", shadowed by `{}`", by.display()))
+                }
+                Outcome::Ignored => line.push_str(", ignored (`replace = false`)"),
+                Outcome::NotIncluded => line.push_str(", not in the set's `includes`"),
+            }
+            out.push_str(&line);
+            out.push('\n');
+        }
+        if entries.len() > HOVER_LIMIT {
+            out.push_str(&format!("- …and {} more\n", entries.len() - HOVER_LIMIT));
+        }
+
+        if sfs::read_to_string(&file).is_ok_and(|saved| saved != text) {
+            out.push_str("\n_As last saved._\n");
+        }
+
+        Ok(Some(markdown(out)))
+    }
+
+    // `[[dotfiles]] path` to the config it points to, whether a file or a directory with one
+    fn definition(&self, params: GotoDefinitionParams) -> Result<Option<GotoDefinitionResponse>> {
+        let uri = params.text_document_position_params.text_document.uri;
+        let n = params.text_document_position_params.position.line as usize;
+        let file = Self::path(&uri)?;
+        let text = self.text(&uri)?;
+        let doc = Document::new(&text);
+
+        if doc.table(n).is_none_or(|(_, h)| h.path != ["dotfiles"]) {
+            return Ok(None);
+        }
+        let Some((key, value)) = split_assignment(doc.line(n)) else {
+            return Ok(None);
+        };
+        let Some(path) = string_value(value).filter(|_| key.trim() == "path") else {
+            return Ok(None);
+        };
+        if path.contains("://") {
+            return Ok(None);
+        }
+
+        let target = self.resolve(&file, path);
+        let config = if sfs::is_file(&target) {
+            target
+        } else if sfs::is_dir(&target) {
+            match Trackfile::find_config(&target)? {
+                Some(config) => config,
+                None => return Ok(None),
+            }
+        } else {
+            return Ok(None);
+        };
+
+        let uri = Url::from_file_path(&config)
+            .map_err(|_| anyhow!("Invalid path {}", config.display()))?;
+        Ok(Some(GotoDefinitionResponse::Scalar(Location::new(
+            uri,
+            Range::default(),
+        ))))
+    }
+}
+
+impl Dots {
+    // stdout belongs to the protocol from here on, so nothing else may print to it
+    pub fn lsp(&self, _args: &DotsLspArgs) -> Result<()> {
+        let (connection, io_threads) = Connection::stdio();
+
+        let capabilities = ServerCapabilities {
+            text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+            completion_provider: Some(CompletionOptions {
+                trigger_characters: Some(
+                    ["\"", "'", "/", "<", ".", "["].map(String::from).to_vec(),
+                ),
+                ..Default::default()
+            }),
+            hover_provider: Some(HoverProviderCapability::Simple(true)),
+            definition_provider: Some(OneOf::Left(true)),
+            ..Default::default()
+        };
+        connection
+            .initialize(serde_json::to_value(capabilities)?)
+            .context("Failed to initialize language server")?;
+
+        let mut server = Server {
+            dots: self,
+            docs: HashMap::new(),
+        };
+        for message in &connection.receiver {
+            match message {
+                Message::Request(req) => {
+                    if connection.handle_shutdown(&req)? {
+                        break;
+                    }
+                    connection
+                        .sender
+                        .send(Message::Response(server.respond(req)))?;
+                }
+                Message::Notification(notification) => match server.notify(notification) {
+                    Ok(Some(notification)) => connection
+                        .sender
+                        .send(Message::Notification(notification))?,
+                    Ok(None) => {}
+                    // a bad notification shouldn't take the whole server down
+                    Err(e) => eprintln!("dots lsp: {:#}", e),
+                },
+                Message::Response(_) => {}
+            }
+        }
+
+        drop(connection);
+        io_threads.join().context("Language server I/O failed")?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing, Commands};
+    use serde_json::json;
+
+    // a config next to a set with `nvim` and `git` in it, linking them into `root/home` (spelled
+    // out, since `~` is whoever's running the tests outside of `run`)
+    fn config(root: &Path) -> (Url, String) {
+        for app in ["nvim", "git"] {
+            fs::create_dir_all(root.join("set/home/.config").join(app)).unwrap();
+        }
+        let text = format!(
+            "[[dotfiles]]\npath = \"set\"\n\n[dotfiles.use.\"home/.config/<app>\"]\napp = \"*\"\ntarget = \"{}/<app>\"\n",
+            root.join("home/.config").display()
+        );
+        let file = root.join("dots.toml");
+        fs::write(&file, &text).unwrap();
+        (Url::from_file_path(file).unwrap(), text)
+    }
+
+    fn open(server: &mut Server, uri: &Url, text: &str) -> Vec<lsp_types::Diagnostic> {
+        let notification = Notification::new(
+            DidOpenTextDocument::METHOD.to_string(),
+            json!({
+                "textDocument": { "uri": uri, "languageId": "toml", "version": 1, "text": text }
+            }),
+        );
+        let published = server.notify(notification).unwrap().unwrap();
+        let params: PublishDiagnosticsParams = serde_json::from_value(published.params).unwrap();
+        params.diagnostics
+    }
+
+    fn hover(server: &Server, uri: &Url, line: u32) -> Option<String> {
+        let params = json!({
+            "textDocument": { "uri": uri },
+            "position": { "line": line, "character": 3 },
+        });
+        let hover = server
+            .hover(serde_json::from_value(params).unwrap())
+            .unwrap()?;
+        match hover.contents {
+            HoverContents::Markup(content) => Some(content.value),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn diagnostics_point_at_the_problem_and_clear_on_close() {
+        let root = tempfile::tempdir().unwrap();
+        let (uri, text) = config(root.path());
+        let dots = testing::dots(
+            Commands::Lsp(DotsLspArgs { stdio: true }),
+            root.path(),
+            [""; 0],
+        );
+        let mut server = Server {
+            dots: &dots,
+            docs: HashMap::new(),
+        };
+
+        assert!(open(&mut server, &uri, &text).is_empty());
+
+        // what's open is checked, not what's saved
+        let found = open(
+            &mut server,
+            &uri,
+            &format!("[dots]\nelevat = \"sudo\"\n\n{}", text),
+        );
+        assert_eq!(found.len(), 1);
+        assert!(
+            found[0].message.contains("unknown key `elevat`"),
+            "{}",
+            found[0].message
+        );
+        assert_eq!(found[0].range.start.line, 1);
+
+        let close = Notification::new(
+            DidCloseTextDocument::METHOD.to_string(),
+            json!({ "textDocument": { "uri": uri } }),
+        );
+        let published = server.notify(close).unwrap().unwrap();
+        let params: PublishDiagnosticsParams = serde_json::from_value(published.params).unwrap();
+        assert!(params.diagnostics.is_empty());
+        assert!(server.docs.is_empty());
+    }
+
+    #[test]
+    fn hovering_a_use_rule_lists_what_it_resolves_to() {
+        let root = tempfile::tempdir().unwrap();
+        let (uri, text) = config(root.path());
+        let dots = testing::dots(
+            Commands::Lsp(DotsLspArgs { stdio: true }),
+            root.path(),
+            [""; 0],
+        );
+        let mut server = Server {
+            dots: &dots,
+            docs: HashMap::new(),
+        };
+        open(&mut server, &uri, &text);
+
+        // the header, and a line inside its table
+        for line in [3, 5] {
+            let out = hover(&server, &uri, line).unwrap();
+            let home = root.path().join("home");
+            for app in ["nvim", "git"] {
+                let entry = format!(
+                    "- `{}` ← `home/.config/{}` (app = {})",
+                    home.join(".config").join(app).display(),
+                    app,
+                    app
+                );
+                assert!(out.contains(&entry), "{}", out);
+            }
+            assert!(!out.contains("As last saved"), "{}", out);
+        }
+        assert_eq!(hover(&server, &uri, 1), None);
+
+        // what's resolved is what's on disk, which an unsaved buffer may not match
+        open(&mut server, &uri, &format!("{}\n", text));
+        assert!(hover(&server, &uri, 3).unwrap().contains("As last saved"));
+    }
+}
@@ -0,0 +1,30 @@
+use clap::Parser;
+
+use dotscli::{exit_code, run, Args, DotsBuilder};
+
+#[derive(Parser)]
+#[command(
+    name = "dots",
+    version,
+    about = "Manage dotfiles as symlinks into source sets"
+)]
+struct Cli {
+    #[command(flatten)]
+    args: Args,
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    let result = DotsBuilder::default()
+        .args(&cli.args)
+        .build()
+        .map_err(anyhow::Error::from)
+        .and_then(run);
+
+    // the exit status says what stopped us (see `exit_code`), so scripts can tell them apart
+    if let Err(e) = &result {
+        eprintln!("Error: {:#}", e);
+    }
+    std::process::exit(exit_code(&result));
+}
@@ -0,0 +1,425 @@
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::privilege::needs_privilege;
+use crate::{Dots, DotsMvArgs};
+use shared::fs as sfs;
+
+// where a source sits in the structured layout (see DOTFILE_START_DIRS): `os/arch` + `home` +
+// `.config/nvim/init.lua`. the scope and base decide which machines (and whose home) get it
+#[derive(Debug, PartialEq, Eq)]
+struct Placement {
+    scope: PathBuf, // empty for everyone, otherwise `os/<id>`, `user/<name>`, `guix` or `nix`
+    base: String,   // `home` or `root`
+    rel: PathBuf,
+}
+
+impl Placement {
+    fn of(set_dir: &Path, path: &Path) -> Option<Self> {
+        let rel = path.strip_prefix(set_dir).ok()?;
+        let mut parts = rel.components().map(|c| match c {
+            Component::Normal(part) => part.to_string_lossy().to_string(),
+            _ => String::new(),
+        });
+
+        let mut scope = PathBuf::new();
+        let mut next = parts.next()?;
+        match next.as_str() {
+            "os" | "user" => {
+                scope.push(&next);
+                scope.push(parts.next()?);
+                next = parts.next()?;
+            }
+            "guix" | "nix" => {
+                scope.push(&next);
+                next = parts.next()?;
+            }
+            _ => {}
+        }
+        if next != "home" && next != "root" {
+            return None;
+        }
+
+        Some(Self {
+            scope,
+            base: next,
+            rel: parts.collect(),
+        })
+    }
+
+    fn describe(&self) -> String {
+        self.scope.join(&self.base).display().to_string()
+    }
+
+    fn same_machines(&self, other: &Self) -> bool {
+        self.scope == other.scope && self.base == other.base
+    }
+
+    // where `link` puts it
+    fn dest(&self, dots: &Dots, suffix: &Path) -> PathBuf {
+        let base = match self.base.as_str() {
+            "home" => dots.env.home.clone(),
+            _ => PathBuf::from("/"),
+        };
+        // joining an empty suffix would leave a trailing `/` (moving a file has none)
+        let rel = if suffix.as_os_str().is_empty() {
+            self.rel.clone()
+        } else {
+            self.rel.join(suffix)
+        };
+        if rel.as_os_str().is_empty() {
+            base
+        } else {
+            base.join(rel)
+        }
+    }
+}
+
+struct Retarget {
+    dest: PathBuf,
+    source: PathBuf,
+    new_dest: PathBuf,
+    new_source: PathBuf,
+    is_ours: bool, // our link is still at `dest`; otherwise only the entry moves
+}
+
+// points the link at `link` to `target` by renaming a fresh link over it, so there's never a
+// moment with nothing (or a dangling link) there
+pub(crate) fn swap_link(target: &Path, link: &Path) -> Result<()> {
+    let name = link
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid destination {}", link.display()))?;
+    let tmp = link.with_file_name(format!(".{}.dots-mv", name.to_string_lossy()));
+
+    sfs::create_symlink(target, &tmp)?;
+    if let Err(e) = fs::rename(&tmp, link) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e).with_context(|| {
+            format!(
+                "Failed to replace {} with {}",
+                link.display(),
+                tmp.display()
+            )
+        });
+    }
+    Ok(())
+}
+
+impl Dots {
+    pub fn mv(&mut self, args: &DotsMvArgs) -> Result<()> {
+        let set_dir = match args.set.as_ref() {
+            Some(set) => self.absolute_path(set)?,
+            None => self
+                .args
+                .dotfiles
+                .as_ref()
+                .map(|d| self.absolute_path(Path::new(d)))
+                .ok_or_else(|| anyhow!("No source set given (use --set <dir>)"))??,
+        };
+        if !sfs::is_dir(&set_dir) {
+            return Err(anyhow!(
+                "Source set {} is not a directory",
+                set_dir.display()
+            ));
+        }
+
+        let from = self.absolute_path(&args.from)?;
+        let mut to = self.absolute_path(&args.to)?;
+        // like mv(1): moving onto a directory moves into it
+        if sfs::is_dir(&to) && !sfs::is_symlink(&to) {
+            let name = from
+                .file_name()
+                .ok_or_else(|| anyhow!("Can't move {}", from.display()))?;
+            to = to.join(name);
+        }
+
+        if !sfs::path_exists(&from) {
+            return Err(anyhow!("{} doesn't exist", from.display()));
+        }
+        if sfs::path_exists(&to) {
+            return Err(anyhow!("{} already exists", to.display()));
+        }
+        for path in [&from, &to] {
+            if !path.starts_with(&set_dir) || path == &set_dir {
+                return Err(anyhow!(
+                    "{} isn't inside the source set {}",
+                    path.display(),
+                    set_dir.display()
+                ));
+            }
+        }
+        if to.starts_with(&from) {
+            return Err(anyhow!("Can't move {} into itself", from.display()));
+        }
+
+        let from_placement = Placement::of(&set_dir, &from);
+        let to_placement = Placement::of(&set_dir, &to);
+
+        // moving between e.g. `home/` and `os/linux/home/` silently adds or drops the file on
+        // other machines the next time they link
+        let same_machines = match (&from_placement, &to_placement) {
+            (Some(from), Some(to)) => from.same_machines(to),
+            (None, None) => true,
+            _ => false,
+        };
+        if !same_machines && !args.force {
+            let describe = |p: &Option<Placement>| {
+                p.as_ref()
+                    .map(Placement::describe)
+                    .unwrap_or_else(|| "outside the layout".to_string())
+            };
+            return Err(anyhow!(
+                "Moving from `{}` to `{}` changes which machines get it (use --force)",
+                describe(&from_placement),
+                describe(&to_placement)
+            ));
+        }
+
+        if self
+            .system_state
+            .iter()
+            .any(|(_, source)| source.starts_with(&from))
+        {
+            return Err(anyhow!(
+                "{} is linked through the system trackfile, which `mv` doesn't touch",
+                from.display()
+            ));
+        }
+
+        let mut plan = vec![];
+        for (dest, source) in self.state.iter() {
+            let Ok(suffix) = source.strip_prefix(&from) else {
+                continue;
+            };
+            let new_source = if suffix.as_os_str().is_empty() {
+                to.clone()
+            } else {
+                to.join(suffix)
+            };
+
+            // the destination follows the source only if it's where the layout put it; anything
+            // mapped elsewhere by a `use` rule stays where it is. if our link isn't even there
+            // anymore, only the entry's source changes
+            let is_ours = sfs::is_symlink(dest) && sfs::read_link(dest)? == *source;
+            let new_dest = match (&from_placement, &to_placement) {
+                (Some(from), Some(to)) if is_ours && &from.dest(self, suffix) == dest => {
+                    to.dest(self, suffix)
+                }
+                _ => dest.clone(),
+            };
+
+            plan.push(Retarget {
+                is_ours,
+                dest: dest.clone(),
+                source: source.clone(),
+                new_dest,
+                new_source,
+            });
+        }
+
+        for entry in plan.iter().filter(|e| e.new_dest != e.dest) {
+            if needs_privilege(&entry.new_dest) {
+                return Err(anyhow!(
+                    "{} needs elevated privileges to link, which `mv` doesn't do",
+                    entry.new_dest.display()
+                ));
+            }
+            if sfs::path_exists(&entry.new_dest) || sfs::is_symlink(&entry.new_dest) {
+                return Err(anyhow!(
+                    "{} is already in the way of {}",
+                    entry.new_dest.display(),
+                    entry.new_source.display()
+                ));
+            }
+        }
+
+        if args.dry_run {
+            println!(
+                "[ DRY RUN --- Move ] {} -> {}",
+                from.display(),
+                to.display()
+            );
+            for entry in plan.iter() {
+                println!(
+                    "[ DRY RUN --- Relink ] {} -> {}",
+                    entry.new_dest.display(),
+                    entry.new_source.display()
+                );
+            }
+            return Ok(());
+        }
+
+        if let Some(parent) = to.parent() {
+            for dir in sfs::create_dir_all_missing(parent)? {
+                sfs::chown(&dir, self.env.owner)?;
+            }
+        }
+        // same source set, so same filesystem: a plain rename, all or nothing
+        fs::rename(&from, &to)
+            .with_context(|| format!("Failed to move {} to {}", from.display(), to.display()))?;
+
+        let mut done = vec![];
+        for entry in plan.iter().filter(|e| e.is_ours) {
+            match self.retarget(entry) {
+                Ok(()) => done.push(entry),
+                Err(e) => {
+                    // put everything back the way it was before reporting
+                    for entry in done.into_iter().rev() {
+                        if let Err(e) = self.undo_retarget(entry) {
+                            eprintln!("Failed to restore {}: {:#}", entry.dest.display(), e);
+                        }
+                    }
+                    if let Err(e) = fs::rename(&to, &from) {
+                        eprintln!(
+                            "Failed to move {} back to {}: {}",
+                            to.display(),
+                            from.display(),
+                            e
+                        );
+                    }
+                    return Err(e).with_context(|| {
+                        format!(
+                            "Failed to relink {}, nothing was moved",
+                            entry.dest.display()
+                        )
+                    });
+                }
+            }
+        }
+
+        println!("[ MOVE ] {} -> {}", from.display(), to.display());
+        for entry in plan.into_iter() {
+            if args.verbose {
+                println!(
+                    "Relinked {} -> {}",
+                    entry.new_dest.display(),
+                    entry.new_source.display()
+                );
+            }
+            self.state.remove(&entry.dest);
+            self.state.insert(entry.new_dest, entry.new_source);
+        }
+
+        Ok(())
+    }
+
+    fn retarget(&self, entry: &Retarget) -> Result<()> {
+        if entry.new_dest == entry.dest {
+            return swap_link(&entry.new_source, &entry.dest);
+        }
+
+        if let Some(parent) = entry.new_dest.parent() {
+            for dir in sfs::create_dir_all_missing(parent)? {
+                sfs::chown(&dir, self.env.owner)?;
+            }
+        }
+        sfs::create_symlink(&entry.new_source, &entry.new_dest)?;
+        // half done isn't something undo_retarget knows about
+        let result =
+            sfs::chown(&entry.new_dest, self.env.owner).and_then(|_| sfs::remove_file(&entry.dest));
+        if result.is_err() {
+            let _ = sfs::remove_file(&entry.new_dest);
+        }
+        result
+    }
+
+    fn undo_retarget(&self, entry: &Retarget) -> Result<()> {
+        if entry.new_dest == entry.dest {
+            return swap_link(&entry.source, &entry.dest);
+        }
+
+        sfs::create_symlink(&entry.source, &entry.dest)?;
+        sfs::chown(&entry.dest, self.env.owner)?;
+        sfs::remove_file(&entry.new_dest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing, Commands};
+
+    fn mv_args(from: &Path, to: &Path, set: &Path, force: bool) -> DotsMvArgs {
+        DotsMvArgs {
+            from: from.to_path_buf(),
+            to: to.to_path_buf(),
+            set: Some(set.to_path_buf()),
+            force,
+            dry_run: false,
+            verbose: false,
+        }
+    }
+
+    // `set/home/.vimrc` linked at `home/.vimrc`, and again at `home/elsewhere` (as a `use` rule
+    // might map it)
+    fn linked(root: &Path) -> (Dots, PathBuf, PathBuf) {
+        let set = root.join("set");
+        let home = root.join("home");
+        let source = set.join("home/.vimrc");
+        fs::create_dir_all(source.parent().unwrap()).unwrap();
+        fs::create_dir_all(&home).unwrap();
+        fs::write(&source, "set nu").unwrap();
+
+        let args = mv_args(&source, &source, &set, false);
+        let mut dots = testing::dots(Commands::Mv(args), root, [""; 0]);
+        for dest in [home.join(".vimrc"), home.join("elsewhere")] {
+            sfs::create_symlink(&source, &dest).unwrap();
+            dots.state.insert(dest, source.clone());
+        }
+        (dots, set, home)
+    }
+
+    #[test]
+    fn placements_follow_the_layout() {
+        let set = Path::new("/dots");
+        let placement = Placement::of(set, &set.join("os/arch/home/.config/nvim")).unwrap();
+        assert_eq!(placement.scope, Path::new("os/arch"));
+        assert_eq!(placement.base, "home");
+        assert_eq!(placement.rel, Path::new(".config/nvim"));
+
+        let guix = Placement::of(set, &set.join("guix/root/etc/hosts")).unwrap();
+        assert_eq!(guix.describe(), "guix/root");
+        assert!(!guix.same_machines(&placement));
+
+        assert_eq!(Placement::of(set, &set.join("notes/todo")), None);
+        assert_eq!(Placement::of(set, &set.join("user/alice")), None);
+        assert_eq!(Placement::of(set, Path::new("/elsewhere/home/x")), None);
+    }
+
+    #[test]
+    fn links_the_layout_put_there_move_along_and_the_rest_only_repoint() {
+        let root = tempfile::tempdir().unwrap();
+        let (mut dots, set, home) = linked(root.path());
+        let to = set.join("home/.vim/vimrc");
+
+        dots.mv(&mv_args(&set.join("home/.vimrc"), &to, &set, false))
+            .unwrap();
+
+        assert_eq!(fs::read_to_string(&to).unwrap(), "set nu");
+        assert!(!sfs::path_exists(&home.join(".vimrc")));
+        assert_eq!(sfs::read_link(&home.join(".vim/vimrc")).unwrap(), to);
+        assert_eq!(sfs::read_link(&home.join("elsewhere")).unwrap(), to);
+        assert_eq!(dots.state.get_source(&home.join(".vim/vimrc")), Some(&to));
+        assert_eq!(dots.state.get_source(&home.join("elsewhere")), Some(&to));
+        assert!(!dots.state.contains_dest(&home.join(".vimrc")));
+    }
+
+    #[test]
+    fn changing_which_machines_get_it_takes_force() {
+        let root = tempfile::tempdir().unwrap();
+        let (mut dots, set, home) = linked(root.path());
+        let from = set.join("home/.vimrc");
+        let to = set.join("os/linux/home/.vimrc");
+
+        let e = dots.mv(&mv_args(&from, &to, &set, false)).unwrap_err();
+        assert!(e.to_string().contains("changes which machines"), "{}", e);
+        assert!(sfs::path_exists(&from));
+
+        // on this machine it's still linked in the same place
+        dots.mv(&mv_args(&from, &to, &set, true)).unwrap();
+        assert_eq!(sfs::read_link(&home.join(".vimrc")).unwrap(), to);
+        assert_eq!(dots.state.get_source(&home.join(".vimrc")), Some(&to));
+    }
+}
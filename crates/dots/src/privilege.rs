@@ -0,0 +1,487 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use nix::unistd::{access, AccessFlags, Uid};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::trackfile::*;
+use crate::DotsHelperArgs;
+use shared::bos;
+use shared::fs as sfs;
+
+// root-owned entries are tracked separately so the user trackfile never has to be written as root
+// (and so a `dots unlink --hard` as the user can't forget about links it can't remove anyways)
+pub const SYSTEM_TRACKFILE: &str = "/var/lib/bos/dots/trackfile.toml";
+
+#[derive(
+    ValueEnum, Deserialize, Serialize, JsonSchema, Clone, Copy, Debug, Default, PartialEq, Eq,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Elevation {
+    #[default]
+    Sudo,
+    Doas,
+    Run0,
+}
+
+impl Elevation {
+    pub fn program(&self) -> &'static str {
+        match self {
+            Elevation::Sudo => "sudo",
+            Elevation::Doas => "doas",
+            Elevation::Run0 => "run0",
+        }
+    }
+}
+
+impl fmt::Display for Elevation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.program())
+    }
+}
+
+// the link itself gets created (or removed) inside its parent, so that's the directory we need
+// write access to. if the parent doesn't exist yet, whichever ancestor we'd start creating
+// directories in decides it instead
+pub fn needs_privilege(dest: &Path) -> bool {
+    if Uid::effective().is_root() {
+        return false;
+    }
+
+    let mut dir = dest.parent();
+    while let Some(d) = dir {
+        if sfs::path_exists(d) {
+            return access(d, AccessFlags::W_OK | AccessFlags::X_OK).is_err();
+        }
+        dir = d.parent();
+    }
+
+    false
+}
+
+// as the user, needs_privilege already decides what goes through the helper. as root under
+// sudo/doas everything is writable, so anything outside the user's home goes there instead; that's
+// what keeps /etc out of the user's trackfile (and out of their hands)
+pub fn is_system_dest(dest: &Path, home: &Path) -> bool {
+    outside_home(bos::Env::invoking_user().as_deref(), dest, home)
+}
+
+fn outside_home(invoked_by: Option<&str>, dest: &Path, home: &Path) -> bool {
+    invoked_by.is_some() && !dest.starts_with(home)
+}
+
+// preflight: split a generated plan into what we can do ourselves and what has to go through the
+// helper
+pub fn partition(targets: &Trackfile, home: &Path) -> (Trackfile, Trackfile) {
+    let mut user = Trackfile::default();
+    let mut privileged = Trackfile::default();
+
+    for (dest, source) in targets.iter() {
+        if is_system_dest(dest, home) || needs_privilege(dest) {
+            privileged.insert(dest.clone(), source.clone());
+        } else {
+            user.insert(dest.clone(), source.clone());
+        }
+    }
+
+    (user, privileged)
+}
+
+// only already-decided operations are ever handed to the helper; every conflict check, prompt and
+// force flag is resolved by the unprivileged process beforehand
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum HelperAction {
+    Link {
+        dest: PathBuf,
+        source: PathBuf,
+        remove_existing: bool,
+        #[serde(default)]
+        backup: bool,
+    },
+    Unlink {
+        dest: PathBuf,
+        #[serde(default)]
+        backup: bool,
+    },
+}
+
+impl HelperAction {
+    pub fn dest(&self) -> &Path {
+        match self {
+            HelperAction::Link { dest, .. } => dest,
+            HelperAction::Unlink { dest, .. } => dest,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct HelperBatch {
+    pub actions: Vec<HelperAction>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HelperResult {
+    pub dest: PathBuf,
+    pub removed_symlink: bool,
+    pub removed_file: bool,
+    #[serde(default)]
+    pub backed_up: Option<PathBuf>,
+    #[serde(default)]
+    pub dirs_created: Vec<PathBuf>,
+    #[serde(default)]
+    pub dirs_removed: Vec<PathBuf>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct HelperReport {
+    pub results: Vec<HelperResult>,
+}
+
+// figure out how to re-invoke ourselves; `bos dots ...` needs the extra subcommand, `dots ...`
+// doesn't
+fn helper_invocation() -> Result<Vec<String>> {
+    let exe = std::env::current_exe().context("Failed to locate the current executable")?;
+    let mut argv = vec![exe.to_string_lossy().to_string()];
+
+    if exe.file_stem().is_some_and(|stem| stem != "dots") {
+        argv.push("dots".to_string());
+    }
+    argv.push("helper".to_string());
+
+    Ok(argv)
+}
+
+pub fn run_batch(elevation: Elevation, batch: &HelperBatch) -> Result<HelperReport> {
+    // already root (e.g., `sudo dots link`): nothing to elevate
+    if Uid::effective().is_root() {
+        return apply_batch(batch, Path::new(SYSTEM_TRACKFILE), bos::Env::detect());
+    }
+
+    let payload = toml::to_string(batch).context("Failed to serialize privileged batch")?;
+
+    let mut child = Command::new(elevation.program())
+        .args(helper_invocation()?)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to spawn privileged helper via {}", elevation))?;
+
+    child
+        .stdin
+        .take()
+        .ok_or_else(|| anyhow!("Failed to open helper stdin"))?
+        .write_all(payload.as_bytes())
+        .context("Failed to send batch to privileged helper")?;
+
+    let output = child
+        .wait_with_output()
+        .context("Failed to wait for privileged helper")?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Privileged helper exited with {} (was {} denied?)",
+            output.status,
+            elevation
+        ));
+    }
+
+    let report = String::from_utf8(output.stdout).context("Helper output was not valid UTF-8")?;
+    toml::from_str(&report).context("Failed to parse privileged helper report")
+}
+
+fn perform(action: &HelperAction, state: &mut Trackfile) -> Result<HelperResult> {
+    let mut result = HelperResult {
+        dest: action.dest().to_path_buf(),
+        removed_symlink: false,
+        removed_file: false,
+        backed_up: None,
+        dirs_created: vec![],
+        dirs_removed: vec![],
+        error: None,
+    };
+
+    match action {
+        HelperAction::Link {
+            dest,
+            source,
+            remove_existing,
+            backup,
+        } => {
+            if *remove_existing && sfs::path_exists(dest) {
+                result.removed_symlink = sfs::is_symlink(dest);
+                result.removed_file = !result.removed_symlink;
+                if *backup {
+                    result.backed_up = Some(sfs::backup(dest)?);
+                } else {
+                    sfs::remove_file(dest)?;
+                }
+            }
+
+            if let Some(parent) = dest.parent() {
+                for dir in sfs::create_dir_all_missing(parent)? {
+                    state.insert_dir(dir.clone());
+                    result.dirs_created.push(dir);
+                }
+            }
+            sfs::create_symlink(source, dest)?;
+
+            state.insert(dest.clone(), source.clone());
+        }
+        HelperAction::Unlink { dest, backup } => {
+            // already gone (e.g. removed by hand since the plan was made): just forget it
+            if sfs::path_exists(dest) {
+                result.removed_symlink = sfs::is_symlink(dest);
+                result.removed_file = !result.removed_symlink;
+                if *backup {
+                    result.backed_up = Some(sfs::backup(dest)?);
+                } else {
+                    sfs::remove_file(dest)?;
+                }
+            }
+
+            state.remove(dest);
+            if let Some(parent) = dest.parent() {
+                result.dirs_removed = state.prune_dirs(parent, false)?;
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+// as root: apply a batch and record it in the trackfile at `trackfile_path` (the system one,
+// outside of tests)
+fn apply_batch(batch: &HelperBatch, trackfile_path: &Path, env: bos::Env) -> Result<HelperReport> {
+    let mut state =
+        Trackfile::load(trackfile_path, &env).context("Failed to load system trackfile state")?;
+
+    let mut report = HelperReport::default();
+    for action in batch.actions.iter() {
+        let result = match perform(action, &mut state) {
+            Ok(result) => result,
+            Err(e) => HelperResult {
+                dest: action.dest().to_path_buf(),
+                removed_symlink: false,
+                removed_file: false,
+                backed_up: None,
+                dirs_created: vec![],
+                dirs_removed: vec![],
+                error: Some(format!("{:#}", e)),
+            },
+        };
+        report.results.push(result);
+    }
+
+    state
+        .save(trackfile_path, env)
+        .context("Failed to save system trackfile state")?;
+
+    Ok(report)
+}
+
+// the minimal mode we get re-executed in under sudo/doas/run0: read a batch from stdin, apply it,
+// record it in the system trackfile and report back on stdout. deliberately doesn't touch config
+// or the user's trackfile; the only env detection is what loading the system trackfile takes
+pub fn run_helper(_args: &DotsHelperArgs) -> Result<()> {
+    if !Uid::effective().is_root() {
+        return Err(anyhow!("The dots helper must be run as root"));
+    }
+
+    let mut input = String::new();
+    io::stdin()
+        .read_to_string(&mut input)
+        .context("Failed to read privileged batch")?;
+    let batch: HelperBatch = toml::from_str(&input).context("Failed to parse privileged batch")?;
+
+    let report = apply_batch(&batch, Path::new(SYSTEM_TRACKFILE), bos::Env::detect())?;
+
+    let output = toml::to_string(&report).context("Failed to serialize helper report")?;
+    io::stdout().write_all(output.as_bytes())?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::fs;
+
+    fn link(dest: &Path, source: &Path, remove_existing: bool, backup: bool) -> HelperAction {
+        HelperAction::Link {
+            dest: dest.to_path_buf(),
+            source: source.to_path_buf(),
+            remove_existing,
+            backup,
+        }
+    }
+
+    #[test]
+    fn under_sudo_only_the_invoking_users_home_stays_theirs() {
+        let home = Path::new("/home/alice");
+        assert!(outside_home(Some("alice"), Path::new("/etc/hosts"), home));
+        assert!(!outside_home(Some("alice"), &home.join(".gitconfig"), home));
+        // as the user themselves, needs_privilege decides instead
+        assert!(!outside_home(None, Path::new("/etc/hosts"), home));
+    }
+
+    #[test]
+    fn link_creates_its_dirs_and_unlink_prunes_them() {
+        let root = tempfile::tempdir().unwrap();
+        let source = root.path().join("conf");
+        fs::write(&source, "").unwrap();
+        let dest = root.path().join("etc/app/conf");
+        let mut state = Trackfile::default();
+
+        let linked = perform(&link(&dest, &source, false, false), &mut state).unwrap();
+        assert_eq!(sfs::read_link(&dest).unwrap(), source);
+        assert_eq!(
+            linked.dirs_created,
+            [root.path().join("etc"), root.path().join("etc/app")]
+        );
+        assert!(state.contains_dest(&dest));
+
+        let unlink = HelperAction::Unlink {
+            dest: dest.clone(),
+            backup: false,
+        };
+        let unlinked = perform(&unlink, &mut state).unwrap();
+        assert!(unlinked.removed_symlink && !unlinked.removed_file);
+        assert!(!sfs::path_exists(&root.path().join("etc")));
+        assert_eq!(unlinked.dirs_removed.len(), 2);
+        assert_eq!(state.len(), 0);
+        assert_eq!(state.dirs().count(), 0);
+    }
+
+    #[test]
+    fn whats_in_the_way_is_backed_up_when_asked() {
+        let root = tempfile::tempdir().unwrap();
+        let source = root.path().join("conf");
+        fs::write(&source, "ours").unwrap();
+        let dest = root.path().join("target");
+        fs::write(&dest, "theirs").unwrap();
+        let mut state = Trackfile::default();
+
+        let linked = perform(&link(&dest, &source, true, true), &mut state).unwrap();
+        let backup = root.path().join("target.bak");
+        assert!(linked.removed_file && !linked.removed_symlink);
+        assert_eq!(linked.backed_up.as_deref(), Some(backup.as_path()));
+        assert_eq!(fs::read_to_string(&backup).unwrap(), "theirs");
+        assert_eq!(sfs::read_link(&dest).unwrap(), source);
+
+        let unlink = HelperAction::Unlink {
+            dest: dest.clone(),
+            backup: true,
+        };
+        let unlinked = perform(&unlink, &mut state).unwrap();
+        let second = root.path().join("target.bak.1");
+        assert_eq!(unlinked.backed_up.as_deref(), Some(second.as_path()));
+        assert_eq!(sfs::read_link(&second).unwrap(), source);
+        assert!(!sfs::path_exists(&dest));
+    }
+
+    #[test]
+    fn unlinking_something_already_gone_just_forgets_it() {
+        let root = tempfile::tempdir().unwrap();
+        let dest = root.path().join("gone");
+        let mut state = Trackfile::default();
+        state.insert(dest.clone(), root.path().join("conf"));
+
+        let unlink = HelperAction::Unlink {
+            dest: dest.clone(),
+            backup: false,
+        };
+        let result = perform(&unlink, &mut state).unwrap();
+        assert!(!result.removed_symlink && !result.removed_file);
+        assert!(!state.contains_dest(&dest));
+    }
+
+    #[test]
+    fn a_batch_is_recorded_in_the_given_trackfile_and_failures_reported() {
+        let root = tempfile::tempdir().unwrap();
+        let source = root.path().join("conf");
+        fs::write(&source, "").unwrap();
+        let taken = root.path().join("taken");
+        fs::write(&taken, "").unwrap();
+        let dest = root.path().join("etc/conf");
+        let trackfile = root.path().join("system/trackfile.toml");
+
+        let batch = HelperBatch {
+            actions: vec![
+                link(&dest, &source, false, false),
+                // nothing said to remove what's there, so this one fails
+                link(&taken, &source, false, false),
+            ],
+        };
+        let report = apply_batch(&batch, &trackfile, testing::env(root.path())).unwrap();
+
+        assert_eq!(report.results.len(), 2);
+        assert_eq!(report.results[0].error, None);
+        assert_eq!(report.results[1].dest, taken);
+        assert!(report.results[1].error.is_some());
+
+        let state = Trackfile::load(&trackfile, &testing::env(root.path())).unwrap();
+        assert!(state.contains_dest(&dest));
+        assert!(!state.contains_dest(&taken));
+        assert_eq!(state.dirs().collect::<Vec<_>>(), [&root.path().join("etc")]);
+    }
+
+    #[test]
+    fn batches_and_reports_survive_the_trip_through_toml() {
+        let batch = HelperBatch {
+            actions: vec![
+                link(Path::new("/etc/a"), Path::new("/dots/a"), true, true),
+                HelperAction::Unlink {
+                    dest: PathBuf::from("/etc/b"),
+                    backup: false,
+                },
+            ],
+        };
+        let sent = toml::to_string(&batch).unwrap();
+        let received: HelperBatch = toml::from_str(&sent).unwrap();
+        assert!(matches!(
+            &received.actions[0],
+            HelperAction::Link { dest, source, remove_existing: true, backup: true }
+                if dest == Path::new("/etc/a") && source == Path::new("/dots/a")
+        ));
+        assert!(matches!(
+            &received.actions[1],
+            HelperAction::Unlink { dest, backup: false } if dest == Path::new("/etc/b")
+        ));
+
+        // backup is optional on the wire
+        let older: HelperBatch =
+            toml::from_str("[[actions]]\nop = \"unlink\"\ndest = \"/etc/b\"\n").unwrap();
+        assert!(matches!(
+            &older.actions[0],
+            HelperAction::Unlink { backup: false, .. }
+        ));
+
+        let report = HelperReport {
+            results: vec![HelperResult {
+                dest: PathBuf::from("/etc/a"),
+                removed_symlink: false,
+                removed_file: true,
+                backed_up: Some(PathBuf::from("/etc/a.bak")),
+                dirs_created: vec![PathBuf::from("/etc")],
+                dirs_removed: vec![],
+                error: Some("denied".to_string()),
+            }],
+        };
+        let sent = toml::to_string(&report).unwrap();
+        let received: HelperReport = toml::from_str(&sent).unwrap();
+        let result = &received.results[0];
+        assert_eq!(result.dest, PathBuf::from("/etc/a"));
+        assert!(result.removed_file && !result.removed_symlink);
+        assert_eq!(result.backed_up, Some(PathBuf::from("/etc/a.bak")));
+        assert_eq!(result.dirs_created, [PathBuf::from("/etc")]);
+        assert_eq!(result.error.as_deref(), Some("denied"));
+    }
+}
@@ -0,0 +1,248 @@
+use std::collections::VecDeque;
+use std::io::{self, BufRead, IsTerminal, Write};
+
+use crate::handlers::Reason;
+use crate::stats;
+
+pub enum Choice {
+    Yes { all: bool },
+    No { all: bool },
+    Quit,
+    Info,
+    Diff,
+    Show,
+    Absorb,
+    SkipDir,
+    Trust,
+}
+
+#[derive(Clone, Copy)]
+pub enum Opts {
+    YesNo,
+    YesNoAll,
+    All,
+    File,  // a regular file is in the way of a link
+    Trust, // an untrusted config wants to run shell commands
+}
+
+impl Opts {
+    // whatever's in brackets is what `process` takes for it (as is the choice spelled out without
+    // spaces, e.g., `skipdir`)
+    pub fn get(&self) -> &'static str {
+        match self {
+            Opts::YesNo => "[Y]es/[N]o",
+            Opts::YesNoAll => "[Y]es/[N]o/[YA] yes to all/[NA] no to all",
+            Opts::All => "[Y]es/[N]o/[YA] yes to all/[NA] no to all/[I]nfo/[Q]uit",
+            Opts::File => {
+                "[Y]es/[N]o/[YA] yes to all/[NA] no to all/[D]iff/[S]how/[A]bsorb/[SD] skip dir/[I]nfo/[Q]uit"
+            }
+            Opts::Trust => "[Y]es/[N]o/[T]rust its directory from now on",
+        }
+    }
+
+    pub fn process(&self, input: &str) -> Option<Choice> {
+        match self {
+            Opts::YesNo => match input {
+                "y" | "yes" => Some(Choice::Yes { all: false }),
+                "n" | "no" => Some(Choice::No { all: false }),
+                _ => None,
+            },
+            Opts::YesNoAll => Opts::YesNo.process(input).or_else(|| match input {
+                "ya" | "yesall" => Some(Choice::Yes { all: true }),
+                "na" | "noall" => Some(Choice::No { all: true }),
+                _ => None,
+            }),
+            Opts::All => Opts::YesNoAll.process(input).or_else(|| match input {
+                "i" | "info" => Some(Choice::Info),
+                "q" | "quit" | "cancel" => Some(Choice::Quit),
+                _ => None,
+            }),
+            Opts::File => Opts::All.process(input).or_else(|| match input {
+                "d" | "diff" => Some(Choice::Diff),
+                "s" | "show" => Some(Choice::Show),
+                "a" | "absorb" => Some(Choice::Absorb),
+                "sd" | "skipdir" => Some(Choice::SkipDir),
+                _ => None,
+            }),
+            Opts::Trust => Opts::YesNo.process(input).or_else(|| match input {
+                "t" | "trust" => Some(Choice::Trust),
+                _ => None,
+            }),
+        }
+    }
+}
+
+// where answers come from: a terminal, piped input, or a script (tests, provisioning)
+pub trait Prompter {
+    // None once there's nothing left to answer with
+    fn read_answer(&mut self, prompt: &str) -> Option<String>;
+    fn say(&mut self, message: &str);
+}
+
+// a line at a time; stdin is only locked for as long as it takes to read one, since a lock held
+// any longer keeps everything else (the privileged helper's input, the language server's
+// transport) waiting on it for good
+pub trait LineSource {
+    fn read_line(&mut self, buf: &mut String) -> io::Result<usize>;
+}
+
+impl LineSource for io::Stdin {
+    fn read_line(&mut self, buf: &mut String) -> io::Result<usize> {
+        self.lock().read_line(buf)
+    }
+}
+
+impl<R: io::Read> LineSource for io::BufReader<R> {
+    fn read_line(&mut self, buf: &mut String) -> io::Result<usize> {
+        BufRead::read_line(self, buf)
+    }
+}
+
+pub struct LinePrompter<R, W> {
+    input: R,
+    output: W,
+    echo: bool, // input isn't a terminal, so show what was answered
+}
+
+impl<R: LineSource, W: Write> LinePrompter<R, W> {
+    pub fn new(input: R, output: W, echo: bool) -> Self {
+        Self {
+            input,
+            output,
+            echo,
+        }
+    }
+}
+
+// stdout, unless that's reserved for a JSON report; decided on every write, since the prompter is
+// made before anyone knows the format
+pub struct Console;
+
+impl Write for Console {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if stats::json_output() {
+            io::stderr().write(buf)
+        } else {
+            io::stdout().write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()?;
+        io::stderr().flush()
+    }
+}
+
+impl LinePrompter<io::Stdin, Console> {
+    pub fn stdio() -> Self {
+        let echo = !io::stdin().is_terminal();
+        Self::new(io::stdin(), Console, echo)
+    }
+}
+
+impl<R: LineSource, W: Write> Prompter for LinePrompter<R, W> {
+    fn read_answer(&mut self, prompt: &str) -> Option<String> {
+        write!(self.output, "{}", prompt).ok()?;
+        self.output.flush().ok()?;
+
+        let mut input = String::new();
+        match self.input.read_line(&mut input) {
+            Ok(0) => None,
+            Ok(_) => {
+                if self.echo {
+                    let _ = writeln!(self.output, "{}", input.trim());
+                }
+                Some(input)
+            }
+            Err(e) => {
+                let _ = writeln!(self.output, "Error reading input: {}", e);
+                None
+            }
+        }
+    }
+
+    fn say(&mut self, message: &str) {
+        let _ = writeln!(self.output, "{}", message);
+    }
+}
+
+// answers handed out in order; everything asked and said is kept in `transcript`
+#[derive(Default)]
+pub struct ScriptedPrompter {
+    answers: VecDeque<String>,
+    pub transcript: Vec<String>,
+}
+
+impl ScriptedPrompter {
+    pub fn new<I, S>(answers: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            answers: answers.into_iter().map(Into::into).collect(),
+            transcript: vec![],
+        }
+    }
+}
+
+impl Prompter for ScriptedPrompter {
+    fn read_answer(&mut self, prompt: &str) -> Option<String> {
+        self.transcript.push(prompt.to_string());
+        let answer = self.answers.pop_front()?;
+        self.transcript.push(answer.clone());
+        Some(answer)
+    }
+
+    fn say(&mut self, message: &str) {
+        self.transcript.push(message.to_string());
+    }
+}
+
+pub fn prompt_user_choice(
+    prompter: &mut dyn Prompter,
+    prompt: String,
+    reason: &Reason,
+    opts: Opts,
+) -> Choice {
+    let choices_help = opts.get();
+    let full_prompt = format!("{}\n{}: ", prompt, choices_help);
+
+    loop {
+        // out of input (EOF, script ran out): don't guess
+        let Some(input) = prompter.read_answer(&full_prompt) else {
+            return Choice::Quit;
+        };
+
+        match opts.process(input.trim().to_lowercase().as_str()) {
+            Some(Choice::Info) => prompter.say(&reason.info()),
+            Some(choice) => return choice,
+            None => prompter.say(&format!(
+                "Invalid input. Please choose from {}.",
+                choices_help
+            )),
+        }
+    }
+}
+
+// asked until the answer is one of `opts`; None once there's nothing left to answer with
+pub fn ask(prompter: &mut dyn Prompter, prompt: String, opts: Opts) -> Option<Choice> {
+    let full_prompt = format!("{}\n{}: ", prompt, opts.get());
+
+    loop {
+        let input = prompter.read_answer(&full_prompt)?;
+
+        match opts.process(input.trim().to_lowercase().as_str()) {
+            Some(choice) => return Some(choice),
+            None => prompter.say(&format!(
+                "Invalid input. Please choose from {}.",
+                opts.get()
+            )),
+        }
+    }
+}
+
+// a plain yes/no; running out of input counts as no
+pub fn confirm(prompter: &mut dyn Prompter, prompt: String) -> bool {
+    matches!(ask(prompter, prompt, Opts::YesNo), Some(Choice::Yes { .. }))
+}
@@ -0,0 +1,317 @@
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
+
+use crate::privilege::needs_privilege;
+use crate::prompt::confirm;
+use crate::stats::OutputFormat;
+use crate::trackfile::*;
+use crate::{Dots, DotsRecoverArgs};
+use shared::fs as sfs;
+
+#[derive(Serialize)]
+struct Recovered {
+    dest: PathBuf,
+    source: PathBuf,
+    source_set: PathBuf,
+    planned: bool, // the current plan maps the dest to this source too
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    recovered: &'a [Recovered],
+    system: &'a [Recovered], // need the system trackfile, so only listed
+}
+
+// every symlink under `dir`, without following links or descending into `skip`
+fn find_links(dir: &Path, skip: &[PathBuf], links: &mut Vec<PathBuf>) {
+    // unreadable directories are just not ours to find anything in
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if sfs::is_symlink(&path) {
+            links.push(path);
+        } else if sfs::is_dir(&path) && !skip.iter().any(|s| path.starts_with(s)) {
+            find_links(&path, skip, links);
+        }
+    }
+}
+
+// `..` and `.` resolved without touching the filesystem, so a relative link's target can be
+// compared against the sets as written (following symlinks along the way could take it elsewhere)
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            other => normalized.push(other),
+        }
+    }
+    normalized
+}
+
+impl Dots {
+    // directories any of our links could point into: the sets the target resolves to, plus every
+    // repo we've cloned
+    fn known_source_sets(
+        &self,
+        plan: Option<&Trackfile>,
+        extra: &[PathBuf],
+    ) -> Result<Vec<PathBuf>> {
+        let mut sets: Vec<PathBuf> = vec![];
+        for set in extra.iter() {
+            sets.push(self.absolute_path(set)?);
+        }
+
+        if let Some(plan) = plan {
+            for (dest, _) in plan.iter() {
+                if let Some(set) = plan.source_set(dest) {
+                    sets.push(set.clone());
+                }
+            }
+        }
+
+        let repos = self.env.cache_dir.join("repos");
+        if let Ok(entries) = fs::read_dir(&repos) {
+            for entry in entries.flatten() {
+                if sfs::is_dir(&entry.path()) {
+                    sets.push(entry.path());
+                }
+            }
+        }
+
+        sets.sort();
+        sets.dedup();
+        Ok(sets)
+    }
+
+    pub fn recover(&mut self, args: &DotsRecoverArgs) -> Result<()> {
+        let target = args.target.clone().or_else(|| self.args.dotfiles.clone());
+        let plan = match target {
+            Some(target) => Some(
+                Trackfile::generate(target, &self.env, None, &mut *self.prompter)
+                    .context("Failed to resolve link targets")?,
+            ),
+            None => None,
+        };
+
+        let sets = self.known_source_sets(plan.as_ref(), &args.sets)?;
+        if sets.is_empty() {
+            return Err(anyhow!(
+                "No source sets to recover links into (use --target or --set)"
+            ));
+        }
+
+        let scan = if args.scan.is_empty() {
+            let mut scan = vec![self.env.home.clone()];
+            if args.etc {
+                scan.push(PathBuf::from("/etc"));
+            }
+            scan
+        } else {
+            args.scan
+                .iter()
+                .map(|dir| self.absolute_path(dir))
+                .collect::<Result<Vec<_>>>()?
+        };
+
+        // the sets themselves (and our cache) are full of links that aren't ours
+        let mut skip = sets.clone();
+        skip.push(self.env.cache_dir.clone());
+
+        let mut links = vec![];
+        for dir in scan.iter() {
+            if args.verbose {
+                say!("Scanning {}", dir.display());
+            }
+            find_links(dir, &skip, &mut links);
+        }
+
+        let mut recovered = vec![];
+        let mut system = vec![];
+        for dest in links.into_iter() {
+            if self.tracked_source(&dest).is_some() {
+                continue;
+            }
+            let Ok(points_to) = sfs::read_link(&dest) else {
+                continue;
+            };
+            let source = match dest.parent() {
+                Some(parent) if points_to.is_relative() => normalize(&parent.join(points_to)),
+                _ => normalize(&points_to),
+            };
+
+            // the innermost set wins, same as it would have when linking
+            let Some(source_set) = sets
+                .iter()
+                .filter(|set| source.starts_with(set))
+                .max_by_key(|set| set.components().count())
+            else {
+                continue;
+            };
+
+            let entry = Recovered {
+                planned: plan
+                    .as_ref()
+                    .and_then(|p| p.get_source(&dest))
+                    .is_some_and(|s| *s == source),
+                source_set: source_set.clone(),
+                dest,
+                source,
+            };
+
+            // those live in the system trackfile, which only the privileged helper writes
+            if needs_privilege(&entry.dest) {
+                system.push(entry);
+            } else {
+                recovered.push(entry);
+            }
+        }
+
+        if recovered.is_empty() && system.is_empty() {
+            say!("No untracked links into known source sets found.");
+            return Ok(());
+        }
+
+        match self.args.format {
+            OutputFormat::Text => {
+                for entry in recovered.iter() {
+                    let planned = if entry.planned { "" } else { " (not planned)" };
+                    println!(
+                        "[ RECOVER ] {} -> {} (from {}){}",
+                        entry.dest.display(),
+                        entry.source.display(),
+                        entry.source_set.display(),
+                        planned
+                    );
+                }
+                for entry in system.iter() {
+                    println!(
+                        "[ SYSTEM ] {} -> {} (from {})",
+                        entry.dest.display(),
+                        entry.source.display(),
+                        entry.source_set.display()
+                    );
+                }
+            }
+            OutputFormat::Json => {
+                let report = Report {
+                    recovered: &recovered,
+                    system: &system,
+                };
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&report)
+                        .context("Failed to serialize recovered links")?
+                );
+            }
+        }
+
+        if !system.is_empty() {
+            say!(
+                "{} link(s) need elevated privileges and belong in the system trackfile; relink their source sets to track them again",
+                system.len()
+            );
+        }
+
+        if args.dry_run || recovered.is_empty() {
+            return Ok(());
+        }
+
+        if !args.yes
+            && !confirm(
+                &mut *self.prompter,
+                format!("Track these {} link(s) again?", recovered.len()),
+            )
+        {
+            say!("Nothing recovered");
+            return Ok(());
+        }
+
+        let count = recovered.len();
+        for entry in recovered.into_iter() {
+            self.state
+                .insert_from_set(entry.dest, entry.source, entry.source_set);
+        }
+        say!("Recovered {} link(s)", count);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing, Commands};
+
+    fn recover_args(home: &Path, sets: &[PathBuf]) -> DotsRecoverArgs {
+        DotsRecoverArgs {
+            scan: vec![home.to_path_buf()],
+            etc: false,
+            target: None,
+            sets: sets.to_vec(),
+            yes: false,
+            dry_run: false,
+            verbose: false,
+        }
+    }
+
+    #[test]
+    fn dots_are_resolved_without_touching_the_filesystem() {
+        assert_eq!(
+            normalize(Path::new("/home/a/.config/../dots/./home/.vimrc")),
+            PathBuf::from("/home/a/dots/home/.vimrc")
+        );
+        assert_eq!(normalize(Path::new("/../etc")), PathBuf::from("/etc"));
+    }
+
+    #[test]
+    fn untracked_links_into_a_set_are_tracked_again_once_confirmed() {
+        let root = tempfile::tempdir().unwrap();
+        let home = root.path().join("home");
+        let set = root.path().join("dots");
+        let inner = set.join("nested");
+        fs::create_dir_all(home.join(".config")).unwrap();
+        fs::create_dir_all(&inner).unwrap();
+        for name in ["vimrc", "nested/init.lua", "tracked"] {
+            fs::write(set.join(name), "").unwrap();
+        }
+
+        let vimrc = home.join(".vimrc");
+        let init = home.join(".config/init.lua");
+        sfs::create_symlink(&set.join("vimrc"), &vimrc).unwrap();
+        // relative to the directory the link is in
+        sfs::create_symlink(Path::new("../../dots/./nested/init.lua"), &init).unwrap();
+        sfs::create_symlink(&set.join("tracked"), &home.join(".tracked")).unwrap();
+        sfs::create_symlink(Path::new("/etc/hosts"), &home.join(".hosts")).unwrap();
+
+        let sets = [set.clone(), inner.clone()];
+        for (answer, recovered) in [("n", 1), ("y", 3)] {
+            let args = recover_args(&home, &sets);
+            let mut dots = testing::dots(
+                Commands::Recover(recover_args(&home, &sets)),
+                root.path(),
+                [answer],
+            );
+            dots.state
+                .insert(home.join(".tracked"), set.join("tracked"));
+
+            dots.recover(&args).unwrap();
+            assert_eq!(dots.state.len(), recovered);
+            if answer == "y" {
+                assert_eq!(dots.state.get_source(&vimrc), Some(&set.join("vimrc")));
+                assert_eq!(dots.state.get_source(&init), Some(&inner.join("init.lua")));
+                // the innermost set wins
+                assert_eq!(dots.state.source_set(&init), Some(&inner));
+                assert!(!dots.state.contains_dest(&home.join(".hosts")));
+            }
+        }
+    }
+}
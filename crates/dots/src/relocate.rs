@@ -0,0 +1,313 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+
+use crate::mv::swap_link;
+use crate::{Dots, DotsRelocateArgs};
+use shared::fs as sfs;
+use shared::fs::FilesystemStatus;
+
+// how deep under $HOME to look for where a source set went
+const SEARCH_DEPTH: usize = 3;
+
+fn common_prefix<'a>(mut paths: impl Iterator<Item = &'a PathBuf>) -> Option<PathBuf> {
+    let mut prefix = paths.next()?.clone();
+    for path in paths {
+        while !path.starts_with(&prefix) {
+            if !prefix.pop() {
+                return None;
+            }
+        }
+    }
+    Some(prefix)
+}
+
+// directories named `name` under `dir`, not following links or looking into hidden directories
+fn find_dirs(dir: &Path, name: &std::ffi::OsStr, depth: usize, found: &mut Vec<PathBuf>) {
+    if depth == 0 {
+        return;
+    }
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if !sfs::is_dir(&path) || sfs::is_symlink(&path) {
+            continue;
+        }
+        if path.file_name() == Some(name) {
+            found.push(path.clone());
+        }
+        if !entry.file_name().to_string_lossy().starts_with('.') {
+            find_dirs(&path, name, depth - 1, found);
+        }
+    }
+}
+
+impl Dots {
+    pub fn relocate(&mut self, args: &DotsRelocateArgs) -> Result<()> {
+        let old_root = self.absolute_path(&args.old_root)?;
+        let new_root = self.absolute_path(&args.new_root)?;
+
+        if !sfs::is_dir(&new_root) {
+            return Err(anyhow!("{} is not a directory", new_root.display()));
+        }
+        if old_root == new_root {
+            return Err(anyhow!("Old and new root are the same"));
+        }
+
+        let entries: Vec<(PathBuf, PathBuf)> = self
+            .state
+            .iter()
+            .filter(|(_, source)| source.starts_with(&old_root))
+            .map(|(dest, source)| (dest.clone(), source.clone()))
+            .collect();
+        if entries.is_empty() {
+            println!("No tracked sources under {}", old_root.display());
+        }
+
+        let mut relinked = 0;
+        let mut missing = 0;
+        for (dest, source) in entries.iter() {
+            let new_source = new_root.join(source.strip_prefix(&old_root).unwrap());
+
+            // not part of the new root (deleted or renamed since), so it's left for `clean`
+            if !sfs::path_exists(&new_source) {
+                eprintln!(
+                    "[ SKIP ] {}: {} isn't in {}",
+                    dest.display(),
+                    new_source.display(),
+                    new_root.display()
+                );
+                missing += 1;
+                continue;
+            }
+
+            let is_ours = sfs::is_symlink(dest) && sfs::read_link(dest)? == *source;
+
+            if args.dry_run {
+                println!(
+                    "[ DRY RUN --- Relocate ] {} -> {}",
+                    dest.display(),
+                    new_source.display()
+                );
+                relinked += 1;
+                continue;
+            }
+
+            // whatever is there now isn't our link, so only the entry is updated
+            if is_ours {
+                if let Err(e) = swap_link(&new_source, dest) {
+                    eprintln!("[ ERROR ] {}: {:#}", dest.display(), e);
+                    continue;
+                }
+                sfs::chown(dest, self.env.owner)?;
+            } else if args.verbose {
+                println!(
+                    "{} is no longer our link, only updating the entry",
+                    dest.display()
+                );
+            }
+
+            if args.verbose {
+                println!("Relocated {} -> {}", dest.display(), new_source.display());
+            }
+            self.state.insert(dest.clone(), new_source);
+            relinked += 1;
+        }
+
+        let system = self
+            .system_state
+            .iter()
+            .filter(|(_, source)| source.starts_with(&old_root))
+            .count();
+        if system > 0 {
+            println!(
+                "{} link(s) in the system trackfile also point into {}; `relocate` doesn't touch those",
+                system,
+                old_root.display()
+            );
+        }
+
+        let prefix = if args.dry_run {
+            "Would have relocated"
+        } else {
+            "Relocated"
+        };
+        println!(
+            "{} {} link(s), {} source(s) missing from {}.",
+            prefix,
+            relinked,
+            missing,
+            new_root.display()
+        );
+
+        Ok(())
+    }
+
+    // all of our dangling links point under one directory that's gone, and a directory by the same
+    // name (or the dotfiles argument) has every one of those sources: that's a moved source set.
+    // returns the old and new root
+    pub(crate) fn detect_relocation(&self) -> Option<(PathBuf, PathBuf)> {
+        let dangling: Vec<&PathBuf> = self
+            .state
+            .iter()
+            .filter(|(dest, source)| {
+                matches!(
+                    sfs::get_status(dest),
+                    FilesystemStatus::Symlink { dangling: true, points_to: Some(ref to) } if to == *source
+                )
+            })
+            .map(|(_, source)| source)
+            .collect();
+
+        // one dangling link is just a deleted source, not a moved set
+        if dangling.len() < 2 {
+            return None;
+        }
+
+        // the highest directory that's gone is what got moved
+        let mut old_root = common_prefix(dangling.iter().copied())?;
+        if sfs::path_exists(&old_root) {
+            return None;
+        }
+        while let Some(parent) = old_root.parent() {
+            if sfs::path_exists(parent) {
+                break;
+            }
+            old_root = parent.to_path_buf();
+        }
+        if old_root == self.env.home || old_root.parent().is_none() {
+            return None;
+        }
+        // has to be a directory they're all inside of, not one of the sources itself
+        if dangling.contains(&&old_root) {
+            return None;
+        }
+
+        let mut candidates: Vec<PathBuf> = self
+            .args
+            .dotfiles
+            .iter()
+            .map(|d| self.env.expand_home(Path::new(d)))
+            .filter(|d| sfs::is_dir(d))
+            .collect();
+        if let Some(name) = old_root.file_name() {
+            find_dirs(&self.env.home, name, SEARCH_DEPTH, &mut candidates);
+        }
+
+        // a set can't have moved into what used to be part of itself (e.g., a file deleted from
+        // ~/dots/home isn't ~/dots having moved)
+        candidates
+            .into_iter()
+            .filter(|new_root| !old_root.starts_with(new_root))
+            .find(|new_root| {
+                dangling.iter().all(|source| {
+                    let rel = source.strip_prefix(&old_root).unwrap();
+                    !rel.as_os_str().is_empty() && sfs::path_exists(&new_root.join(rel))
+                })
+            })
+            .map(|new_root| (old_root, new_root))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing, Commands};
+
+    fn relocate_args(old_root: &Path, new_root: &Path) -> DotsRelocateArgs {
+        DotsRelocateArgs {
+            old_root: old_root.to_path_buf(),
+            new_root: new_root.to_path_buf(),
+            dry_run: false,
+            verbose: false,
+        }
+    }
+
+    // `~/dots/{vimrc,bashrc}` linked into the home directory, and then `~/dots` moved to
+    // `~/src/dots`
+    fn moved(root: &Path) -> (Dots, PathBuf, PathBuf) {
+        let home = root.join("home");
+        let old_root = home.join("dots");
+        let new_root = home.join("src/dots");
+        fs::create_dir_all(&old_root).unwrap();
+
+        let mut dots = testing::dots(
+            Commands::Relocate(relocate_args(&old_root, &new_root)),
+            root,
+            [""; 0],
+        );
+        for name in ["vimrc", "bashrc"] {
+            let source = old_root.join(name);
+            fs::write(&source, name).unwrap();
+            let dest = home.join(format!(".{}", name));
+            sfs::create_symlink(&source, &dest).unwrap();
+            dots.state.insert(dest, source);
+        }
+
+        fs::create_dir_all(new_root.parent().unwrap()).unwrap();
+        fs::rename(&old_root, &new_root).unwrap();
+        (dots, old_root, new_root)
+    }
+
+    #[test]
+    fn the_common_prefix_is_the_deepest_shared_directory() {
+        let paths = [
+            PathBuf::from("/home/a/dots/home/.vimrc"),
+            PathBuf::from("/home/a/dots/root/etc/hosts"),
+        ];
+        assert_eq!(
+            common_prefix(paths.iter()),
+            Some(PathBuf::from("/home/a/dots"))
+        );
+        assert_eq!(common_prefix(std::iter::empty()), None);
+    }
+
+    #[test]
+    fn a_moved_set_is_found_where_it_went() {
+        let root = tempfile::tempdir().unwrap();
+        let (dots, old_root, new_root) = moved(root.path());
+        assert_eq!(dots.detect_relocation(), Some((old_root, new_root)));
+    }
+
+    #[test]
+    fn deleted_sources_arent_a_move() {
+        let root = tempfile::tempdir().unwrap();
+        let (dots, old_root, new_root) = moved(root.path());
+        // a directory by the same name that lacks one of them isn't where the set went
+        fs::remove_file(new_root.join("vimrc")).unwrap();
+        assert_eq!(dots.detect_relocation(), None);
+
+        // and with the set still where it was, its files were just deleted
+        fs::rename(&new_root, &old_root).unwrap();
+        fs::remove_file(old_root.join("bashrc")).unwrap();
+        assert_eq!(dots.detect_relocation(), None);
+    }
+
+    #[test]
+    fn our_links_are_repointed_and_anything_else_only_updated() {
+        let root = tempfile::tempdir().unwrap();
+        let (mut dots, old_root, new_root) = moved(root.path());
+        let home = root.path().join("home");
+        // replaced by hand since; it's not ours to touch anymore
+        fs::remove_file(home.join(".bashrc")).unwrap();
+        fs::write(home.join(".bashrc"), "mine").unwrap();
+        // and one whose source didn't come along
+        let gone = home.join(".gone");
+        dots.state.insert(gone.clone(), old_root.join("gone"));
+
+        dots.relocate(&relocate_args(&old_root, &new_root)).unwrap();
+
+        let vimrc = new_root.join("vimrc");
+        assert_eq!(sfs::read_link(&home.join(".vimrc")).unwrap(), vimrc);
+        assert_eq!(dots.state.get_source(&home.join(".vimrc")), Some(&vimrc));
+        assert_eq!(fs::read_to_string(home.join(".bashrc")).unwrap(), "mine");
+        assert_eq!(
+            dots.state.get_source(&home.join(".bashrc")),
+            Some(&new_root.join("bashrc"))
+        );
+        assert_eq!(dots.state.get_source(&gone), Some(&old_root.join("gone")));
+    }
+}
@@ -0,0 +1,142 @@
+use std::fs;
+
+use anyhow::{Context, Result};
+use schemars::r#gen::SchemaSettings;
+use schemars::schema::RootSchema;
+
+use crate::{Config, Dots, DotsSchemaArgs};
+
+// straight from the types the config deserializes into, so it can't drift from what's accepted
+pub fn config_schema() -> RootSchema {
+    // toml has no null, so an absent key is the only way to leave an option unset
+    let settings = SchemaSettings::draft07().with(|s| s.option_add_null_type = false);
+    let mut schema = settings.into_generator().into_root_schema_for::<Config>();
+
+    let metadata = schema.schema.metadata();
+    metadata.title = Some("dots config".to_string());
+    metadata.description =
+        Some("Configuration for dots: `.dots`, `dots.toml`, `bos.toml` and the like".to_string());
+
+    schema
+}
+
+impl Dots {
+    pub fn schema(&self, args: &DotsSchemaArgs) -> Result<()> {
+        let schema =
+            serde_json::to_string_pretty(&config_schema()).context("Failed to serialize schema")?;
+
+        match args.output.as_ref() {
+            Some(path) => {
+                let path = self.absolute_path(path)?;
+                fs::write(&path, schema + "\n")
+                    .with_context(|| format!("Failed to write schema to {}", path.display()))?;
+                println!("Schema written to {}", path.display());
+            }
+            None => println!("{}", schema),
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use jsonschema::JSONSchema;
+    use serde_json::Value;
+
+    use super::*;
+
+    // every key the schema publishes for [dots] and [[dotfiles]], each in a shape it allows
+    const EVERYTHING: &str = r#"
+[general]
+strict = true
+
+[dots]
+use_target = { "home" = "~" }
+exclude = ["home/.cache"]
+inherits = ["use", "exclude"]
+elevate = "doas"
+hooks = { post_link = "true" }
+policy = { rule = [{ dest = "~/.bashrc", action = "backup" }] }
+shell = { interpreter = "sh", timeout = 5 }
+
+[dots.use]
+"home" = "~"
+"root" = false
+"os/linux" = { target = "/", when = { os = "linux" } }
+"user/<name>" = [{ name = "*", target = "~/<name>", exclude = "x" }]
+
+[[dotfiles]]
+path = "~/dots"
+replace = false
+use_config = true
+includes = ["home/.config"]
+excludes = ["home/.config/secrets"]
+map = { "scripts/run" = "~/.local/bin/run" }
+hooks = { pre_link = "true" }
+use_target = "~"
+exclude = "home/.cache"
+inherits = ["use_target"]
+
+[dotfiles.use]
+"home" = true
+"#;
+
+    fn schema() -> (Value, JSONSchema) {
+        let value = serde_json::to_value(config_schema()).unwrap();
+        let compiled = JSONSchema::compile(&value).unwrap();
+        (value, compiled)
+    }
+
+    // (what the schema says, what the loader says)
+    fn verdicts(schema: &JSONSchema, text: &str) -> (bool, bool) {
+        let instance: Value = toml::from_str(text).unwrap();
+        let loaded = Config::parse(text, Path::new("dots.toml"));
+        (schema.is_valid(&instance), loaded.is_ok())
+    }
+
+    #[test]
+    fn every_published_key_loads() {
+        let (value, schema) = schema();
+        assert_eq!(verdicts(&schema, EVERYTHING), (true, true));
+        assert_eq!(verdicts(&schema, ""), (true, true));
+
+        // nothing's published that the sample above leaves out
+        let config: Value = toml::from_str(EVERYTHING).unwrap();
+        for (definition, table) in [
+            ("DotsConfig", &config["dots"]),
+            ("DotfileConfig", &config["dotfiles"][0]),
+        ] {
+            let published = value["definitions"][definition]["properties"]
+                .as_object()
+                .unwrap();
+            for key in published.keys() {
+                assert!(
+                    table.get(key).is_some(),
+                    "{}.{} isn't covered",
+                    definition,
+                    key
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn the_schema_refuses_what_the_loader_does() {
+        let (_, schema) = schema();
+        for text in [
+            "[dots]\nuse_target = 5\n",
+            "[dots]\nexclude = [1]\n",
+            "[dots]\nelevate = \"su\"\n",
+            "[dots.use]\n\"home\" = 1\n",
+            "[[dotfiles]]\nreplace = true\n",
+            "[[dotfiles]]\npath = \"x\"\nincludes = \"home\"\n",
+            "[[dotfiles]]\npath = \"x\"\nmap = { \"a\" = 1 }\n",
+            "[nope]\n",
+        ] {
+            assert_eq!(verdicts(&schema, text), (false, false), "{:?}", text);
+        }
+    }
+}
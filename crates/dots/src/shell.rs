@@ -0,0 +1,379 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::{self, Read};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::Pid;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use toml::Table;
+
+use crate::stats;
+use crate::trust;
+
+pub const DEFAULT_TIMEOUT: u64 = 10; // seconds
+
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Interpreter {
+    #[default]
+    Sh,
+    Bash,
+    Fish,
+}
+
+impl Interpreter {
+    pub fn program(&self) -> &'static str {
+        match self {
+            Interpreter::Sh => "sh",
+            Interpreter::Bash => "bash",
+            Interpreter::Fish => "fish",
+        }
+    }
+}
+
+impl fmt::Display for Interpreter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.program())
+    }
+}
+
+// [dots.shell]
+#[derive(Deserialize, JsonSchema, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ShellConfig {
+    pub interpreter: Option<Interpreter>,
+    pub timeout: Option<u64>, // seconds, per call; hooks aren't held to it
+}
+
+impl ShellConfig {
+    // straight from the merged layers: `when` and vars get evaluated (and so need the runner set
+    // up) while the rest of the config is still being deserialized
+    pub fn from_table(table: &Table) -> Result<Self> {
+        match table.get("dots").and_then(|d| d.get("shell")) {
+            Some(shell) => shell.clone().try_into().context("Invalid [dots.shell]"),
+            None => Ok(Self::default()),
+        }
+    }
+}
+
+// single quotes, so nothing inside gets expanded (fish and sh agree on `'\''`)
+pub fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+#[derive(Clone, Debug)]
+struct Finished {
+    code: Option<i32>, // None if it was killed (by us, on timeout, or otherwise)
+    timed_out: bool,
+    stdout: String,
+    stderr: String,
+}
+
+// the same script with the same vars gives the same answer for the rest of the run
+type Invocation = (String, BTreeMap<String, String>);
+
+pub struct ShellRunner {
+    interpreter: Interpreter,
+    timeout: Duration,
+    cache: Mutex<HashMap<Invocation, Finished>>,
+}
+
+static RUNNER: OnceLock<ShellRunner> = OnceLock::new();
+
+// has to happen before anything gets run; the first config wins for the whole run
+pub fn configure(config: &ShellConfig) {
+    let _ = RUNNER.set(ShellRunner::new(config));
+}
+
+pub fn runner() -> &'static ShellRunner {
+    RUNNER.get_or_init(|| ShellRunner::new(&ShellConfig::default()))
+}
+
+impl ShellRunner {
+    pub fn new(config: &ShellConfig) -> Self {
+        Self {
+            interpreter: config.interpreter.unwrap_or_default(),
+            timeout: Duration::from_secs(config.timeout.unwrap_or(DEFAULT_TIMEOUT)),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn command(&self, script: &str, vars: &HashMap<String, String>) -> Command {
+        let mut command = Command::new(self.interpreter.program());
+        command.arg("-c").arg(script).envs(vars);
+        command
+    }
+
+    fn invoke(&self, script: &str, vars: &HashMap<String, String>) -> Result<Finished> {
+        trust::allow_shell(script)?;
+
+        let key = (script.to_string(), vars.clone().into_iter().collect());
+        if let Some(finished) = self.cache.lock().unwrap().get(&key) {
+            return Ok(finished.clone());
+        }
+
+        let mut child = self
+            .command(script, vars)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .process_group(0) // so a timeout takes down whatever it started, too
+            .spawn()
+            .with_context(|| format!("Failed to run {}", self.interpreter))?;
+
+        // drained as it comes, so a chatty script can't fill the pipe and stall until the timeout
+        let drain = |pipe: Option<Box<dyn Read + Send>>| {
+            thread::spawn(move || {
+                let mut bytes = vec![];
+                if let Some(mut pipe) = pipe {
+                    let _ = pipe.read_to_end(&mut bytes);
+                }
+                String::from_utf8_lossy(&bytes).trim().to_string()
+            })
+        };
+        let stdout = drain(child.stdout.take().map(|p| Box::new(p) as _));
+        let stderr = drain(child.stderr.take().map(|p| Box::new(p) as _));
+
+        let deadline = Instant::now() + self.timeout;
+        let mut timed_out = false;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                timed_out = true;
+                let _ = killpg(Pid::from_raw(child.id() as i32), Signal::SIGKILL);
+                break child.wait()?;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+
+        let finished = Finished {
+            code: status.code(),
+            timed_out,
+            stdout: stdout.join().unwrap_or_default(),
+            stderr: stderr.join().unwrap_or_default(),
+        };
+        self.cache.lock().unwrap().insert(key, finished.clone());
+        Ok(finished)
+    }
+
+    fn failure(&self, script: &str, finished: &Finished) -> anyhow::Error {
+        let what = if finished.timed_out {
+            format!("timed out after {}s", self.timeout.as_secs())
+        } else {
+            match finished.code {
+                Some(code) => format!("exited with {}", code),
+                None => "was killed".to_string(),
+            }
+        };
+
+        if finished.stderr.is_empty() {
+            anyhow!("`{}` ({}) {}", script, self.interpreter, what)
+        } else {
+            anyhow!(
+                "`{}` ({}) {}: {}",
+                script,
+                self.interpreter,
+                what,
+                finished.stderr
+            )
+        }
+    }
+
+    // its output, trimmed; failing (or timing out) is an error
+    pub fn run(&self, script: &str, vars: &HashMap<String, String>) -> Result<String> {
+        let finished = self.invoke(script, vars)?;
+        if finished.timed_out || finished.code != Some(0) {
+            return Err(self.failure(script, &finished));
+        }
+        Ok(finished.stdout)
+    }
+
+    pub fn run_for_bool(&self, script: &str, vars: &HashMap<String, String>) -> Result<bool> {
+        let output = self.run(script, vars)?;
+
+        match output.as_str() {
+            "0" | "false" => Ok(false),
+            "1" | "true" => Ok(true),
+            _ => Err(anyhow!(
+                "`{}` printed `{}` rather than true/false (or 1/0)",
+                script,
+                output
+            )),
+        }
+    }
+
+    // exit status 0 is true, 1 false; anything else (or a timeout) means it couldn't tell
+    pub fn status(&self, script: &str, vars: &HashMap<String, String>) -> Result<bool> {
+        let finished = self.invoke(script, vars)?;
+
+        match finished.code {
+            Some(0) if !finished.timed_out => Ok(true),
+            Some(1) if !finished.timed_out => Ok(false),
+            _ => Err(self.failure(script, &finished)),
+        }
+    }
+
+    pub fn test_if(&self, expression: &str, vars: &HashMap<String, String>) -> Result<bool> {
+        self.status(&format!("[ {} ]", expression), vars)
+    }
+
+    // `command -v` has no other way of failing than not finding it
+    pub fn test_command(&self, name: &str, vars: &HashMap<String, String>) -> Result<bool> {
+        let script = format!("command -v {} >/dev/null", quote(name));
+        let finished = self.invoke(&script, vars)?;
+        if finished.timed_out {
+            return Err(self.failure(&script, &finished));
+        }
+        Ok(finished.code == Some(0))
+    }
+
+    // for commands whose output is meant for the user (e.g., hooks) rather than for us: never
+    // cached, and not held to the timeout
+    pub fn exec(&self, script: &str, vars: &HashMap<String, String>) -> Result<()> {
+        trust::allow_shell(script)?;
+
+        let mut command = self.command(script, vars);
+        // a JSON report on stdout can't have a hook's output mixed into it
+        if stats::json_output() {
+            command.stdout(io::stderr());
+        }
+        let status = command
+            .status()
+            .with_context(|| format!("Failed to run {}", self.interpreter))?;
+
+        if !status.success() {
+            return Err(anyhow!(
+                "`{}` ({}) exited with {}",
+                script,
+                self.interpreter,
+                status
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn runner(timeout: u64) -> ShellRunner {
+        ShellRunner::new(&ShellConfig {
+            interpreter: Some(Interpreter::Sh),
+            timeout: Some(timeout),
+        })
+    }
+
+    fn vars_of(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn quoting_survives_the_shell() {
+        assert_eq!(quote("it's"), r"'it'\''s'");
+
+        let tricky = "it's $HOME `x` \"y\" \\ *";
+        let output = runner(5)
+            .run(&format!("printf %s {}", quote(tricky)), &HashMap::new())
+            .unwrap();
+        assert_eq!(output, tricky);
+    }
+
+    #[test]
+    fn timed_out_commands_are_killed_with_everything_they_started() {
+        let dir = tempfile::tempdir().unwrap();
+        let pidfile = dir.path().join("pid");
+        let script = format!(
+            "sleep 30 & echo $! > {}; wait",
+            quote(&pidfile.to_string_lossy())
+        );
+
+        let started = Instant::now();
+        let e = runner(1).run(&script, &HashMap::new()).unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert!(e.to_string().contains("timed out after 1s"), "{}", e);
+
+        // the background sleep was in the same process group, so it went down too
+        let pid: i32 = fs::read_to_string(&pidfile)
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while nix::sys::signal::kill(Pid::from_raw(pid), None).is_ok() {
+            assert!(
+                Instant::now() < deadline,
+                "pid {} outlived the timeout",
+                pid
+            );
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        // a timeout can't tell us anything either way
+        assert!(runner(1).status("sleep 30", &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn results_are_cached_per_script_and_vars() {
+        let dir = tempfile::tempdir().unwrap();
+        let counter = dir.path().join("count");
+        let script = format!(
+            "echo ran >> {}; echo \"$GREETING\"",
+            quote(&counter.to_string_lossy())
+        );
+        let runner = runner(5);
+        let ran = || {
+            fs::read_to_string(&counter)
+                .unwrap_or_default()
+                .lines()
+                .count()
+        };
+
+        let hi = vars_of(&[("GREETING", "hi")]);
+        assert_eq!(runner.run(&script, &hi).unwrap(), "hi");
+        assert_eq!(runner.run(&script, &hi).unwrap(), "hi");
+        assert_eq!(ran(), 1);
+
+        // different vars are a different invocation
+        let bye = vars_of(&[("GREETING", "bye")]);
+        assert_eq!(runner.run(&script, &bye).unwrap(), "bye");
+        assert_eq!(ran(), 2);
+
+        // failures are remembered just the same
+        let failing = format!("{}; exit 3", script);
+        assert!(runner.run(&failing, &hi).is_err());
+        assert!(runner.status(&failing, &hi).is_err());
+        assert_eq!(ran(), 3);
+    }
+
+    #[test]
+    fn exit_status_and_output_are_read_as_bools() {
+        let runner = runner(5);
+        let none = HashMap::new();
+
+        assert!(runner.status("true", &none).unwrap());
+        assert!(!runner.status("false", &none).unwrap());
+        assert!(runner.status("exit 2", &none).is_err());
+
+        assert!(runner.run_for_bool("echo 1", &none).unwrap());
+        assert!(!runner.run_for_bool("echo false", &none).unwrap());
+        assert!(runner.run_for_bool("echo maybe", &none).is_err());
+
+        assert!(runner.test_command("sh", &none).unwrap());
+        assert!(!runner.test_command("dots-no-such-command", &none).unwrap());
+    }
+}
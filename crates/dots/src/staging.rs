@@ -9,6 +9,7 @@ use std::slice::Iter;
 use anyhow::{anyhow, Context, Result};
 use toml;
 
+use crate::interp;
 use crate::Config;
 use shared::bos;
 use shared::fs as sfs;
@@ -99,10 +100,6 @@ impl TrackMap {
     }
 }
 
-fn strip_first_char(s: &str) -> &str {
-    s.chars().next().map_or("", |c| &s[c.len_utf8()..])
-}
-
 fn _handle_inclusions(
     inclusions: &Vec<PathBuf>,
     exclusions: Option<&Vec<PathBuf>>,
@@ -142,15 +139,8 @@ fn process(base_path: &Path, source: &Path, parts: Components, env: &bos::Env) -
                 "nix/user" => env.nix_home_name,
                 _ => Err(()), //TODO: better error
             },
-            "~" => env.get("HOME"),
-            _ => {
-                let part_as_str = part.as_os_str().to_str();
-                if part_as_str?.starts_with("$") {
-                    env.get(strip_first_char(part_as_str.unwrap()))
-                } else {
-                    part
-                }
-            }
+            // `~`, `$VAR`, `${VAR:-default}` and the like, same as everywhere else
+            _ => interp::expand_with(part.as_os_str().to_str()?, env, &HashMap::new())?,
         };
 
         process(base_path, &source.join(adj_part), parts, env)
@@ -0,0 +1,283 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use clap::ValueEnum;
+use serde::{Serialize, Serializer};
+
+use crate::handlers::{Op, Reason};
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+// set once in run(): with `--format json`, stdout carries the report and nothing else, so
+// progress and status text (see `say!`) goes to stderr instead
+static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+pub fn set_format(format: OutputFormat) {
+    JSON_OUTPUT.store(format == OutputFormat::Json, Ordering::Relaxed);
+}
+
+pub fn json_output() -> bool {
+    JSON_OUTPUT.load(Ordering::Relaxed)
+}
+
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    #[default]
+    Link,
+    Unlink,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Phase::Link => write!(f, "link"),
+            Phase::Unlink => write!(f, "unlink"),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct SkippedEntry {
+    pub dest: PathBuf,
+    pub reason: &'static str,
+    pub explanation: String, // the Op's own explanation, e.g., which flag would have allowed it
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ErrorEntry {
+    pub dest: Option<PathBuf>, // None for errors not tied to a single entry (e.g., hooks)
+    pub message: String,
+}
+
+fn serialize_ms<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u128(duration.as_millis())
+}
+
+const NO_SET: &str = "<none>";
+
+// shared by link and unlink (and both phases of relink)
+#[derive(Serialize, Debug, Default)]
+pub struct Stats {
+    pub phase: Phase,
+    pub targets: usize,
+    pub symlinks_added: usize,
+    pub symlinks_removed: usize,
+    pub files_removed: usize,
+    pub dirs_removed: usize,
+    pub backed_up: usize,
+    pub absorbed: usize,
+    pub unchanged: usize, // already linked to the intended source
+    pub by_reason: BTreeMap<&'static str, usize>,
+    pub by_set: BTreeMap<String, usize>,
+    pub skipped: Vec<SkippedEntry>,
+    pub errors: Vec<ErrorEntry>,
+    #[serde(rename = "elapsed_ms", serialize_with = "serialize_ms")]
+    pub elapsed: Duration,
+
+    #[serde(skip)]
+    pub changed: Vec<PathBuf>, // handed to post_* hooks
+    #[serde(skip)]
+    started: Option<Instant>,
+}
+
+pub type LinkStats = Stats;
+pub type UnlinkStats = Stats;
+
+impl Stats {
+    pub fn new(phase: Phase, targets: usize) -> Self {
+        Self {
+            phase,
+            targets,
+            started: Some(Instant::now()),
+            ..Default::default()
+        }
+    }
+
+    pub fn finish(&mut self) {
+        if let Some(started) = self.started.take() {
+            self.elapsed = started.elapsed();
+        }
+    }
+
+    pub fn record_reason(&mut self, reason: &Reason) {
+        *self.by_reason.entry(reason.key()).or_default() += 1;
+    }
+
+    pub fn record_set(&mut self, source_set: Option<&PathBuf>) {
+        let key = source_set
+            .map(|s| s.display().to_string())
+            .unwrap_or_else(|| NO_SET.to_string());
+        *self.by_set.entry(key).or_default() += 1;
+    }
+
+    pub fn skip(&mut self, dest: &Path, op: &Op) {
+        self.skipped.push(SkippedEntry {
+            dest: dest.to_path_buf(),
+            reason: op.reason().key(),
+            explanation: op.to_string(),
+        });
+    }
+
+    pub fn error(&mut self, dest: Option<&Path>, e: &anyhow::Error) {
+        self.errors.push(ErrorEntry {
+            dest: dest.map(Path::to_path_buf),
+            message: format!("{:#}", e),
+        });
+    }
+
+    pub fn changes(&self) -> usize {
+        match self.phase {
+            Phase::Link => self.symlinks_added,
+            Phase::Unlink => self.symlinks_removed + self.files_removed,
+        }
+    }
+
+    pub fn report(&self, format: OutputFormat, dry_run: bool) {
+        match format {
+            OutputFormat::Text => self.display(dry_run),
+            OutputFormat::Json => match serde_json::to_string_pretty(self) {
+                Ok(json) => println!("{}", json),
+                Err(e) => eprintln!("Failed to serialize stats: {}", e),
+            },
+        }
+    }
+
+    pub fn display(&self, dry_run: bool) {
+        let verb = match self.phase {
+            Phase::Link => "linked",
+            Phase::Unlink => "unlinked",
+        };
+
+        let summary = format!(
+            "{}/{} entries in {:.2?}.",
+            self.changes(),
+            self.targets,
+            self.elapsed
+        );
+        let phase = self.phase.to_string().to_uppercase();
+        if dry_run {
+            println!("[ {} ] Would have {} {}", phase, verb, summary);
+        } else {
+            println!("[ {} ] {} {}", phase, capitalize(verb), summary);
+        }
+
+        let mut counts = vec![];
+        if self.symlinks_added > 0 {
+            counts.push(format!("{} added", self.symlinks_added));
+        }
+        if self.symlinks_removed > 0 {
+            counts.push(format!("{} symlinks removed", self.symlinks_removed));
+        }
+        if self.files_removed > 0 {
+            counts.push(format!("{} files removed", self.files_removed));
+        }
+        if self.dirs_removed > 0 {
+            counts.push(format!("{} dirs removed", self.dirs_removed));
+        }
+        if self.backed_up > 0 {
+            counts.push(format!("{} backed up", self.backed_up));
+        }
+        if self.absorbed > 0 {
+            counts.push(format!("{} absorbed", self.absorbed));
+        }
+        if self.unchanged > 0 {
+            counts.push(format!("{} unchanged", self.unchanged));
+        }
+        counts.push(format!("{} skipped", self.skipped.len()));
+        counts.push(format!("{} errors", self.errors.len()));
+        println!("  {}", counts.join(", "));
+
+        if !self.by_reason.is_empty() {
+            let reasons: Vec<String> = self
+                .by_reason
+                .iter()
+                .map(|(reason, n)| format!("{}={}", reason, n))
+                .collect();
+            println!("  by reason: {}", reasons.join(" "));
+        }
+
+        // a single (or no) set isn't worth a breakdown
+        if self.by_set.len() > 1 {
+            let sets: Vec<String> = self
+                .by_set
+                .iter()
+                .map(|(set, n)| format!("{} ({})", set, n))
+                .collect();
+            println!("  by source set: {}", sets.join(", "));
+        }
+
+        for skipped in self.skipped.iter() {
+            println!(
+                "  [ SKIP ] {}: {}",
+                skipped.dest.display(),
+                skipped.explanation
+            );
+        }
+        for error in self.errors.iter() {
+            match &error.dest {
+                Some(dest) => println!("  [ ERROR ] {}: {}", dest.display(), error.message),
+                None => println!("  [ ERROR ] {}", error.message),
+            }
+        }
+    }
+}
+
+fn capitalize(s: &str) -> String {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) => c.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+// both phases kept intact, totals derived from them
+#[derive(Serialize, Debug, Default)]
+pub struct RelinkStats {
+    pub unlink: Stats,
+    pub link: Stats,
+    #[serde(rename = "elapsed_ms", serialize_with = "serialize_ms")]
+    pub elapsed: Duration,
+    pub errors: usize,
+}
+
+impl RelinkStats {
+    pub fn new(unlink_stats: UnlinkStats, link_stats: LinkStats) -> Self {
+        Self {
+            elapsed: unlink_stats.elapsed + link_stats.elapsed,
+            errors: unlink_stats.errors.len() + link_stats.errors.len(),
+            unlink: unlink_stats,
+            link: link_stats,
+        }
+    }
+
+    pub fn report(&self, format: OutputFormat, dry_run: bool) {
+        match format {
+            OutputFormat::Text => self.display(dry_run),
+            OutputFormat::Json => match serde_json::to_string_pretty(self) {
+                Ok(json) => println!("{}", json),
+                Err(e) => eprintln!("Failed to serialize stats: {}", e),
+            },
+        }
+    }
+
+    pub fn display(&self, dry_run: bool) {
+        self.unlink.display(dry_run);
+        self.link.display(dry_run);
+        println!(
+            "[ RELINK ] {} removed, {} linked, {} errors in {:.2?}.",
+            self.unlink.changes(),
+            self.link.changes(),
+            self.errors,
+            self.elapsed
+        );
+    }
+}
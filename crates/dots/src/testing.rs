@@ -0,0 +1,52 @@
+// fixtures shared by the unit tests; everything lives under a `tempfile::TempDir` the test holds on
+// to, so it's cleaned up even when an assertion fails
+
+use std::path::Path;
+
+use shared::bos;
+
+use crate::prompt::ScriptedPrompter;
+use crate::stats::OutputFormat;
+use crate::{Args, Commands, Dots, DotsBuilder, DotsLinkArgs};
+
+pub fn link_args(target: &Path) -> DotsLinkArgs {
+    DotsLinkArgs {
+        target: target.to_path_buf(),
+        ..Default::default()
+    }
+}
+
+pub fn args(command: Commands) -> Args {
+    Args {
+        command,
+        dotfiles: None,
+        user: None,
+        format: OutputFormat::Text,
+        overrides: vec![],
+        no_shell: true,
+    }
+}
+
+// the environment of a user whose home (and cache) are inside `root`
+pub fn env(root: &Path) -> bos::Env {
+    let mut env = bos::Env::detect();
+    env.home = root.join("home");
+    env.cache_dir = root.join("cache");
+    env.owner = None;
+    env
+}
+
+// a Dots acting for that user, answering any prompt from `answers` (Dots only ever borrows its
+// args, and a test's are small enough to leak)
+pub fn dots<I, S>(command: Commands, root: &Path, answers: I) -> Dots
+where
+    I: IntoIterator<Item = S>,
+    S: Into<String>,
+{
+    DotsBuilder::default()
+        .args(Box::leak(Box::new(args(command))))
+        .env(env(root))
+        .prompter(Box::new(ScriptedPrompter::new(answers)))
+        .build()
+        .unwrap()
+}
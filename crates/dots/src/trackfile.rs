@@ -1,18 +1,24 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
 use std::fs;
 use std::iter::Peekable;
 use std::path::{Components, Path, PathBuf};
 
 use anyhow::{anyhow, Context, Result};
 use git2::Repository;
+use serde::{Deserialize, Serialize};
 use toml;
 use url::Url;
 
+use crate::hooks::SetHooks;
+use crate::prompt::Prompter;
+use crate::trust;
 use crate::{DotfileConfig, TomlConfig};
 use shared::bos;
 use shared::fs as sfs;
+use shared::fs::FilesystemStatus;
 
-const DOTFILE_START_DIRS: [&str; 6] = ["home", "root", "os", "user", "guix", "nix"];
+pub const DOTFILE_START_DIRS: [&str; 6] = ["home", "root", "os", "user", "guix", "nix"];
 
 pub fn change_file_prefix(old_prefix: &Path, new_prefix: &Path, path: &Path) -> PathBuf {
     new_prefix.join(path.strip_prefix(old_prefix)).to_path_buf()
@@ -42,17 +48,113 @@ pub fn change_file_prefix(old_prefix: &Path, new_prefix: &Path, path: &Path) ->
 
 pub type TrackfileContent = HashMap<PathBuf, PathBuf>; // dest, source
 
+// what actually gets written to disk
+#[derive(Serialize, Deserialize, Default)]
+struct TrackfileRepr {
+    #[serde(default)]
+    links: TrackfileContent,
+    #[serde(default)]
+    dirs: BTreeSet<PathBuf>, // directories we created (and so are allowed to remove again)
+}
+
+// what became of one candidate source for a dest
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum Outcome {
+    Chosen,
+    Excluded { by: PathBuf }, // the exclude that matched
+    Shadowed { by: PathBuf }, // the source set (or later `use` in it) that won instead
+    Ignored,                  // a later set wanted the dest but had `replace = false`
+    NotIncluded,              // the set has `includes`, and none of them covers it
+}
+
+// a config (or a source set cloned from git) that generation went through on the way to a mapping
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Include {
+    pub path: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>, // what it was cloned from
+}
+
+impl fmt::Display for Include {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.url {
+            Some(url) => write!(f, "{} ({})", url, self.path.display()),
+            None => write!(f, "{}", self.path.display()),
+        }
+    }
+}
+
+// the includes currently being composed, outermost first
+#[derive(Debug, Clone, Default)]
+pub struct IncludeStack(Vec<Include>);
+
+impl IncludeStack {
+    // the stack with `include` on top; a config that's already on it would include itself forever
+    pub fn push(&self, include: Include) -> Result<Self> {
+        let key = |p: &Path| fs::canonicalize(p).unwrap_or_else(|_| p.to_path_buf());
+        if self.0.iter().any(|i| key(&i.path) == key(&include.path)) {
+            return Err(anyhow!(
+                "Circular config composition: {} -> {}",
+                self,
+                include
+            ));
+        }
+
+        let mut stack = self.clone();
+        stack.0.push(include);
+        Ok(stack)
+    }
+
+    pub fn chain(&self) -> &[Include] {
+        &self.0
+    }
+}
+
+impl fmt::Display for IncludeStack {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let chain: Vec<String> = self.0.iter().map(Include::to_string).collect();
+        write!(f, "{}", chain.join(" -> "))
+    }
+}
+
+// how a candidate source came to be mapped to a dest, kept around for `dots explain`
+#[derive(Serialize, Debug, Clone)]
+pub struct Derivation {
+    pub source: PathBuf,
+    pub source_set: Option<PathBuf>,
+    pub config: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub via: Vec<Include>, // every config (and git source) that led here, outermost first
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub when: Option<String>, // the predicates its `use` entry's `when` held on
+    pub rule: String, // e.g., `use "home" : "<dir>/x" -> "~/<dir>/x"`
+    pub vars: BTreeMap<String, String>, // replace_map vars substituted along the way
+    #[serde(flatten)]
+    pub outcome: Outcome,
+}
+
 #[derive(Debug, Default)]
 pub struct Trackfile {
     content: TrackfileContent,
+    dirs: BTreeSet<PathBuf>,
     dirty: bool,
+
+    // generation-time only (never saved): which source set produced each dest, and the hooks of
+    // every set that contributed
+    sets: HashMap<PathBuf, PathBuf>, // dest, source set
+    hooks: Vec<SetHooks>,
+    derivations: HashMap<PathBuf, Vec<Derivation>>, // dest, every candidate in the order seen
 }
 
 // Context struct as a global singelton?
 // come up with nice pattern for merging context from bos?
-#[derive(Default)]
+#[derive(Default, Clone)]
 pub struct TrackfileGenOptions {
     verbose: bool,
+    pub includes: IncludeStack, // how generation got to where it is
+    pub url: Option<String>,    // what the target was cloned from, if it was
+    pub offline: bool,          // git sources are left out rather than cloned (e.g., for hovers)
 }
 
 #[derive(Default)]
@@ -80,13 +182,29 @@ impl Trackfile {
                 if toml_content.trim().is_empty() {
                     Ok(Trackfile::default())
                 } else {
-                    let content: TrackfileContent =
-                        toml::from_str(&toml_content).with_context(|| {
-                            format!("Failed to parse trackfile {}", trackfile_path.display())
-                        })?;
+                    let table: toml::Table = toml::from_str(&toml_content).with_context(|| {
+                        format!("Failed to parse trackfile {}", trackfile_path.display())
+                    })?;
+
+                    // older trackfiles are just a flat dest = source table (dests are always
+                    // absolute, so they can't collide with our keys)
+                    let repr = if table.contains_key("links") || table.contains_key("dirs") {
+                        table.try_into()
+                    } else {
+                        table.try_into().map(|links| TrackfileRepr {
+                            links,
+                            ..Default::default()
+                        })
+                    }
+                    .with_context(|| {
+                        format!("Failed to parse trackfile {}", trackfile_path.display())
+                    })?;
+
                     Ok(Self {
-                        content,
+                        content: repr.links,
+                        dirs: repr.dirs,
                         dirty: false,
+                        ..Default::default()
                     })
                 }
             }
@@ -113,8 +231,12 @@ impl Trackfile {
             return Ok(());
         }
 
-        let toml_string = toml::to_string_pretty(&self.content)
-            .context("Failed to serialize trackfile content")?;
+        let repr = TrackfileRepr {
+            links: self.content.clone(),
+            dirs: self.dirs.clone(),
+        };
+        let toml_string =
+            toml::to_string_pretty(&repr).context("Failed to serialize trackfile content")?;
 
         if let Some(parent) = trackfile_path.parent() {
             sfs::create_dir_all(parent).with_context(|| {
@@ -139,15 +261,52 @@ impl Trackfile {
     // 1. a url (git)
     // 2. a path to a file (config toml)
     // 3. a path to a directory (dotfiles atom)
-    pub fn generate(target: String, env: &bos::Env, opts: Option<TrackfileGenOptions>) {
-        let opts = opts
+    // `prompter` is asked about any untrusted shell code on the way
+    pub fn generate(
+        target: String,
+        env: &bos::Env,
+        opts: Option<TrackfileGenOptions>,
+        prompter: &mut dyn Prompter,
+    ) -> Result<Self> {
+        let mut opts = opts
             .or_else(|| Some(TrackfileGenOptions::default()))
             .unwrap();
 
         // try git
         if let Ok(url) = Url::parse(target.as_str()) {
-            Repository::clone_recurse(url, PathBuf::from(env.cache_dir).join("repos"))
+            if opts.offline {
+                return Ok(Self::default());
+            }
+            let dir = Trackfile::clone_repo(&url, env)?;
+            opts.url = Some(url.to_string());
+            return Trackfile::generate_from_dir(&dir, env, Some(opts), prompter);
         }
+
+        let loc = env.expand_home(Path::new(&target));
+        if sfs::is_file(&loc) {
+            return Ok(
+                Trackfile::generate_from_config(&loc, env, Some(opts), prompter)?
+                    .unwrap_or_default(),
+            );
+        }
+        Trackfile::generate_from_dir(&loc, env, Some(opts), prompter)
+    }
+
+    // cached under repos/, so later runs (and `recover`) find it there
+    fn clone_repo(url: &Url, env: &bos::Env) -> Result<PathBuf> {
+        let name = url
+            .path_segments()
+            .and_then(|mut s| s.next_back())
+            .map(|s| s.trim_end_matches(".git"))
+            .filter(|s| !s.is_empty())
+            .ok_or_else(|| anyhow!("Can't name a checkout for {}", url))?;
+        let dir = env.cache_dir.join("repos").join(name);
+
+        if !sfs::path_exists(&dir) {
+            Repository::clone_recurse(url.as_str(), &dir)
+                .with_context(|| format!("Failed to clone {}", url))?;
+        }
+        Ok(dir)
     }
 
     // Let's say we have a global config then are pointed at a directory:
@@ -166,10 +325,44 @@ impl Trackfile {
         loc: &PathBuf,
         env: &bos::Env,
         opts: Option<TrackfileGenOptions>,
+        prompter: &mut dyn Prompter,
     ) -> Result<Option<Self>> {
-        let dir_config = Trackfile::detect_config(loc)?;
-        if let None = dir_config {
+        let opts = opts.unwrap_or_default();
+        let config_path = match sfs::is_file(loc) {
+            true => Some(loc.clone()),
+            false => Trackfile::find_config(loc)?,
+        };
+        let Some(config_path) = config_path else {
             return Ok(None);
+        };
+
+        let includes = opts.includes.push(Include {
+            path: config_path.clone(),
+            url: opts.url.clone(),
+        })?;
+        // read once, so what gets vetted is exactly what gets parsed
+        let content = sfs::read_to_string(&config_path)
+            .with_context(|| format!("Failed to load {}", includes))?;
+        trust::vet(includes.chain(), &content, prompter)?;
+        let state = TomlConfig::parse(&content, &config_path)
+            .with_context(|| format!("Failed to load {}", includes))?;
+        let base = config_path.parent().unwrap_or(Path::new("/"));
+
+        let mut track = Self::default();
+        for dotfile in state.dotfiles.iter().flatten() {
+            let set = Trackfile::compose_dotfile(
+                &state,
+                dotfile,
+                base,
+                env,
+                &includes,
+                opts.offline,
+                prompter,
+            )
+            .with_context(|| {
+                format!("In [[dotfiles]] {} ({})", dotfile.path.display(), includes)
+            })?;
+            track.merge_with(set, dotfile.replace.unwrap_or(true));
         }
 
         // handle inheritance
@@ -199,6 +392,67 @@ impl Trackfile {
         //   - Maybe to just avoid having to care, just always do two steps
         //     Unless not using callee config, in which case we could supply the caller config in
         //     its place
+
+        Ok(Some(track))
+    }
+
+    // one [[dotfiles]] entry of `state`: another config to compose (directly, or through a
+    // directory or repo that has one), or a source set
+    fn compose_dotfile(
+        state: &TomlConfig,
+        dotfile: &DotfileConfig,
+        base: &Path,
+        env: &bos::Env,
+        includes: &IncludeStack,
+        offline: bool,
+        prompter: &mut dyn Prompter,
+    ) -> Result<Self> {
+        let opts = |url: Option<String>| TrackfileGenOptions {
+            includes: includes.clone(),
+            url,
+            offline,
+            ..Default::default()
+        };
+
+        let path = dotfile.path.to_string_lossy();
+        let (set, includes) = match Url::parse(&path) {
+            Ok(_) if offline => return Ok(Self::default()),
+            Ok(url) => {
+                let dir = Trackfile::clone_repo(&url, env)?;
+                if dotfile.use_config && Trackfile::find_config(&dir)?.is_some() {
+                    let composed = Trackfile::generate_from_config(
+                        &dir,
+                        env,
+                        Some(opts(Some(url.to_string()))),
+                        prompter,
+                    )?;
+                    return Ok(composed.unwrap_or_default());
+                }
+                // the repo itself is part of how its files got here
+                let includes = includes.push(Include {
+                    path: dir.clone(),
+                    url: Some(url.to_string()),
+                })?;
+                (dir, includes)
+            }
+            Err(_) => {
+                let resolved = base.join(env.expand_home(&dotfile.path));
+                let composes = sfs::is_file(&resolved)
+                    || (dotfile.use_config && Trackfile::find_config(&resolved)?.is_some());
+                if composes {
+                    let composed = Trackfile::generate_from_config(
+                        &resolved,
+                        env,
+                        Some(opts(None)),
+                        prompter,
+                    )?;
+                    return Ok(composed.unwrap_or_default());
+                }
+                (resolved, includes.clone())
+            }
+        };
+
+        Trackfile::process_dotfile(state, dotfile, &set, &includes)
     }
 
     // expects config.dotfiles to be empty (MAKE SURE THIS IS ACTUALLY WHAT WE WANT)
@@ -206,6 +460,7 @@ impl Trackfile {
         loc: &PathBuf,
         env: &bos::Env,
         opts: Option<TrackfileGenOptions>,
+        prompter: &mut dyn Prompter,
     ) -> Result<Self> {
         if !loc.try_exists()? {
             return Err(anyhow!("target directory {} does not exist", loc.display()));
@@ -213,7 +468,7 @@ impl Trackfile {
         let opts = opts.unwrap_or_else(|| TrackfileGenOptions::default());
 
         Ok(
-            Trackfile::generate_from_config(loc, env, Some(opts))?.or_else(|| {
+            Trackfile::generate_from_config(loc, env, Some(opts), prompter)?.or_else(|| {
                 // da whole crop
             }),
         )
@@ -230,8 +485,87 @@ impl Trackfile {
         //};
         //
     }
-    pub fn process_dotfile(state: &TomlConfig, dotfile_config: &DotfileConfig) -> Self {}
+    pub fn process_dotfile(
+        state: &TomlConfig,
+        dotfile_config: &DotfileConfig,
+        set: &Path,
+        includes: &IncludeStack,
+    ) -> Result<Self> {
+        let mut track = Self::default();
+        let set = set.to_path_buf();
+        let global = state.dots.as_ref().map(|d| &d.options);
+
+        if let Some(use_map) = &dotfile_config.options.use_map {
+            for (use_path, uses) in use_map.iter() {
+                for dots_use in uses.iter().filter(|u| u.when) {
+                    let mut trace = vec![];
+                    let track_map = dots_use
+                        .try_use(
+                            &set,
+                            use_path,
+                            global.and_then(|o| o.use_target.clone()),
+                            global.and_then(|o| o.exclude.clone()),
+                            &mut trace,
+                        )
+                        .with_context(|| {
+                            format!("In use \"{}\" ({})", use_path.display(), includes)
+                        })?;
+
+                    for (dest, mut derivation) in trace.into_iter() {
+                        if derivation.outcome == Outcome::Chosen {
+                            derivation.outcome = dotfile_config.filter(&set, &derivation.source);
+                        }
+                        derivation.source_set = Some(set.clone());
+                        derivation.config = state.path.clone();
+                        derivation.via = includes.chain().to_vec();
+                        track.record(dest, derivation);
+                    }
+                    for (source, dest) in track_map.into_iter().flatten() {
+                        if dotfile_config.filter(&set, &source) == Outcome::Chosen {
+                            track.claim(dest, source, &set);
+                        }
+                    }
+                }
+            }
+        }
+
+        // after every `use`, so a mapping has the last word within the set
+        let mapped = dotfile_config
+            .mapped(&set)
+            .with_context(|| format!("In map ({})", includes))?;
+        for (source, dest, rule) in mapped.into_iter() {
+            let derivation = Derivation {
+                source: source.clone(),
+                source_set: Some(set.clone()),
+                config: state.path.clone(),
+                via: includes.chain().to_vec(),
+                when: None,
+                rule,
+                vars: BTreeMap::new(),
+                outcome: Outcome::Chosen,
+            };
+            track.record(dest.clone(), derivation);
+            track.claim(dest, source, &set);
+        }
+
+        if let Some(hooks) = &dotfile_config.hooks {
+            track.add_hooks(SetHooks {
+                source_set: set.clone(),
+                hooks: hooks.clone(),
+                vars: dotfile_config.options.replace_vars(),
+            });
+        }
+
+        Ok(track)
+    }
     pub fn detect_config(target_dir: &PathBuf) -> Result<Option<TomlConfig>> {
+        match Trackfile::find_config(target_dir)? {
+            Some(target) => Ok(Some(TomlConfig::read(target)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn find_config(target_dir: &Path) -> Result<Option<PathBuf>> {
         let config_names = vec![
             "dots.toml",
             "config.dots",
@@ -244,7 +578,7 @@ impl Trackfile {
         for name in config_names.into_iter() {
             let target = target_dir.join(name);
             if target.try_exists()? {
-                return Ok(Some(TomlConfig::read(target)?));
+                return Ok(Some(target));
             }
         }
 
@@ -256,6 +590,118 @@ impl Trackfile {
         self.dirty = true;
     }
 
+    pub fn insert_from_set(&mut self, dest: PathBuf, source: PathBuf, source_set: PathBuf) {
+        self.sets.insert(dest.clone(), source_set);
+        self.insert(dest, source);
+    }
+
+    // a later `use` (or mapping) in the same set wins, like a later set would
+    fn claim(&mut self, dest: PathBuf, source: PathBuf, set: &Path) {
+        if let Some(earlier) = self.get_source(&dest).cloned() {
+            if earlier != source {
+                self.mark(
+                    &dest,
+                    &earlier,
+                    Outcome::Shadowed {
+                        by: set.to_path_buf(),
+                    },
+                );
+            }
+        }
+        self.insert_from_set(dest, source, set.to_path_buf());
+    }
+
+    pub fn source_set(&self, dest: &Path) -> Option<&PathBuf> {
+        self.sets.get(dest)
+    }
+
+    pub fn add_hooks(&mut self, hooks: SetHooks) {
+        self.hooks.push(hooks);
+    }
+
+    pub fn hooks(&self) -> &[SetHooks] {
+        &self.hooks
+    }
+
+    // later sets win on conflicting dests (`replace = true` semantics); hooks accumulate
+    pub fn merge(&mut self, other: Self) {
+        self.merge_with(other, true);
+    }
+
+    // `replace = false` keeps whatever an earlier set already mapped
+    pub fn merge_with(&mut self, mut other: Self, replace: bool) {
+        for (dest, derivations) in other.derivations.drain() {
+            self.derivations
+                .entry(dest)
+                .or_default()
+                .extend(derivations);
+        }
+
+        for (dest, source) in other.content {
+            let set = other.sets.get(&dest).cloned();
+
+            if self.content.contains_key(&dest) {
+                let (loser, outcome) = if replace {
+                    let by = set.clone().unwrap_or_default();
+                    (self.content.get(&dest).cloned(), Outcome::Shadowed { by })
+                } else {
+                    (Some(source.clone()), Outcome::Ignored)
+                };
+                if let Some(loser) = loser {
+                    self.mark(&dest, &loser, outcome);
+                }
+
+                if !replace {
+                    continue;
+                }
+            }
+
+            if let Some(set) = set {
+                self.sets.insert(dest.clone(), set);
+            }
+            self.insert(dest, source);
+        }
+        self.hooks.extend(other.hooks);
+    }
+
+    pub fn record(&mut self, dest: PathBuf, derivation: Derivation) {
+        self.derivations.entry(dest).or_default().push(derivation);
+    }
+
+    // a candidate that was chosen at first, but lost the dest later on
+    fn mark(&mut self, dest: &Path, source: &Path, outcome: Outcome) {
+        if let Some(derivations) = self.derivations.get_mut(dest) {
+            for derivation in derivations.iter_mut().rev() {
+                if derivation.source == source && derivation.outcome == Outcome::Chosen {
+                    derivation.outcome = outcome;
+                    return;
+                }
+            }
+        }
+    }
+
+    pub fn derivations(&self, dest: &Path) -> &[Derivation] {
+        self.derivations
+            .get(dest)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    // every candidate for every dest, in no particular order
+    pub fn all_derivations(&self) -> impl Iterator<Item = (&PathBuf, &Derivation)> {
+        self.derivations
+            .iter()
+            .flat_map(|(dest, derivations)| derivations.iter().map(move |d| (dest, d)))
+    }
+
+    // the dest a source is mapped to, if any
+    pub fn dest_of(&self, source: &Path) -> Option<&PathBuf> {
+        self.content
+            .iter()
+            .find(|(_, s)| s.as_path() == source)
+            .map(|(dest, _)| dest)
+    }
+
     pub fn remove(&mut self, dest: &Path) -> Option<PathBuf> {
         let removed = self.content.remove(dest);
         if removed.is_some() {
@@ -264,6 +710,114 @@ impl Trackfile {
         removed
     }
 
+    pub fn insert_dir(&mut self, dir: PathBuf) {
+        if self.dirs.insert(dir) {
+            self.dirty = true;
+        }
+    }
+
+    pub fn remove_dir(&mut self, dir: &Path) {
+        if self.dirs.remove(dir) {
+            self.dirty = true;
+        }
+    }
+
+    pub fn created_dir(&self, dir: &Path) -> bool {
+        self.dirs.contains(dir)
+    }
+
+    pub fn dirs(&self) -> impl Iterator<Item = &PathBuf> {
+        self.dirs.iter()
+    }
+
+    // walk up from `from`, removing every directory we created that has become empty. stops at the
+    // first one that we didn't create or that still has something in it
+    pub fn prune_dirs(&mut self, from: &Path, dry_run: bool) -> Result<Vec<PathBuf>> {
+        self.prune_dirs_after(from, dry_run, &[])
+    }
+
+    // with dry_run nothing actually goes away, so a directory counts as empty once everything left
+    // in it is something we'd have removed already
+    fn is_empty_once_pruned(dir: &Path, gone: &[PathBuf], removed: &[PathBuf]) -> Result<bool> {
+        let entries = fs::read_dir(dir)
+            .with_context(|| format!("Failed to read directory {}", dir.display()))?;
+        for entry in entries {
+            let path = entry?.path();
+            if !gone.contains(&path) && !removed.contains(&path) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    // `gone` are directories an earlier (dry) pass already reported as removed
+    fn prune_dirs_after(
+        &mut self,
+        from: &Path,
+        dry_run: bool,
+        gone: &[PathBuf],
+    ) -> Result<Vec<PathBuf>> {
+        let mut removed = vec![];
+
+        let mut dir = Some(from);
+        while let Some(d) = dir {
+            if !self.created_dir(d) {
+                break;
+            }
+
+            let walk_on = match sfs::get_status(d) {
+                FilesystemStatus::Directory => {
+                    let empty = if dry_run {
+                        Self::is_empty_once_pruned(d, gone, &removed)?
+                    } else {
+                        sfs::is_empty_dir(d)?
+                    };
+                    if !empty {
+                        break;
+                    }
+                    if !dry_run {
+                        sfs::remove_dir(d)?;
+                    }
+                    removed.push(d.to_path_buf());
+                    true
+                }
+                // gone already (e.g., removed by hand): nothing to remove (or report), just forget
+                // about it
+                FilesystemStatus::NotFound => true,
+                // something else took its place: not ours anymore, and whatever's above it has that
+                // in it, so isn't empty either
+                _ => false,
+            };
+
+            if !dry_run {
+                self.remove_dir(d);
+            }
+            if !walk_on {
+                break;
+            }
+
+            dir = d.parent();
+        }
+
+        Ok(removed)
+    }
+
+    // same as prune_dirs, but for every directory we've ever created (deepest first)
+    pub fn prune_all_dirs(&mut self, dry_run: bool) -> Result<Vec<PathBuf>> {
+        let mut removed = vec![];
+
+        let dirs: Vec<PathBuf> = self.dirs.iter().rev().cloned().collect();
+        for dir in dirs.iter() {
+            if removed.contains(dir) {
+                continue;
+            }
+            let pruned = self.prune_dirs_after(dir, dry_run, &removed)?;
+            removed.extend(pruned);
+        }
+
+        Ok(removed)
+    }
+
     pub fn get_source(&self, dest: &Path) -> Option<&PathBuf> {
         self.content.get(dest)
     }
@@ -338,3 +892,179 @@ where
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prompt::ScriptedPrompter;
+    use crate::testing;
+
+    #[test]
+    fn sources_are_where_the_set_is_not_relative_to_it() {
+        let root = tempfile::tempdir().unwrap();
+        let set = root.path().join("set");
+        for app in ["nvim", "git"] {
+            fs::create_dir_all(set.join("home").join(".config").join(app)).unwrap();
+        }
+        let config = root.path().join("dots.toml");
+        fs::write(
+            &config,
+            "[[dotfiles]]\npath = \"set\"\n\n[dotfiles.use.\"home/.config/<app>\"]\napp = \"*\"\ntarget = \"~/.config/<app>\"\n",
+        )
+        .unwrap();
+
+        let track = Trackfile::generate(
+            config.to_string_lossy().to_string(),
+            &testing::env(root.path()),
+            None,
+            &mut ScriptedPrompter::default(),
+        )
+        .unwrap();
+
+        assert_eq!(track.len(), 2);
+        for (dest, source) in track.iter() {
+            let app = dest.file_name().unwrap();
+            assert_eq!(*source, set.join("home").join(".config").join(app));
+            assert_eq!(track.source_set(dest), Some(&set));
+            assert_eq!(track.derivations(dest)[0].source, *source);
+        }
+    }
+
+    #[test]
+    fn includes_excludes_and_map_shape_what_a_set_brings() {
+        let root = tempfile::tempdir().unwrap();
+        let set = root.path().join("set");
+        let elsewhere = root.path().join("elsewhere");
+        for dir in [
+            "home/nvim",
+            "home/git",
+            "home/fish",
+            "extra/keep",
+            "extra/drop",
+        ] {
+            fs::create_dir_all(set.join(dir)).unwrap();
+        }
+        fs::write(set.join("run"), "").unwrap();
+        let config = root.path().join("dots.toml");
+        fs::write(
+            &config,
+            format!(
+                "[[dotfiles]]\npath = \"set\"\nincludes = [\"home/nvim\", \"home/git\"]\nexcludes = [\"home/git\", \"extra/drop\"]\n\n[dotfiles.map]\n\"extra\" = {:?}\n\"run\" = {:?}\n\n[dotfiles.use.\"home/<app>\"]\napp = \"*\"\ntarget = {:?}\n",
+                elsewhere.join("extra"),
+                elsewhere.join("run"),
+                elsewhere.join("home").join("<app>"),
+            ),
+        )
+        .unwrap();
+
+        let track = Trackfile::generate(
+            config.to_string_lossy().to_string(),
+            &testing::env(root.path()),
+            None,
+            &mut ScriptedPrompter::default(),
+        )
+        .unwrap();
+
+        let mut linked: Vec<(&PathBuf, &PathBuf)> = track.iter().collect();
+        linked.sort();
+        assert_eq!(
+            linked,
+            [
+                (&elsewhere.join("extra/keep"), &set.join("extra/keep")),
+                (&elsewhere.join("home/nvim"), &set.join("home/nvim")),
+                (&elsewhere.join("run"), &set.join("run")),
+            ]
+        );
+
+        let outcome = |app: &str| {
+            track.derivations(&elsewhere.join("home").join(app))[0]
+                .outcome
+                .clone()
+        };
+        assert_eq!(
+            outcome("git"),
+            Outcome::Excluded {
+                by: PathBuf::from("home/git")
+            }
+        );
+        assert_eq!(outcome("fish"), Outcome::NotIncluded);
+        assert_eq!(
+            track.derivations(&elsewhere.join("run"))[0].rule,
+            format!("map \"run\" -> \"{}\"", elsewhere.join("run").display())
+        );
+    }
+
+    // `dirs` (relative to `root`) created and remembered as ours, deepest last
+    fn created(root: &Path, dirs: &[&str]) -> (Trackfile, Vec<PathBuf>) {
+        let mut track = Trackfile::default();
+        let dirs: Vec<PathBuf> = dirs.iter().map(|d| root.join(d)).collect();
+        for dir in dirs.iter() {
+            fs::create_dir_all(dir).unwrap();
+            track.insert_dir(dir.clone());
+        }
+        (track, dirs)
+    }
+
+    #[test]
+    fn pruning_walks_up_through_everything_we_created() {
+        let root = tempfile::tempdir().unwrap();
+        let (mut track, dirs) = created(root.path(), &["a", "a/b", "a/b/c"]);
+        let [a, b, c] = [dirs[0].as_path(), dirs[1].as_path(), dirs[2].as_path()];
+
+        // a dry run reports the whole chain, and leaves it be
+        assert_eq!(track.prune_dirs(c, true).unwrap(), [c, b, a]);
+        assert!(c.exists() && track.created_dir(c));
+
+        assert_eq!(track.prune_dirs(c, false).unwrap(), [c, b, a]);
+        assert!(!a.exists());
+        assert_eq!(track.dirs().count(), 0);
+        // (and never anything above what we created)
+        assert!(root.path().exists());
+    }
+
+    #[test]
+    fn a_dry_run_counts_siblings_it_already_pruned() {
+        let root = tempfile::tempdir().unwrap();
+        let (mut track, dirs) = created(root.path(), &["a", "a/b1", "a/b2"]);
+
+        let mut pruned = track.prune_all_dirs(true).unwrap();
+        pruned.sort();
+        assert_eq!(pruned, dirs);
+        assert!(dirs.iter().all(|d| d.exists()));
+
+        // with something of the user's in there, `a` stays
+        fs::write(dirs[0].join("theirs"), "").unwrap();
+        assert_eq!(
+            track.prune_all_dirs(true).unwrap(),
+            [dirs[2].as_path(), dirs[1].as_path()]
+        );
+    }
+
+    #[test]
+    fn dirs_that_are_gone_or_replaced_are_forgotten_not_reported() {
+        let root = tempfile::tempdir().unwrap();
+        let (mut track, dirs) = created(root.path(), &["a", "a/b", "a/b/c", "x", "x/y"]);
+        let [a, b, c, x, y] = [
+            dirs[0].as_path(),
+            dirs[1].as_path(),
+            dirs[2].as_path(),
+            dirs[3].as_path(),
+            dirs[4].as_path(),
+        ];
+
+        // removed by hand: nothing to report, but what's above it is still pruned
+        fs::remove_dir(c).unwrap();
+        assert_eq!(track.prune_dirs(c, true).unwrap(), [b, a]);
+        assert_eq!(track.prune_dirs(c, false).unwrap(), [b, a]);
+        assert!(!track.created_dir(c));
+
+        // replaced: not ours anymore, and the walk ends there
+        fs::remove_dir(y).unwrap();
+        fs::write(y, "").unwrap();
+        assert!(track.prune_dirs(y, true).unwrap().is_empty());
+        assert!(track.created_dir(y));
+        assert!(track.prune_dirs(y, false).unwrap().is_empty());
+        assert!(!track.created_dir(y));
+        assert!(x.exists() && track.created_dir(x));
+    }
+}
@@ -0,0 +1,450 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use toml::{Table, Value};
+
+use crate::config::{user_config_paths, SYSTEM_CONFIG};
+use crate::prompt::{ask, Choice, Opts, Prompter};
+use crate::trackfile::Include;
+use shared::bos;
+use shared::fs as sfs;
+
+pub const TRUST_FILE: &str = "trust.toml";
+
+// what's been approved so far, pinned to the exact content it was approved for
+#[derive(Deserialize, Serialize, Default)]
+struct TrustStore {
+    #[serde(default)]
+    approved: BTreeMap<String, String>, // config (as in `Include`'s Display) -> sha256 of it
+    #[serde(default)]
+    roots: BTreeSet<PathBuf>, // directories everything in is trusted, whatever it holds
+}
+
+pub struct Gate {
+    no_shell: bool,
+    interactive: bool, // false for whatever can't ask (e.g., the language server)
+    path: PathBuf,
+    owner: Option<bos::Owner>,
+    own: Vec<PathBuf>, // the user's own configs (see `user_config_paths`)
+}
+
+static GATE: OnceLock<Gate> = OnceLock::new();
+static STORE: Mutex<Option<TrustStore>> = Mutex::new(None);
+
+pub fn configure(env: &bos::Env, no_shell: bool, interactive: bool) {
+    let _ = GATE.set(Gate {
+        no_shell,
+        interactive,
+        path: env.cache_dir.join("bos").join(TRUST_FILE),
+        owner: env.owner,
+        own: user_config_paths(env),
+    });
+}
+
+fn gate() -> &'static Gate {
+    GATE.get_or_init(|| {
+        let env = bos::Env::detect();
+        Gate {
+            no_shell: false,
+            interactive: false,
+            path: env.cache_dir.join("bos").join(TRUST_FILE),
+            owner: env.owner,
+            own: user_config_paths(&env),
+        }
+    })
+}
+
+// every command the runner is about to run goes through here first
+pub fn allow_shell(script: &str) -> Result<()> {
+    if gate().no_shell {
+        return Err(anyhow!(
+            "Not running `{}`: shell commands are disabled (--no-shell)",
+            script
+        ));
+    }
+    Ok(())
+}
+
+fn key(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+// ours: nothing along the way was cloned, and it's one of the user's own configs, the system
+// config (which only root can write), or in a directory the user said to trust
+fn is_trusted(gate: &Gate, store: &TrustStore, chain: &[Include]) -> bool {
+    if chain.iter().any(|i| i.url.is_some()) {
+        return false;
+    }
+    let Some(config) = chain.last() else {
+        return true;
+    };
+
+    let path = key(&config.path);
+    path == key(Path::new(SYSTEM_CONFIG))
+        || gate
+            .own
+            .iter()
+            .chain(store.roots.iter())
+            .any(|trusted| path.starts_with(key(trusted)))
+}
+
+fn push_script(found: &mut Vec<(String, String)>, at: String, value: &Value) {
+    if let Some(script) = value.as_str() {
+        found.push((at, script.to_string()));
+    }
+}
+
+fn when_snippets(when: &Table, at: &str, found: &mut Vec<(String, String)>) {
+    for key in ["shell", "if", "test_command"] {
+        if let Some(value) = when.get(key) {
+            push_script(found, format!("{}.{}", at, key), value);
+        }
+    }
+    for key in ["all", "any"] {
+        for (i, nested) in when
+            .get(key)
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .enumerate()
+        {
+            if let Some(nested) = nested.as_table() {
+                when_snippets(nested, &format!("{}.{}[{}]", at, key, i), found);
+            }
+        }
+    }
+    if let Some(not) = when.get("not").and_then(Value::as_table) {
+        when_snippets(not, &format!("{}.not", at), found);
+    }
+}
+
+fn use_entry_snippets(entry: &Table, at: &str, found: &mut Vec<(String, String)>) {
+    for (key, value) in entry.iter() {
+        let here = format!("{}.{}", at, key);
+        match (key.as_str(), value) {
+            ("when", Value::Table(when)) => when_snippets(when, &here, found),
+            // paths and the rest of the vars are interpolated without a shell
+            ("target" | "exclude", _) => {}
+            (_, Value::Table(var)) => {
+                if let Some(shell) = var.get("shell") {
+                    push_script(found, format!("{}.shell", here), shell);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// [dots] or one [[dotfiles]]: its `use` entries and hooks
+fn options_snippets(options: &Table, at: &str, found: &mut Vec<(String, String)>) {
+    if let Some(uses) = options.get("use").and_then(Value::as_table) {
+        for (key, value) in uses.iter() {
+            let here = format!("{}.use.{:?}", at, key);
+            match value {
+                Value::Table(entry) => use_entry_snippets(entry, &here, found),
+                Value::Array(entries) => {
+                    for (i, entry) in entries.iter().enumerate() {
+                        if let Some(entry) = entry.as_table() {
+                            use_entry_snippets(entry, &format!("{}[{}]", here, i), found);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    if let Some(hooks) = options.get("hooks").and_then(Value::as_table) {
+        for (kind, command) in hooks.iter() {
+            push_script(found, format!("{}.hooks.{}", at, kind), command);
+        }
+    }
+}
+
+// every bit of a config that would end up in front of a shell, and where it is
+pub fn shell_snippets(config: &Table) -> Vec<(String, String)> {
+    let mut found = vec![];
+
+    if let Some(dots) = config.get("dots").and_then(Value::as_table) {
+        options_snippets(dots, "dots", &mut found);
+    }
+    for (i, dotfile) in config
+        .get("dotfiles")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .enumerate()
+    {
+        if let Some(dotfile) = dotfile.as_table() {
+            options_snippets(dotfile, &format!("dotfiles[{}]", i), &mut found);
+        }
+    }
+
+    found
+}
+
+fn load_store(gate: &Gate) -> Result<TrustStore> {
+    if !sfs::is_file(&gate.path) {
+        return Ok(TrustStore::default());
+    }
+    let content = sfs::read_to_string(&gate.path)?;
+    toml::from_str(&content)
+        .with_context(|| format!("Failed to parse trust file {}", gate.path.display()))
+}
+
+fn save_store(gate: &Gate, store: &TrustStore) -> Result<()> {
+    let content = toml::to_string_pretty(store).context("Failed to serialize trust file")?;
+    if let Some(parent) = gate.path.parent() {
+        sfs::create_dir_all(parent)?;
+    }
+    sfs::write_file(&gate.path, content.as_bytes())
+        .with_context(|| format!("Failed to write trust file {}", gate.path.display()))?;
+    sfs::chown(&gate.path, gate.owner)
+}
+
+// before a config is loaded (which is when its `when`s and vars run): an untrusted one with shell
+// code in it needs approval for exactly this content, asked for once and remembered after.
+// `content` is what the caller is about to parse, so what's approved is what runs
+pub fn vet(chain: &[Include], content: &str, prompter: &mut dyn Prompter) -> Result<()> {
+    let gate = gate();
+    // nothing's going to run anyway
+    if gate.no_shell {
+        return Ok(());
+    }
+
+    let mut store = STORE.lock().unwrap();
+    if store.is_none() {
+        *store = Some(load_store(gate)?);
+    }
+    review(gate, store.as_mut().unwrap(), chain, content, prompter)
+}
+
+fn review(
+    gate: &Gate,
+    store: &mut TrustStore,
+    chain: &[Include],
+    content: &str,
+    prompter: &mut dyn Prompter,
+) -> Result<()> {
+    let Some(config) = chain.last() else {
+        return Ok(());
+    };
+    if is_trusted(gate, store, chain) {
+        return Ok(());
+    }
+
+    // a broken config fails on its own once it's loaded
+    let Ok(table) = toml::from_str::<Table>(content) else {
+        return Ok(());
+    };
+    let snippets = shell_snippets(&table);
+    if snippets.is_empty() {
+        return Ok(());
+    }
+
+    let id = config.to_string();
+    let hash = format!("{:x}", Sha256::digest(content.as_bytes()));
+    if store.approved.get(&id) == Some(&hash) {
+        return Ok(());
+    }
+
+    let chain_str: Vec<String> = chain.iter().map(Include::to_string).collect();
+    let mut listing = format!(
+        "{} is untrusted ({}), and wants to run:\n",
+        id,
+        chain_str.join(" -> ")
+    );
+    for (at, script) in snippets.iter() {
+        listing.push_str(&format!("  {}: {}\n", at, script));
+    }
+
+    if !gate.interactive {
+        return Err(anyhow!(
+            "{}Not running shell commands from {} until they're approved (run a dots command interactively to review them, or pass --no-shell)",
+            listing,
+            id
+        ));
+    }
+
+    let changed = if store.approved.contains_key(&id) {
+        " (it changed since it was last approved)"
+    } else {
+        ""
+    };
+    // anything cloned stays untrusted wherever it is, so there's no directory worth offering
+    let cloned = chain.iter().any(|i| i.url.is_some());
+    let opts = if cloned { Opts::YesNo } else { Opts::Trust };
+    match ask(prompter, format!("{}Run these{}?", listing, changed), opts) {
+        Some(Choice::Yes { .. }) => {
+            store.approved.insert(id, hash);
+        }
+        Some(Choice::Trust) => {
+            let dir = config.path.parent().unwrap_or(&config.path);
+            store.roots.insert(key(dir));
+        }
+        _ => {
+            return Err(anyhow!(
+                "Declined to run shell commands from {} (pass --no-shell to go without them)",
+                id
+            ))
+        }
+    }
+
+    save_store(gate, store)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prompt::ScriptedPrompter;
+    use crate::testing;
+
+    // a user whose home and cache are inside `root`
+    fn gate(root: &Path) -> Gate {
+        let env = testing::env(root);
+        Gate {
+            no_shell: false,
+            interactive: true,
+            path: env.cache_dir.join("bos").join(TRUST_FILE),
+            owner: None,
+            own: user_config_paths(&env),
+        }
+    }
+
+    #[test]
+    fn approval_is_asked_once_per_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let config = root.join("cloned").join("dots.toml");
+        fs::create_dir_all(config.parent().unwrap()).unwrap();
+        let content = "[dots.hooks]\npost_link = \"echo hi\"\n";
+        fs::write(&config, content).unwrap();
+
+        let gate = gate(root);
+        let chain = [Include {
+            path: config.clone(),
+            url: Some("https://example.com/dots.git".to_string()),
+        }];
+        let mut store = TrustStore::default();
+        assert!(!is_trusted(&gate, &store, &chain));
+
+        // declining stops it, and nothing is remembered
+        let mut prompter = ScriptedPrompter::new(["n"]);
+        assert!(review(&gate, &mut store, &chain, content, &mut prompter).is_err());
+        assert!(prompter.transcript[0].contains("dots.hooks.post_link: echo hi"));
+        assert!(store.approved.is_empty());
+
+        // running out of answers counts as declining
+        let mut prompter = ScriptedPrompter::new(Vec::<String>::new());
+        assert!(review(&gate, &mut store, &chain, content, &mut prompter).is_err());
+
+        let mut prompter = ScriptedPrompter::new(["y"]);
+        review(&gate, &mut store, &chain, content, &mut prompter).unwrap();
+        assert!(sfs::is_file(&gate.path));
+
+        // the same content isn't asked about again
+        let mut prompter = ScriptedPrompter::new(Vec::<String>::new());
+        review(&gate, &mut store, &chain, content, &mut prompter).unwrap();
+        assert!(prompter.transcript.is_empty());
+
+        // ... but changed content is
+        let changed = "[dots.hooks]\npost_link = \"echo bye\"\n";
+        let mut prompter = ScriptedPrompter::new(["n"]);
+        assert!(review(&gate, &mut store, &chain, changed, &mut prompter).is_err());
+        assert!(prompter.transcript[0].contains("changed since it was last approved"));
+
+        // no shell code, nothing to ask
+        let plain = "[dots.use]\n\"home\" = \"~\"\n";
+        let mut prompter = ScriptedPrompter::new(Vec::<String>::new());
+        review(&gate, &mut store, &chain, plain, &mut prompter).unwrap();
+
+        // what's checked is what was passed in, not whatever the file holds by then
+        fs::write(&config, changed).unwrap();
+        let mut prompter = ScriptedPrompter::new(Vec::<String>::new());
+        review(&gate, &mut store, &chain, content, &mut prompter).unwrap();
+    }
+
+    #[test]
+    fn only_the_users_own_configs_are_trusted_outright() {
+        let dir = tempfile::tempdir().unwrap();
+        let gate = gate(dir.path());
+        let store = TrustStore::default();
+        let local = |path: PathBuf| [Include { path, url: None }];
+
+        let home = dir.path().join("home");
+        assert!(is_trusted(&gate, &store, &local(home.join(".dots.toml"))));
+        // (wherever XDG_CONFIG_HOME puts it)
+        let user_config = gate.own[0].join("config.toml");
+        assert!(user_config.ends_with("dots/config.toml"));
+        assert!(is_trusted(&gate, &store, &local(user_config)));
+        assert!(is_trusted(
+            &gate,
+            &store,
+            &local(PathBuf::from(SYSTEM_CONFIG))
+        ));
+
+        // being under home (and outside the cache) isn't enough
+        assert!(!is_trusted(
+            &gate,
+            &store,
+            &local(home.join("src").join("someones-dots").join("dots.toml"))
+        ));
+        assert!(!is_trusted(
+            &gate,
+            &store,
+            &local(home.join(".dots.toml.d"))
+        ));
+    }
+
+    #[test]
+    fn a_trusted_directory_is_remembered_for_everything_in_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let gate = gate(dir.path());
+        let dots = dir.path().join("home").join("dotfiles");
+        fs::create_dir_all(dots.join("nested")).unwrap();
+        let content = "[dots.hooks]\npost_link = \"echo hi\"\n";
+        let chain = |path: PathBuf| [Include { path, url: None }];
+        let mut store = TrustStore::default();
+
+        let mut prompter = ScriptedPrompter::new(["t"]);
+        review(
+            &gate,
+            &mut store,
+            &chain(dots.join("dots.toml")),
+            content,
+            &mut prompter,
+        )
+        .unwrap();
+        assert!(prompter.transcript[0].contains("[T]rust its directory"));
+        assert!(store.approved.is_empty());
+
+        // anything in there, whatever it holds, isn't asked about again
+        let mut prompter = ScriptedPrompter::default();
+        let nested = chain(dots.join("nested").join("dots.toml"));
+        review(
+            &gate,
+            &mut store,
+            &nested,
+            "[dots.hooks]\npost_link = \"true\"\n",
+            &mut prompter,
+        )
+        .unwrap();
+        assert!(prompter.transcript.is_empty());
+        assert_eq!(load_store(&gate).unwrap().roots, store.roots);
+
+        // ... unless it was cloned there
+        let cloned = [Include {
+            path: dots.join("dots.toml"),
+            url: Some("https://example.com/dots.git".to_string()),
+        }];
+        assert!(!is_trusted(&gate, &store, &cloned));
+        let mut prompter = ScriptedPrompter::new(["t", "n"]);
+        assert!(review(&gate, &mut store, &cloned, content, &mut prompter).is_err());
+        assert!(!prompter.transcript[0].contains("[T]rust"));
+        assert!(prompter.transcript[2].starts_with("Invalid input."));
+    }
+}
@@ -0,0 +1,426 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use anyhow::{Context, Result};
+use globset::GlobBuilder;
+
+use shared::bos;
+
+use crate::*;
+
+// `when` gets evaluated while the config is being deserialized, which has no way of handing us
+// the env, so run() leaves it here first (anything else falls back to detecting it)
+static ENV: OnceLock<bos::Env> = OnceLock::new();
+
+pub fn set_env(env: &bos::Env) {
+    let _ = ENV.set(env.clone());
+}
+
+pub fn env() -> &'static bos::Env {
+    ENV.get_or_init(bos::Env::detect)
+}
+
+// exact unless it has glob characters in it
+pub fn pattern_matches(pattern: &str, value: &str) -> Result<bool> {
+    let glob = GlobBuilder::new(pattern)
+        .build()
+        .with_context(|| format!("Invalid pattern `{}` in `when`", pattern))?;
+    Ok(glob.compile_matcher().is_match(value))
+}
+
+pub fn hostname() -> Option<String> {
+    nix::unistd::gethostname()
+        .ok()
+        .map(|h| h.to_string_lossy().to_string())
+}
+
+// what `command -v` would find, minus the shell
+pub fn find_command(name: &str, env: &bos::Env) -> Option<PathBuf> {
+    let is_executable = |path: &Path| {
+        path.metadata()
+            .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false)
+    };
+
+    if name.contains('/') {
+        let path = env.expand_home(Path::new(name));
+        return is_executable(&path).then_some(path);
+    }
+
+    std::env::split_paths(&env.get("PATH")?)
+        .map(|dir| dir.join(name))
+        .find(|path| is_executable(path))
+}
+
+// how a `when` came out, and the predicates that settled it: the one that failed, or every one
+// that had to hold. nested ones are named by where they sit, e.g. `any[1].command = "zsh"`
+#[derive(Debug, Clone)]
+pub struct Verdict {
+    pub holds: bool,
+    pub decided_by: Vec<String>,
+}
+
+impl Verdict {
+    fn failed(predicate: String) -> Self {
+        Self {
+            holds: false,
+            decided_by: vec![predicate],
+        }
+    }
+
+    fn within(mut self, prefix: &str) -> Self {
+        for predicate in self.decided_by.iter_mut() {
+            *predicate = format!("{}.{}", prefix, predicate);
+        }
+        self
+    }
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.decided_by.is_empty() {
+            return write!(f, "nothing to check");
+        }
+        write!(f, "{}", self.decided_by.join(", "))
+    }
+}
+
+impl RawUseWhen {
+    // every predicate that's set has to hold, and we stop at the first one that doesn't. the
+    // native ones go first so a false one spares us spawning a shell for the rest
+    pub fn evaluate(&self, env: &bos::Env, vars: &HashMap<String, String>) -> Result<Verdict> {
+        let mut held = vec![];
+
+        if let Some(os) = &self.os {
+            let predicate = format!("os = {:?}", os);
+            if !pattern_matches(os, &env.os)? {
+                return Ok(Verdict::failed(predicate));
+            }
+            held.push(predicate);
+        }
+        if let Some(distro) = &self.distro {
+            let predicate = format!("distro = {:?}", distro);
+            let current = env.distro.as_deref();
+            if !current.map_or(Ok(false), |c| pattern_matches(distro, c))? {
+                return Ok(Verdict::failed(predicate));
+            }
+            held.push(predicate);
+        }
+        if let Some(host) = &self.hostname {
+            let predicate = format!("hostname = {:?}", host);
+            let current = hostname();
+            if !current.map_or(Ok(false), |c| pattern_matches(host, &c))? {
+                return Ok(Verdict::failed(predicate));
+            }
+            held.push(predicate);
+        }
+        if let Some(user) = &self.user {
+            let predicate = format!("user = {:?}", user);
+            if !pattern_matches(user, &env.user)? {
+                return Ok(Verdict::failed(predicate));
+            }
+            held.push(predicate);
+        }
+
+        // an unset var never matches, not even `*`
+        if let Some(env_vars) = &self.env {
+            for (var, pattern) in env_vars.iter() {
+                let predicate = format!("env.{} = {:?}", var, pattern);
+                let current = env.get(var);
+                if !current.map_or(Ok(false), |c| pattern_matches(pattern, &c))? {
+                    return Ok(Verdict::failed(predicate));
+                }
+                held.push(predicate);
+            }
+        }
+
+        if let Some(path) = &self.exists {
+            let predicate = format!("exists = {:?}", path);
+            if !sfs::path_exists(&env.expand_home(Path::new(path))) {
+                return Ok(Verdict::failed(predicate));
+            }
+            held.push(predicate);
+        }
+        if let Some(command) = &self.command {
+            let predicate = format!("command = {:?}", command);
+            if find_command(command, env).is_none() {
+                return Ok(Verdict::failed(predicate));
+            }
+            held.push(predicate);
+        }
+
+        if let Some(guix_home) = self.guix_home {
+            let predicate = format!("guix_home = {}", guix_home);
+            if guix_home != env.using_guix_home {
+                return Ok(Verdict::failed(predicate));
+            }
+            held.push(predicate);
+        }
+        if let Some(nix_home) = self.nix_home {
+            let predicate = format!("nix_home = {}", nix_home);
+            if nix_home != env.using_nix_home {
+                return Ok(Verdict::failed(predicate));
+            }
+            held.push(predicate);
+        }
+
+        if let Some(all) = &self.all {
+            for (i, when) in all.iter().enumerate() {
+                let verdict = when.evaluate(env, vars)?.within(&format!("all[{}]", i));
+                if !verdict.holds {
+                    return Ok(verdict);
+                }
+                held.extend(verdict.decided_by);
+            }
+        }
+        // the first branch that holds settles it; if none do, they all had a say
+        if let Some(any) = &self.any {
+            let mut failed = vec![];
+            let mut found = None;
+            for (i, when) in any.iter().enumerate() {
+                let verdict = when.evaluate(env, vars)?.within(&format!("any[{}]", i));
+                if verdict.holds {
+                    found = Some(verdict);
+                    break;
+                }
+                failed.extend(verdict.decided_by);
+            }
+
+            match found {
+                Some(verdict) => held.extend(verdict.decided_by),
+                None if failed.is_empty() => return Ok(Verdict::failed("any = []".to_string())),
+                None => {
+                    return Ok(Verdict {
+                        holds: false,
+                        decided_by: failed,
+                    })
+                }
+            }
+        }
+        if let Some(not) = &self.not {
+            let inner = not.evaluate(env, vars)?;
+            let verdict = Verdict {
+                holds: !inner.holds,
+                decided_by: inner.decided_by,
+            }
+            .within("not");
+            if !verdict.holds {
+                return Ok(verdict);
+            }
+            held.extend(verdict.decided_by);
+        }
+
+        if let Some(s) = &self.shell {
+            let predicate = format!("shell = {:?}", s);
+            if !shell::runner().run_for_bool(s, vars)? {
+                return Ok(Verdict::failed(predicate));
+            }
+            held.push(predicate);
+        }
+        if let Some(i) = &self.test_if {
+            let predicate = format!("if = {:?}", i);
+            if !shell::runner().test_if(i, vars)? {
+                return Ok(Verdict::failed(predicate));
+            }
+            held.push(predicate);
+        }
+        if let Some(c) = &self.test_command {
+            let predicate = format!("test_command = {:?}", c);
+            if !shell::runner().test_command(c, vars)? {
+                return Ok(Verdict::failed(predicate));
+            }
+            held.push(predicate);
+        }
+
+        Ok(Verdict {
+            holds: true,
+            decided_by: held,
+        })
+    }
+
+    // whether anything in here (however deeply nested) would have to spawn a shell
+    pub fn uses_shell(&self) -> bool {
+        self.shell.is_some()
+            || self.test_if.is_some()
+            || self.test_command.is_some()
+            || self.all.iter().flatten().any(RawUseWhen::uses_shell)
+            || self.any.iter().flatten().any(RawUseWhen::uses_shell)
+            || self.not.as_deref().is_some_and(RawUseWhen::uses_shell)
+    }
+}
+
+#[derive(Deserialize)]
+struct Standalone {
+    when: RawUseWhenType,
+}
+
+// a `when` table taken out of its config (`value` being the table itself), evaluated against
+// `env` for `dots check`. None if it's a plain bool, or would take running a shell to settle
+pub fn check_when(value: &str, env: &bos::Env) -> Option<Result<Verdict>> {
+    let text = format!("when = {}", value);
+    let standalone: Standalone = toml::from_str(&text).ok()?;
+
+    match standalone.when {
+        RawUseWhenType::Table(when) if !when.uses_shell() => {
+            Some(when.evaluate(env, &HashMap::new()))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env() -> bos::Env {
+        let mut env = bos::Env::detect();
+        env.os = "linux".to_string();
+        env.home = "/home/test".into();
+        env.user = "test".to_string();
+        env
+    }
+
+    fn when(text: &str) -> RawUseWhen {
+        toml::from_str(text).unwrap()
+    }
+
+    fn verdict(text: &str) -> Verdict {
+        when(text).evaluate(&env(), &HashMap::new()).unwrap()
+    }
+
+    // a shell predicate that leaves `marker` behind if it ever runs
+    fn touch(marker: &Path) -> String {
+        format!(
+            "shell = {:?}",
+            format!(
+                "touch {} && echo true",
+                shell::quote(&marker.to_string_lossy())
+            )
+        )
+    }
+
+    // nothing sets this, so it's as unset as it gets
+    const UNSET: &str = "DOTS_WHEN_TEST_UNSET";
+
+    #[test]
+    fn native_predicates_match_exactly_or_by_glob() {
+        let v = verdict("os = \"linux\"\nuser = \"t*\"");
+        assert!(v.holds);
+        assert_eq!(v.decided_by, ["os = \"linux\"", "user = \"t*\""]);
+
+        let v = verdict("os = \"linux\"\nuser = \"tes\"");
+        assert!(!v.holds);
+        assert_eq!(v.decided_by, ["user = \"tes\""]);
+
+        assert!(when("os = \"[\"")
+            .evaluate(&env(), &HashMap::new())
+            .is_err());
+    }
+
+    #[test]
+    fn an_unset_env_var_never_matches() {
+        let v = verdict(&format!("env = {{ {} = \"*\" }}", UNSET));
+        assert!(!v.holds);
+        assert_eq!(v.decided_by, [format!("env.{} = \"*\"", UNSET)]);
+
+        // HOME and USER are the ones we act for, not whatever's in the environment
+        assert!(verdict("env = { HOME = \"/home/*\", USER = \"test\" }").holds);
+    }
+
+    #[test]
+    fn native_predicates_settle_it_before_the_shell_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("ran");
+
+        let v = verdict(&format!("os = \"not-this-os\"\n{}", touch(&marker)));
+        assert!(!v.holds);
+        assert_eq!(v.decided_by, ["os = \"not-this-os\""]);
+        assert!(!marker.exists());
+
+        let v = verdict(&format!("os = \"linux\"\n{}", touch(&marker)));
+        assert!(v.holds);
+        assert!(marker.exists());
+    }
+
+    #[test]
+    fn all_stops_at_the_first_that_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("ran");
+
+        let v = verdict(&format!(
+            "all = [{{ user = \"test\" }}, {{ user = \"nope\" }}, {{ {} }}]",
+            touch(&marker)
+        ));
+        assert!(!v.holds);
+        assert_eq!(v.decided_by, ["all[1].user = \"nope\""]);
+        assert!(!marker.exists());
+
+        let v = verdict("all = [{ user = \"test\" }, { os = \"linux\" }]");
+        assert!(v.holds);
+        assert_eq!(
+            v.decided_by,
+            ["all[0].user = \"test\"", "all[1].os = \"linux\""]
+        );
+    }
+
+    #[test]
+    fn any_is_settled_by_the_first_that_holds() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("ran");
+
+        let v = verdict(&format!(
+            "any = [{{ command = \"dots-no-such-command\" }}, {{ command = \"sh\" }}, {{ {} }}]",
+            touch(&marker)
+        ));
+        assert!(v.holds);
+        assert_eq!(v.decided_by, ["any[1].command = \"sh\""]);
+        assert!(!marker.exists());
+
+        // none of them did, so they all had a say
+        let v = verdict("any = [{ user = \"a\" }, { user = \"b\" }]");
+        assert!(!v.holds);
+        assert_eq!(v.decided_by, ["any[0].user = \"a\"", "any[1].user = \"b\""]);
+    }
+
+    #[test]
+    fn empty_any_never_holds() {
+        let v = verdict("any = []");
+        assert!(!v.holds);
+        assert_eq!(v.to_string(), "any = []");
+
+        // ... while nothing at all always does
+        let v = verdict("");
+        assert!(v.holds);
+        assert_eq!(v.to_string(), "nothing to check");
+    }
+
+    #[test]
+    fn not_flips_what_is_inside() {
+        let v = verdict("not = { user = \"test\" }");
+        assert!(!v.holds);
+        assert_eq!(v.decided_by, ["not.user = \"test\""]);
+
+        let v = verdict("not = { any = [{ user = \"a\" }, { os = \"b\" }] }");
+        assert!(v.holds);
+        assert_eq!(
+            v.to_string(),
+            "not.any[0].user = \"a\", not.any[1].os = \"b\""
+        );
+    }
+
+    #[test]
+    fn check_leaves_out_anything_needing_a_shell() {
+        let env = env();
+
+        let v = check_when("{ not = { user = \"test\" } }", &env)
+            .unwrap()
+            .unwrap();
+        assert!(!v.holds);
+
+        assert!(check_when("{ any = [{ shell = \"echo true\" }] }", &env).is_none());
+        assert!(check_when("true", &env).is_none());
+    }
+}
@@ -1,53 +1,164 @@
+use schemars::JsonSchema;
 use serde::Deserialize;
-use std::{collections::HashSet, env::VarError, path::PathBuf};
-
-//use shellexpand;
-//
-//pub mod env {
-//    pub enum Env {
-//        Var(&str),
-//        BosDir,
-//        CacheDir,
-//        Home,
-//        User,
-//        OS,
-//        Distro,
-//        SystemName,
-//        GuixHomeName,
-//        NixHomeName,
-//    }
-//    pub struct Env {
-//        pub bos_dir: PathBuf,
-//        pub cache_dir: PathBuf,
-//        pub home: PathBuf,
-//        pub user: String,
-//        pub os: String,
-//        pub using_guix_system: bool,
-//        pub using_nix_system: bool,
-//        pub system_name: String,
-//        pub guix_home_name: String,
-//        pub nix_home_name: String,
-//    }
-//    impl Env {
-//        pub fn detect() {
-//            Self {
-//                cache_dir: Self::get(Env::Home),
-//                home: Self::get(Env::Home),
-//            }
-//        }
-//
-//        pub fn get(var: Env) -> Result<String, VarError> {
-//            std::env::var(match var {
-//                Env::CacheDir => "BOS_CACHE_DIR",
-//                Env::Var(v) => v,
-//                ..rest => match rest {},
-//            })
-//        }
-//
-//        pub fn expand() {}
-//    }
-//}
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use nix::unistd::{Gid, Uid, User};
+
+// uid/gid that files we create should end up belonging to, when that isn't whoever we're running as
+// (i.e., `sudo dots link` or `--user <name>`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Owner {
+    pub uid: Uid,
+    pub gid: Gid,
+}
+
+#[derive(Debug, Clone)]
+pub struct Env {
+    pub cache_dir: PathBuf,
+    pub home: PathBuf,
+    pub user: String,
+    pub owner: Option<Owner>,
+    pub os: String,
+    pub distro: Option<String>,
+    pub using_guix_system: bool,
+    pub using_nix_system: bool,
+    pub using_guix_home: bool,
+    pub using_nix_home: bool,
+    pub system_name: String,
+    pub guix_home_name: String,
+    pub nix_home_name: String,
+}
+
+// sudo and doas both leave the name of whoever invoked them behind
+const INVOKING_USER_VARS: [&str; 2] = ["SUDO_USER", "DOAS_USER"];
+
+impl Env {
+    pub fn detect() -> Self {
+        Self::detect_for(None).unwrap_or_else(|e| panic!("Failed to detect environment: {}", e))
+    }
+
+    // `user` is an explicit `--user <name>`; otherwise we act on behalf of whoever invoked
+    // sudo/doas (if that's how we got here), falling back to ourselves
+    pub fn detect_for(user: Option<&str>) -> Result<Self, String> {
+        let target = match user {
+            Some(name) => Some(name.to_string()),
+            None => Self::invoking_user(),
+        };
+
+        let (user, home, owner) = match target {
+            Some(name) => {
+                let entry = User::from_name(&name)
+                    .map_err(|e| format!("Failed to look up user '{}': {}", name, e))?
+                    .ok_or_else(|| format!("No such user '{}'", name))?;
+
+                let owner = if entry.uid == Uid::effective() {
+                    None
+                } else {
+                    Some(Owner {
+                        uid: entry.uid,
+                        gid: entry.gid,
+                    })
+                };
+
+                (entry.name, entry.dir, owner)
+            }
+            None => {
+                let current = User::from_uid(Uid::effective()).ok().flatten();
+                let user = Self::get_var("USER")
+                    .or_else(|| current.as_ref().map(|u| u.name.clone()))
+                    .ok_or_else(|| "Could not determine the current user".to_string())?;
+                let home = Self::get_var("HOME")
+                    .map(PathBuf::from)
+                    .or_else(|| current.as_ref().map(|u| u.dir.clone()))
+                    .ok_or_else(|| "Could not determine the home directory".to_string())?;
+
+                (user, home, None)
+            }
+        };
+
+        // XDG_CACHE_HOME belongs to whoever's environment we inherited, which isn't necessarily
+        // the user we're acting for
+        let cache_dir = match Self::get_var("BOS_CACHE_DIR") {
+            Some(dir) => PathBuf::from(dir),
+            None => match Self::get_var("XDG_CACHE_HOME") {
+                Some(dir) if owner.is_none() => PathBuf::from(dir),
+                _ => home.join(".cache"),
+            },
+        };
+
+        let distro = Self::detect_distro();
+
+        Ok(Self {
+            using_guix_system: distro.as_deref() == Some("guix"),
+            using_nix_system: distro.as_deref() == Some("nixos"),
+            using_guix_home: home.join(".guix-home").exists(),
+            using_nix_home: home.join(".nix-profile").exists()
+                || home.join(".local/state/home-manager").exists(),
+            system_name: Self::get_var("BOS_SYSTEM_NAME").unwrap_or_default(),
+            guix_home_name: Self::get_var("BOS_HOME_NAME").unwrap_or_default(),
+            nix_home_name: Self::get_var("BOS_NIX_HOME_NAME")
+                .or_else(|| Self::get_var("BOS_HOME_NAME"))
+                .unwrap_or_default(),
+            os: std::env::consts::OS.to_string(),
+            distro,
+            cache_dir,
+            home,
+            user,
+            owner,
+        })
+    }
+
+    pub fn invoking_user() -> Option<String> {
+        Self::invoker(Uid::effective().is_root(), Self::get_var)
+    }
+
+    // only trusted while we're actually root; a regular user could just set SUDO_USER themselves
+    fn invoker(is_root: bool, get_var: impl Fn(&str) -> Option<String>) -> Option<String> {
+        if !is_root {
+            return None;
+        }
+
+        INVOKING_USER_VARS
+            .iter()
+            .filter_map(|var| get_var(var))
+            .find(|name| !name.is_empty() && name != "root")
+    }
+
+    fn detect_distro() -> Option<String> {
+        let os_release = std::fs::read_to_string("/etc/os-release").ok()?;
+        os_release.lines().find_map(|line| {
+            line.strip_prefix("ID=")
+                .map(|id| id.trim_matches('"').to_string())
+        })
+    }
+
+    fn get_var(var: &str) -> Option<String> {
+        std::env::var(var).ok().filter(|v| !v.is_empty())
+    }
+
+    // HOME resolves to the home of the user we're acting for, not necessarily $HOME
+    pub fn get(&self, var: &str) -> Option<String> {
+        match var {
+            "HOME" => Some(self.home.to_string_lossy().to_string()),
+            "USER" => Some(self.user.clone()),
+            _ => Self::get_var(var),
+        }
+    }
+
+    pub fn expand_home(&self, path: &Path) -> PathBuf {
+        match path.strip_prefix("~") {
+            Ok(rest) => self.home.join(rest),
+            Err(_) => path.to_path_buf(),
+        }
+    }
+}
 
+// [general]
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct GeneralConfig {
     inherits: Option<HashSet<String>>, // determines whether and what to inherit from the current config state
     strict: Option<bool>,
@@ -72,3 +183,43 @@ impl GeneralConfig {
 //pub struct Bos {
 //    //args:
 //}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars<'a>(set: &'a [(&str, &str)]) -> impl Fn(&str) -> Option<String> + 'a {
+        |var| {
+            set.iter()
+                .find(|(name, _)| *name == var)
+                .map(|(_, value)| value.to_string())
+        }
+    }
+
+    #[test]
+    fn the_invoking_user_is_only_believed_as_root() {
+        let sudo = [("SUDO_USER", "alice")];
+        assert_eq!(Env::invoker(true, vars(&sudo)), Some("alice".to_string()));
+        assert_eq!(Env::invoker(false, vars(&sudo)), None);
+
+        // `sudo -i` from root, then doas: root isn't anyone to act for
+        let both = [("SUDO_USER", "root"), ("DOAS_USER", "bob")];
+        assert_eq!(Env::invoker(true, vars(&both)), Some("bob".to_string()));
+        assert_eq!(Env::invoker(true, vars(&[])), None);
+    }
+
+    #[test]
+    fn another_users_home_comes_from_passwd() {
+        let env = Env::detect_for(Some("root")).unwrap();
+        assert_eq!(env.user, "root");
+        assert_eq!(env.home, User::from_name("root").unwrap().unwrap().dir);
+        // what we create is only handed over when we aren't already them
+        assert_eq!(
+            env.owner.map(|o| o.uid),
+            (!Uid::effective().is_root()).then_some(Uid::from_raw(0))
+        );
+
+        let e = Env::detect_for(Some("no-such-user-here")).unwrap_err();
+        assert!(e.contains("No such user"), "{}", e);
+    }
+}
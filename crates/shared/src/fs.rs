@@ -4,6 +4,8 @@ use std::path::{Path, PathBuf};
 use anyhow;
 use anyhow::Result;
 
+use crate::bos::Owner;
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum FilesystemStatus {
     NotFound,
@@ -153,12 +155,137 @@ pub fn remove_dir_all(path: &Path) -> Result<()> {
     })
 }
 
+// only ever removes empty directories
+pub fn remove_dir(path: &Path) -> Result<()> {
+    std::fs::remove_dir(path).map_err(|e| {
+        anyhow::Error::new(e).context(format!("Failed to remove directory {}", path.display()))
+    })
+}
+
+// rename, falling back to copy + remove when `to` is on another filesystem
+pub fn move_file(path: &Path, to: &Path) -> Result<()> {
+    if std::fs::rename(path, to).is_ok() {
+        return Ok(());
+    }
+
+    if is_dir(path) && !is_symlink(path) {
+        copy_dir_all(path, to)?;
+        remove_dir_all(path)
+    } else {
+        copy_file(path, to)?;
+        remove_file(path)
+    }
+}
+
+fn copy_file(path: &Path, to: &Path) -> Result<()> {
+    std::fs::copy(path, to).map(|_| ()).map_err(|e| {
+        anyhow::Error::new(e).context(format!(
+            "Failed to copy {} to {}",
+            path.display(),
+            to.display()
+        ))
+    })
+}
+
+// symlinks inside are recreated as is, not followed
+pub fn copy_dir_all(path: &Path, to: &Path) -> Result<()> {
+    create_dir_all(to)?;
+
+    let entries = std::fs::read_dir(path).map_err(|e| {
+        anyhow::Error::new(e).context(format!("Failed to read directory {}", path.display()))
+    })?;
+    for entry in entries {
+        let entry = entry?;
+        let from = entry.path();
+        let dest = to.join(entry.file_name());
+
+        if is_symlink(&from) {
+            create_symlink(&read_link(&from)?, &dest)?;
+        } else if is_dir(&from) {
+            copy_dir_all(&from, &dest)?;
+        } else {
+            copy_file(&from, &dest)?;
+        }
+    }
+
+    Ok(())
+}
+
+// moves whatever is at `path` aside (to `<name>.bak`, or `<name>.bak.N` if that's taken) and hands
+// back where it ended up
+pub fn backup(path: &Path) -> Result<PathBuf> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("Nothing to back up at {}", path.display()))?
+        .to_string_lossy()
+        .to_string();
+
+    let mut backup = path.with_file_name(format!("{}.bak", name));
+    let mut n = 1;
+    while symlink_metadata(&backup)?.is_some() {
+        backup = path.with_file_name(format!("{}.bak.{}", name, n));
+        n += 1;
+    }
+
+    std::fs::rename(path, &backup).map_err(|e| {
+        anyhow::Error::new(e).context(format!(
+            "Failed to back up {} to {}",
+            path.display(),
+            backup.display()
+        ))
+    })?;
+
+    Ok(backup)
+}
+
+pub fn is_empty_dir(path: &Path) -> Result<bool> {
+    let mut entries = std::fs::read_dir(path).map_err(|e| {
+        anyhow::Error::new(e).context(format!("Failed to read directory {}", path.display()))
+    })?;
+    Ok(entries.next().is_none())
+}
+
 pub fn create_dir_all(path: &Path) -> Result<()> {
     std::fs::create_dir_all(path).map_err(|e| {
         anyhow::Error::new(e).context(format!("Failed to create directory {}", path.display()))
     })
 }
 
+// like create_dir_all, but hands back the directories that didn't exist before (outermost first)
+pub fn create_dir_all_missing(path: &Path) -> Result<Vec<PathBuf>> {
+    let mut missing: Vec<PathBuf> = path
+        .ancestors()
+        .take_while(|dir| !dir.as_os_str().is_empty() && !path_exists(dir))
+        .map(Path::to_path_buf)
+        .collect();
+    missing.reverse();
+
+    create_dir_all(path)?;
+
+    Ok(missing)
+}
+
+// doesn't follow symlinks, so it's safe to use on the links themselves
+pub fn chown(path: &Path, owner: Option<Owner>) -> Result<()> {
+    let Some(owner) = owner else {
+        return Ok(());
+    };
+
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::lchown(path, Some(owner.uid.as_raw()), Some(owner.gid.as_raw())).map_err(
+            |e| {
+                anyhow::Error::new(e)
+                    .context(format!("Failed to change owner of {}", path.display()))
+            },
+        )
+    }
+    #[cfg(not(unix))]
+    {
+        Ok(())
+    }
+}
+
 pub fn write_file(path: &Path, content: &[u8]) -> Result<()> {
     std::fs::write(path, content).map_err(|e| {
         anyhow::Error::new(e).context(format!("Failed to write file {}", path.display()))